@@ -4,7 +4,7 @@ use tokio::io::{self, AsyncBufReadExt, BufReader};
 use tokio::sync::mpsc::{self, Receiver, Sender};
 
 use crate::{
-    command,
+    cfg, command,
     msg::{self, Msg},
     panels::panels_main,
     plugins::plugins_main,
@@ -60,9 +60,15 @@ impl App {
     pub async fn run(mut self) -> Result<(), Box<dyn std::error::Error>> {
         self.plugins.init().await;
 
+        let startup = cfg::startup();
+        if !startup.is_empty() {
+            command::source(&self.msg_tx, &startup);
+        }
+
         loop {
             tokio::select! {
                 Some(msg) = self.msg_rx.recv() => {
+                    self.plugins.record(&msg).await;
                     if msg.plugin == panels_main::NAME {
                         match &msg.data {
                             msg::Data::Devices(_devices) => {
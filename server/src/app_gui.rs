@@ -5,11 +5,12 @@ use tokio::{
     task,
 };
 
-use crate::{msg::Msg, panels::panels_main, plugins::plugins_main, KEY_SIZE};
+use crate::{cfg, command, msg::Msg, panels::panels_main, plugins::plugins_main, KEY_SIZE};
 
 pub struct App {
     panels: panels_main::Panels,
     plugins: plugins_main::Plugins,
+    msg_tx: Sender<Msg>,
     msg_rx: Receiver<Msg>,
     key_rx: Receiver<Event>,
 }
@@ -28,9 +29,15 @@ impl App {
             }
         });
 
+        let mut plugins = plugins_main::Plugins::new(msg_tx.clone());
+        for topic in panels_main::TOPICS {
+            plugins.add_subscription(topic, panels_main::NAME);
+        }
+
         Self {
             panels: panels_main::Panels::new(msg_tx.clone()),
-            plugins: plugins_main::Plugins::new(msg_tx.clone()),
+            plugins,
+            msg_tx,
             msg_rx,
             key_rx,
         }
@@ -43,11 +50,17 @@ impl App {
         self.panels.init().await;
         self.plugins.init().await;
 
+        let startup = cfg::startup();
+        if !startup.is_empty() {
+            command::source(&self.msg_tx, &startup);
+        }
+
         loop {
             terminal.draw(|frame| self.draw(frame))?;
 
             tokio::select! {
                 Some(msg) = self.msg_rx.recv() => {
+                    self.plugins.record(&msg).await;
                     if msg.plugin == panels_main::NAME {
                         self.panels.msg(&msg).await;
                     }
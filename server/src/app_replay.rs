@@ -0,0 +1,185 @@
+use std::path::PathBuf;
+
+use async_trait::async_trait;
+use tokio::sync::mpsc::{Receiver, Sender};
+
+use crate::{
+    msg::{self, Msg},
+    panels::panels_main,
+    plugins::{
+        plugin_alerts, plugin_mqtt, plugin_nas, plugin_ping, plugin_shell, plugin_stocks,
+        plugin_system, plugin_todos, plugin_weather, plugin_wol, plugin_worldtime, plugins_main,
+    },
+    recorder,
+};
+
+// Replay feeds the msgs of a recording, from mqtt, the web or this device, into
+// a headless Plugins in order. Msgs the plugins send while replaying are
+// printed but not dispatched, since they are in the recording themselves.
+// Side effects are isolated: the plugins which reach outside the device are
+// stood in for, and what the others persist goes to a scratch folder.
+const ISOLATED: &[&str] = &[
+    plugin_mqtt::NAME,
+    plugin_shell::NAME,
+    plugin_wol::NAME,
+    plugin_system::NAME,
+    plugin_ping::NAME,
+    plugin_nas::NAME,
+    plugin_weather::NAME,
+    plugin_stocks::NAME,
+    plugin_worldtime::NAME,
+    plugin_todos::NAME,
+    plugin_alerts::NAME,
+];
+
+fn is_replayed(msg: &Msg) -> bool {
+    // no panels in headless mode, and what was published is in the recording
+    // as each subscriber's copy
+    msg.plugin != panels_main::NAME && msg.plugin != msg::BUS
+}
+
+// in place of a plugin which reaches outside, what it would have run is printed
+struct Isolated {
+    name: &'static str,
+}
+
+#[async_trait]
+impl plugins_main::Plugin for Isolated {
+    fn name(&self) -> &str {
+        self.name
+    }
+
+    async fn msg(&mut self, msg: &Msg) -> bool {
+        println!("[replay] isolated: {} {:?}", msg.plugin, msg.data);
+        false
+    }
+}
+
+fn isolate(plugins: &mut plugins_main::Plugins) {
+    for name in ISOLATED {
+        plugins.replace(Box::new(Isolated { name }));
+    }
+}
+
+fn scratch() -> PathBuf {
+    std::env::temp_dir().join(format!("cng_replay_{}", std::process::id()))
+}
+
+pub struct App {
+    plugins: plugins_main::Plugins,
+    msg_rx: Receiver<Msg>,
+}
+
+impl App {
+    pub fn new(msg_tx: Sender<Msg>, msg_rx: Receiver<Msg>) -> Self {
+        let mut plugins = plugins_main::Plugins::new(msg_tx);
+        isolate(&mut plugins);
+
+        Self { plugins, msg_rx }
+    }
+
+    pub async fn run(mut self, path: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let records = recorder::load(path)?;
+
+        // every file the plugins use is relative to the working folder
+        let scratch = scratch();
+        std::fs::create_dir_all(&scratch)?;
+        std::env::set_current_dir(&scratch)?;
+        println!(
+            "Replaying {} msgs from {path} in {}",
+            records.len(),
+            scratch.display()
+        );
+
+        // drop what main sent before the replay started
+        while self.msg_rx.try_recv().is_ok() {}
+
+        for record in records {
+            let msg = record.into_msg();
+
+            if !is_replayed(&msg) {
+                continue;
+            }
+
+            if self.plugins.msg(&msg).await {
+                println!("[replay] quit");
+                break;
+            }
+
+            while let Ok(msg) = self.msg_rx.try_recv() {
+                println!("[replay] -> {}: {:?}", msg.plugin, msg.data);
+            }
+        }
+
+        println!("Replay done.");
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::msg::{Cmd, Data, DevInfo, Reply};
+    use crate::plugins::plugin_devices;
+
+    fn cmd(plugin: &str, action: &str) -> Msg {
+        Msg {
+            ts: 0,
+            plugin: plugin.to_owned(),
+            data: Data::Cmd(Cmd {
+                action: action.to_owned(),
+                reply: Reply::Device("pi5".to_owned()),
+                data: vec![],
+            }),
+        }
+    }
+
+    fn data(plugin: &str, data: Data) -> Msg {
+        Msg {
+            ts: 0,
+            plugin: plugin.to_owned(),
+            data,
+        }
+    }
+
+    #[test]
+    fn recorded_msgs_are_replayed() {
+        assert!(is_replayed(&cmd(plugin_devices::NAME, msg::ACT_SHOW)));
+        assert!(is_replayed(&cmd(plugin_shell::NAME, msg::ACT_CMD)));
+        assert!(is_replayed(&cmd(plugin_devices::NAME, msg::ACT_INIT)));
+        assert!(is_replayed(&data(
+            plugin_devices::NAME,
+            Data::DeviceUpdate(DevInfo::new("pi4"))
+        )));
+        // the subscribers' copies, not what was published
+        assert!(is_replayed(&data(plugin_nas::NAME, Data::Devices(vec![]))));
+        assert!(!is_replayed(&data(msg::BUS, Data::Devices(vec![]))));
+        assert!(!is_replayed(&data(
+            panels_main::NAME,
+            Data::Devices(vec![])
+        )));
+    }
+
+    #[tokio::test]
+    async fn what_reaches_outside_is_isolated() {
+        let (msg_tx, mut msg_rx) = tokio::sync::mpsc::channel(1024);
+        let mut plugins = plugins_main::Plugins::new(msg_tx);
+        isolate(&mut plugins);
+
+        let touched = scratch().join("touched");
+        let mut shell = cmd(plugin_shell::NAME, msg::ACT_CMD);
+        if let Data::Cmd(cmd) = &mut shell.data {
+            cmd.data = vec![format!("touch {}", touched.display())];
+        }
+        assert!(!plugins.msg(&shell).await);
+        assert!(!plugins.msg(&cmd(plugin_wol::NAME, msg::ACT_WAKE)).await);
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        assert!(!touched.exists());
+        assert!(msg_rx.try_recv().is_err());
+
+        // the others run
+        plugins.msg(&cmd(plugin_devices::NAME, msg::ACT_HELP)).await;
+        assert!(msg_rx.try_recv().is_ok());
+    }
+}
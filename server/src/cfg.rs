@@ -1,3 +1,4 @@
+use std::collections::BTreeMap;
 use std::fs::{self, File};
 use std::io::Write;
 use std::path::Path;
@@ -12,12 +13,42 @@ const KEY: &str = "an example very very secret key."; // length is fixed
 const SHELL: &str = "sh";
 pub const MODE_CLI: &str = "cli";
 pub const MODE_GUI: &str = "gui";
+pub const MODE_REPLAY: &str = "replay";
 const TRACE: u8 = 1;
+const RECORD: u8 = 0;
+pub const RECORD_FILE: &str = "./record/bus.jsonl";
+pub const SCHEDULER_FILE: &str = "./scheduler.json";
+pub const RULES_FILE: &str = "./rules.json";
+pub const ALERTS_FILE: &str = "./alerts.json";
+pub const DEVICES_FILE: &str = "./devices.json";
+// the hash of every shared file at the last NAS sync
+pub const SYNC_STATE_FILE: &str = "./sync_state.json";
+// on the NAS, the shared files deleted
+pub const TOMBSTONES_FILE: &str = "./tombstones.json";
+// the hash of every shared file by its size, mtime and inode
+pub const INDEX_FILE: &str = "./files_index.json";
+pub const HISTORY_FOLDER: &str = "./history";
+pub const METRICS_FOLDER: &str = "./metrics";
+pub const RELEASES_FOLDER: &str = "./releases";
+pub const UPGRADE_FILE: &str = "./upgrade.json";
+// run by utils/daemon.sh once a release is installed
+pub const BINARY_FILE: &str = "./cng";
+const STARTUP: &str = ""; // no startup script
+const STALE_AFTER: u64 = 15 * 60; // three system polls
 pub const DEF_NAS: &str = "pi5";
+const NAS_ADDRESS: &str = "tailscale";
+const NAS_TOMBSTONE_DAYS: u64 = 30;
+const NAS_HASH: &str = "md5";
+const NAS_BACKUP_DAILY: usize = 7;
+const NAS_BACKUP_WEEKLY: usize = 4;
+const NAS_BACKUP_MONTHLY: usize = 12;
+const NAS_IDLE_TIMEOUT: u64 = 600;
 
 pub const FILE_FOLDER: &str = "./shared";
 pub const NOTE_FOLDER: &str = "./shared/note";
 pub const UPLOAD_FOLDER: &str = "./shared/upload";
+// gitignore patterns of what the NAS sync leaves out
+pub const IGNORE_FILE: &str = "./shared/.cngignore";
 
 static INSTANCE: Lazy<Mutex<Cfg>> = Lazy::new(|| Mutex::new(Cfg::new()));
 
@@ -45,6 +76,76 @@ fn default_nas() -> String {
     DEF_NAS.to_string()
 }
 
+fn default_record() -> u8 {
+    RECORD
+}
+
+fn default_replay() -> String {
+    RECORD_FILE.to_string()
+}
+
+fn default_aliases() -> BTreeMap<String, String> {
+    BTreeMap::new()
+}
+
+fn default_startup() -> String {
+    STARTUP.to_string()
+}
+
+fn default_stale_after() -> u64 {
+    STALE_AFTER
+}
+
+fn default_tags() -> Vec<String> {
+    vec![]
+}
+
+fn default_nas_addresses() -> Vec<String> {
+    vec![NAS_ADDRESS.to_owned()]
+}
+
+fn default_nas_tombstone_days() -> u64 {
+    NAS_TOMBSTONE_DAYS
+}
+
+fn default_nas_roots() -> Vec<String> {
+    vec![]
+}
+
+fn default_nas_hash() -> String {
+    NAS_HASH.to_owned()
+}
+
+fn default_nas_backup_daily() -> usize {
+    NAS_BACKUP_DAILY
+}
+
+fn default_nas_backup_weekly() -> usize {
+    NAS_BACKUP_WEEKLY
+}
+
+fn default_nas_backup_monthly() -> usize {
+    NAS_BACKUP_MONTHLY
+}
+
+fn default_nas_idle_timeout() -> u64 {
+    NAS_IDLE_TIMEOUT
+}
+
+fn default_release_key() -> String {
+    String::new()
+}
+
+// random, so no two installs share it
+fn default_nas_secret() -> String {
+    let bytes: [u8; 32] = rand::random();
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn default_nas_key() -> String {
+    String::new()
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct Cfg {
     #[serde(default = "default_name")]
@@ -60,6 +161,56 @@ pub struct Cfg {
     db: String,
     #[serde(default = "default_nas")]
     nas: String,
+    // tried in order: an IP, a CIDR, an interface name or "tailscale"
+    #[serde(default = "default_nas_addresses")]
+    nas_addresses: Vec<String>,
+    // how long a deletion is kept for the devices offline meanwhile
+    #[serde(default = "default_nas_tombstone_days")]
+    nas_tombstone_days: u64,
+    // the folders of the share this device syncs, e.g. ["note"], all of it
+    // if empty; the NAS holds everything
+    #[serde(default = "default_nas_roots")]
+    nas_roots: Vec<String>,
+    // "md5" or the faster "blake3", as set on the NAS the devices follow
+    #[serde(default = "default_nas_hash")]
+    nas_hash: String,
+    // the snapshots the NAS keeps: the latest days, then one a week and one
+    // a month before those
+    #[serde(default = "default_nas_backup_daily")]
+    nas_backup_daily: usize,
+    #[serde(default = "default_nas_backup_weekly")]
+    nas_backup_weekly: usize,
+    #[serde(default = "default_nas_backup_monthly")]
+    nas_backup_monthly: usize,
+    // seconds the NAS waits for the next request, the device hashes its
+    // share in between
+    #[serde(default = "default_nas_idle_timeout")]
+    nas_idle_timeout: u64,
+    // only read on the NAS, the key of each device is derived from it
+    #[serde(default = "default_nas_secret")]
+    nas_secret: String,
+    // this device's key with the NAS, as `p nas key <name>` prints it there
+    #[serde(default = "default_nas_key")]
+    nas_key: String,
+    #[serde(default = "default_record")]
+    record: u8,
+    #[serde(default = "default_replay")]
+    replay: String,
+    #[serde(default = "default_aliases")]
+    aliases: BTreeMap<String, String>,
+    #[serde(default = "default_startup")]
+    startup: String,
+    #[serde(default = "default_stale_after")]
+    stale_after: u64,
+    // e.g. ["pi", "office"], for `ask @pi ...`
+    #[serde(default = "default_tags")]
+    tags: Vec<String>,
+    // hex ed25519 public key of the releases, no upgrade if empty
+    #[serde(default = "default_release_key")]
+    release_key: String,
+    // hex ed25519 secret key, only where releases are signed
+    #[serde(default = "default_release_key")]
+    release_secret: String,
 }
 
 impl Cfg {
@@ -75,6 +226,24 @@ impl Cfg {
                 trace: TRACE,
                 db: "mongodb://localhost:27017".to_owned(),
                 nas: DEF_NAS.to_owned(),
+                nas_addresses: default_nas_addresses(),
+                nas_tombstone_days: NAS_TOMBSTONE_DAYS,
+                nas_roots: default_nas_roots(),
+                nas_hash: NAS_HASH.to_owned(),
+                nas_backup_daily: NAS_BACKUP_DAILY,
+                nas_backup_weekly: NAS_BACKUP_WEEKLY,
+                nas_backup_monthly: NAS_BACKUP_MONTHLY,
+                nas_idle_timeout: NAS_IDLE_TIMEOUT,
+                nas_secret: default_nas_secret(),
+                nas_key: default_nas_key(),
+                record: RECORD,
+                replay: RECORD_FILE.to_owned(),
+                aliases: BTreeMap::new(),
+                startup: STARTUP.to_owned(),
+                stale_after: STALE_AFTER,
+                tags: vec![],
+                release_key: String::new(),
+                release_secret: String::new(),
             }
         } else {
             let file_content = fs::read_to_string(CFG_FILE).unwrap();
@@ -115,6 +284,78 @@ impl Cfg {
     fn nas(&self) -> &str {
         &self.nas
     }
+
+    fn nas_addresses(&self) -> &[String] {
+        &self.nas_addresses
+    }
+
+    fn nas_tombstone_days(&self) -> u64 {
+        self.nas_tombstone_days
+    }
+
+    fn nas_roots(&self) -> &[String] {
+        &self.nas_roots
+    }
+
+    fn nas_hash(&self) -> &str {
+        &self.nas_hash
+    }
+
+    fn nas_backup_daily(&self) -> usize {
+        self.nas_backup_daily
+    }
+
+    fn nas_backup_weekly(&self) -> usize {
+        self.nas_backup_weekly
+    }
+
+    fn nas_backup_monthly(&self) -> usize {
+        self.nas_backup_monthly
+    }
+
+    fn nas_idle_timeout(&self) -> u64 {
+        self.nas_idle_timeout
+    }
+
+    fn nas_secret(&self) -> &str {
+        &self.nas_secret
+    }
+
+    fn nas_key(&self) -> &str {
+        &self.nas_key
+    }
+
+    fn record(&self) -> u8 {
+        self.record
+    }
+
+    fn replay(&self) -> &str {
+        &self.replay
+    }
+
+    fn alias(&self, name: &str) -> Option<&String> {
+        self.aliases.get(name)
+    }
+
+    fn startup(&self) -> &str {
+        &self.startup
+    }
+
+    fn stale_after(&self) -> u64 {
+        self.stale_after
+    }
+
+    fn tags(&self) -> &[String] {
+        &self.tags
+    }
+
+    fn release_key(&self) -> &str {
+        &self.release_key
+    }
+
+    fn release_secret(&self) -> &str {
+        &self.release_secret
+    }
 }
 
 pub fn name() -> String {
@@ -151,3 +392,93 @@ pub fn nas() -> String {
     let cfg = Cfg::get_instance();
     cfg.nas().to_owned()
 }
+
+pub fn nas_addresses() -> Vec<String> {
+    let cfg = Cfg::get_instance();
+    cfg.nas_addresses().to_vec()
+}
+
+pub fn nas_tombstone_days() -> u64 {
+    let cfg = Cfg::get_instance();
+    cfg.nas_tombstone_days()
+}
+
+pub fn nas_roots() -> Vec<String> {
+    let cfg = Cfg::get_instance();
+    cfg.nas_roots().to_vec()
+}
+
+pub fn nas_hash() -> String {
+    let cfg = Cfg::get_instance();
+    cfg.nas_hash().to_owned()
+}
+
+pub fn nas_backup_daily() -> usize {
+    let cfg = Cfg::get_instance();
+    cfg.nas_backup_daily()
+}
+
+pub fn nas_backup_weekly() -> usize {
+    let cfg = Cfg::get_instance();
+    cfg.nas_backup_weekly()
+}
+
+pub fn nas_backup_monthly() -> usize {
+    let cfg = Cfg::get_instance();
+    cfg.nas_backup_monthly()
+}
+
+pub fn nas_idle_timeout() -> u64 {
+    let cfg = Cfg::get_instance();
+    cfg.nas_idle_timeout()
+}
+
+pub fn nas_secret() -> String {
+    let cfg = Cfg::get_instance();
+    cfg.nas_secret().to_owned()
+}
+
+pub fn nas_key() -> String {
+    let cfg = Cfg::get_instance();
+    cfg.nas_key().to_owned()
+}
+
+pub fn record() -> u8 {
+    let cfg = Cfg::get_instance();
+    cfg.record()
+}
+
+pub fn replay() -> String {
+    let cfg = Cfg::get_instance();
+    cfg.replay().to_owned()
+}
+
+pub fn alias(name: &str) -> Option<String> {
+    let cfg = Cfg::get_instance();
+    cfg.alias(name).cloned()
+}
+
+pub fn startup() -> String {
+    let cfg = Cfg::get_instance();
+    cfg.startup().to_owned()
+}
+
+pub fn stale_after() -> u64 {
+    let cfg = Cfg::get_instance();
+    cfg.stale_after()
+}
+
+pub fn tags() -> Vec<String> {
+    let cfg = Cfg::get_instance();
+    cfg.tags().to_vec()
+}
+
+pub fn release_key() -> String {
+    let cfg = Cfg::get_instance();
+    cfg.release_key().to_owned()
+}
+
+pub fn release_secret() -> String {
+    let cfg = Cfg::get_instance();
+    cfg.release_secret().to_owned()
+}
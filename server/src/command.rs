@@ -1,14 +1,21 @@
+use std::fs;
+
 use clap::{Parser, Subcommand};
+use log::Level::{Error, Info};
 use tokio::sync::mpsc::Sender;
 
 use crate::cfg;
-use crate::msg::{self, Msg, Reply};
-use crate::reply_me;
+use crate::msg::{self, log, Msg, Reply};
+use crate::{error, info, reply_me};
 
 pub const UNKNOWN_COMMAND: &str = "Unknown command. Input 'h' for help.";
 pub const ALL_TEXT: &str = "All";
 pub const EDITOR_TEXT: &str = "Editor, which is not supported in CLI mode.";
 
+const SOURCE: &str = "source";
+const WAIT: &str = "wait";
+const MAX_SOURCE_DEPTH: usize = 8;
+
 #[derive(Parser, Debug)]
 #[command(
     name = "Center NG",
@@ -34,6 +41,9 @@ pub enum Commands {
         action: String,
         data: Vec<String>,
     },
+    Source {
+        filename: String,
+    },
 }
 
 pub fn get_help() -> Vec<String> {
@@ -41,6 +51,8 @@ pub fn get_help() -> Vec<String> {
         "Commands:".to_owned(),
         "h    - Help".to_owned(),
         "q    - Quit".to_owned(),
+        "source <file> - Run the commands in file".to_owned(),
+        "<alias> ...   - Run an alias in cfg.json".to_owned(),
         "p <plugin> <action> ...".to_owned(),
         "    plugin: plugins, device, log, ...".to_owned(),
         "            use 'p plugins show' to get plugin list".to_owned(),
@@ -49,6 +61,8 @@ pub fn get_help() -> Vec<String> {
         "    p plugins show".to_owned(),
         "    p devices show".to_owned(),
         "    p devices show pi5".to_owned(),
+        "    p devices history pi5".to_owned(),
+        "    p devices metrics pi5 temperature 7d".to_owned(),
         "    p mqtt show".to_owned(),
         "    p mqtt ask pi5 p wol wake linds".to_owned(),
         "    p mqtt ask pi5 p system quit".to_owned(),
@@ -57,11 +71,165 @@ pub fn get_help() -> Vec<String> {
         "    p shell start".to_owned(),
         "    p shell cmd \"pwd\"".to_owned(),
         "    p shell stop".to_owned(),
+        "    source morning.cng".to_owned(),
+        "    p scheduler list".to_owned(),
+        "    p scheduler add wake \"0 7 * * 1-5\" p wol wake linds".to_owned(),
+        "    p scheduler add pi5 \"@every 1h\" p mqtt ask pi5 p system update".to_owned(),
     ]
 }
 
+// "p <plugin> <action> ..." as typed in the cli
+pub fn parse_p(cmd: &str) -> Result<(String, String, Vec<String>), String> {
+    let words = shlex::split(cmd).ok_or(format!("invalid quoting: {cmd:?}"))?;
+    match words.as_slice() {
+        [p, plugin, action, data @ ..] if p == "p" => {
+            Ok((plugin.clone(), action.clone(), data.to_vec()))
+        }
+        _ => Err(format!("cmd should be 'p <plugin> <action> ...': {cmd:?}")),
+    }
+}
+
+// replace the first word if it is an alias in cfg, the rest is appended
+pub fn expand_alias(line: &str) -> String {
+    let line = line.trim();
+    let (first, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+
+    match cfg::alias(first) {
+        Some(alias) if rest.is_empty() => alias,
+        Some(alias) => format!("{alias} {}", rest.trim_start()),
+        None => line.to_owned(),
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Step {
+    Wait(f64),
+    Cmd(String),
+}
+
+// a script is a command per line, with '#' comments, 'wait <secs>' and
+// nested 'source <file>'
+pub fn load_script(filename: &str) -> Result<Vec<Step>, String> {
+    let mut steps = vec![];
+    load_script_into(filename, 0, &mut steps)?;
+
+    Ok(steps)
+}
+
+fn load_script_into(filename: &str, depth: usize, steps: &mut Vec<Step>) -> Result<(), String> {
+    if depth >= MAX_SOURCE_DEPTH {
+        return Err(format!("{filename}: too many nested sources"));
+    }
+
+    let content =
+        fs::read_to_string(filename).map_err(|e| format!("Failed to read {filename}: {e}"))?;
+
+    for (idx, line) in content.lines().enumerate() {
+        let line = strip_comment(line).trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        let line = expand_alias(line);
+        let words =
+            shlex::split(&line).ok_or(format!("{filename}:{}: invalid quoting", idx + 1))?;
+
+        match words.as_slice() {
+            [wait, secs] if wait == WAIT => {
+                let secs = secs
+                    .parse::<f64>()
+                    .ok()
+                    .filter(|secs| *secs >= 0.0)
+                    .ok_or(format!("{filename}:{}: invalid wait: {secs:?}", idx + 1))?;
+                steps.push(Step::Wait(secs));
+            }
+            [source, source_filename] if source == SOURCE => {
+                load_script_into(source_filename, depth + 1, steps)?
+            }
+            [first, ..] if first == WAIT || first == SOURCE => {
+                return Err(format!("{filename}:{}: invalid line: {line:?}", idx + 1));
+            }
+            _ => steps.push(Step::Cmd(line)),
+        }
+    }
+
+    Ok(())
+}
+
+// from a '#' starting a word outside quotes, as shlex reads it
+fn strip_comment(line: &str) -> &str {
+    let mut quote = None;
+    let mut escaped = false;
+    let mut word_start = true;
+    for (idx, c) in line.char_indices() {
+        match (quote, c) {
+            _ if escaped => escaped = false,
+            (Some('\''), '\'') | (Some('"'), '"') => quote = None,
+            (Some('"'), '\\') | (None, '\\') => escaped = true,
+            (Some(_), _) => (),
+            (None, '\'') | (None, '"') => quote = Some(c),
+            (None, '#') if word_start => return &line[..idx],
+            _ => (),
+        }
+        word_start = quote.is_none() && c.is_whitespace();
+    }
+
+    line
+}
+
+// run a script in the background, a line at a time
+pub fn source(msg_tx: &Sender<Msg>, filename: &str) {
+    let msg_tx = msg_tx.clone();
+    let filename = filename.to_owned();
+
+    tokio::spawn(async move {
+        let steps = match load_script(&filename) {
+            Ok(steps) => steps,
+            Err(e) => {
+                error!(&msg_tx, format!("[{SOURCE}] {e}"));
+                return;
+            }
+        };
+
+        info!(&msg_tx, format!("[{SOURCE}] {filename} started."));
+
+        for step in steps {
+            match step {
+                Step::Wait(secs) => {
+                    tokio::time::sleep(tokio::time::Duration::from_secs_f64(secs)).await;
+                }
+                Step::Cmd(line) => run_script_line(&msg_tx, &line).await,
+            }
+        }
+
+        info!(&msg_tx, format!("[{SOURCE}] {filename} done."));
+    });
+}
+
+// only plugin commands make sense without a prompt
+async fn run_script_line(msg_tx: &Sender<Msg>, line: &str) {
+    let cli = shlex::split(&format!("cmd {line}")).and_then(|args| Cli::try_parse_from(args).ok());
+
+    match cli.and_then(|cli| cli.command) {
+        Some(Commands::P {
+            plugin,
+            action,
+            data,
+        }) => {
+            msg::cmd(msg_tx, reply_me!(), plugin, action, data).await;
+        }
+        _ => {
+            error!(
+                msg_tx,
+                format!("[{SOURCE}] not a 'p' command, skipped: {line:?}")
+            );
+        }
+    }
+}
+
 pub async fn run(msg_tx: &Sender<Msg>, cmd: &str) -> bool {
     let mut ret = false;
+    let cmd = expand_alias(cmd);
     let args = shlex::split(&format!("cmd {cmd}"))
         .ok_or("error: Invalid quoting")
         .unwrap();
@@ -94,6 +262,9 @@ pub async fn run(msg_tx: &Sender<Msg>, cmd: &str) -> bool {
         }) => {
             msg::cmd(msg_tx, reply_me!(), plugin, action, data).await;
         }
+        Some(Commands::Source { filename }) => {
+            source(msg_tx, &filename);
+        }
         None => {
             println!(); // cli mode
         }
@@ -101,3 +272,93 @@ pub async fn run(msg_tx: &Sender<Msg>, cmd: &str) -> bool {
 
     ret
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::harness::TempDir;
+
+    fn script(dir: &TempDir, name: &str, content: &str) -> String {
+        dir.write(name, content).to_string_lossy().to_string()
+    }
+
+    #[test]
+    fn load_script_with_wait_comments_and_source() {
+        let dir = TempDir::new("command");
+        let inner = script(&dir, "inner.cng", "p weather update\n");
+        let outer = script(
+            &dir,
+            "outer.cng",
+            &format!(
+                "# morning\n\np mqtt ask pi5 p wol wake linds\n  wait 2.5\nsource {inner}\np shell cmd \"ls -l\" # list\n"
+            ),
+        );
+
+        let steps = load_script(&outer).unwrap();
+
+        assert_eq!(
+            steps,
+            vec![
+                Step::Cmd("p mqtt ask pi5 p wol wake linds".to_owned()),
+                Step::Wait(2.5),
+                Step::Cmd("p weather update".to_owned()),
+                Step::Cmd("p shell cmd \"ls -l\"".to_owned()),
+            ]
+        );
+    }
+
+    #[test]
+    fn comments_outside_quotes_are_stripped() {
+        assert_eq!(strip_comment("# all"), "");
+        assert_eq!(
+            strip_comment("p shell cmd \"echo #1\" # note"),
+            "p shell cmd \"echo #1\" "
+        );
+        assert_eq!(strip_comment("p shell cmd 'a # b'"), "p shell cmd 'a # b'");
+        assert_eq!(strip_comment("p shell cmd a#b"), "p shell cmd a#b");
+        assert_eq!(strip_comment("p shell cmd \\#a # b"), "p shell cmd \\#a ");
+    }
+
+    #[test]
+    fn load_script_errors() {
+        assert!(load_script("/nonexistent/cng.script").is_err());
+
+        let dir = TempDir::new("command_errors");
+        let path = script(&dir, "wait.cng", "wait soon\n");
+        let e = load_script(&path).unwrap_err();
+        assert!(e.ends_with(":1: invalid wait: \"soon\""));
+
+        let path = script(&dir, "loop.cng", "");
+        fs::write(&path, format!("source {path}\n")).unwrap();
+        let e = load_script(&path).unwrap_err();
+        assert!(e.ends_with("too many nested sources"));
+    }
+
+    #[test]
+    fn parse_p_cmd() {
+        assert_eq!(
+            parse_p("p mqtt ask pi5 p system update"),
+            Ok((
+                "mqtt".to_owned(),
+                "ask".to_owned(),
+                vec![
+                    "pi5".to_owned(),
+                    "p".to_owned(),
+                    "system".to_owned(),
+                    "update".to_owned()
+                ]
+            ))
+        );
+        assert_eq!(
+            parse_p("p shell cmd \"ls -l\"").unwrap().2,
+            vec!["ls -l".to_owned()]
+        );
+        assert!(parse_p("wol wake linds").is_err());
+        assert!(parse_p("p wol").is_err());
+    }
+
+    #[test]
+    fn expand_alias_keeps_unknown_lines() {
+        assert_eq!(expand_alias("  p devices show "), "p devices show");
+    }
+}
@@ -0,0 +1,320 @@
+// In-process harness for tests: a Plugins on an in-memory bus, without init,
+// so nothing touches the network, MongoDB or the disk behind our back. The
+// todos are kept in memory instead of MongoDB, the weather and the stocks are
+// answered by FakeHttp instead of their sites.
+//
+// The mqtt plugin is replaced by a loopback broker: an ask is encrypted,
+// published and parsed back through mqtt::utils as if it came from the broker,
+// a broadcast ask is expanded by the devices plugin, a reply to another device
+// is captured.
+
+use std::fs;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use rumqttc::{Event, Packet, Publish, QoS};
+use tokio::sync::mpsc::{self, Receiver, Sender};
+
+use crate::cfg;
+use crate::msg::{self, Cmd, Data, Log, Msg, Reply};
+use crate::panels::panels_main;
+use crate::plugins::nas::files_data::{FileData, FilesData};
+use crate::plugins::nas::index;
+use crate::plugins::plugin_todos::{self, Store, Todo};
+use crate::plugins::{mqtt, plugin_devices, plugin_log, plugin_mqtt, plugins_main};
+use crate::plugins::{plugin_stocks, plugin_weather};
+use crate::utils::{self, Fetch};
+
+const MSG_SIZE: usize = 4096;
+
+// MongoDB for the todos plugin
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    todos: Mutex<Vec<Todo>>,
+}
+
+#[async_trait]
+impl Store for MemoryStore {
+    async fn find(&self) -> Result<Vec<Todo>, String> {
+        Ok(self.todos.lock().unwrap().clone())
+    }
+
+    async fn insert(&self, todo: Todo) -> Result<(), String> {
+        self.todos.lock().unwrap().push(todo);
+        Ok(())
+    }
+}
+
+// the weather and the stock sites, 2330 is the only stock
+#[derive(Debug)]
+pub struct FakeHttp;
+
+pub const FAKE_WEATHER: &str = r#"{
+    "current_weather": {"time": "2025-05-01T10:00", "temperature": 27.5, "weathercode": 3},
+    "daily": {
+        "time": ["2025-05-01", "2025-05-02"],
+        "temperature_2m_max": [30.1, 29.0],
+        "temperature_2m_min": [22.3, 21.8],
+        "precipitation_probability_max": [10, 80],
+        "weather_code": [3, 61]
+    }
+}"#;
+
+pub const FAKE_STOCK: &str = r#"{"msgArray": [{
+    "n": "台積電", "z": "950.00", "h": "955.00", "l": "940.00",
+    "y": "945.00", "d": "20250501", "t": "13:30:00"
+}]}"#;
+
+#[async_trait]
+impl Fetch for FakeHttp {
+    async fn get(&self, url: &str, _timeout: u64) -> Result<String, String> {
+        if url.starts_with("https://api.open-meteo.com/") {
+            Ok(FAKE_WEATHER.to_owned())
+        } else if url.ends_with("ex_ch=tse_2330.tw") {
+            Ok(FAKE_STOCK.to_owned())
+        } else if url.starts_with("https://mis.twse.com.tw/") {
+            Ok(r#"{"msgArray": []}"#.to_owned())
+        } else {
+            Err(format!("{url} is not faked"))
+        }
+    }
+}
+
+// a folder of its own under the temp dir, gone once dropped even if the test
+// fails
+pub struct TempDir {
+    path: PathBuf,
+}
+
+impl TempDir {
+    pub fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("cng_{name}_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+
+        Self { path }
+    }
+
+    // its folders are made as needed
+    pub fn write(&self, relative: &str, content: impl AsRef<[u8]>) -> PathBuf {
+        let path = self.path.join(relative);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, content).unwrap();
+
+        path
+    }
+}
+
+impl Deref for TempDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
+
+// a share as the NAS or a device lists it, (filename, hash, modified)
+pub fn files_data(files: &[(&str, &str, u64)]) -> FilesData {
+    FilesData {
+        algorithm: index::MD5.to_owned(),
+        files_data: files
+            .iter()
+            .map(|(filename, hash, modified)| FileData {
+                filename: filename.to_string(),
+                hash: hash.to_string(),
+                modified: *modified,
+            })
+            .collect(),
+        tombstones: vec![],
+    }
+}
+
+pub struct Harness {
+    pub plugins: plugins_main::Plugins,
+    pub msg_tx: Sender<Msg>,
+    msg_rx: Receiver<Msg>,
+    // logs replied to myself
+    pub logs: Vec<Log>,
+    // (device, msg) replied to other devices
+    pub replies: Vec<(String, String)>,
+    // asks published to other devices
+    pub asks: Vec<(String, String)>,
+    // everything published on the bus
+    pub published: Vec<Data>,
+}
+
+impl Harness {
+    pub fn new() -> Self {
+        let (msg_tx, msg_rx) = mpsc::channel(MSG_SIZE);
+
+        let mut plugins = plugins_main::Plugins::new(msg_tx.clone());
+        plugins.replace(Box::new(plugin_todos::Plugin::with_store(
+            msg_tx.clone(),
+            Box::new(MemoryStore::default()),
+        )));
+        plugins.replace(Box::new(plugin_weather::Plugin::with_fetcher(
+            msg_tx.clone(),
+            Arc::new(FakeHttp),
+        )));
+        plugins.replace(Box::new(plugin_stocks::Plugin::with_fetcher(
+            msg_tx.clone(),
+            Arc::new(FakeHttp),
+        )));
+
+        Self {
+            plugins,
+            msg_tx,
+            msg_rx,
+            logs: vec![],
+            replies: vec![],
+            asks: vec![],
+            published: vec![],
+        }
+    }
+
+    pub async fn send(&mut self, plugin: &str, data: Data) {
+        self.msg_tx
+            .send(Msg {
+                ts: utils::ts(),
+                plugin: plugin.to_owned(),
+                data,
+            })
+            .await
+            .unwrap();
+
+        self.run().await;
+    }
+
+    pub async fn cmd(&mut self, plugin: &str, action: &str, data: &[&str]) {
+        msg::cmd(
+            &self.msg_tx,
+            Reply::Device(cfg::name()),
+            plugin.to_owned(),
+            action.to_owned(),
+            data.iter().map(|s| s.to_string()).collect(),
+        )
+        .await;
+
+        self.run().await;
+    }
+
+    // run a cmd as the web api does and collect what is sent back
+    pub async fn web_cmd(
+        &mut self,
+        plugin: &str,
+        action: &str,
+        data: &[&str],
+    ) -> Vec<serde_json::Value> {
+        let (resp_tx, mut resp_rx) = mpsc::channel(MSG_SIZE);
+
+        msg::cmd(
+            &self.msg_tx,
+            Reply::Web(resp_tx),
+            plugin.to_owned(),
+            action.to_owned(),
+            data.iter().map(|s| s.to_string()).collect(),
+        )
+        .await;
+
+        self.run().await;
+
+        let mut values = vec![];
+        while let Ok(value) = resp_rx.try_recv() {
+            values.push(value);
+        }
+
+        values
+    }
+
+    // dispatch until the bus is idle
+    pub async fn run(&mut self) {
+        while let Ok(msg) = self.msg_rx.try_recv() {
+            self.dispatch(msg).await;
+        }
+    }
+
+    // as run, letting what a plugin spawned finish first
+    pub async fn settle(&mut self) {
+        for _ in 0..100 {
+            tokio::task::yield_now().await;
+            self.run().await;
+        }
+    }
+
+    async fn dispatch(&mut self, msg: Msg) {
+        match msg.plugin.as_str() {
+            plugin_mqtt::NAME => self.broker(msg).await,
+            plugin_log::NAME => {
+                if let Data::Log(log) = msg.data {
+                    self.logs.push(log);
+                }
+            }
+            panels_main::NAME => (),
+            _ => {
+                if msg.plugin == msg::BUS {
+                    self.published.push(msg.data.clone());
+                }
+                self.plugins.msg(&msg).await;
+            }
+        }
+    }
+
+    async fn broker(&mut self, msg: Msg) {
+        let cmd = match msg.data {
+            Data::Cmd(cmd) => cmd,
+            _ => return,
+        };
+
+        match cmd.action.as_str() {
+            msg::ACT_ASK => self.broker_ask(cmd).await,
+            msg::ACT_REPLY => {
+                if let Reply::Device(device) = cmd.reply {
+                    self.replies.push((device, cmd.data.join(" ")));
+                }
+            }
+            _ => (),
+        }
+    }
+
+    async fn broker_ask(&mut self, cmd: Cmd) {
+        let target_device = cmd.data[0].clone();
+        if mqtt::utils::is_broadcast(&target_device) {
+            msg::cmd(
+                &self.msg_tx,
+                cmd.reply,
+                plugin_devices::NAME.to_owned(),
+                msg::ACT_ASK.to_owned(),
+                cmd.data,
+            )
+            .await;
+            return;
+        }
+
+        let payload = mqtt::utils::ask_payload(&cfg::name(), &cmd.data[1..]);
+
+        if target_device != cfg::name() {
+            self.asks.push((target_device, payload));
+            return;
+        }
+
+        let enc_payload = utils::encrypt(&cfg::key(), &payload).unwrap();
+        let publish = Publish::new(
+            format!("tln/{target_device}/ask"),
+            QoS::AtMostOnce,
+            enc_payload,
+        );
+        mqtt::utils::process_event(&self.msg_tx, Event::Incoming(Packet::Publish(publish))).await;
+    }
+
+    pub fn log_msgs(&self) -> Vec<String> {
+        self.logs.iter().map(|l| l.msg.clone()).collect()
+    }
+}
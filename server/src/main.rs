@@ -3,11 +3,16 @@ use tokio::sync::mpsc;
 
 mod app_cli;
 mod app_gui;
+mod app_replay;
 mod cfg;
 mod command;
+#[cfg(test)]
+mod harness;
 mod msg;
 mod panels;
 mod plugins;
+mod recorder;
+mod shared;
 mod utils;
 mod web;
 
@@ -27,10 +32,13 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     info!(&msg_tx, format!("Welcome to {}!", cfg::name()));
 
-    web::web_main::run(msg_tx.clone()).await?;
-
     let mode = cfg::mode();
 
+    // replay is headless, no web
+    if mode != cfg::MODE_REPLAY {
+        web::web_main::run(msg_tx.clone()).await?;
+    }
+
     match mode.as_str() {
         cfg::MODE_GUI => {
             let terminal = ratatui::init();
@@ -40,6 +48,14 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         cfg::MODE_CLI => {
             let _app_result = app_cli::App::new(msg_tx, msg_rx).run().await;
         }
+        cfg::MODE_REPLAY => {
+            if let Err(e) = app_replay::App::new(msg_tx, msg_rx)
+                .run(&cfg::replay())
+                .await
+            {
+                println!("replay failed: {e}");
+            }
+        }
         _ => {
             println!("unknown mode: {}", mode);
         }
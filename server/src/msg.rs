@@ -1,4 +1,7 @@
-use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt;
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use tokio::sync::mpsc::Sender;
 
 use crate::cfg;
@@ -7,7 +10,7 @@ use crate::plugins::{plugin_devices, plugin_log, plugin_mqtt};
 use crate::utils;
 
 #[allow(clippy::large_enum_variant)]
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum Data {
     Log(Log),
     Devices(Vec<DevInfo>),
@@ -17,6 +20,22 @@ pub enum Data {
     Worldtime(Vec<Worldtime>),
     Cmd(Cmd),
     Stocks(Vec<utils::Stock>),
+    FileEvent(FileEvent),
+}
+
+impl Data {
+    // topic of the data when it is published on the bus
+    pub fn topic(&self) -> Option<&'static str> {
+        match self {
+            Data::Log(_) => Some(TOPIC_LOG),
+            Data::Devices(_) => Some(TOPIC_DEVICES),
+            Data::Weather(_) => Some(TOPIC_WEATHER),
+            Data::Worldtime(_) => Some(TOPIC_WORLDTIME),
+            Data::Stocks(_) => Some(TOPIC_STOCKS),
+            Data::FileEvent(_) => Some(TOPIC_FILE),
+            _ => None,
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -28,9 +47,13 @@ pub struct Msg {
 
 //              plugin      data[0]         data[1]         data[2]         data[3] data[4]
 //  show        devices     device (opt)    -               -               -       -
+//  history     devices     device          count (opt)     -               -       -
+//  metrics     devices     device          metric (opt)    range (opt)     -       -
 //  show        others      -               -               -               -       -
 //  init        all         -               -               -               -       -
 //  ask         mqtt        target_device   p               plugin          action  -
+//  ask         devices     @tag or *       p               plugin          action  -
+//  reply       devices     from_device     level           msg             -       -
 //  reply       all         level           msg             -               -       -
 //  quit        all         -               -               -               -       -
 //  publish     mqtt        topic           retain          payload         -       -
@@ -39,6 +62,10 @@ pub struct Msg {
 //  ping        ping        ip              -               -               -       -
 //  update      system      -               -               -               -       -
 //  update_item system      item            value           -               -       -
+//  upgrade     system      version         -               -               -       -
+//  release     system      version         binary          -               -       -
+//  tick        system      -               -               -               -       -
+//  versions    devices     -               -               -               -       -
 //  start       shell       -               -               -               -       -
 //  cmd         shell       cmd             -               -               -       -
 //  stop        shell       -               -               -               -       -
@@ -49,6 +76,61 @@ pub struct Msg {
 //  stop        file        -               -               -               -       -
 //  worldtime   worldtime   name            datetime        -               -       -
 //  add         todos       title           desc            priority        -       -
+//  subscribe   plugins     topic           subscriber      -               -       -
+//  unsubscribe plugins     topic           subscriber      -               -       -
+//  record      plugins     start/stop      -               -               -       -
+//  poll        weather     -               -               -               -       -
+//  poll        worldtime   -               -               -               -       -
+//  poll        stocks      -               -               -               -       -
+//  poll        system      -               -               -               -       -
+//  list        scheduler   -               -               -               -       -
+//  add         scheduler   name            schedule        p               plugin  action
+//  remove      scheduler   name            -               -               -       -
+//  run-now     scheduler   name            -               -               -       -
+//  tick        scheduler   -               -               -               -       -
+//  tick        devices     -               -               -               -       -
+//  list        rules       -               -               -               -       -
+//  reload      rules       -               -               -               -       -
+//  list        alerts      -               -               -               -       -
+//  reload      alerts      -               -               -               -       -
+//  test        alerts      channel (opt)   ...             -               -       -
+
+// a msg sent to BUS is published to all subscribers of its topic
+pub const BUS: &str = "bus";
+
+pub const TOPIC_LOG: &str = "log";
+pub const TOPIC_DEVICES: &str = "devices";
+pub const TOPIC_WEATHER: &str = "weather";
+pub const TOPIC_WORLDTIME: &str = "worldtime";
+pub const TOPIC_STOCKS: &str = "stocks";
+pub const TOPIC_FILE: &str = "file";
+
+// telemetry published by plugin_system as tln/<name>/<key>, any other key is
+// a custom metric
+pub const TM_APP_UPTIME: &str = "app_uptime";
+pub const TM_HOST_UPTIME: &str = "host_uptime";
+pub const TM_VERSION: &str = "version";
+pub const TM_TEMPERATURE: &str = "temperature";
+pub const TM_WEATHER: &str = "weather";
+pub const TM_TAILSCALE_IP: &str = "tailscale_ip";
+pub const TM_OS: &str = "os";
+pub const TM_CPU_ARCH: &str = "cpu_arch";
+pub const TM_CPU_USAGE: &str = "cpu_usage";
+pub const TM_MEMORY_USAGE: &str = "memory_usage";
+pub const TM_DISK_USAGE: &str = "disk_usage";
+pub const TM_SWAP_USAGE: &str = "swap_usage";
+pub const TM_PROCESSES: &str = "processes";
+pub const TM_LOAD_1: &str = "load.1";
+pub const TM_LOAD_5: &str = "load.5";
+pub const TM_LOAD_15: &str = "load.15";
+// comma separated, from cfg
+pub const TM_TAGS: &str = "tags";
+pub const TM_ADDRESSES: &str = "addresses";
+// per core, disk and interface: cpu.<n>, disk.<mount>.usage, disk.<mount>.free,
+// net.<interface>.rx and net.<interface>.tx
+pub const TM_CPU: &str = "cpu";
+pub const TM_DISK: &str = "disk";
+pub const TM_NET: &str = "net";
 
 pub const ACT_SHOW: &str = "show";
 pub const ACT_INIT: &str = "init";
@@ -74,6 +156,24 @@ pub const ACT_HELP: &str = "help";
 pub const ACT_ADD: &str = "add";
 pub const ACT_NAS: &str = "nas";
 pub const ACT_STOCK: &str = "stock";
+pub const ACT_SUBSCRIBE: &str = "subscribe";
+pub const ACT_UNSUBSCRIBE: &str = "unsubscribe";
+pub const ACT_RECORD: &str = "record";
+pub const ACT_POLL: &str = "poll";
+pub const ACT_LIST: &str = "list";
+pub const ACT_REMOVE: &str = "remove";
+pub const ACT_RUN_NOW: &str = "run-now";
+pub const ACT_TICK: &str = "tick";
+pub const ACT_RELOAD: &str = "reload";
+pub const ACT_HISTORY: &str = "history";
+pub const ACT_METRICS: &str = "metrics";
+pub const ACT_TEST: &str = "test";
+pub const ACT_UPGRADE: &str = "upgrade";
+pub const ACT_RELEASE: &str = "release";
+pub const ACT_VERSIONS: &str = "versions";
+pub const ACT_BACKUPS: &str = "backups";
+pub const ACT_RESTORE: &str = "restore";
+pub const ACT_KEY: &str = "key";
 
 #[derive(Debug, Clone)]
 pub enum Reply {
@@ -81,39 +181,196 @@ pub enum Reply {
     Web(Sender<serde_json::Value>),
 }
 
-#[derive(Debug, Clone)]
+// Reply::Web holds a channel which can't be recorded, so it is serialized as
+// REPLY_WEB and deserialized as a reply to myself.
+const REPLY_WEB: &str = "@web";
+
+impl Serialize for Reply {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Reply::Device(device) => serializer.serialize_str(device),
+            Reply::Web(_) => serializer.serialize_str(REPLY_WEB),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Reply {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let device = String::deserialize(deserializer)?;
+        if device == REPLY_WEB {
+            Ok(Reply::Device(cfg::name()))
+        } else {
+            Ok(Reply::Device(device))
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Cmd {
     pub reply: Reply,
     pub action: String,
     pub data: Vec<String>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Log {
     pub level: log::Level,
     pub msg: String,
 }
 
-#[derive(Debug, Clone, Serialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Value {
+    Bool(bool),
+    Number(f64),
+    Text(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Metric {
+    pub value: Value,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub unit: Option<String>,
+}
+
+impl Metric {
+    pub fn new(value: Value, unit: Option<&str>) -> Self {
+        Self {
+            value,
+            unit: unit.map(|u| u.to_owned()),
+        }
+    }
+
+    // a payload is either {"value": .., "unit": ..} or a plain value, the
+    // built-in keys have a default unit
+    pub fn parse(key: &str, payload: &str) -> Self {
+        if let Ok(metric) = serde_json::from_str::<Metric>(payload) {
+            return metric;
+        }
+
+        let value = match payload.trim() {
+            _ if is_text(key) => Value::Text(payload.to_owned()),
+            "true" => Value::Bool(true),
+            "false" => Value::Bool(false),
+            t => match t.parse::<f64>() {
+                Ok(n) if n.is_finite() => Value::Number(n),
+                _ => Value::Text(payload.to_owned()),
+            },
+        };
+
+        Self::new(value, default_unit(key))
+    }
+
+    pub fn number(&self) -> Option<f64> {
+        match self.value {
+            Value::Number(n) => Some(n),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Metric {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let unit = self.unit.as_deref().unwrap_or("");
+        match &self.value {
+            Value::Bool(b) => write!(f, "{b}"),
+            Value::Text(t) => write!(f, "{t}{unit}"),
+            Value::Number(n) if unit == "s" => write!(f, "{}", utils::uptime_str(*n as u64)),
+            Value::Number(n) if unit == "B" => write!(f, "{}", utils::size_str(*n)),
+            Value::Number(n) if unit == "B/s" => write!(f, "{}/s", utils::size_str(*n)),
+            Value::Number(n) if n.fract() == 0.0 => write!(f, "{n:.0}{unit}"),
+            Value::Number(n) => write!(f, "{n:.1}{unit}"),
+        }
+    }
+}
+
+// a version "1.0" or a tag "10" is still a description, never a reading
+fn is_text(key: &str) -> bool {
+    matches!(
+        key,
+        TM_VERSION | TM_OS | TM_TAILSCALE_IP | TM_CPU_ARCH | TM_WEATHER | TM_TAGS
+    )
+}
+
+fn default_unit(key: &str) -> Option<&'static str> {
+    match key {
+        TM_APP_UPTIME | TM_HOST_UPTIME => Some("s"),
+        TM_TEMPERATURE => Some("°C"),
+        TM_CPU_USAGE | TM_MEMORY_USAGE | TM_DISK_USAGE | TM_SWAP_USAGE => Some("%"),
+        _ => None,
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct DevInfo {
     pub ts: u64,
     pub name: String,
     pub onboard: Option<bool>,
-    pub app_uptime: Option<u64>,
-    pub host_uptime: Option<u64>,
-    pub version: Option<String>,
-    pub temperature: Option<f32>,
-    pub os: Option<String>,
-    pub cpu_arch: Option<String>,
-    pub cpu_usage: Option<f32>,
-    pub memory_usage: Option<f32>,
-    pub disk_usage: Option<f32>,
-    pub weather: Option<String>,
     pub last_seen: Option<u64>,
-    pub tailscale_ip: Option<String>,
+    // onboard but silent for longer than cfg stale_after
+    #[serde(default)]
+    pub stale: bool,
+    #[serde(default)]
+    pub telemetry: BTreeMap<String, Metric>,
 }
 
-#[derive(Debug, Clone, Serialize)]
+impl DevInfo {
+    pub fn new(name: &str) -> Self {
+        Self {
+            ts: utils::ts(),
+            name: name.to_owned(),
+            onboard: None,
+            last_seen: None,
+            stale: false,
+            telemetry: BTreeMap::new(),
+        }
+    }
+
+    pub fn number(&self, key: &str) -> Option<f64> {
+        self.telemetry.get(key).and_then(|m| m.number())
+    }
+
+    // the raw text of a value, e.g. an ip or a version
+    pub fn text(&self, key: &str) -> Option<String> {
+        self.telemetry.get(key).map(|m| match &m.value {
+            Value::Text(t) => t.clone(),
+            Value::Number(n) => n.to_string(),
+            Value::Bool(b) => b.to_string(),
+        })
+    }
+
+    // a comma separated text
+    pub fn list(&self, key: &str) -> Vec<String> {
+        self.text(key)
+            .map(|t| {
+                t.split(',')
+                    .map(|item| item.trim().to_owned())
+                    .filter(|item| !item.is_empty())
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    pub fn tags(&self) -> Vec<String> {
+        self.list(TM_TAGS)
+    }
+
+    // formatted with the unit, "n/a" if unknown
+    pub fn display(&self, key: &str) -> String {
+        self.telemetry
+            .get(key)
+            .map(|m| m.to_string())
+            .unwrap_or("n/a".to_owned())
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileEvent {
+    pub action: String, // create, modify or remove
+    pub filename: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct City {
     pub name: String,
     pub latitude: f32,
@@ -121,7 +378,7 @@ pub struct City {
     pub weather: Option<utils::Weather>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Worldtime {
     pub name: String,
     pub timezone: String,
@@ -266,14 +523,7 @@ pub async fn log(msg_tx: &Sender<Msg>, reply: Reply, level: log::Level, msg: Str
 }
 
 pub async fn devices(msg_tx: &Sender<Msg>, devices: Vec<DevInfo>) {
-    msg_tx
-        .send(Msg {
-            ts: utils::ts(),
-            plugin: panels_main::NAME.to_owned(),
-            data: Data::Devices(devices),
-        })
-        .await
-        .unwrap();
+    publish(msg_tx, Data::Devices(devices)).await;
 }
 
 pub async fn device_countdown(msg_tx: &Sender<Msg>) {
@@ -299,25 +549,11 @@ pub async fn device_update(msg_tx: &Sender<Msg>, device: DevInfo) {
 }
 
 pub async fn weather(msg_tx: &Sender<Msg>, weather: Vec<City>) {
-    msg_tx
-        .send(Msg {
-            ts: utils::ts(),
-            plugin: panels_main::NAME.to_owned(),
-            data: Data::Weather(weather),
-        })
-        .await
-        .unwrap();
+    publish(msg_tx, Data::Weather(weather)).await;
 }
 
 pub async fn worldtime(msg_tx: &Sender<Msg>, worldtime: Vec<Worldtime>) {
-    msg_tx
-        .send(Msg {
-            ts: utils::ts(),
-            plugin: panels_main::NAME.to_owned(),
-            data: Data::Worldtime(worldtime),
-        })
-        .await
-        .unwrap();
+    publish(msg_tx, Data::Worldtime(worldtime)).await;
 }
 
 #[allow(clippy::too_many_arguments)]
@@ -343,11 +579,26 @@ pub async fn cmd(
 }
 
 pub async fn stocks(msg_tx: &Sender<Msg>, stocks: Vec<utils::Stock>) {
+    publish(msg_tx, Data::Stocks(stocks)).await;
+}
+
+pub async fn file_event(msg_tx: &Sender<Msg>, action: &str, filename: &str) {
+    publish(
+        msg_tx,
+        Data::FileEvent(FileEvent {
+            action: action.to_owned(),
+            filename: filename.to_owned(),
+        }),
+    )
+    .await;
+}
+
+pub async fn publish(msg_tx: &Sender<Msg>, data: Data) {
     msg_tx
         .send(Msg {
             ts: utils::ts(),
-            plugin: panels_main::NAME.to_owned(),
-            data: Data::Stocks(stocks),
+            plugin: BUS.to_owned(),
+            data,
         })
         .await
         .unwrap();
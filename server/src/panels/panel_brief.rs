@@ -8,8 +8,8 @@ use crate::cfg;
 use crate::command::{self, Cli, Commands};
 use crate::msg::{self, log, Data, Msg, Reply};
 use crate::panels::panels_main::{self, PanelInfo, Popup};
-use crate::utils;
 use crate::{error, info, init, reply_me, unknown};
+use crate::{shared, utils};
 
 pub const NAME: &str = "Brief";
 
@@ -118,20 +118,16 @@ impl panels_main::Panel for Panel {
                 KeyCode::Backspace => {
                     myself.panel_info.input.pop();
                 }
-                KeyCode::Up => {
-                    if myself.history_index > 0 {
-                        myself.history_index -= 1;
-                        myself.panel_info.input = myself.history[myself.history_index].clone();
-                    }
+                KeyCode::Up if myself.history_index > 0 => {
+                    myself.history_index -= 1;
+                    myself.panel_info.input = myself.history[myself.history_index].clone();
                 }
-                KeyCode::Down => {
+                KeyCode::Down if myself.history_index < myself.history.len() => {
+                    myself.history_index += 1;
                     if myself.history_index < myself.history.len() {
-                        myself.history_index += 1;
-                        if myself.history_index < myself.history.len() {
-                            myself.panel_info.input = myself.history[myself.history_index].clone();
-                        } else {
-                            myself.panel_info.input.clear();
-                        }
+                        myself.panel_info.input = myself.history[myself.history_index].clone();
+                    } else {
+                        myself.panel_info.input.clear();
                     }
                 }
                 _ => {}
@@ -220,10 +216,8 @@ impl panels_main::Panel for Panel {
                         active_popup.output[cursor_y].push_str(&line);
                     }
                 }
-                KeyCode::Delete => {
-                    if cursor_x < active_popup.output[cursor_y].len() {
-                        active_popup.output[cursor_y].remove(cursor_x);
-                    }
+                KeyCode::Delete if cursor_x < active_popup.output[cursor_y].len() => {
+                    active_popup.output[cursor_y].remove(cursor_x);
                 }
                 KeyCode::Tab => {
                     let spaces_to_add = 4 - (cursor_x % 4);
@@ -275,6 +269,7 @@ impl panels_main::Panel for Panel {
 
     async fn run(&mut self, cmd: &str) -> bool {
         let mut ret = false;
+        let cmd = command::expand_alias(cmd);
         let args = shlex::split(&format!("cmd {cmd}"))
             .ok_or("error: Invalid quoting")
             .unwrap();
@@ -302,6 +297,15 @@ impl panels_main::Panel for Panel {
                 ret = true;
             }
             Some(Commands::E { filename }) => {
+                let filename = filename.unwrap_or(NOTE_PATH.to_owned());
+                let filename = match shared::resolve(&format!("{}/{filename}", cfg::NOTE_FOLDER)) {
+                    Ok(filename) => filename.display().to_string(),
+                    Err(e) => {
+                        panels_main::output_push(&mut self.panel_info.output, e);
+                        return ret;
+                    }
+                };
+
                 panels_main::output_push(
                     &mut self.panel_info.output,
                     "Popup Editor window".to_owned(),
@@ -309,9 +313,6 @@ impl panels_main::Panel for Panel {
 
                 self.panel_info.active_popup_name = Some(POPUP_EDITOR.to_owned());
 
-                let filename = filename.unwrap_or(NOTE_PATH.to_owned());
-                let filename = format!("{}/{filename}", cfg::NOTE_FOLDER);
-
                 let active_popup = self
                     .panel_info
                     .popup
@@ -361,6 +362,10 @@ impl panels_main::Panel for Panel {
             }) => {
                 msg::cmd(&self.panel_info.msg_tx, reply_me!(), plugin, action, data).await;
             }
+            Some(Commands::Source { filename }) => {
+                panels_main::output_push(&mut self.panel_info.output, format!("Source {filename}"));
+                command::source(&self.panel_info.msg_tx, &filename);
+            }
             None => {
                 panels_main::output_push(&mut self.panel_info.output, "".to_owned());
             }
@@ -1,7 +1,14 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+
 use async_trait::async_trait;
 use chrono::{Datelike, NaiveDate};
 use log::Level::{Error, Info};
-use ratatui::crossterm::event::{KeyCode, KeyEvent};
+use ratatui::{
+    crossterm::event::{KeyCode, KeyEvent},
+    layout::{Constraint, Direction, Layout, Rect},
+    widgets::{Paragraph, Sparkline},
+    Frame,
+};
 use tokio::sync::mpsc::Sender;
 use unicode_width::UnicodeWidthChar;
 
@@ -16,7 +23,44 @@ pub const NAME: &str = "Infos";
 const POPUP_ALL: &str = "All";
 const POPUP_HELP: &str = "Help";
 const DEVICES_POLLING: u64 = 60;
-const TABS: usize = 7;
+const TABS: usize = 8;
+const TAB_METRICS: usize = 7;
+const METRICS_LEN: usize = 60;
+
+// the latest numeric telemetry of a device, from the devices updates
+#[derive(Debug, Default)]
+struct DevMetrics {
+    ts: u64,
+    values: BTreeMap<String, VecDeque<f32>>,
+}
+
+impl DevMetrics {
+    fn push(&mut self, device: &DevInfo) {
+        if device.ts == self.ts {
+            return;
+        }
+        self.ts = device.ts;
+
+        for (key, metric) in &device.telemetry {
+            if let Some(value) = metric.number() {
+                let history = self.values.entry(key.clone()).or_default();
+                history.push_back(value as f32);
+                if history.len() > METRICS_LEN {
+                    history.pop_front();
+                }
+            }
+        }
+    }
+}
+
+fn onboard_str(device: &DevInfo) -> &'static str {
+    match device.onboard {
+        Some(true) if device.stale => "Stale",
+        Some(true) => "On",
+        Some(false) => "Off",
+        None => "n/a",
+    }
+}
 
 fn format_date(input: &str) -> String {
     let date = NaiveDate::parse_from_str(input, "%Y-%m-%d").expect("無法解析日期");
@@ -30,6 +74,7 @@ pub struct Panel {
     weather: Vec<City>,
     worldtime: Vec<Worldtime>,
     stocks: Vec<utils::Stock>,
+    metrics: HashMap<String, DevMetrics>,
 }
 
 impl Panel {
@@ -38,6 +83,7 @@ impl Panel {
             "Commands:".to_owned(),
             "h    - Help".to_owned(),
             "⭠ / ⭢  - Change tab".to_owned(),
+            "Tab 8  - Device metrics".to_owned(),
         ];
 
         let panel_info = PanelInfo::new(
@@ -70,6 +116,7 @@ impl Panel {
             weather: vec![],
             worldtime: vec![],
             stocks: vec![],
+            metrics: HashMap::new(),
         }
     }
 
@@ -89,115 +136,79 @@ impl Panel {
                     "Disk Usage",
                     "Temp",
                     "Last update",
-                    "Countdown"
+                    "Silent for"
                 ));
 
                 for device in self.devices.iter() {
-                    // onboard
-                    let onboard = if let Some(t) = device.onboard {
-                        if t {
-                            "On"
-                        } else {
-                            "Off"
-                        }
-                    } else {
-                        "n/a"
-                    };
-
-                    // version
-                    let version = if let Some(t) = &device.version {
-                        t.clone()
-                    } else {
-                        "n/a".to_owned()
-                    };
-
-                    let os = if let Some(t) = &device.os {
-                        t.clone()
-                    } else {
-                        "n/a".to_owned()
-                    };
-
-                    let cpu = if let (Some(t), Some(u)) = (&device.cpu_arch, &device.cpu_usage) {
-                        format!("{}/{:.1}%", t, u)
-                    } else {
-                        "n/a".to_owned()
-                    };
-
-                    // memory
-                    let memory_usage = if let Some(t) = device.memory_usage {
-                        format!("{:.1}%", t)
-                    } else {
-                        "n/a".to_owned()
+                    let cpu = match (
+                        device.text(msg::TM_CPU_ARCH),
+                        device.number(msg::TM_CPU_USAGE),
+                    ) {
+                        (Some(t), Some(u)) => format!("{}/{:.1}%", t, u),
+                        _ => "n/a".to_owned(),
                     };
 
-                    // disk
-                    let disk_usage = if let Some(t) = device.disk_usage {
-                        format!("{:.1}%", t)
-                    } else {
-                        "n/a".to_owned()
-                    };
-
-                    // temperature
-                    let temperature = if let Some(t) = device.temperature {
-                        format!("{:.1}°C", t)
-                    } else {
-                        "n/a".to_owned()
-                    };
-
-                    // countdown
-                    let countdown = match utils::ts().cmp(&device.ts) {
-                        std::cmp::Ordering::Less | std::cmp::Ordering::Equal => 10.to_string(),
-                        std::cmp::Ordering::Greater => {
-                            if (utils::ts() - device.ts) / 60 >= 10 {
-                                "failed".to_owned()
-                            } else {
-                                (10 - (utils::ts() - device.ts) / 60).to_string()
-                            }
-                        }
+                    // since the last publish, stale is decided by the devices plugin
+                    let silent = match device.last_seen {
+                        Some(t) => utils::uptime_str(utils::ts().saturating_sub(t)),
+                        None => "n/a".to_owned(),
                     };
 
                     self.panel_info.output.push(format!(
-                        "{:<12} {onboard:<7} {version:<10} {os:<18} {cpu:14} {memory_usage:9} {disk_usage:10} {temperature:<7} {:<11} {countdown:<10}",
+                        "{:<12} {:<7} {:<10} {:<18} {cpu:14} {:9} {:10} {:<7} {:<11} {silent:<10}",
                         device.name,
+                        onboard_str(device),
+                        device.display(msg::TM_VERSION),
+                        device.display(msg::TM_OS),
+                        device.display(msg::TM_MEMORY_USAGE),
+                        device.display(msg::TM_DISK_USAGE),
+                        device.display(msg::TM_TEMPERATURE),
                         utils::ts_str(device.ts),
                     ));
                 }
             }
             1 => {
                 self.panel_info.output.push(format!(
-                    "{:<12} {:<7} {:13} {:13} {:16}",
-                    "Name", "Onboard", "App uptime", "Host uptime", "Tailscale IP"
+                    "{:<12} {:<7} {:13} {:13} {:16} {:16} {:6} {:6} {}",
+                    "Name",
+                    "Onboard",
+                    "App uptime",
+                    "Host uptime",
+                    "Tailscale IP",
+                    "Load",
+                    "Swap",
+                    "Procs",
+                    "Disks free"
                 ));
                 for device in self.devices.iter() {
-                    // onboard
-                    let onboard = if let Some(t) = device.onboard {
-                        if t {
-                            "On"
-                        } else {
-                            "Off"
-                        }
-                    } else {
-                        "n/a"
-                    };
-
-                    // app uptime
-                    let app_uptime = if let Some(t) = device.app_uptime {
-                        utils::uptime_str(t)
-                    } else {
-                        "n/a".to_owned()
-                    };
-
-                    // host uptime
-                    let host_uptime = if let Some(t) = device.host_uptime {
-                        utils::uptime_str(t)
-                    } else {
-                        "n/a".to_owned()
-                    };
+                    let load = format!(
+                        "{}/{}/{}",
+                        device.display(msg::TM_LOAD_1),
+                        device.display(msg::TM_LOAD_5),
+                        device.display(msg::TM_LOAD_15)
+                    );
+
+                    // disk.<mount>.free
+                    let disks_free: Vec<String> = device
+                        .telemetry
+                        .iter()
+                        .filter_map(|(key, metric)| {
+                            key.strip_prefix(&format!("{}.", msg::TM_DISK))
+                                .and_then(|k| k.strip_suffix(".free"))
+                                .map(|mount| format!("{mount} {metric}"))
+                        })
+                        .collect();
 
                     self.panel_info.output.push(format!(
-                        "{:<12} {onboard:<7} {app_uptime:13} {host_uptime:13} {:16}",
+                        "{:<12} {:<7} {:13} {:13} {:16} {load:16} {:6} {:6} {}",
                         device.name,
-                        device.tailscale_ip.clone().unwrap_or("n/a".to_owned())
+                        onboard_str(device),
+                        device.display(msg::TM_APP_UPTIME),
+                        device.display(msg::TM_HOST_UPTIME),
+                        device.display(msg::TM_TAILSCALE_IP),
+                        device.display(msg::TM_SWAP_USAGE),
+                        device.display(msg::TM_PROCESSES),
+                        disks_free.join(", ")
                     ));
                 }
             }
@@ -207,24 +218,6 @@ impl Panel {
                     "Name", "Onboard", "Last seen", "Weather"
                 ));
                 for device in self.devices.iter() {
-                    // onboard
-                    let onboard = if let Some(t) = device.onboard {
-                        if t {
-                            "On"
-                        } else {
-                            "Off"
-                        }
-                    } else {
-                        "n/a"
-                    };
-
-                    // weather
-                    let weather = if let Some(t) = &device.weather {
-                        t.clone()
-                    } else {
-                        "n/a".to_owned()
-                    };
-
                     // last_seen
                     let last_seen = if let Some(t) = device.last_seen {
                         utils::ts_str_full(t)
@@ -233,11 +226,10 @@ impl Panel {
                     };
 
                     self.panel_info.output.push(format!(
-                        "{:<12} {onboard:<7} {last_seen:<27} {weather:64}",
+                        "{:<12} {:<7} {last_seen:<27} {:64}",
                         device.name,
-                        onboard = onboard,
-                        last_seen = last_seen,
-                        weather = weather,
+                        onboard_str(device),
+                        device.display(msg::TM_WEATHER),
                     ));
                 }
             }
@@ -352,6 +344,26 @@ impl Panel {
                     self.panel_info.output.push(info.to_string());
                 }
             }
+            TAB_METRICS => {
+                self.panel_info
+                    .output
+                    .push(format!("{:<12} {:<16} {:<10}", "Name", "Metric", "Last"));
+                for device in self.devices.iter() {
+                    let Some(metrics) = self.metrics.get(&device.name) else {
+                        continue;
+                    };
+
+                    for (key, values) in &metrics.values {
+                        let values: Vec<f32> = values.iter().cloned().collect();
+                        self.panel_info.output.push(format!(
+                            "{:<12} {key:<16} {:<10} {}",
+                            device.name,
+                            device.display(key),
+                            utils::sparkline(&values)
+                        ));
+                    }
+                }
+            }
             _ => {}
         }
     }
@@ -367,6 +379,69 @@ impl panels_main::Panel for Panel {
         format!("{} - {}/{TABS}", self.panel_info.name, self.tab_index + 1)
     }
 
+    // a sparkline per device and metric on the metrics tab
+    fn draw(&self, frame: &mut Frame, area: Rect) -> bool {
+        if self.tab_index != TAB_METRICS {
+            return false;
+        }
+
+        let rows: Vec<(&DevInfo, &String, &VecDeque<f32>)> = self
+            .devices
+            .iter()
+            .filter_map(|d| self.metrics.get(&d.name).map(|m| (d, m)))
+            .flat_map(|(d, m)| m.values.iter().map(move |(k, v)| (d, k, v)))
+            .collect();
+
+        let areas = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints(
+                std::iter::once(Constraint::Length(1))
+                    .chain(rows.iter().map(|_| Constraint::Length(1)))
+                    .chain(std::iter::once(Constraint::Min(0))),
+            )
+            .split(area);
+
+        let columns = |row: Rect| {
+            Layout::default()
+                .direction(Direction::Horizontal)
+                .constraints([
+                    Constraint::Length(13),
+                    Constraint::Length(17),
+                    Constraint::Length(11),
+                    Constraint::Min(0),
+                ])
+                .split(row)
+        };
+
+        let header = columns(areas[0]);
+        for (idx, title) in ["Name", "Metric", "Last"].iter().enumerate() {
+            frame.render_widget(Paragraph::new(*title), header[idx]);
+        }
+
+        for (idx, (device, key, values)) in rows.iter().enumerate() {
+            let cells = columns(areas[idx + 1]);
+
+            // scaled to the visible range, the values are of any unit
+            let shown: Vec<f32> = values
+                .iter()
+                .skip(values.len().saturating_sub(cells[3].width as usize))
+                .cloned()
+                .collect();
+            let min = shown.iter().cloned().fold(f32::INFINITY, f32::min);
+            let data: Vec<u64> = shown
+                .iter()
+                .map(|v| ((v - min) * 100.0).round() as u64 + 1)
+                .collect();
+
+            frame.render_widget(Paragraph::new(device.name.as_str()), cells[0]);
+            frame.render_widget(Paragraph::new(key.as_str()), cells[1]);
+            frame.render_widget(Paragraph::new(device.display(key)), cells[2]);
+            frame.render_widget(Sparkline::default().data(&data), cells[3]);
+        }
+
+        true
+    }
+
     async fn init(&mut self) {
         init!(&self.panel_info.msg_tx, NAME);
 
@@ -382,6 +457,12 @@ impl panels_main::Panel for Panel {
     async fn msg(&mut self, msg: &Msg) {
         match &msg.data {
             Data::Devices(devices) => {
+                for device in devices {
+                    self.metrics
+                        .entry(device.name.clone())
+                        .or_default()
+                        .push(device);
+                }
                 self.devices = devices.clone();
                 self.tab_refresh();
             }
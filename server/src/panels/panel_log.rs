@@ -72,6 +72,17 @@ impl panels_main::Panel for Panel {
                     format!("{} {}", utils::ts_str(msg.ts), log.msg.clone()),
                 );
             }
+            Data::FileEvent(file_event) => {
+                panels_main::output_push(
+                    &mut self.panel_info.output,
+                    format!(
+                        "{} [file] {}: {}",
+                        utils::ts_str(msg.ts),
+                        file_event.action,
+                        file_event.filename
+                    ),
+                );
+            }
             _ => {
                 unknown!(&self.panel_info.msg_tx, NAME, msg);
             }
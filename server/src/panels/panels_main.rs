@@ -11,10 +11,20 @@ use ratatui::{
 use tokio::sync::mpsc::Sender;
 
 use crate::cfg;
-use crate::msg::{log, Data, Msg, Reply};
+use crate::msg::{self, log, Data, Msg, Reply};
 use crate::panels::{panel_brief, panel_error, panel_infos, panel_log};
 use crate::{error, info, init, unknown};
 
+// subscribed by the gui as the panels are created
+pub const TOPICS: [&str; 6] = [
+    msg::TOPIC_LOG,
+    msg::TOPIC_DEVICES,
+    msg::TOPIC_WEATHER,
+    msg::TOPIC_WORLDTIME,
+    msg::TOPIC_STOCKS,
+    msg::TOPIC_FILE,
+];
+
 pub const NAME: &str = "panels";
 
 #[derive(Debug)]
@@ -62,6 +72,11 @@ pub trait Panel {
     async fn run(&mut self, _cmd: &str) -> bool {
         false
     }
+    // custom widgets inside the panel border, the output text is skipped
+    // when it returns true
+    fn draw(&self, _frame: &mut Frame, _area: Rect) -> bool {
+        false
+    }
 }
 
 pub struct Panels {
@@ -154,34 +169,26 @@ impl Panels {
                     Style::default()
                 });
 
-            let area_height = match window.get_panel_info().name.as_str() {
-                panel_log::NAME => area_log.height,
-                panel_brief::NAME => area_brief.height,
-                panel_infos::NAME => area_info.height,
-                panel_error::NAME => area_error.height,
+            let area = match window.get_panel_info().name.as_str() {
+                panel_log::NAME => area_log,
+                panel_brief::NAME => area_brief,
+                panel_infos::NAME => area_info,
+                panel_error::NAME => area_error,
                 _ => panic!(),
             };
 
+            if window.draw(frame, block.inner(area)) {
+                frame.render_widget(block, area);
+                continue;
+            }
+
             let window_output_len = window.get_panel_info().output.len() as u16;
-            let scroll_offset = if window_output_len > (area_height - 2) {
-                window_output_len - (area_height - 2)
-            } else {
-                0
-            };
+            let scroll_offset = window_output_len.saturating_sub(area.height - 2);
 
             let paragraph = Paragraph::new(window.get_panel_info().output.join("\n"))
                 .block(block)
                 .scroll((scroll_offset, 0));
-            frame.render_widget(
-                paragraph,
-                match window.get_panel_info().name.as_str() {
-                    panel_log::NAME => area_log,
-                    panel_brief::NAME => area_brief,
-                    panel_infos::NAME => area_info,
-                    panel_error::NAME => area_error,
-                    _ => panic!(),
-                },
-            );
+            frame.render_widget(paragraph, area);
         }
 
         // Popup
@@ -305,6 +312,9 @@ impl Panels {
             Data::Stocks(_stocks) => {
                 self.get_panel_mut(panel_infos::NAME).msg(msg).await;
             }
+            Data::FileEvent(_file_event) => {
+                self.get_panel_mut(panel_log::NAME).msg(msg).await;
+            }
             _ => {
                 unknown!(&self.msg_tx, NAME, msg);
             }
@@ -0,0 +1,469 @@
+use async_trait::async_trait;
+use log::Level::{Error, Warn};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc::Sender;
+
+use crate::msg::{self, log, Msg, Reply};
+use crate::plugins::{mqtt, plugin_mqtt};
+use crate::{cfg, reply_me};
+
+pub const TUI: &str = "tui";
+pub const MQTT: &str = "mqtt";
+pub const WEBHOOK: &str = "webhook";
+pub const SMTP: &str = "smtp";
+
+const TIMEOUT: u64 = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum State {
+    Firing,
+    Resolved,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Notification {
+    pub ts: u64,
+    pub device: String, // who evaluated the alert
+    pub alert: String,
+    pub key: String, // device or stock code, empty for log and file alerts
+    pub state: State,
+    pub severity: String,
+    pub message: String,
+}
+
+impl Notification {
+    pub fn subject(&self) -> String {
+        let state = match self.state {
+            State::Firing => "FIRING",
+            State::Resolved => "RESOLVED",
+        };
+        format!("[{state}] {}: {}", self.alert, self.message)
+    }
+}
+
+#[async_trait]
+pub trait Channel: std::fmt::Debug + Send + Sync {
+    fn name(&self) -> &str;
+    async fn send(&self, notification: &Notification) -> Result<(), String>;
+}
+
+// as in alerts.json "channels", the tui is always on
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ChannelsCfg {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mqtt: Option<MqttCfg>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub webhook: Option<WebhookCfg>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub smtp: Option<SmtpCfg>,
+}
+
+// published as tln/<name>/alert
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct MqttCfg {}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WebhookCfg {
+    pub url: String,
+}
+
+// a relay without auth or TLS, e.g. the local MTA
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SmtpCfg {
+    pub server: String, // host:port
+    pub from: String,
+    pub to: Vec<String>,
+}
+
+pub fn channels(msg_tx: &Sender<Msg>, channels_cfg: &ChannelsCfg) -> Vec<Box<dyn Channel>> {
+    let mut channels: Vec<Box<dyn Channel>> = vec![Box::new(Tui {
+        msg_tx: msg_tx.clone(),
+    })];
+
+    if channels_cfg.mqtt.is_some() {
+        channels.push(Box::new(Mqtt {
+            msg_tx: msg_tx.clone(),
+        }));
+    }
+    if let Some(webhook) = &channels_cfg.webhook {
+        channels.push(Box::new(Webhook::new(&webhook.url)));
+    }
+    if let Some(smtp) = &channels_cfg.smtp {
+        channels.push(Box::new(Smtp { cfg: smtp.clone() }));
+    }
+
+    channels
+}
+
+// the Error panel
+#[derive(Debug)]
+pub struct Tui {
+    msg_tx: Sender<Msg>,
+}
+
+#[async_trait]
+impl Channel for Tui {
+    fn name(&self) -> &str {
+        TUI
+    }
+
+    async fn send(&self, notification: &Notification) -> Result<(), String> {
+        let level = match notification.state {
+            State::Firing => Error,
+            State::Resolved => Warn,
+        };
+        log(
+            &self.msg_tx,
+            reply_me!(),
+            level,
+            format!("[alerts] {}", notification.subject()),
+        )
+        .await;
+
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub struct Mqtt {
+    msg_tx: Sender<Msg>,
+}
+
+#[async_trait]
+impl Channel for Mqtt {
+    fn name(&self) -> &str {
+        MQTT
+    }
+
+    async fn send(&self, notification: &Notification) -> Result<(), String> {
+        let payload = serde_json::to_string(notification).map_err(|e| e.to_string())?;
+        msg::cmd(
+            &self.msg_tx,
+            reply_me!(),
+            plugin_mqtt::NAME.to_owned(),
+            msg::ACT_PUBLISH.to_owned(),
+            vec![mqtt::utils::ALERT.to_owned(), "false".to_owned(), payload],
+        )
+        .await;
+
+        Ok(())
+    }
+}
+
+// the notification as a JSON POST
+#[derive(Debug)]
+pub struct Webhook {
+    url: String,
+    client: reqwest::Client,
+}
+
+impl Webhook {
+    pub fn new(url: &str) -> Self {
+        Self {
+            url: url.to_owned(),
+            client: reqwest::Client::builder()
+                .timeout(std::time::Duration::from_secs(TIMEOUT))
+                .build()
+                .unwrap(),
+        }
+    }
+}
+
+#[async_trait]
+impl Channel for Webhook {
+    fn name(&self) -> &str {
+        WEBHOOK
+    }
+
+    async fn send(&self, notification: &Notification) -> Result<(), String> {
+        let response = self
+            .client
+            .post(&self.url)
+            .json(notification)
+            .send()
+            .await
+            .map_err(|e| format!("POST {}: {e}", self.url))?;
+
+        if !response.status().is_success() {
+            return Err(format!("POST {}: {}", self.url, response.status()));
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub struct Smtp {
+    cfg: SmtpCfg,
+}
+
+impl Smtp {
+    // one command, the reply has to be the expected code
+    async fn command(
+        reader: &mut BufReader<TcpStream>,
+        line: Option<&str>,
+        expected: &str,
+    ) -> Result<(), String> {
+        if let Some(line) = line {
+            reader
+                .get_mut()
+                .write_all(format!("{line}\r\n").as_bytes())
+                .await
+                .map_err(|e| e.to_string())?;
+        }
+
+        // multiline replies are "250-..." until "250 ..."
+        loop {
+            let mut reply = String::new();
+            if reader
+                .read_line(&mut reply)
+                .await
+                .map_err(|e| e.to_string())?
+                == 0
+            {
+                return Err("connection closed".to_owned());
+            }
+            if !reply.starts_with(expected) {
+                return Err(format!("unexpected reply: {:?}", reply.trim_end()));
+            }
+            if reply.as_bytes().get(3) != Some(&b'-') {
+                return Ok(());
+            }
+        }
+    }
+
+    async fn deliver(&self, notification: &Notification) -> Result<(), String> {
+        let stream = TcpStream::connect(&self.cfg.server)
+            .await
+            .map_err(|e| format!("connect {}: {e}", self.cfg.server))?;
+        let mut reader = BufReader::new(stream);
+
+        Self::command(&mut reader, None, "220").await?;
+        Self::command(&mut reader, Some(&format!("HELO {}", cfg::name())), "250").await?;
+        Self::command(
+            &mut reader,
+            Some(&format!("MAIL FROM:<{}>", self.cfg.from)),
+            "250",
+        )
+        .await?;
+        for to in &self.cfg.to {
+            Self::command(&mut reader, Some(&format!("RCPT TO:<{to}>")), "250").await?;
+        }
+        Self::command(&mut reader, Some("DATA"), "354").await?;
+
+        // a line with a single dot ends the data, so dots are doubled
+        let body = serde_json::to_string_pretty(notification).map_err(|e| e.to_string())?;
+        let body = body
+            .lines()
+            .map(|l| {
+                if l.starts_with('.') {
+                    format!(".{l}")
+                } else {
+                    l.to_owned()
+                }
+            })
+            .collect::<Vec<_>>()
+            .join("\r\n");
+        let data = format!(
+            "From: <{}>\r\nTo: {}\r\nSubject: {}\r\nDate: {}\r\nContent-Type: text/plain; charset=utf-8\r\n\r\n{body}\r\n.",
+            self.cfg.from,
+            self.cfg
+                .to
+                .iter()
+                .map(|t| format!("<{t}>"))
+                .collect::<Vec<_>>()
+                .join(", "),
+            notification.subject(),
+            chrono::Local::now().to_rfc2822()
+        );
+        Self::command(&mut reader, Some(&data), "250").await?;
+        Self::command(&mut reader, Some("QUIT"), "221").await
+    }
+}
+
+#[async_trait]
+impl Channel for Smtp {
+    fn name(&self) -> &str {
+        SMTP
+    }
+
+    async fn send(&self, notification: &Notification) -> Result<(), String> {
+        tokio::time::timeout(
+            tokio::time::Duration::from_secs(TIMEOUT),
+            self.deliver(notification),
+        )
+        .await
+        .map_err(|_| format!("{}: timeout", self.cfg.server))?
+        .map_err(|e| format!("{}: {e}", self.cfg.server))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils;
+    use tokio::io::AsyncReadExt;
+    use tokio::net::TcpListener;
+
+    fn notification() -> Notification {
+        Notification {
+            ts: utils::ts(),
+            device: "pi5".to_owned(),
+            alert: "disk-full".to_owned(),
+            key: "nas".to_owned(),
+            state: State::Firing,
+            severity: "critical".to_owned(),
+            message: "nas disk 91.5%".to_owned(),
+        }
+    }
+
+    #[tokio::test]
+    async fn webhook_posts_json() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+
+        // a stand-in answering one request
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = vec![];
+            let mut buf = [0; 4096];
+            loop {
+                let n = stream.read(&mut buf).await.unwrap();
+                request.extend_from_slice(&buf[..n]);
+                let text = String::from_utf8_lossy(&request);
+                if let Some((head, body)) = text.split_once("\r\n\r\n") {
+                    let len = head
+                        .lines()
+                        .find_map(|l| {
+                            l.to_ascii_lowercase()
+                                .strip_prefix("content-length: ")
+                                .map(|v| v.parse::<usize>().unwrap())
+                        })
+                        .unwrap_or(0);
+                    if body.len() >= len {
+                        break;
+                    }
+                }
+            }
+            stream
+                .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n")
+                .await
+                .unwrap();
+            String::from_utf8(request).unwrap()
+        });
+
+        Webhook::new(&url).send(&notification()).await.unwrap();
+
+        let request = server.await.unwrap();
+        assert!(request.starts_with("POST /hook HTTP/1.1"));
+        let body = request.split_once("\r\n\r\n").unwrap().1;
+        let value: serde_json::Value = serde_json::from_str(body).unwrap();
+        assert_eq!(value["alert"], "disk-full");
+        assert_eq!(value["state"], "firing");
+    }
+
+    #[tokio::test]
+    async fn webhook_error_status() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = [0; 4096];
+            let _ = stream.read(&mut buf).await;
+            stream
+                .write_all(b"HTTP/1.1 500 Internal Server Error\r\ncontent-length: 0\r\n\r\n")
+                .await
+                .unwrap();
+        });
+
+        let e = Webhook::new(&url).send(&notification()).await.unwrap_err();
+        assert!(e.contains("500"));
+    }
+
+    #[tokio::test]
+    async fn smtp_dialog() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server = listener.local_addr().unwrap().to_string();
+
+        // a stand-in recording the dialog
+        let stand_in = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut lines = vec![];
+            reader
+                .get_mut()
+                .write_all(b"220 localhost ESMTP\r\n")
+                .await
+                .unwrap();
+            let mut in_data = false;
+            loop {
+                let mut line = String::new();
+                if reader.read_line(&mut line).await.unwrap() == 0 {
+                    break;
+                }
+                let line = line.trim_end().to_owned();
+                let reply: &[u8] = if in_data {
+                    if line == "." {
+                        in_data = false;
+                        b"250 queued\r\n"
+                    } else {
+                        b""
+                    }
+                } else if line.starts_with("HELO") {
+                    b"250-localhost\r\n250 OK\r\n"
+                } else if line == "DATA" {
+                    in_data = true;
+                    b"354 go ahead\r\n"
+                } else if line == "QUIT" {
+                    b"221 bye\r\n"
+                } else {
+                    b"250 OK\r\n"
+                };
+                lines.push(line);
+                reader.get_mut().write_all(reply).await.unwrap();
+                if lines.last().unwrap() == "QUIT" {
+                    break;
+                }
+            }
+            lines
+        });
+
+        let smtp = Smtp {
+            cfg: SmtpCfg {
+                server,
+                from: "cng@localhost".to_owned(),
+                to: vec!["tim@localhost".to_owned(), "ops@localhost".to_owned()],
+            },
+        };
+        smtp.send(&notification()).await.unwrap();
+
+        let lines = stand_in.await.unwrap();
+        assert!(lines.contains(&"MAIL FROM:<cng@localhost>".to_owned()));
+        assert!(lines.contains(&"RCPT TO:<ops@localhost>".to_owned()));
+        assert!(lines.contains(&"Subject: [FIRING] disk-full: nas disk 91.5%".to_owned()));
+        assert_eq!(lines.last().unwrap(), "QUIT");
+    }
+
+    #[tokio::test]
+    async fn smtp_rejected() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            stream.write_all(b"554 no service\r\n").await.unwrap();
+        });
+
+        let smtp = Smtp {
+            cfg: SmtpCfg {
+                server,
+                from: "cng@localhost".to_owned(),
+                to: vec!["tim@localhost".to_owned()],
+            },
+        };
+        let e = smtp.send(&notification()).await.unwrap_err();
+        assert!(e.contains("554 no service"));
+    }
+}
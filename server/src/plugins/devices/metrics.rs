@@ -0,0 +1,265 @@
+use std::collections::{HashMap, VecDeque};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+// raw samples kept in memory per device and metric, about a day at the
+// 5 minutes polling of plugin_system
+const RING_SIZE: usize = 512;
+// older samples are only on disk, averaged per hour
+const BUCKET_SECS: u64 = 60 * 60;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Sample {
+    pub ts: u64,
+    pub value: f32,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Bucket {
+    pub ts: u64, // start of the hour
+    pub avg: f32,
+    pub min: f32,
+    pub max: f32,
+    pub count: u32,
+}
+
+impl Bucket {
+    fn new(sample: Sample) -> Self {
+        Self {
+            ts: sample.ts - sample.ts % BUCKET_SECS,
+            avg: sample.value,
+            min: sample.value,
+            max: sample.value,
+            count: 1,
+        }
+    }
+
+    fn add(&mut self, value: f32) {
+        self.avg = (self.avg * self.count as f32 + value) / (self.count + 1) as f32;
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+        self.count += 1;
+    }
+}
+
+#[derive(Debug, Default)]
+struct Series {
+    ring: VecDeque<Sample>,
+    bucket: Option<Bucket>, // the hour being filled
+}
+
+// in-memory ring buffers plus hourly averages on disk,
+// <folder>/<device>/<metric>.jsonl
+#[derive(Debug, Default)]
+pub struct Metrics {
+    folder: Option<String>,
+    series: HashMap<(String, String), Series>,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // nothing is written to disk before the folder is opened
+    pub fn open(&mut self, folder: &str) {
+        self.folder = Some(folder.to_owned());
+    }
+
+    fn path(folder: &str, device: &str, metric: &str) -> String {
+        format!(
+            "{folder}/{}/{}.jsonl",
+            sanitize_filename::sanitize(device),
+            sanitize_filename::sanitize(metric)
+        )
+    }
+
+    pub fn record(&mut self, device: &str, metric: &str, sample: Sample) -> Result<(), String> {
+        let series = self
+            .series
+            .entry((device.to_owned(), metric.to_owned()))
+            .or_default();
+
+        series.ring.push_back(sample);
+        if series.ring.len() > RING_SIZE {
+            series.ring.pop_front();
+        }
+
+        let closed = match series.bucket.as_mut() {
+            Some(bucket) if sample.ts < bucket.ts + BUCKET_SECS => {
+                bucket.add(sample.value);
+                None
+            }
+            _ => series.bucket.replace(Bucket::new(sample)),
+        };
+
+        match (closed, &self.folder) {
+            (Some(bucket), Some(folder)) => {
+                let path = Self::path(folder, device, metric);
+                append_bucket(&path, &bucket)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // raw samples where the ring covers the range, hourly averages before
+    pub fn query(
+        &self,
+        device: &str,
+        metric: &str,
+        from: u64,
+        to: u64,
+    ) -> Result<Vec<Sample>, String> {
+        let series = self.series.get(&(device.to_owned(), metric.to_owned()));
+        let ring_start = series
+            .and_then(|s| s.ring.front())
+            .map(|s| s.ts)
+            .unwrap_or(u64::MAX);
+
+        let mut samples = vec![];
+
+        if from < ring_start {
+            if let Some(folder) = &self.folder {
+                let path = Self::path(folder, device, metric);
+                for bucket in load_buckets(&path)? {
+                    if bucket.ts >= from && bucket.ts < to && bucket.ts < ring_start {
+                        samples.push(Sample {
+                            ts: bucket.ts,
+                            value: bucket.avg,
+                        });
+                    }
+                }
+            }
+        }
+
+        if let Some(series) = series {
+            samples.extend(
+                series
+                    .ring
+                    .iter()
+                    .filter(|s| s.ts >= from && s.ts < to)
+                    .cloned(),
+            );
+        }
+
+        Ok(samples)
+    }
+
+    pub fn metrics(&self, device: &str) -> Vec<String> {
+        let mut metrics: Vec<String> = self
+            .series
+            .keys()
+            .filter(|(d, _)| d == device)
+            .map(|(_, m)| m.clone())
+            .collect();
+        metrics.sort();
+
+        metrics
+    }
+}
+
+fn append_bucket(path: &str, bucket: &Bucket) -> Result<(), String> {
+    if let Some(parent) = Path::new(path).parent() {
+        fs::create_dir_all(parent).map_err(|e| format!("Failed to create {parent:?}: {e}"))?;
+    }
+
+    let line = serde_json::to_string(bucket).map_err(|e| e.to_string())?;
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .and_then(|mut file| writeln!(file, "{line}"))
+        .map_err(|e| format!("Failed to write {path}: {e}"))
+}
+
+fn load_buckets(path: &str) -> Result<Vec<Bucket>, String> {
+    if !Path::new(path).exists() {
+        return Ok(vec![]);
+    }
+
+    let file = File::open(path).map_err(|e| format!("Failed to open {path}: {e}"))?;
+    let mut buckets = vec![];
+    for line in BufReader::new(file).lines() {
+        let line = line.map_err(|e| format!("Failed to read {path}: {e}"))?;
+        if let Ok(bucket) = serde_json::from_str(&line) {
+            buckets.push(bucket);
+        }
+    }
+
+    Ok(buckets)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(ts: u64, value: f32) -> Sample {
+        Sample { ts, value }
+    }
+
+    #[test]
+    fn ring_and_hourly_buckets() {
+        let dir = std::env::temp_dir().join(format!("cng_metrics_{}", std::process::id()));
+        let folder = dir.to_string_lossy().to_string();
+
+        let mut metrics = Metrics::new();
+        metrics.open(&folder);
+
+        // two hours at 10 minutes, the third hour opens a bucket
+        for idx in 0..13 {
+            metrics
+                .record("pi5", "cpu_usage", sample(idx * 600, idx as f32))
+                .unwrap();
+        }
+
+        let buckets = load_buckets(&Metrics::path(&folder, "pi5", "cpu_usage")).unwrap();
+        assert_eq!(buckets.len(), 2);
+        assert_eq!(buckets[0].ts, 0);
+        assert_eq!(buckets[0].avg, 2.5);
+        assert_eq!((buckets[0].min, buckets[0].max), (0.0, 5.0));
+        assert_eq!(buckets[0].count, 6);
+        assert_eq!(buckets[1].ts, 3600);
+
+        assert_eq!(
+            metrics.query("pi5", "cpu_usage", 1200, 2400).unwrap().len(),
+            2
+        );
+        assert_eq!(metrics.metrics("pi5"), vec!["cpu_usage".to_owned()]);
+
+        // after a restart the ring is empty, the hours come from disk
+        let mut restarted = Metrics::new();
+        restarted.open(&folder);
+        restarted
+            .record("pi5", "cpu_usage", sample(7300, 20.0))
+            .unwrap();
+        let samples = restarted.query("pi5", "cpu_usage", 0, 8000).unwrap();
+
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(
+            samples,
+            vec![sample(0, 2.5), sample(3600, 8.5), sample(7300, 20.0)]
+        );
+    }
+
+    #[test]
+    fn memory_only_without_folder() {
+        let mut metrics = Metrics::new();
+        for idx in 0..(RING_SIZE as u64 + 10) {
+            metrics
+                .record("pi5", "temperature", sample(idx * 3600, 40.0))
+                .unwrap();
+        }
+
+        let samples = metrics.query("pi5", "temperature", 0, u64::MAX).unwrap();
+        assert_eq!(samples.len(), RING_SIZE);
+        assert_eq!(samples[0].ts, 10 * 3600);
+        assert!(metrics
+            .query("linds", "temperature", 0, u64::MAX)
+            .unwrap()
+            .is_empty());
+    }
+}
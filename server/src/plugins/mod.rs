@@ -1,12 +1,17 @@
+pub mod alerts;
+pub mod devices;
 pub mod mongodb;
 pub mod mqtt;
 pub mod nas;
+pub mod plugin_alerts;
 pub mod plugin_devices;
 pub mod plugin_file;
 pub mod plugin_log;
 pub mod plugin_mqtt;
 pub mod plugin_nas;
 pub mod plugin_ping;
+pub mod plugin_rules;
+pub mod plugin_scheduler;
 pub mod plugin_shell;
 pub mod plugin_stocks;
 pub mod plugin_system;
@@ -15,3 +20,4 @@ pub mod plugin_weather;
 pub mod plugin_wol;
 pub mod plugin_worldtime;
 pub mod plugins_main;
+pub mod system;
@@ -2,13 +2,17 @@ use log::Level::{Error, Info, Trace};
 use rumqttc::{AsyncClient, Event, Outgoing, Packet, Publish, QoS};
 use tokio::sync::mpsc::Sender;
 
-use crate::msg::{self, device_update, log, DevInfo, Msg, Reply};
-use crate::plugins::{plugin_file, plugin_mqtt, plugin_nas, plugin_system};
+use crate::msg::{self, device_update, log, Cmd, DevInfo, Metric, Msg, Reply};
+use crate::plugins::{plugin_devices, plugin_file, plugin_mqtt, plugin_nas, plugin_system};
 use crate::{cfg, utils};
 use crate::{error, info, reply_me, trace};
 
 const NAME: &str = "mqtt::utils";
 const RESTART_DELAY: u64 = 30;
+pub const ONBOARD: &str = "onboard";
+pub const ALERT: &str = "alert";
+pub const ALL_DEVICES: &str = "*";
+pub const TAG_PREFIX: char = '@';
 
 pub async fn subscribe(msg_tx: &Sender<Msg>, client: Option<&AsyncClient>, topic: &str) {
     if client.is_none() {
@@ -119,15 +123,12 @@ async fn process_event_conn_ack(msg_tx: &Sender<Msg>) {
         reply_me!(),
         plugin_system::NAME.to_owned(),
         msg::ACT_UPDATE.to_owned(),
-        vec![],
+        vec![ONBOARD.to_owned()],
     )
     .await;
 }
 
 async fn process_event_publish(msg_tx: &Sender<Msg>, publish: &Publish) {
-    if process_event_publish_system(msg_tx, publish).await {
-        return;
-    }
     if process_event_publish_ask(msg_tx, publish).await {
         return;
     }
@@ -140,10 +141,16 @@ async fn process_event_publish(msg_tx: &Sender<Msg>, publish: &Publish) {
     if process_event_publish_nas(msg_tx, publish).await {
         return;
     }
+    if process_event_publish_alert(msg_tx, publish).await {
+        return;
+    }
+    if process_event_publish_system(msg_tx, publish).await {
+        return;
+    }
     trace!(msg_tx, format!("[{NAME}] <- ({publish:?})"));
 }
 
-fn parse(input: &str) -> Vec<String> {
+pub fn parse(input: &str) -> Vec<String> {
     let re = regex::Regex::new(r#""([^"]+)"|(\S+)"#).unwrap();
     re.captures_iter(input)
         .map(|cap| {
@@ -156,6 +163,67 @@ fn parse(input: &str) -> Vec<String> {
         .collect()
 }
 
+// an ask to all devices or the devices of a tag, expanded by the devices plugin
+pub fn is_broadcast(target_device: &str) -> bool {
+    target_device == ALL_DEVICES || target_device.starts_with(TAG_PREFIX)
+}
+
+// payload of an ask: r <reply_device> p <plugin> <action> [data ...]
+pub fn ask_payload(reply_device: &str, data: &[String]) -> String {
+    let mut msg = String::new();
+    msg += &format!("r {reply_device} ");
+    for t in data {
+        // if t content space
+        if t.contains(" ") {
+            msg += &format!("\"{t}\"");
+        } else {
+            msg += t;
+        }
+        msg += " ";
+    }
+
+    msg.trim().to_owned()
+}
+
+// returns the plugin and the cmd of an ask, or where to reply the error
+pub fn parse_ask(payload_vec: &[String]) -> Result<(String, Cmd), (Reply, String)> {
+    match payload_vec.first() {
+        Some(t) if t == "r" => (),
+        _ => return Err((reply_me!(), "r is missing.".to_owned())),
+    }
+
+    let reply = match payload_vec.get(1) {
+        Some(t) => Reply::Device(t.to_owned()),
+        None => return Err((reply_me!(), "reply is missing.".to_owned())),
+    };
+
+    match payload_vec.get(2) {
+        Some(t) if t == "p" => (),
+        _ => return Err((reply, "p is missing.".to_owned())),
+    }
+
+    let plugin = match payload_vec.get(3) {
+        Some(t) => t.to_owned(),
+        None => return Err((reply, "plugin is missing.".to_owned())),
+    };
+
+    let action = match payload_vec.get(4) {
+        Some(t) => t.to_owned(),
+        None => return Err((reply, "action is missing.".to_owned())),
+    };
+
+    let data = payload_vec[5..].to_vec();
+
+    Ok((
+        plugin,
+        Cmd {
+            reply,
+            action,
+            data,
+        },
+    ))
+}
+
 async fn process_event_publish_ask(msg_tx: &Sender<Msg>, publish: &Publish) -> bool {
     let topic = &publish.topic;
 
@@ -174,55 +242,14 @@ async fn process_event_publish_ask(msg_tx: &Sender<Msg>, publish: &Publish) -> b
             );
 
             if name == cfg::name() {
-                if let Some(t) = payload_vec.first() {
-                    if t != "r" {
-                        error!(msg_tx, format!("[{NAME}] r is missing."));
-                        return true;
+                match parse_ask(&payload_vec) {
+                    Ok((plugin, cmd)) => {
+                        msg::cmd(msg_tx, cmd.reply, plugin, cmd.action, cmd.data).await;
                     }
-                } else {
-                    error!(msg_tx, format!("[{NAME}] r is missing."));
-                    return true;
-                };
-
-                let reply = if let Some(t) = payload_vec.get(1) {
-                    Reply::Device(t.to_owned())
-                } else {
-                    error!(msg_tx, format!("[{NAME}] reply is missing."));
-                    return true;
-                };
-
-                if let Some(t) = payload_vec.get(2) {
-                    if t != "p" {
-                        log(msg_tx, reply, Error, format!("[{NAME}] p is missing.")).await;
-                        return true;
-                    }
-                } else {
-                    log(msg_tx, reply, Error, format!("[{NAME}] p is missing.")).await;
-                    return true;
-                };
-
-                let plugin = if let Some(t) = payload_vec.get(3) {
-                    t.to_owned()
-                } else {
-                    log(msg_tx, reply, Error, format!("[{NAME}] plugin is missing.")).await;
-                    return true;
-                };
-
-                let action = if let Some(t) = payload_vec.get(4) {
-                    t.to_owned()
-                } else {
-                    log(msg_tx, reply, Error, format!("[{NAME}] action is missing.")).await;
-                    return true;
-                };
-
-                let mut data = vec![];
-                for i in 5..=payload_vec.len() - 1 {
-                    if let Some(t) = payload_vec.get(i) {
-                        data.push(t.to_owned());
+                    Err((reply, e)) => {
+                        log(msg_tx, reply, Error, format!("[{NAME}] {e}")).await;
                     }
                 }
-
-                msg::cmd(msg_tx, reply, plugin, action, data).await;
             }
         }
 
@@ -232,10 +259,11 @@ async fn process_event_publish_ask(msg_tx: &Sender<Msg>, publish: &Publish) -> b
     false
 }
 
+// tln/<name>/reply/<from>, or tln/<name>/reply from older devices
 async fn process_event_publish_reply(msg_tx: &Sender<Msg>, publish: &Publish) -> bool {
     let topic = &publish.topic;
 
-    let re = regex::Regex::new(r"^tln/([^/]+)/reply$").unwrap();
+    let re = regex::Regex::new(r"^tln/([^/]+)/reply(?:/([^/]+))?$").unwrap();
     if let Some(captures) = re.captures(topic) {
         if let Some(name) = captures.get(1) {
             let name = name.as_str();
@@ -249,7 +277,22 @@ async fn process_event_publish_reply(msg_tx: &Sender<Msg>, publish: &Publish) ->
                     format!("[{NAME}] <- pub::reply: {name}, '{dec_payload}'")
                 );
 
-                info!(msg_tx, format!("R: {dec_payload}"));
+                match captures.get(2) {
+                    // the devices plugin knows if it is for a broadcast
+                    Some(from) => {
+                        msg::cmd(
+                            msg_tx,
+                            reply_me!(),
+                            plugin_devices::NAME.to_owned(),
+                            msg::ACT_REPLY.to_owned(),
+                            vec![from.as_str().to_owned(), dec_payload],
+                        )
+                        .await;
+                    }
+                    None => {
+                        info!(msg_tx, format!("R: {dec_payload}"));
+                    }
+                }
             }
         }
 
@@ -259,299 +302,40 @@ async fn process_event_publish_reply(msg_tx: &Sender<Msg>, publish: &Publish) ->
     false
 }
 
+// alerts of the devices for other subscribers, e.g. a phone
+async fn process_event_publish_alert(msg_tx: &Sender<Msg>, publish: &Publish) -> bool {
+    let re = regex::Regex::new(&format!(r"^tln/([^/]+)/{ALERT}$")).unwrap();
+    if let Some(captures) = re.captures(&publish.topic) {
+        trace!(
+            msg_tx,
+            format!(
+                "[{NAME}] <- pub::{ALERT}: {}, '{}'",
+                &captures[1],
+                String::from_utf8_lossy(&publish.payload)
+            )
+        );
+
+        return true;
+    }
+
+    false
+}
+
+// tln/<name>/onboard or any tln/<name>/<key> telemetry, the other topics are
+// processed before
 async fn process_event_publish_system(msg_tx: &Sender<Msg>, publish: &Publish) -> bool {
     let topic = &publish.topic;
 
-    let re = regex::Regex::new(
-        r"^tln/([^/]+)/(onboard|app_uptime|host_uptime|version|temperature|weather|tailscale_ip|os|cpu_arch|cpu_usage|memory_usage|disk_usage)$",
-    )
-    .unwrap();
+    let re = regex::Regex::new(r"^tln/([^/]+)/([A-Za-z0-9_.-]+)$").unwrap();
     if let Some(captures) = re.captures(topic) {
         let name = &captures[1];
         let key = &captures[2];
-        let payload = std::str::from_utf8(&publish.payload).unwrap();
-
-        let (
-            onboard,
-            app_uptime,
-            host_uptime,
-            version,
-            temperature,
-            weather,
-            tailscale_ip,
-            os,
-            cpu_arch,
-            cpu_usage,
-            memory_usage,
-            disk_usage,
-        ) = match key {
-            "onboard" => {
-                let onboard = match payload.parse::<u64>() {
-                    Ok(t) => t,
-                    Err(e) => {
-                        error!(
-                            msg_tx,
-                            format!("[{NAME}] Error: <- pub::onboard: {name}: {e:?}.")
-                        );
-                        return true;
-                    }
-                };
-
-                if onboard != 0 && onboard != 1 {
-                    error!(
-                        msg_tx,
-                        format!(
-                            "[{NAME}] Error: <- pub::onboard: {name}. Wrong onboard: '{onboard}'."
-                        )
-                    );
-                    return true;
-                }
-
-                (
-                    Some(onboard == 1),
-                    None,
-                    None,
-                    None,
-                    None,
-                    None,
-                    None,
-                    None,
-                    None,
-                    None,
-                    None,
-                    None,
-                )
-            }
-            "app_uptime" => {
-                let app_uptime = match payload.parse::<u64>() {
-                    Ok(t) => t,
-                    Err(e) => {
-                        error!(
-                            msg_tx,
-                            format!("[{NAME}] Error: <- pub::app_uptime: {name}. Wrong uptime: {payload}: {e:?}.")
-                        );
-                        return true;
-                    }
-                };
-                (
-                    None,
-                    Some(app_uptime),
-                    None,
-                    None,
-                    None,
-                    None,
-                    None,
-                    None,
-                    None,
-                    None,
-                    None,
-                    None,
-                )
-            }
-            "host_uptime" => {
-                let host_uptime = match payload.parse::<u64>() {
-                    Ok(t) => t,
-                    Err(e) => {
-                        error!(
-                            msg_tx,
-                            format!("[{NAME}] Error: <- pub::host_uptime: {name}. Wrong uptime: {payload}: {e:?}.")
-                        );
-                        return true;
-                    }
-                };
-                (
-                    None,
-                    None,
-                    Some(host_uptime),
-                    None,
-                    None,
-                    None,
-                    None,
-                    None,
-                    None,
-                    None,
-                    None,
-                    None,
-                )
-            }
-            "version" => (
-                None,
-                None,
-                None,
-                Some(payload.to_owned()),
-                None,
-                None,
-                None,
-                None,
-                None,
-                None,
-                None,
-                None,
-            ),
-            "temperature" => {
-                let temperature = match payload.parse::<f32>() {
-                    Ok(t) => t,
-                    Err(e) => {
-                        error!(
-                            msg_tx,
-                            format!("[{NAME}] Error: <- pub::temperature: {name}: Wrong temperature: {payload}: {e:?}.")
-                        );
-                        return true;
-                    }
-                };
-                (
-                    None,
-                    None,
-                    None,
-                    None,
-                    Some(temperature),
-                    None,
-                    None,
-                    None,
-                    None,
-                    None,
-                    None,
-                    None,
-                )
-            }
-            "weather" => (
-                None,
-                None,
-                None,
-                None,
-                None,
-                Some(payload.to_owned()),
-                None,
-                None,
-                None,
-                None,
-                None,
-                None,
-            ),
-            "tailscale_ip" => (
-                None,
-                None,
-                None,
-                None,
-                None,
-                None,
-                Some(payload.to_owned()),
-                None,
-                None,
-                None,
-                None,
-                None,
-            ),
-            "os" => (
-                None,
-                None,
-                None,
-                None,
-                None,
-                None,
-                None,
-                Some(payload.to_owned()),
-                None,
-                None,
-                None,
-                None,
-            ),
-            "cpu_arch" => (
-                None,
-                None,
-                None,
-                None,
-                None,
-                None,
-                None,
-                None,
-                Some(payload.to_owned()),
-                None,
-                None,
-                None,
-            ),
-            "cpu_usage" => {
-                let cpu_usage = match payload.parse::<f32>() {
-                    Ok(t) => t,
-                    Err(e) => {
-                        error!(
-                            msg_tx,
-                            format!("[{NAME}] Error: <- pub::cpu_usage: {name}: Wrong cpu_usage: {payload}: {e:?}.")
-                        );
-                        return true;
-                    }
-                };
-                (
-                    None,
-                    None,
-                    None,
-                    None,
-                    None,
-                    None,
-                    None,
-                    None,
-                    None,
-                    Some(cpu_usage),
-                    None,
-                    None,
-                )
-            }
-            "memory_usage" => {
-                let memory_usage = match payload.parse::<f32>() {
-                    Ok(t) => t,
-                    Err(e) => {
-                        error!(
-                            msg_tx,
-                            format!("[{NAME}] Error: <- pub::memory_usage: {name}: Wrong memory_usage: {payload}: {e:?}.")
-                        );
-                        return true;
-                    }
-                };
-                (
-                    None,
-                    None,
-                    None,
-                    None,
-                    None,
-                    None,
-                    None,
-                    None,
-                    None,
-                    None,
-                    Some(memory_usage),
-                    None,
-                )
-            }
-            "disk_usage" => {
-                let disk_usage = match payload.parse::<f32>() {
-                    Ok(t) => t,
-                    Err(e) => {
-                        error!(
-                            msg_tx,
-                            format!("[{NAME}] Error: <- pub::disk_usage: {name}: Wrong disk_usage: {payload}: {e:?}.")
-                        );
-                        return true;
-                    }
-                };
-                (
-                    None,
-                    None,
-                    None,
-                    None,
-                    None,
-                    None,
-                    None,
-                    None,
-                    None,
-                    None,
-                    None,
-                    Some(disk_usage),
-                )
-            }
-            _ => {
+        let payload = match std::str::from_utf8(&publish.payload) {
+            Ok(payload) => payload,
+            Err(e) => {
                 error!(
                     msg_tx,
-                    format!("[{NAME}] Error: <- pub: {name}. Unknown key: '{key}'.")
+                    format!("[{NAME}] Error: <- pub::{key}: {name}: {e:?}.")
                 );
                 return true;
             }
@@ -562,46 +346,18 @@ async fn process_event_publish_system(msg_tx: &Sender<Msg>, publish: &Publish) -
             format!("[{NAME}] <- pub::{key}: {name}, '{payload}'")
         );
 
-        // update the last seen if onboard is true OR any of uptime, version, temperature, os, cpu_arch, cpu_usage, memory_usage, disk_usage, weather is not None
-        let last_seen = if (onboard.is_some() && onboard.unwrap())
-            || app_uptime.is_some()
-            || host_uptime.is_some()
-            || version.is_some()
-            || temperature.is_some()
-            || os.is_some()
-            || cpu_arch.is_some()
-            || cpu_usage.is_some()
-            || memory_usage.is_some()
-            || disk_usage.is_some()
-            || weather.is_some()
-            || tailscale_ip.is_some()
-        {
-            Some(utils::ts())
-        } else {
-            None
+        let device = match device_info(name, key, payload) {
+            Ok(device) => device,
+            Err(e) => {
+                error!(
+                    msg_tx,
+                    format!("[{NAME}] Error: <- pub::{key}: {name}. {e}")
+                );
+                return true;
+            }
         };
 
-        device_update(
-            msg_tx,
-            DevInfo {
-                ts: utils::ts(),
-                name: name.to_owned(),
-                onboard,
-                app_uptime,
-                host_uptime,
-                version,
-                temperature,
-                os,
-                cpu_arch,
-                cpu_usage,
-                memory_usage,
-                disk_usage,
-                weather,
-                last_seen,
-                tailscale_ip,
-            },
-        )
-        .await;
+        device_update(msg_tx, device).await;
 
         return true;
     }
@@ -609,6 +365,30 @@ async fn process_event_publish_system(msg_tx: &Sender<Msg>, publish: &Publish) -
     false
 }
 
+// the device update of a publish, anything but an offboard means it is seen
+pub fn device_info(name: &str, key: &str, payload: &str) -> Result<DevInfo, String> {
+    let mut device = DevInfo::new(name);
+
+    if key == ONBOARD {
+        let onboard = match payload.parse::<u64>() {
+            Ok(t @ (0 | 1)) => t == 1,
+            _ => return Err(format!("Wrong onboard: '{payload}'.")),
+        };
+        device.onboard = Some(onboard);
+        if !onboard {
+            return Ok(device);
+        }
+    } else {
+        device
+            .telemetry
+            .insert(key.to_owned(), Metric::parse(key, payload));
+    }
+
+    device.last_seen = Some(utils::ts());
+
+    Ok(device)
+}
+
 async fn process_event_publish_file(msg_tx: &Sender<Msg>, publish: &Publish) -> bool {
     let topic = &publish.topic;
 
@@ -686,3 +466,89 @@ async fn process_event_publish_nas(msg_tx: &Sender<Msg>, publish: &Publish) -> b
 
     false
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(v: &[&str]) -> Vec<String> {
+        v.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn parse_keeps_quoted_words() {
+        assert_eq!(
+            parse(r#"r pi5 p shell cmd "ls -al" now"#),
+            strings(&["r", "pi5", "p", "shell", "cmd", "ls -al", "now"])
+        );
+    }
+
+    #[test]
+    fn ask_payload_round_trip() {
+        let payload = ask_payload("pi5", &strings(&["p", "shell", "cmd", "ls -al"]));
+        assert_eq!(payload, r#"r pi5 p shell cmd "ls -al""#);
+
+        let (plugin, cmd) = parse_ask(&parse(&payload)).unwrap();
+        assert_eq!(plugin, "shell");
+        assert_eq!(cmd.action, "cmd");
+        assert_eq!(cmd.data, strings(&["ls -al"]));
+        assert!(matches!(cmd.reply, Reply::Device(d) if d == "pi5"));
+    }
+
+    #[test]
+    fn parse_ask_without_data() {
+        let (plugin, cmd) = parse_ask(&strings(&["r", "pi5", "p", "system", "update"])).unwrap();
+        assert_eq!(plugin, "system");
+        assert_eq!(cmd.action, "update");
+        assert!(cmd.data.is_empty());
+    }
+
+    #[test]
+    fn device_info_from_telemetry() {
+        let device = device_info("pi5", "temperature", "48.3").unwrap();
+        assert_eq!(device.number("temperature"), Some(48.3));
+        assert_eq!(device.display("temperature"), "48.3°C");
+        assert!(device.last_seen.is_some());
+
+        let device = device_info("nas", "ups_battery", r#"{"value": 87, "unit": "%"}"#).unwrap();
+        assert_eq!(device.display("ups_battery"), "87%");
+
+        let device = device_info("pi5", "os", "Debian GNU/Linux").unwrap();
+        assert_eq!(device.text("os").as_deref(), Some("Debian GNU/Linux"));
+
+        let device = device_info("pi5", "onboard", "0").unwrap();
+        assert_eq!(device.onboard, Some(false));
+        assert!(device.last_seen.is_none());
+        assert!(device.telemetry.is_empty());
+
+        assert!(device_info("pi5", "onboard", "2").is_err());
+    }
+
+    #[test]
+    fn broadcast_targets() {
+        assert!(is_broadcast("*"));
+        assert!(is_broadcast("@pi"));
+        assert!(!is_broadcast("pi5"));
+    }
+
+    #[test]
+    fn parse_ask_errors() {
+        let (reply, e) = parse_ask(&strings(&["x", "pi5"])).unwrap_err();
+        assert_eq!(e, "r is missing.");
+        assert!(matches!(reply, Reply::Device(d) if d == cfg::name()));
+
+        let (_, e) = parse_ask(&strings(&["r"])).unwrap_err();
+        assert_eq!(e, "reply is missing.");
+
+        // once the reply is known, errors go back to the asking device
+        let (reply, e) = parse_ask(&strings(&["r", "pi5", "q"])).unwrap_err();
+        assert_eq!(e, "p is missing.");
+        assert!(matches!(reply, Reply::Device(d) if d == "pi5"));
+
+        let (_, e) = parse_ask(&strings(&["r", "pi5", "p"])).unwrap_err();
+        assert_eq!(e, "plugin is missing.");
+
+        let (_, e) = parse_ask(&strings(&["r", "pi5", "p", "wol"])).unwrap_err();
+        assert_eq!(e, "action is missing.");
+    }
+}
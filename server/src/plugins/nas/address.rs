@@ -0,0 +1,194 @@
+use std::net::{IpAddr, Ipv4Addr};
+
+use sysinfo::Networks;
+use tokio::net::TcpStream;
+use tokio::time::{timeout, Duration};
+
+use crate::msg::{self, DevInfo};
+use crate::utils;
+
+pub const TAILSCALE: &str = "tailscale";
+
+// an unreachable LAN address must not hold the others
+const CONNECT_TIMEOUT: u64 = 5;
+
+fn in_cidr(ip: &IpAddr, cidr: &str) -> bool {
+    let (network, bits) = match cidr.split_once('/') {
+        Some(t) => t,
+        None => return false,
+    };
+    match (ip, network.parse::<Ipv4Addr>(), bits.parse::<u32>()) {
+        (IpAddr::V4(ip), Ok(network), Ok(bits)) if bits <= 32 => {
+            let mask = u32::MAX.checked_shl(32 - bits).unwrap_or(0);
+            u32::from(*ip) & mask == u32::from(network) & mask
+        }
+        _ => false,
+    }
+}
+
+// the addresses of the strategies in order, each one is an IP, a CIDR, an
+// interface name or "tailscale"
+pub fn select(strategies: &[String], interfaces: &[(String, Vec<IpAddr>)]) -> Vec<String> {
+    let mut addresses: Vec<String> = vec![];
+    let v4 = |ips: &Vec<IpAddr>| -> Vec<IpAddr> {
+        ips.iter().filter(|ip| ip.is_ipv4()).cloned().collect()
+    };
+
+    for strategy in strategies {
+        let selected: Vec<String> = if strategy == TAILSCALE {
+            let tailscale_ip = utils::get_tailscale_ip();
+            if tailscale_ip == "n/a" {
+                vec![]
+            } else {
+                vec![tailscale_ip]
+            }
+        } else if strategy.parse::<IpAddr>().is_ok() {
+            vec![strategy.clone()]
+        } else if strategy.contains('/') {
+            interfaces
+                .iter()
+                .flat_map(|(_, ips)| v4(ips))
+                .filter(|ip| in_cidr(ip, strategy))
+                .map(|ip| ip.to_string())
+                .collect()
+        } else {
+            interfaces
+                .iter()
+                .filter(|(name, _)| name == strategy)
+                .flat_map(|(_, ips)| v4(ips))
+                .map(|ip| ip.to_string())
+                .collect()
+        };
+
+        for address in selected {
+            if !addresses.contains(&address) {
+                addresses.push(address);
+            }
+        }
+    }
+
+    addresses
+}
+
+// where this device can be reached for the NAS traffic
+pub fn addresses(strategies: &[String]) -> Vec<String> {
+    let networks = Networks::new_with_refreshed_list();
+    let interfaces: Vec<(String, Vec<IpAddr>)> = networks
+        .iter()
+        .map(|(name, network)| {
+            (
+                name.clone(),
+                network.ip_networks().iter().map(|n| n.addr).collect(),
+            )
+        })
+        .collect();
+
+    select(strategies, &interfaces)
+}
+
+// as advertised, or the tailscale ip of the devices before the addresses
+pub fn device_addresses(device: &DevInfo) -> Vec<String> {
+    let addresses = device.list(msg::TM_ADDRESSES);
+    if !addresses.is_empty() {
+        return addresses;
+    }
+
+    match device.text(msg::TM_TAILSCALE_IP) {
+        Some(ip) if ip != "n/a" => vec![ip],
+        _ => vec![],
+    }
+}
+
+// as sent between the devices, "a,b,c"
+pub fn split(addresses: &str) -> Vec<String> {
+    addresses
+        .split(',')
+        .map(|s| s.trim())
+        .filter(|s| !s.is_empty() && *s != "n/a")
+        .map(|s| s.to_owned())
+        .collect()
+}
+
+// the first reachable address, moved first for the next connect
+pub async fn connect(addresses: &mut [String], port: u16) -> Result<TcpStream, String> {
+    let mut errors = vec![];
+    for idx in 0..addresses.len() {
+        let address = format!("{}:{port}", addresses[idx]);
+        match timeout(
+            Duration::from_secs(CONNECT_TIMEOUT),
+            TcpStream::connect(&address),
+        )
+        .await
+        {
+            Ok(Ok(stream)) => {
+                addresses[..=idx].rotate_right(1);
+                return Ok(stream);
+            }
+            Ok(Err(e)) => errors.push(format!("{address}: {e}")),
+            Err(_) => errors.push(format!("{address}: timeout")),
+        }
+    }
+
+    if errors.is_empty() {
+        return Err("no address".to_owned());
+    }
+
+    Err(errors.join(", "))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(v: &[&str]) -> Vec<String> {
+        v.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn select_in_order() {
+        let interfaces = vec![
+            (
+                "eth0".to_owned(),
+                vec!["192.168.1.20".parse().unwrap(), "fe80::1".parse().unwrap()],
+            ),
+            ("wlan0".to_owned(), vec!["10.0.0.7".parse().unwrap()]),
+        ];
+
+        assert_eq!(
+            select(
+                &strings(&["10.0.0.0/8", "eth0", "203.0.113.5"]),
+                &interfaces
+            ),
+            strings(&["10.0.0.7", "192.168.1.20", "203.0.113.5"])
+        );
+        // deduplicated, nothing for an unknown interface or another network
+        assert_eq!(
+            select(
+                &strings(&["192.168.1.0/24", "eth0", "eth1", "172.16.0.0/12"]),
+                &interfaces
+            ),
+            strings(&["192.168.1.20"])
+        );
+        assert!(in_cidr(&"192.168.1.20".parse().unwrap(), "0.0.0.0/0"));
+        assert!(!in_cidr(&"192.168.1.20".parse().unwrap(), "192.168.1.0"));
+
+        assert_eq!(
+            split("100.64.0.3, 192.168.1.20,"),
+            strings(&["100.64.0.3", "192.168.1.20"])
+        );
+        assert!(split("n/a").is_empty());
+    }
+
+    #[tokio::test]
+    async fn connect_to_the_first_reachable() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        // nothing listens on 127.0.0.2 with this port
+        let mut addresses = strings(&["127.0.0.2", "127.0.0.1"]);
+
+        assert!(connect(&mut addresses, port).await.is_ok());
+        assert_eq!(addresses, strings(&["127.0.0.1", "127.0.0.2"]));
+
+        assert_eq!(connect(&mut [], port).await.unwrap_err(), "no address");
+    }
+}
@@ -1,105 +1,655 @@
+// A snapshot of the share a day in ./backup/YYYY-MM-DD, with a manifest of
+// the size, mtime and hash of every file in ./backup/YYYY-MM-DD.json. A file
+// unchanged since the previous snapshot is a hard link to it, so a day costs
+// only what changed. Snapshots are built in a part folder renamed once
+// complete, then thinned out to the daily, weekly and monthly ones kept.
+
+use std::collections::{BTreeMap, BTreeSet};
 use std::fs;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
-use chrono::NaiveDate;
-use log::Level::Info;
+use chrono::{Datelike, NaiveDate};
+use log::Level::{Error, Info};
+use serde::{Deserialize, Serialize};
 use tokio::sync::mpsc::Sender;
 use tokio::time::Duration;
 
-use crate::cfg;
-use crate::info;
 use crate::msg::{log, Msg, Reply};
+use crate::plugins::nas::{index, transfer};
+use crate::{cfg, error, info};
 
 pub const NAME: &str = "nas";
 
-const BACKUP_DIR: &str = "./backup";
+pub const BACKUP_DIR: &str = "./backup";
 
-pub fn backup(msg_tx_clone: Sender<Msg>) {
-    tokio::spawn(async move {
-        loop {
-            // check if backup is needed
-            // backup dir is BACKUP_DIR+current_date (e.g. ./backup/2023-10-01)
-            let now = chrono::Local::now();
-            let date = now.format("%Y-%m-%d").to_string();
-            let backup_dir = format!("{BACKUP_DIR}/{date}");
-            if !Path::new(&backup_dir).exists() {
-                fs::create_dir_all(&backup_dir).unwrap();
-
-                // copy all files from cfg::FILE_FOLDER to backup_dir recursively
-                let files = get_all_files_recursively(Path::new(cfg::FILE_FOLDER));
-                for file in &files {
-                    let src = Path::new(file);
-                    let dst =
-                        Path::new(&backup_dir).join(src.strip_prefix("./shared").unwrap_or(src));
-                    if let Some(parent) = dst.parent() {
-                        fs::create_dir_all(parent).unwrap();
+const DATE_FORMAT: &str = "%Y-%m-%d";
+
+// between the checks for today's snapshot
+const CHECK_INTERVAL: u64 = 4 * 60 * 60;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct Entry {
+    size: u64,
+    mtime: u64, // nanoseconds, of the shared file
+    hash: String,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Manifest {
+    algorithm: String,
+    files: BTreeMap<String, Entry>,
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct Stats {
+    pub copied: usize,
+    pub linked: usize,
+    pub size: u64,
+    // what could not be read, in the next snapshot once it can
+    pub skipped: Vec<String>,
+}
+
+pub struct Retention {
+    pub daily: usize,
+    pub weekly: usize,
+    pub monthly: usize,
+}
+
+fn snapshot_path(backup: &Path, date: NaiveDate) -> PathBuf {
+    backup.join(date.format(DATE_FORMAT).to_string())
+}
+
+fn manifest_path(backup: &Path, date: NaiveDate) -> PathBuf {
+    backup.join(format!("{}.json", date.format(DATE_FORMAT)))
+}
+
+fn load_manifest(backup: &Path, date: NaiveDate) -> Result<Manifest, String> {
+    let path = manifest_path(backup, date);
+    let content =
+        fs::read_to_string(&path).map_err(|e| format!("Failed to read {}: {e}", path.display()))?;
+
+    serde_json::from_str(&content).map_err(|e| format!("Failed to parse {}: {e}", path.display()))
+}
+
+pub fn parse_date(date: &str) -> Result<NaiveDate, String> {
+    NaiveDate::parse_from_str(date, DATE_FORMAT)
+        .map_err(|_| format!("{date} is not a date, e.g. 2025-01-31"))
+}
+
+// complete ones, oldest first
+pub fn snapshots(backup: &Path) -> Vec<NaiveDate> {
+    let mut dates: Vec<NaiveDate> = fs::read_dir(backup)
+        .map(|entries| {
+            entries
+                .filter_map(|entry| entry.ok())
+                .filter(|entry| entry.path().is_dir())
+                .filter_map(|entry| parse_date(&entry.file_name().to_string_lossy()).ok())
+                .filter(|date| manifest_path(backup, *date).exists())
+                .collect()
+        })
+        .unwrap_or_default();
+    dates.sort();
+
+    dates
+}
+
+// relative to the share, with no part files; only the share itself failing
+// to be read is an error, any folder under it is skipped
+fn files(
+    share: &Path,
+    dir: &Path,
+    files: &mut Vec<String>,
+    skipped: &mut Vec<String>,
+) -> Result<(), String> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if dir == share => return Err(format!("{}: {e}", dir.display())),
+        Err(e) => {
+            skipped.push(format!("{}: {e}", dir.display()));
+            return Ok(());
+        }
+    };
+    for entry in entries {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(e) => {
+                skipped.push(format!("{}: {e}", dir.display()));
+                continue;
+            }
+        };
+        // a link is not followed, it may lead out of the share or back up it
+        let file_type = match entry.file_type() {
+            Ok(file_type) if !file_type.is_symlink() => file_type,
+            _ => continue,
+        };
+        let path = entry.path();
+        if file_type.is_dir() {
+            self::files(share, &path, files, skipped)?;
+        } else if file_type.is_file() && !transfer::is_part(&path) {
+            if let Ok(relative) = path.strip_prefix(share) {
+                files.push(relative.to_string_lossy().to_string());
+            }
+        }
+    }
+
+    Ok(())
+}
+
+pub fn snapshot(
+    share: &Path,
+    backup: &Path,
+    date: NaiveDate,
+    algorithm: &str,
+) -> Result<Stats, String> {
+    let target = snapshot_path(backup, date);
+    let part = transfer::part_path(&target);
+    if part.exists() {
+        fs::remove_dir_all(&part).map_err(|e| format!("{}: {e}", part.display()))?;
+    }
+    fs::create_dir_all(&part).map_err(|e| format!("{}: {e}", part.display()))?;
+
+    // the latest before this one, to link to
+    let previous = snapshots(backup)
+        .into_iter()
+        .rfind(|d| *d < date)
+        .and_then(|d| Some((snapshot_path(backup, d), load_manifest(backup, d).ok()?)))
+        .filter(|(_, manifest)| manifest.algorithm == algorithm);
+
+    let mut stats = Stats::default();
+    let mut relatives = vec![];
+    files(share, share, &mut relatives, &mut stats.skipped)?;
+
+    let mut manifest = Manifest {
+        algorithm: algorithm.to_owned(),
+        files: BTreeMap::new(),
+    };
+    for relative in relatives {
+        let src = share.join(&relative);
+        let dst = part.join(&relative);
+        // gone since it was listed, it is in the next one
+        let meta = match fs::metadata(&src) {
+            Ok(meta) => meta,
+            Err(_) => continue,
+        };
+        if let Some(parent) = dst.parent() {
+            if let Err(e) = fs::create_dir_all(parent) {
+                stats.skipped.push(format!("{}: {e}", parent.display()));
+                continue;
+            }
+        }
+
+        let (size, mtime) = (meta.len(), index::mtime(&meta));
+        let unchanged = previous.as_ref().and_then(|(dir, manifest)| {
+            let entry = manifest.files.get(&relative)?;
+            if entry.size != size || entry.mtime != mtime {
+                return None;
+            }
+            fs::hard_link(dir.join(&relative), &dst).ok()?;
+            Some(entry.clone())
+        });
+
+        let entry = match unchanged {
+            Some(entry) => {
+                stats.linked += 1;
+                entry
+            }
+            None => {
+                let copied = fs::copy(&src, &dst).and_then(|_| index::hash_file(&dst, algorithm));
+                match copied {
+                    Ok(hash) => {
+                        stats.copied += 1;
+                        Entry { size, mtime, hash }
+                    }
+                    Err(e) => {
+                        let _ = fs::remove_file(&dst);
+                        stats.skipped.push(format!("{relative}: {e}"));
+                        continue;
                     }
-                    fs::copy(src, dst).unwrap();
                 }
+            }
+        };
+        stats.size += entry.size;
+        manifest.files.insert(relative, entry);
+    }
+
+    let content = serde_json::to_string_pretty(&manifest).unwrap();
+    let manifest_path = manifest_path(backup, date);
+    fs::write(&manifest_path, content).map_err(|e| format!("{}: {e}", manifest_path.display()))?;
+    fs::rename(&part, &target).map_err(|e| format!("{}: {e}", target.display()))?;
+
+    Ok(stats)
+}
+
+// the full copies taken before the manifests, made snapshots so they are
+// listed, restored and pruned as the others
+pub fn migrate(backup: &Path, algorithm: &str) -> Result<Vec<NaiveDate>, String> {
+    let legacy: Vec<NaiveDate> = fs::read_dir(backup)
+        .map(|entries| {
+            entries
+                .filter_map(|entry| entry.ok())
+                .filter(|entry| entry.path().is_dir())
+                .filter_map(|entry| parse_date(&entry.file_name().to_string_lossy()).ok())
+                .filter(|date| !manifest_path(backup, *date).exists())
+                .collect()
+        })
+        .unwrap_or_default();
+
+    let mut migrated = vec![];
+    for date in legacy {
+        let dir = snapshot_path(backup, date);
+        let (mut relatives, mut skipped) = (vec![], vec![]);
+        files(&dir, &dir, &mut relatives, &mut skipped)?;
+
+        let mut manifest = Manifest {
+            algorithm: algorithm.to_owned(),
+            files: BTreeMap::new(),
+        };
+        for relative in relatives {
+            let path = dir.join(&relative);
+            // left out of the manifest, as a snapshot leaves out what it could not copy
+            let (meta, hash) = match fs::metadata(&path)
+                .and_then(|meta| Ok((meta, index::hash_file(&path, algorithm)?)))
+            {
+                Ok(read) => read,
+                Err(_) => continue,
+            };
+            // of the copy, never the shared file's, so nothing is linked to it
+            let entry = Entry {
+                size: meta.len(),
+                mtime: index::mtime(&meta),
+                hash,
+            };
+            manifest.files.insert(relative, entry);
+        }
+
+        let content = serde_json::to_string_pretty(&manifest).unwrap();
+        let manifest_path = manifest_path(backup, date);
+        fs::write(&manifest_path, content)
+            .map_err(|e| format!("{}: {e}", manifest_path.display()))?;
+        migrated.push(date);
+    }
+    migrated.sort();
+
+    Ok(migrated)
+}
+
+// the latest ones, then the latest of each of the latest weeks and months
+pub fn retained(dates: &[NaiveDate], retention: &Retention) -> BTreeSet<NaiveDate> {
+    let mut newest_first = dates.to_vec();
+    newest_first.sort_by(|a, b| b.cmp(a));
+
+    let mut kept: BTreeSet<NaiveDate> =
+        newest_first.iter().take(retention.daily).copied().collect();
+
+    let mut weeks = BTreeSet::new();
+    let mut months = BTreeSet::new();
+    for date in &newest_first {
+        let week = date.iso_week();
+        if weeks.len() < retention.weekly && weeks.insert((week.year(), week.week())) {
+            kept.insert(*date);
+        }
+        if months.len() < retention.monthly && months.insert((date.year(), date.month())) {
+            kept.insert(*date);
+        }
+    }
 
+    kept
+}
+
+// the snapshots removed
+pub fn prune(backup: &Path, retention: &Retention) -> Result<Vec<NaiveDate>, String> {
+    let dates = snapshots(backup);
+    let kept = retained(&dates, retention);
+
+    let mut removed = vec![];
+    for date in dates.into_iter().filter(|date| !kept.contains(date)) {
+        let path = snapshot_path(backup, date);
+        fs::remove_dir_all(&path).map_err(|e| format!("{}: {e}", path.display()))?;
+        let _ = fs::remove_file(manifest_path(backup, date));
+        removed.push(date);
+    }
+
+    Ok(removed)
+}
+
+// a line a snapshot: date, files, size
+pub fn list(backup: &Path) -> Vec<String> {
+    snapshots(backup)
+        .into_iter()
+        .map(|date| match load_manifest(backup, date) {
+            Ok(manifest) => {
+                let size: u64 = manifest.files.values().map(|entry| entry.size).sum();
+                format!(
+                    "{} {:>8} files {:>16}",
+                    date.format(DATE_FORMAT),
+                    manifest.files.len(),
+                    crate::utils::format_number(size)
+                )
+            }
+            Err(e) => format!("{} {e}", date.format(DATE_FORMAT)),
+        })
+        .collect()
+}
+
+// "+ added", "- removed", "~ changed" from one snapshot to the other
+pub fn diff(backup: &Path, from: NaiveDate, to: NaiveDate) -> Result<Vec<String>, String> {
+    let (from, to) = (load_manifest(backup, from)?, load_manifest(backup, to)?);
+
+    let mut lines = vec![];
+    for (relative, entry) in &to.files {
+        match from.files.get(relative) {
+            None => lines.push(format!("+ {relative}")),
+            Some(old) if old.hash != entry.hash => lines.push(format!("~ {relative}")),
+            Some(_) => (),
+        }
+    }
+    for relative in from.files.keys() {
+        if !to.files.contains_key(relative) {
+            lines.push(format!("- {relative}"));
+        }
+    }
+    lines.sort_by(|a, b| a[2..].cmp(&b[2..]));
+
+    Ok(lines)
+}
+
+// the files missing or not as in the manifest, and how many were checked
+pub fn verify(backup: &Path, date: NaiveDate) -> Result<(Vec<String>, usize), String> {
+    let manifest = load_manifest(backup, date)?;
+    let dir = snapshot_path(backup, date);
+
+    let mut bad = vec![];
+    for (relative, entry) in &manifest.files {
+        match index::hash_file(&dir.join(relative), &manifest.algorithm) {
+            Ok(hash) if hash == entry.hash => (),
+            Ok(_) => bad.push(format!("{relative} differs")),
+            Err(e) => bad.push(format!("{relative} {e}")),
+        }
+    }
+
+    Ok((bad, manifest.files.len()))
+}
+
+// `relative` as it was, a file or all of a folder, copied back over the
+// share through part files; the files restored
+pub fn restore(
+    backup: &Path,
+    date: NaiveDate,
+    share: &Path,
+    relative: &str,
+) -> Result<usize, String> {
+    let manifest = load_manifest(backup, date)?;
+    let dir = snapshot_path(backup, date);
+    let prefix = format!("{}/", relative.trim_end_matches('/'));
+    let restored: Vec<&String> = manifest
+        .files
+        .keys()
+        .filter(|file| *file == relative || file.starts_with(&prefix))
+        .collect();
+    if restored.is_empty() {
+        return Err(format!("{relative} is not in {}", date.format(DATE_FORMAT)));
+    }
+
+    for file in &restored {
+        // copied, a link would let an edit change the snapshot
+        let dst = share.join(file);
+        if let Some(parent) = dst.parent() {
+            fs::create_dir_all(parent).map_err(|e| format!("{}: {e}", parent.display()))?;
+        }
+        let part = transfer::part_path(&dst);
+        fs::copy(dir.join(file), &part)
+            .and_then(|_| fs::rename(&part, &dst))
+            .map_err(|e| {
+                let _ = fs::remove_file(&part);
+                format!("{file}: {e}")
+            })?;
+    }
+
+    Ok(restored.len())
+}
+
+pub fn retention() -> Retention {
+    Retention {
+        daily: cfg::nas_backup_daily(),
+        weekly: cfg::nas_backup_weekly(),
+        monthly: cfg::nas_backup_monthly(),
+    }
+}
+
+pub fn backup(msg_tx_clone: Sender<Msg>) {
+    tokio::spawn(async move {
+        let migrated =
+            tokio::task::spawn_blocking(|| migrate(Path::new(BACKUP_DIR), &cfg::nas_hash()))
+                .await
+                .map_err(|e| e.to_string())
+                .and_then(|result| result);
+        match migrated {
+            Ok(migrated) if !migrated.is_empty() => {
                 info!(
                     &msg_tx_clone,
-                    format!("[{NAME}] Backup created: {backup_dir}")
+                    format!("[{NAME}] Backups migrated: {}", migrated.len())
                 );
+            }
+            Ok(_) => (),
+            Err(e) => {
+                error!(&msg_tx_clone, format!("[{NAME}] Backup migrate: {e}"));
+            }
+        }
+
+        loop {
+            let date = chrono::Local::now().date_naive();
+            let backup = Path::new(BACKUP_DIR);
+            if !snapshot_path(backup, date).exists() {
+                let created = tokio::task::spawn_blocking(move || {
+                    snapshot(
+                        Path::new(cfg::FILE_FOLDER),
+                        Path::new(BACKUP_DIR),
+                        date,
+                        &cfg::nas_hash(),
+                    )
+                })
+                .await
+                .map_err(|e| e.to_string())
+                .and_then(|result| result);
 
-                // we keep at most 7 days of backup
-                let keep_latest_n = 7;
-                let mut date_dirs: Vec<(NaiveDate, String)> = fs::read_dir(BACKUP_DIR)
-                    .unwrap()
-                    .filter_map(|entry| {
-                        let entry = entry.ok().unwrap();
-                        let name = entry.file_name().to_string_lossy().into_owned();
-
-                        match NaiveDate::parse_from_str(&name, "%Y-%m-%d") {
-                            Ok(date) => Some((date, name)),
-                            Err(_) => None,
+                match created {
+                    Ok(stats) => {
+                        for e in &stats.skipped {
+                            error!(&msg_tx_clone, format!("[{NAME}] Backup skipped {e}"));
                         }
-                    })
-                    .collect();
-
-                date_dirs.sort_by_key(|(date, _)| *date);
-
-                if date_dirs.len() > keep_latest_n {
-                    let to_delete = &date_dirs[..date_dirs.len() - keep_latest_n];
-                    for (_, name) in to_delete {
-                        let path = Path::new(BACKUP_DIR).join(name);
-                        if path.is_dir() {
-                            info!(
-                                &msg_tx_clone,
-                                format!("[{NAME}] Backup removed: {}", path.display())
-                            );
-                            fs::remove_dir_all(path).unwrap();
+                        info!(
+                            &msg_tx_clone,
+                            format!(
+                                "[{NAME}] Backup created: {}, {} copied, {} linked, {} skipped, {}",
+                                snapshot_path(backup, date).display(),
+                                stats.copied,
+                                stats.linked,
+                                stats.skipped.len(),
+                                crate::utils::format_number(stats.size)
+                            )
+                        );
+
+                        match prune(backup, &retention()) {
+                            Ok(removed) => {
+                                for date in removed {
+                                    info!(
+                                        &msg_tx_clone,
+                                        format!(
+                                            "[{NAME}] Backup removed: {}",
+                                            snapshot_path(backup, date).display()
+                                        )
+                                    );
+                                }
+                            }
+                            Err(e) => {
+                                error!(&msg_tx_clone, format!("[{NAME}] Backup prune: {e}"));
+                            }
                         }
                     }
+                    Err(e) => {
+                        error!(&msg_tx_clone, format!("[{NAME}] Backup failed: {e}"));
+                    }
                 }
             }
 
-            // sleep for 4 hours
-            tokio::time::sleep(Duration::from_secs(4 * 60 * 60)).await;
+            tokio::time::sleep(Duration::from_secs(CHECK_INTERVAL)).await;
         }
     });
 }
 
-fn get_all_files_recursively(path: &Path) -> Vec<String> {
-    let mut output = Vec::new();
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::harness::TempDir;
 
-    if path.is_dir() {
-        for entry in fs::read_dir(path).unwrap() {
-            let entry = entry.unwrap();
-            let path = entry.path();
-            if path.is_file() {
-                let full_path = path.to_string_lossy().to_string();
-                output.push(full_path);
-            }
+    fn date(date: &str) -> NaiveDate {
+        parse_date(date).unwrap()
+    }
+
+    #[test]
+    fn snapshots_link_restore_and_verify() {
+        let dir = TempDir::new("backup");
+        let share = dir.join("shared");
+        let backup = dir.join("backup");
+        dir.write("shared/note/a.md", "a");
+        dir.write("shared/b.md", "b");
+
+        let (day1, day2) = (date("2026-10-17"), date("2026-10-18"));
+        let stats = snapshot(&share, &backup, day1, index::MD5).unwrap();
+        assert_eq!((stats.copied, stats.linked, stats.size), (2, 0, 2));
+
+        fs::write(share.join("b.md"), "bb").unwrap();
+        fs::write(share.join("c.md"), "c").unwrap();
+        fs::remove_file(share.join("note/a.md")).unwrap();
+        fs::write(share.join("note/d.md"), "d").unwrap();
+        fs::write(share.join("note/d.md.cng-part"), "half").unwrap();
+        let stats = snapshot(&share, &backup, day2, index::MD5).unwrap();
+        assert_eq!((stats.copied, stats.linked), (3, 0));
+
+        // unchanged since, linked
+        let day3 = date("2026-10-19");
+        let stats = snapshot(&share, &backup, day3, index::MD5).unwrap();
+        assert_eq!((stats.copied, stats.linked), (0, 3));
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+            let c = fs::metadata(backup.join("2026-10-19/c.md")).unwrap();
+            assert_eq!(c.nlink(), 2);
         }
-        for entry in fs::read_dir(path).unwrap() {
-            let entry = entry.unwrap();
-            let path = entry.path();
-            if path.is_dir() {
-                output.extend(get_all_files_recursively(&path));
-            }
+
+        assert_eq!(snapshots(&backup), vec![day1, day2, day3]);
+        assert_eq!(list(&backup).len(), 3);
+        assert_eq!(
+            diff(&backup, day1, day2).unwrap(),
+            vec!["~ b.md", "+ c.md", "- note/a.md", "+ note/d.md"]
+        );
+        assert!(diff(&backup, day2, day3).unwrap().is_empty());
+
+        assert_eq!(restore(&backup, day1, &share, "note").unwrap(), 1);
+        assert_eq!(fs::read_to_string(share.join("note/a.md")).unwrap(), "a");
+        assert_eq!(restore(&backup, day1, &share, "b.md").unwrap(), 1);
+        assert_eq!(fs::read_to_string(share.join("b.md")).unwrap(), "b");
+        assert!(restore(&backup, day1, &share, "c.md").is_err());
+        // the snapshot is not touched by an edit of what was restored
+        fs::write(share.join("b.md"), "edited").unwrap();
+        assert_eq!(verify(&backup, day1).unwrap(), (vec![], 2));
+
+        fs::write(backup.join("2026-10-18/c.md"), "x").unwrap();
+        let (bad, checked) = verify(&backup, day3).unwrap();
+        assert_eq!((bad, checked), (vec!["c.md differs".to_owned()], 3));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn links_and_unreadable_files_are_skipped() {
+        use std::os::unix::fs::{symlink, PermissionsExt};
+        let dir = TempDir::new("backup_skip");
+        let share = dir.join("shared");
+        let backup = dir.join("backup");
+        dir.write("outside/secret.md", "s");
+        dir.write("shared/note/a.md", "a");
+        let locked = dir.write("shared/locked.md", "l");
+        symlink(&share, share.join("note/loop")).unwrap();
+        symlink(dir.join("outside"), share.join("outside")).unwrap();
+        symlink(dir.join("outside/secret.md"), share.join("secret.md")).unwrap();
+
+        fs::set_permissions(&locked, fs::Permissions::from_mode(0o000)).unwrap();
+        // root reads it anyway
+        let unreadable = fs::read(&locked).is_err();
+
+        let day = date("2026-10-18");
+        let stats = snapshot(&share, &backup, day, index::MD5).unwrap();
+        if unreadable {
+            assert_eq!((stats.copied, stats.skipped.len()), (1, 1));
+            assert!(!backup.join("2026-10-18/locked.md").exists());
+        } else {
+            assert_eq!((stats.copied, stats.skipped.len()), (2, 0));
         }
+        assert!(!backup.join("2026-10-18/secret.md").exists());
+        assert!(!backup.join("2026-10-18/outside").exists());
+        assert_eq!(verify(&backup, day).unwrap().1, stats.copied);
+
+        fs::set_permissions(&locked, fs::Permissions::from_mode(0o644)).unwrap();
+    }
+
+    #[test]
+    fn legacy_copies_are_migrated_and_pruned() {
+        let dir = TempDir::new("backup_legacy");
+        let backup = dir.join("backup");
+        for day in ["2026-10-01", "2026-10-02", "2026-10-03"] {
+            dir.write(&format!("backup/{day}/note/a.md"), day);
+        }
+        fs::create_dir_all(backup.join("not-a-date")).unwrap();
+        assert!(snapshots(&backup).is_empty());
+
+        let migrated = migrate(&backup, index::MD5).unwrap();
+        assert_eq!(migrated.len(), 3);
+        assert_eq!(snapshots(&backup), migrated);
+        assert_eq!(verify(&backup, date("2026-10-02")).unwrap(), (vec![], 1));
+        // once only
+        assert!(migrate(&backup, index::MD5).unwrap().is_empty());
+
+        let retention = Retention {
+            daily: 1,
+            weekly: 0,
+            monthly: 0,
+        };
+        let removed = prune(&backup, &retention).unwrap();
+        assert_eq!(removed, vec![date("2026-10-01"), date("2026-10-02")]);
+        assert!(!backup.join("2026-10-01").exists());
+        assert!(backup.join("2026-10-03/note/a.md").exists());
+        assert!(backup.join("not-a-date").exists());
     }
 
-    output
+    #[test]
+    fn retention_keeps_days_weeks_and_months() {
+        // a snapshot a day from January to March 2026
+        let dates: Vec<NaiveDate> = date("2026-01-01")
+            .iter_days()
+            .take_while(|d| *d <= date("2026-03-31"))
+            .collect();
+        let retention = Retention {
+            daily: 3,
+            weekly: 3,
+            monthly: 3,
+        };
+
+        let kept: Vec<String> = retained(&dates, &retention)
+            .iter()
+            .map(|d| d.format(DATE_FORMAT).to_string())
+            .collect();
+        assert_eq!(
+            kept,
+            vec![
+                // the end of January and of February
+                "2026-01-31",
+                "2026-02-28",
+                // the Sunday ending the week before
+                "2026-03-22",
+                "2026-03-29",
+                "2026-03-30",
+                "2026-03-31",
+            ]
+        );
+    }
 }
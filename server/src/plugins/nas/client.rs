@@ -1,18 +1,21 @@
-use std::fs::{self, File};
-use std::io::{Read, Write};
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 use log::Level::{Error, Info};
+use tokio::fs;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::{TcpListener, TcpStream};
+use tokio::net::TcpListener;
 use tokio::sync::mpsc::{self, Sender};
-use tokio::time::{timeout, Duration};
+use tokio::time::{timeout, timeout_at, Duration, Instant};
 
 use crate::cfg;
 use crate::msg::{self, log, Msg, Reply};
-use crate::plugins::nas::files_data;
-use crate::utils;
+use crate::plugins::nas::filter::Filter;
+use crate::plugins::nas::proto::{self, Request, Response};
+use crate::plugins::nas::secure::{self, Channel};
+use crate::plugins::nas::{address, delta, files_data, tombstones, transfer};
+use crate::plugins::system::upgrade;
 use crate::{error, info, reply_me, unknown};
+use crate::{shared, utils};
 
 pub const NAME: &str = "nas";
 
@@ -20,7 +23,8 @@ const LISTENING: &str = "0.0.0.0";
 const SERVER_PORT: u16 = 9760;
 const CLIENT_PORT: u16 = 9761;
 
-const BUFFER_SIZE: usize = 4096;
+// for the device to connect back, between its requests see cfg nas_idle_timeout
+const TIMEOUT: u64 = 10;
 
 struct LastSyncInfo {
     device_name: String,
@@ -33,11 +37,7 @@ pub struct ClientMsg {
     pub data: Vec<String>,
 }
 
-pub fn client(
-    msg_tx_clone: Sender<Msg>,
-    mut client_rx: mpsc::Receiver<ClientMsg>,
-    tailscale_ip: String,
-) {
+pub fn client(msg_tx_clone: Sender<Msg>, mut client_rx: mpsc::Receiver<ClientMsg>) {
     tokio::spawn(async move {
         info!(&msg_tx_clone, format!("[{NAME}] Client started"));
 
@@ -47,14 +47,14 @@ pub fn client(
             match event.action.as_str() {
                 "SYNC_DEVICE" => {
                     let device_name = &event.data[0];
-                    let device_tailscale_ip = &event.data[1];
+                    let mut device_addresses = address::split(&event.data[1]);
                     let device_remote_modify_time = &event.data[2];
                     let device_remote_modify_time = device_remote_modify_time
                         .to_string()
                         .parse::<u64>()
                         .unwrap();
 
-                    if device_tailscale_ip == &tailscale_ip {
+                    if *device_name == cfg::name() {
                         continue;
                     }
 
@@ -80,216 +80,76 @@ pub fn client(
                         format!("[{NAME}] Do SYNC_DEVICE for {device_name}")
                     );
 
-                    let files_data = files_data::get_files_data(Path::new(cfg::FILE_FOLDER));
-                    let files_data_str = serde_json::to_string(&files_data).unwrap();
-
-                    // send files_data
+                    if let Err(e) =
+                        sync_device(&msg_tx_clone, device_name, &mut device_addresses).await
                     {
-                        let mut stream = match TcpStream::connect(format!(
-                            "{device_tailscale_ip}:{CLIENT_PORT}"
-                        ))
-                        .await
-                        {
-                            Ok(s) => s,
+                        error!(
+                            &msg_tx_clone,
+                            format!("[{NAME}] Failed to sync {device_name}. Err: {e}")
+                        );
+                    }
+                }
+                "SEND_RELEASE" => {
+                    let device_name = &event.data[0];
+                    let mut device_addresses = address::split(&event.data[1]);
+                    let version = &event.data[2];
+                    let reply = Reply::Device(device_name.to_owned());
+
+                    let (release, bytes) =
+                        match upgrade::load_release(cfg::RELEASES_FOLDER, version) {
+                            Ok(Some(release)) => release,
+                            Ok(None) => {
+                                log(
+                                    &msg_tx_clone,
+                                    reply,
+                                    Error,
+                                    format!(
+                                        "[{NAME}] release {version} not found on {}",
+                                        cfg::name()
+                                    ),
+                                )
+                                .await;
+                                continue;
+                            }
                             Err(e) => {
-                                error!(
+                                log(
                                     &msg_tx_clone,
-                                    format!("[{NAME}] Failed to connect to {device_tailscale_ip}:{CLIENT_PORT}. Err: {e}")
-                                );
+                                    reply,
+                                    Error,
+                                    format!("[{NAME}] release {version}: {e}"),
+                                )
+                                .await;
                                 continue;
                             }
                         };
 
-                        info!(
-                            &msg_tx_clone,
-                            format!(
-                                "[{NAME}] [Go] Send files_data to: {device_name}, {}",
-                                utils::format_number(files_data_str.len() as u64)
-                            )
-                        );
-                        let start_ts = utils::ts();
-
-                        let request = format!("PUT files_data {tailscale_ip}\n");
-                        stream.write_all(request.as_bytes()).await.unwrap();
-                        stream.write_all(files_data_str.as_bytes()).await.unwrap();
+                    info!(
+                        &msg_tx_clone,
+                        format!(
+                            "[{NAME}] [Go] Send release {version} to: {device_name}, {}",
+                            utils::format_number(bytes.len() as u64)
+                        )
+                    );
+                    let start_ts = utils::ts();
 
-                        let escaped_time = utils::ts() - start_ts;
-                        info!(
+                    if let Err(e) =
+                        send_release(device_name, &mut device_addresses, release, &bytes).await
+                    {
+                        error!(
                             &msg_tx_clone,
-                            format!(
-                                "[{NAME}] [Ok] Send files_data to: {device_name}, {}.",
-                                utils::transmit_str(files_data_str.len() as u64, escaped_time)
-                            )
+                            format!("[{NAME}] Failed to send release {version} to {device_name}. Err: {e}")
                         );
+                        continue;
                     }
 
-                    // accept Non-NAS to send GET/PUT
-                    let listening = format!("{LISTENING}:{SERVER_PORT}");
-                    let listener = TcpListener::bind(&listening).await.unwrap();
-                    info!(&msg_tx_clone, format!("[{NAME}] Listening on {listening}"));
-
-                    let mut idx = 0;
-                    loop {
-                        let (mut socket, _addr) = listener.accept().await.unwrap();
-
-                        let mut buffer = [0; BUFFER_SIZE];
-                        match timeout(Duration::from_secs(10), socket.read(&mut buffer)).await {
-                            Ok(Ok(size)) if size > 0 => {
-                                let mut received_data = Vec::new();
-                                received_data.extend_from_slice(&buffer[..size]);
-
-                                let pos = received_data.iter().position(|&b| b == b'\n').unwrap();
-
-                                let command = &received_data[..=pos];
-                                let command = String::from_utf8_lossy(command).trim().to_string();
-                                received_data.drain(..=pos);
-
-                                info!(
-                                    &msg_tx_clone,
-                                    format!("[{NAME}] [{idx}] [Go] Recv: {command}")
-                                );
-
-                                // GET filename
-                                if let Some(filename) = command.strip_prefix("GET ") {
-                                    let filename = Path::new(filename);
-                                    if filename.exists() {
-                                        match File::open(filename) {
-                                            Ok(mut file) => {
-                                                let start_ts = utils::ts();
-
-                                                let mut contents = Vec::new();
-                                                file.read_to_end(&mut contents).unwrap();
-                                                if socket.write_all(&contents).await.is_err() {
-                                                    error!(
-                                                        &msg_tx_clone,
-                                                        format!(
-                                                            "[{NAME}] Failed to send file contents"
-                                                        )
-                                                    );
-
-                                                    break;
-                                                }
-
-                                                let escaped_time = utils::ts() - start_ts;
-                                                info!(
-                                                    &msg_tx_clone,
-                                                    format!("[{NAME}] [{idx}] [Ok] Recv: {command}, {}.",
-                                                        utils::transmit_str(contents.len() as u64, escaped_time)
-                                                    )
-                                                );
-                                            }
-                                            Err(e) => {
-                                                error!(
-                                                    &msg_tx_clone,
-                                                    format!("[{NAME}] Failed to open file {filename:?}. Err: {e}")
-                                                );
-                                            }
-                                        }
-                                    } else {
-                                        error!(
-                                            &msg_tx_clone,
-                                            format!("[{NAME}] ERROR: File not found")
-                                        );
-
-                                        if socket.write_all(b"ERROR: File not found").await.is_err()
-                                        {
-                                            error!(
-                                                &msg_tx_clone,
-                                                format!("[{NAME}] Failed to send error message")
-                                            );
-
-                                            break;
-                                        }
-                                    }
-
-                                    idx += 1;
-                                    continue;
-                                }
-
-                                // PUT filename
-                                if let Some(filename) = command.strip_prefix("PUT ") {
-                                    let start_ts = utils::ts();
-
-                                    while let Ok(size) = socket.read(&mut buffer).await {
-                                        if size == 0 {
-                                            break;
-                                        }
-                                        received_data.extend_from_slice(&buffer[..size]);
-                                    }
-
-                                    let filename = Path::new(filename);
-
-                                    // Ensure the parent directories exist
-                                    if let Some(parent) = filename.parent() {
-                                        fs::create_dir_all(parent).unwrap();
-                                    }
-
-                                    let mut file = File::create(filename).unwrap();
-                                    file.write_all(&received_data).unwrap();
-
-                                    let escaped_time = utils::ts() - start_ts;
-                                    info!(
-                                        &msg_tx_clone,
-                                        format!(
-                                            "[{NAME}] [{idx}] [Ok] Recv: {command}, {}.",
-                                            utils::transmit_str(
-                                                received_data.len() as u64,
-                                                escaped_time
-                                            )
-                                        )
-                                    );
-
-                                    idx += 1;
-                                    continue;
-                                }
-
-                                // END
-                                if command.strip_prefix("END").is_some() {
-                                    msg::cmd(
-                                        &msg_tx_clone,
-                                        reply_me!(),
-                                        NAME.to_owned(),
-                                        msg::ACT_NAS.to_owned(),
-                                        vec!["sync_remote".to_owned(), device_name.to_owned()],
-                                    )
-                                    .await;
-
-                                    info!(
-                                        &msg_tx_clone,
-                                        format!("[{NAME}] [{idx}] [Ok] Recv: END")
-                                    );
-
-                                    break;
-                                }
-                            }
-                            Ok(Ok(0)) => {
-                                error!(&msg_tx_clone, format!("[{NAME}] ⚠️ 客戶端關閉連線"));
-
-                                break;
-                            }
-                            Ok(Err(e)) => {
-                                error!(&msg_tx_clone, format!("[{NAME}] ❌ 讀取錯誤: {e}"));
-
-                                break;
-                            }
-                            Err(_) => {
-                                error!(
-                                    &msg_tx_clone,
-                                    format!("[{NAME}] ⏰ 讀取超時，跳過這次連線")
-                                );
-
-                                break;
-                            }
-                            _ => {
-                                error!(
-                                    &msg_tx_clone,
-                                    format!("[{NAME}] Error reading from socket")
-                                );
-
-                                break;
-                            }
-                        }
-                    }
+                    let escaped_time = utils::ts() - start_ts;
+                    info!(
+                        &msg_tx_clone,
+                        format!(
+                            "[{NAME}] [Ok] Send release {version} to: {device_name}, {}.",
+                            utils::transmit_str(bytes.len() as u64, escaped_time)
+                        )
+                    );
                 }
                 _ => {
                     unknown!(&msg_tx_clone, NAME, event);
@@ -298,3 +158,358 @@ pub fn client(
         }
     });
 }
+
+// ask the device to sync, then serve what it asks for on the connection it
+// opens back
+async fn sync_device(
+    msg_tx: &Sender<Msg>,
+    device_name: &str,
+    device_addresses: &mut [String],
+) -> Result<(), String> {
+    // listening before the SYNC, the device may connect back at once
+    let listening = format!("{LISTENING}:{SERVER_PORT}");
+    let listener = TcpListener::bind(&listening)
+        .await
+        .map_err(|e| format!("{listening}: {e}"))?;
+    info!(msg_tx, format!("[{NAME}] Listening on {listening}"));
+
+    {
+        let stream = address::connect(device_addresses, CLIENT_PORT).await?;
+        let mut stream = secure::connect(stream, device_name).await?;
+        proto::hello(&mut stream).await?;
+        // the interfaces may have changed since the start
+        let sync = Request::Sync {
+            addresses: address::addresses(&cfg::nas_addresses()),
+        };
+        proto::request(&mut stream, &sync).await?;
+    }
+
+    // anyone else connecting meanwhile is turned away
+    let deadline = Instant::now() + Duration::from_secs(TIMEOUT);
+    let mut socket = loop {
+        let socket = match timeout_at(deadline, listener.accept()).await {
+            Ok(Ok((socket, addr))) => match secure::accept(socket, device_name).await {
+                Ok(socket) => socket,
+                Err(e) => {
+                    error!(msg_tx, format!("[{NAME}] Rejected {addr}: {e}"));
+                    continue;
+                }
+            },
+            Ok(Err(e)) => return Err(e.to_string()),
+            Err(_) => return Err(format!("{device_name} did not connect back")),
+        };
+
+        break socket;
+    };
+    let device = proto::accept(&mut socket).await?;
+    if device != device_name {
+        return Err(format!("{device} connected instead"));
+    }
+
+    let idle = Duration::from_secs(cfg::nas_idle_timeout());
+    serve(msg_tx, &mut socket, device_name, idle).await
+}
+
+async fn serve(
+    msg_tx: &Sender<Msg>,
+    socket: &mut Channel,
+    device_name: &str,
+    idle: Duration,
+) -> Result<(), String> {
+    let mut idx = 0;
+    loop {
+        let request: Request = match timeout(idle, proto::read_frame(socket)).await {
+            Ok(Ok(request)) => request,
+            Ok(Err(e)) => return Err(e.to_string()),
+            Err(_) => return Err("timeout".to_owned()),
+        };
+        if request == Request::Ping {
+            proto::write_frame(socket, &Response::ok(0))
+                .await
+                .map_err(|e| e.to_string())?;
+            continue;
+        }
+        info!(msg_tx, format!("[{NAME}] [{idx}] [Go] Recv: {request}"));
+        let start_ts = utils::ts();
+
+        let file = match request.path().map(shared::resolve) {
+            Some(Ok(file)) => file,
+            Some(Err(e)) => {
+                error!(
+                    msg_tx,
+                    format!("[{NAME}] [{idx}] Rejected {request} from {device_name}: {e}")
+                );
+                proto::write_frame(socket, &Response::error(proto::FORBIDDEN, e.clone()))
+                    .await
+                    .map_err(|e| e.to_string())?;
+                // the body of a PUT is on its way already
+                if let Request::Put { .. } | Request::PutDelta { .. } | Request::GetDelta { .. } =
+                    request
+                {
+                    return Err(e);
+                }
+                idx += 1;
+                continue;
+            }
+            None => PathBuf::new(),
+        };
+
+        let size = match &request {
+            Request::List { roots } => {
+                // the device leaves out what it ignores, on both sides alike
+                let filter = Filter::roots(roots);
+                // hashing is blocking, off the runtime
+                let mut files_data = tokio::task::spawn_blocking(move || {
+                    let share = Path::new(cfg::FILE_FOLDER);
+                    files_data::get_files_data(share, &filter, &cfg::nas_hash())
+                })
+                .await
+                .map_err(|e| e.to_string())??;
+                files_data.tombstones = tombstones::listed()?;
+                let json = serde_json::to_vec(&files_data).unwrap();
+                let size = json.len() as u64;
+                proto::write_frame(socket, &Response::ok(size))
+                    .await
+                    .map_err(|e| e.to_string())?;
+                socket.write_all(&json).await.map_err(|e| e.to_string())?;
+
+                size
+            }
+            Request::Get { path } => match fs::metadata(&file).await {
+                Ok(meta) if meta.is_file() => {
+                    proto::write_frame(socket, &Response::ok(meta.len()))
+                        .await
+                        .map_err(|e| e.to_string())?;
+                    // the device can not tell the rest of a file from the next
+                    // response, so a failure mid-file ends the connection
+                    transfer::send_file(msg_tx, &file, meta.len(), socket)
+                        .await
+                        .map_err(|e| format!("GET {path}: {e}"))?
+                }
+                _ => {
+                    error!(msg_tx, format!("[{NAME}] [{idx}] Not found: {path}"));
+                    let response = Response::error(proto::NOT_FOUND, path.to_owned());
+                    proto::write_frame(socket, &response)
+                        .await
+                        .map_err(|e| e.to_string())?;
+                    idx += 1;
+                    continue;
+                }
+            },
+            Request::Put { path, size } => {
+                let result = transfer::recv_file(msg_tx, socket, &file, *size).await;
+                let response = match &result {
+                    Ok(size) => Response::ok(*size),
+                    Err(e) => Response::error(proto::FAILED, e.to_string()),
+                };
+                proto::write_frame(socket, &response)
+                    .await
+                    .map_err(|e| e.to_string())?;
+
+                result.map_err(|e| format!("PUT {path}: {e}"))?
+            }
+            Request::Signature { path } => match delta::sign(&file).await {
+                Ok(signature) => {
+                    let bytes = signature.to_bytes();
+                    let size = bytes.len() as u64;
+                    proto::write_frame(socket, &Response::ok(size))
+                        .await
+                        .map_err(|e| e.to_string())?;
+                    socket.write_all(&bytes).await.map_err(|e| e.to_string())?;
+
+                    size
+                }
+                // no copy here, the device sends the file whole
+                Err(e) => {
+                    let response = Response::error(proto::NOT_FOUND, format!("{path}: {e}"));
+                    proto::write_frame(socket, &response)
+                        .await
+                        .map_err(|e| e.to_string())?;
+                    idx += 1;
+                    continue;
+                }
+            },
+            Request::PutDelta {
+                path,
+                size,
+                block_size,
+            } => {
+                let result = delta::recv_delta(socket, &file, *block_size, &file, *size).await;
+                let response = match &result {
+                    Ok(_) => Response::ok(*size),
+                    Err(e) => Response::error(proto::FAILED, e.to_string()),
+                };
+                proto::write_frame(socket, &response)
+                    .await
+                    .map_err(|e| e.to_string())?;
+
+                result.map_err(|e| format!("PUTDELTA {path}: {e}"))?
+            }
+            Request::GetDelta { path, signature } => {
+                if *signature > delta::MAX_SIGNATURE {
+                    let response =
+                        Response::error(proto::BAD_REQUEST, format!("{signature} bytes"));
+                    let _ = proto::write_frame(socket, &response).await;
+                    return Err(format!("GETDELTA {path}: signature of {signature} bytes"));
+                }
+                let mut bytes = vec![0; *signature as usize];
+                socket
+                    .read_exact(&mut bytes)
+                    .await
+                    .map_err(|e| e.to_string())?;
+
+                let (signature, meta) = match (
+                    delta::Signature::from_bytes(&bytes),
+                    fs::metadata(&file).await,
+                ) {
+                    (Ok(signature), Ok(meta)) if meta.is_file() => (signature, meta),
+                    (Err(e), _) => {
+                        let response = Response::error(proto::BAD_REQUEST, e.to_string());
+                        proto::write_frame(socket, &response)
+                            .await
+                            .map_err(|e| e.to_string())?;
+                        idx += 1;
+                        continue;
+                    }
+                    _ => {
+                        error!(msg_tx, format!("[{NAME}] [{idx}] Not found: {path}"));
+                        let response = Response::error(proto::NOT_FOUND, path.to_owned());
+                        proto::write_frame(socket, &response)
+                            .await
+                            .map_err(|e| e.to_string())?;
+                        idx += 1;
+                        continue;
+                    }
+                };
+
+                proto::write_frame(socket, &Response::ok(meta.len()))
+                    .await
+                    .map_err(|e| e.to_string())?;
+                // as announced, a change meanwhile fails the MD5 on the device
+                let mut reader = fs::File::open(&file)
+                    .await
+                    .map_err(|e| e.to_string())?
+                    .take(meta.len());
+                delta::send_delta(&mut reader, &signature, socket)
+                    .await
+                    .map_err(|e| format!("GETDELTA {path}: {e}"))?
+            }
+            Request::Delete { path } => {
+                let removed = shared::remove(&file);
+                let response = match removed {
+                    Ok(()) => match tombstones::record(path, device_name) {
+                        Ok(()) => Response::ok(0),
+                        Err(e) => Response::error(proto::FAILED, e),
+                    },
+                    Err(e) => Response::error(proto::NOT_FOUND, e.to_string()),
+                };
+                proto::write_frame(socket, &response)
+                    .await
+                    .map_err(|e| e.to_string())?;
+
+                0
+            }
+            Request::End => {
+                proto::write_frame(socket, &Response::ok(0))
+                    .await
+                    .map_err(|e| e.to_string())?;
+
+                msg::cmd(
+                    msg_tx,
+                    reply_me!(),
+                    NAME.to_owned(),
+                    msg::ACT_NAS.to_owned(),
+                    vec!["sync_remote".to_owned(), device_name.to_owned()],
+                )
+                .await;
+
+                info!(msg_tx, format!("[{NAME}] [{idx}] [Ok] Recv: END"));
+
+                return Ok(());
+            }
+            _ => {
+                let response = Response::error(proto::BAD_REQUEST, request.to_string());
+                proto::write_frame(socket, &response)
+                    .await
+                    .map_err(|e| e.to_string())?;
+                return Err(format!("unexpected {request}"));
+            }
+        };
+
+        let escaped_time = utils::ts() - start_ts;
+        info!(
+            msg_tx,
+            format!(
+                "[{NAME}] [{idx}] [Ok] Recv: {request}, {}.",
+                utils::transmit_str(size, escaped_time)
+            )
+        );
+        idx += 1;
+    }
+}
+
+async fn send_release(
+    device_name: &str,
+    device_addresses: &mut [String],
+    release: upgrade::Release,
+    bytes: &[u8],
+) -> Result<(), String> {
+    let stream = address::connect(device_addresses, CLIENT_PORT).await?;
+    let mut stream = secure::connect(stream, device_name).await?;
+    proto::hello(&mut stream).await?;
+
+    let request = Request::Release {
+        release,
+        size: bytes.len() as u64,
+    };
+    proto::write_frame(&mut stream, &request)
+        .await
+        .map_err(|e| e.to_string())?;
+    stream.write_all(bytes).await.map_err(|e| e.to_string())?;
+
+    let response: Response = proto::read_frame(&mut stream)
+        .await
+        .map_err(|e| e.to_string())?;
+    if !response.is_ok() {
+        return Err(format!("{} {}", response.status, response.message));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::harness::TempDir;
+
+    #[tokio::test]
+    async fn a_slow_signer_keeps_the_connection() {
+        let dir = TempDir::new("client");
+        let path = dir.write("big.bin", vec![7; 64 * 1024]);
+        // signing a large copy takes longer than the NAS waits
+        let slow_sign = |path: PathBuf| async move {
+            tokio::time::sleep(Duration::from_millis(600)).await;
+            delta::sign(&path)
+                .await
+                .map(|signature| signature.block_size)
+        };
+        let idle = Duration::from_millis(200);
+
+        let (msg_tx, _msg_rx) = mpsc::channel(1024);
+        let (mut device, mut nas) = tokio::io::duplex(4096);
+        let served = tokio::spawn(async move { serve(&msg_tx, &mut nas, "pi5", idle).await });
+        let every = Duration::from_millis(50);
+        let signed = proto::keep_alive(&mut device, slow_sign(path.clone()), every).await;
+        assert!(matches!(signed, Ok(Ok(_))));
+        proto::request(&mut device, &Request::End).await.unwrap();
+        assert_eq!(served.await.unwrap(), Ok(()));
+
+        // without the pings
+        let (msg_tx, _msg_rx) = mpsc::channel(1024);
+        let (mut device, mut nas) = tokio::io::duplex(4096);
+        let served = tokio::spawn(async move { serve(&msg_tx, &mut nas, "pi5", idle).await });
+        slow_sign(path).await.unwrap();
+        assert_eq!(served.await.unwrap(), Err("timeout".to_owned()));
+        assert!(proto::request(&mut device, &Request::End).await.is_err());
+    }
+}
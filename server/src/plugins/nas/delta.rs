@@ -0,0 +1,465 @@
+// Block-level transfer of a changed file, as rsync does it: the receiver
+// signs the copy it has with a weak rolling and a strong checksum per block,
+// the sender slides over its file a byte at a time and answers with the
+// blocks found in the copy and the literal bytes in between.
+//
+// Ops are a tag byte, COPY a u32 block index, LITERAL a u32 length and the
+// bytes, END the MD5 of the whole file which the receiver checks before the
+// part file is renamed over the copy.
+
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::io::{self, SeekFrom};
+use std::path::Path;
+
+use tokio::fs::{self, File};
+use tokio::io::{
+    AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt, BufReader, BufWriter,
+};
+
+use crate::plugins::nas::transfer;
+
+// smaller files go whole, their signature and ops would save little
+pub const DELTA_MIN: u64 = 1024 * 1024;
+
+const MIN_BLOCK: usize = 2 * 1024;
+const MAX_BLOCK: usize = 128 * 1024;
+const MAX_LITERAL: usize = 64 * 1024;
+const READ_SIZE: usize = 256 * 1024;
+
+// 20 bytes a block, 128 MB for a 1 TB file
+pub const MAX_SIGNATURE: u64 = 128 * 1024 * 1024;
+
+const OP_END: u8 = 0;
+const OP_COPY: u8 = 1;
+const OP_LITERAL: u8 = 2;
+
+// about the square root of the size, so the signature and the ops of a small
+// change grow alike
+pub fn block_size(size: u64) -> usize {
+    ((size as f64).sqrt() as usize).clamp(MIN_BLOCK, MAX_BLOCK)
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+// rsync's: two 16-bit sums, of the bytes and of the running sums
+struct Rolling {
+    a: u32,
+    b: u32,
+    len: u32,
+}
+
+impl Rolling {
+    fn new(block: &[u8]) -> Self {
+        let (mut a, mut b) = (0u32, 0u32);
+        for (i, &x) in block.iter().enumerate() {
+            a = a.wrapping_add(x as u32);
+            b = b.wrapping_add((block.len() - i) as u32 * x as u32);
+        }
+
+        Self {
+            a,
+            b,
+            len: block.len() as u32,
+        }
+    }
+
+    fn roll(&mut self, out: u8, next: u8) {
+        self.a = self.a.wrapping_sub(out as u32).wrapping_add(next as u32);
+        self.b = self
+            .b
+            .wrapping_sub(self.len.wrapping_mul(out as u32))
+            .wrapping_add(self.a);
+    }
+
+    fn digest(&self) -> u32 {
+        (self.a & 0xffff) | (self.b << 16)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Signature {
+    pub block_size: usize,
+    blocks: Vec<(u32, [u8; 16])>,
+}
+
+impl Signature {
+    // of the full blocks, a short last one is sent as literal anyway
+    pub async fn of<R>(reader: &mut R, size: u64) -> io::Result<Self>
+    where
+        R: AsyncRead + Unpin,
+    {
+        let block_size = block_size(size);
+        let mut blocks = vec![];
+        let mut block = vec![0; block_size];
+        for _ in 0..size / block_size as u64 {
+            reader.read_exact(&mut block).await?;
+            blocks.push((Rolling::new(&block).digest(), md5::compute(&block).0));
+        }
+
+        Ok(Self { block_size, blocks })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(8 + self.blocks.len() * 20);
+        bytes.extend_from_slice(&(self.block_size as u32).to_be_bytes());
+        bytes.extend_from_slice(&(self.blocks.len() as u32).to_be_bytes());
+        for (weak, strong) in &self.blocks {
+            bytes.extend_from_slice(&weak.to_be_bytes());
+            bytes.extend_from_slice(strong);
+        }
+
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> io::Result<Self> {
+        let u32_at = |i: usize| u32::from_be_bytes(bytes[i..i + 4].try_into().unwrap());
+        if bytes.len() < 8 {
+            return Err(invalid(format!("signature of {} bytes", bytes.len())));
+        }
+        let block_size = u32_at(0) as usize;
+        let count = u32_at(4) as usize;
+        if !(MIN_BLOCK..=MAX_BLOCK).contains(&block_size) || bytes.len() != 8 + count * 20 {
+            return Err(invalid(format!(
+                "signature of {count} blocks of {block_size} in {} bytes",
+                bytes.len()
+            )));
+        }
+
+        let blocks = (0..count)
+            .map(|i| {
+                let at = 8 + i * 20;
+                (u32_at(at), bytes[at + 4..at + 20].try_into().unwrap())
+            })
+            .collect();
+
+        Ok(Self { block_size, blocks })
+    }
+}
+
+async fn write_literal<W>(writer: &mut W, literal: &mut Vec<u8>) -> io::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    for part in literal.chunks(MAX_LITERAL) {
+        writer.write_u8(OP_LITERAL).await?;
+        writer.write_u32(part.len() as u32).await?;
+        writer.write_all(part).await?;
+    }
+    literal.clear();
+
+    Ok(())
+}
+
+// the ops turning the signed copy into what reader holds, and the literal
+// bytes among them
+pub async fn send_delta<R, W>(
+    reader: &mut R,
+    signature: &Signature,
+    writer: &mut W,
+) -> io::Result<u64>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let block = signature.block_size;
+    let mut weak_blocks: HashMap<u32, Vec<usize>> = HashMap::new();
+    for (idx, (weak, _)) in signature.blocks.iter().enumerate() {
+        weak_blocks.entry(*weak).or_default().push(idx);
+    }
+
+    let mut context = md5::Context::new();
+    let mut buffer = vec![];
+    let mut chunk = vec![0; READ_SIZE];
+    let mut pos = 0;
+    let mut eof = false;
+    let mut rolling: Option<Rolling> = None;
+    let mut literal = vec![];
+    let mut literal_size = 0;
+
+    loop {
+        // a block and the byte after it, to roll into
+        while !eof && buffer.len() - pos <= block {
+            if pos >= READ_SIZE {
+                buffer.drain(..pos);
+                pos = 0;
+            }
+            let n = reader.read(&mut chunk).await?;
+            if n == 0 {
+                eof = true;
+            }
+            context.consume(&chunk[..n]);
+            buffer.extend_from_slice(&chunk[..n]);
+        }
+        if buffer.len() - pos < block {
+            break;
+        }
+
+        let window = &buffer[pos..pos + block];
+        let weak = rolling.get_or_insert_with(|| Rolling::new(window)).digest();
+        let found = weak_blocks.get(&weak).and_then(|candidates| {
+            let strong = md5::compute(window).0;
+            candidates
+                .iter()
+                .find(|&&idx| signature.blocks[idx].1 == strong)
+        });
+        if let Some(&idx) = found {
+            write_literal(writer, &mut literal).await?;
+            writer.write_u8(OP_COPY).await?;
+            writer.write_u32(idx as u32).await?;
+            pos += block;
+            rolling = None;
+            continue;
+        }
+
+        literal.push(buffer[pos]);
+        literal_size += 1;
+        if literal.len() >= MAX_LITERAL {
+            write_literal(writer, &mut literal).await?;
+        }
+        match buffer.get(pos + block) {
+            Some(&next) => rolling.as_mut().unwrap().roll(buffer[pos], next),
+            None => rolling = None,
+        }
+        pos += 1;
+    }
+
+    literal.extend_from_slice(&buffer[pos..]);
+    literal_size += (buffer.len() - pos) as u64;
+    write_literal(writer, &mut literal).await?;
+    writer.write_u8(OP_END).await?;
+    writer.write_all(&context.compute().0).await?;
+    writer.flush().await?;
+
+    Ok(literal_size)
+}
+
+// of the copy at path, to send to the other end
+pub async fn sign(path: &Path) -> io::Result<Signature> {
+    let file = File::open(path).await?;
+    let meta = file.metadata().await?;
+    if !meta.is_file() {
+        return Err(io::Error::new(io::ErrorKind::NotFound, "not a file"));
+    }
+
+    Signature::of(&mut BufReader::with_capacity(READ_SIZE, file), meta.len()).await
+}
+
+// the ops applied to base into <path>.cng-part, renamed over path once its
+// size and MD5 are right; the literal bytes among them
+pub async fn recv_delta<R>(
+    reader: &mut R,
+    base: &Path,
+    block_size: usize,
+    path: &Path,
+    size: u64,
+) -> io::Result<u64>
+where
+    R: AsyncRead + Unpin,
+{
+    if !(MIN_BLOCK..=MAX_BLOCK).contains(&block_size) {
+        return Err(invalid(format!("blocks of {block_size} bytes")));
+    }
+
+    let part = transfer::part_path(path);
+    let result = async {
+        let mut base = File::open(base).await?;
+        let mut file = BufWriter::with_capacity(READ_SIZE, File::create(&part).await?);
+        let mut context = md5::Context::new();
+        let mut block = vec![0; block_size];
+        let mut written = 0;
+        let mut literal_size = 0;
+
+        loop {
+            match reader.read_u8().await? {
+                OP_COPY => {
+                    let idx = reader.read_u32().await? as u64;
+                    // past the size, refused before it is written
+                    if written + block_size as u64 > size {
+                        return Err(invalid(format!("past {size} bytes")));
+                    }
+                    base.seek(SeekFrom::Start(idx * block_size as u64)).await?;
+                    base.read_exact(&mut block).await?;
+                    context.consume(&block);
+                    file.write_all(&block).await?;
+                    written += block_size as u64;
+                }
+                OP_LITERAL => {
+                    let len = reader.read_u32().await? as usize;
+                    if len > MAX_LITERAL {
+                        return Err(invalid(format!("literal of {len} bytes")));
+                    }
+                    if written + len as u64 > size {
+                        return Err(invalid(format!("past {size} bytes")));
+                    }
+                    let mut literal = vec![0; len];
+                    reader.read_exact(&mut literal).await?;
+                    context.consume(&literal);
+                    file.write_all(&literal).await?;
+                    written += len as u64;
+                    literal_size += len as u64;
+                }
+                OP_END => break,
+                op => return Err(invalid(format!("op {op}"))),
+            }
+        }
+
+        let mut digest = [0; 16];
+        reader.read_exact(&mut digest).await?;
+        if written != size {
+            return Err(invalid(format!("{written} of {size} bytes")));
+        }
+        if context.compute().0 != digest {
+            return Err(invalid("MD5 differs".to_owned()));
+        }
+        file.flush().await?;
+        file.get_ref().sync_all().await?;
+        fs::rename(&part, path).await?;
+
+        Ok(literal_size)
+    }
+    .await;
+
+    if result.is_err() {
+        let _ = fs::remove_file(&part).await;
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn data(size: usize, seed: u32) -> Vec<u8> {
+        let mut x = seed;
+        (0..size)
+            .map(|_| {
+                x = x.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+                (x >> 16) as u8
+            })
+            .collect()
+    }
+
+    async fn delta(old: &[u8], new: &[u8]) -> (Vec<u8>, u64, Vec<u8>) {
+        let dir = std::env::temp_dir().join(format!(
+            "cng_delta_{}_{}",
+            std::process::id(),
+            old.len() + new.len()
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("a.db");
+        std::fs::write(&path, old).unwrap();
+
+        let signature = sign(&path).await.unwrap();
+        let signature = Signature::from_bytes(&signature.to_bytes()).unwrap();
+        let mut ops = vec![];
+        let literal = send_delta(&mut &new[..], &signature, &mut ops)
+            .await
+            .unwrap();
+
+        let received_literal = recv_delta(
+            &mut &ops[..],
+            &path,
+            signature.block_size,
+            &path,
+            new.len() as u64,
+        )
+        .await
+        .unwrap();
+        assert_eq!(received_literal, literal);
+        let received = std::fs::read(&path).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        (received, literal, ops)
+    }
+
+    #[test]
+    fn rolling_matches_computed() {
+        let bytes = data(100, 1);
+        let mut rolling = Rolling::new(&bytes[0..32]);
+        for i in 1..=68 {
+            rolling.roll(bytes[i - 1], bytes[i + 31]);
+            assert_eq!(rolling.digest(), Rolling::new(&bytes[i..i + 32]).digest());
+        }
+    }
+
+    #[tokio::test]
+    async fn only_changes_are_sent() {
+        let old = data(1024 * 1024, 7);
+
+        // appended to, as a log is
+        let mut new = old.clone();
+        new.extend_from_slice(b"one more line\n");
+        let (received, literal, _) = delta(&old, &new).await;
+        assert_eq!(received, new);
+        assert_eq!(literal, 14);
+
+        // edited in the middle, shifting the rest by a byte
+        let mut new = old.clone();
+        new[500_000] ^= 0xff;
+        new.insert(600_000, b'x');
+        let (received, literal, ops) = delta(&old, &new).await;
+        assert_eq!(received, new);
+        assert!(literal <= 3 * block_size(old.len() as u64) as u64);
+        assert!(ops.len() < 16 * 1024);
+
+        // nothing in common
+        let new = data(300_000, 8);
+        let (received, literal, _) = delta(&old, &new).await;
+        assert_eq!(received, new);
+        assert_eq!(literal, new.len() as u64);
+    }
+
+    #[tokio::test]
+    async fn broken_deltas_leave_the_copy() {
+        let dir = std::env::temp_dir().join(format!("cng_delta_broken_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("a.db");
+        let old = data(64 * 1024, 3);
+        std::fs::write(&path, &old).unwrap();
+
+        let signature = Signature::of(&mut &old[..], old.len() as u64)
+            .await
+            .unwrap();
+        let mut ops = vec![];
+        send_delta(&mut &old[..], &signature, &mut ops)
+            .await
+            .unwrap();
+        // another file was signed
+        let last = ops.len() - 1;
+        ops[last] ^= 1;
+
+        let size = old.len() as u64;
+        assert!(
+            recv_delta(&mut &ops[..], &path, signature.block_size, &path, size)
+                .await
+                .is_err()
+        );
+        assert_eq!(std::fs::read(&path).unwrap(), old);
+        assert!(!transfer::part_path(&path).exists());
+
+        // more than the size, refused with no end in sight
+        let mut ops = vec![];
+        for op in [OP_COPY, OP_COPY] {
+            ops.push(op);
+            ops.extend_from_slice(&0u32.to_be_bytes());
+        }
+        let e = recv_delta(&mut &ops[..], &path, signature.block_size, &path, 1)
+            .await
+            .unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        let mut ops = vec![OP_LITERAL];
+        ops.extend_from_slice(&2u32.to_be_bytes());
+        ops.extend_from_slice(b"ab");
+        let e = recv_delta(&mut &ops[..], &path, signature.block_size, &path, 1)
+            .await
+            .unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(std::fs::read(&path).unwrap(), old);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
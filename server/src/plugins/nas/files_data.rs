@@ -4,51 +4,190 @@ use std::time::UNIX_EPOCH;
 
 use serde::{Deserialize, Serialize};
 
-use crate::utils;
+use crate::plugins::nas::filter::Filter;
+use crate::plugins::nas::index::{self, Index};
+use crate::plugins::nas::tombstones::Tombstone;
+use crate::plugins::nas::transfer;
+use crate::{cfg, shared};
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct FileData {
     pub filename: String,
-    pub md5: String,
+    #[serde(alias = "md5")]
+    pub hash: String,
     pub modified: u64,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct FilesData {
+    // of every hash, the NAS decides and the devices follow
+    #[serde(default = "default_algorithm")]
+    pub algorithm: String,
     pub files_data: Vec<FileData>,
+    // only from the NAS
+    #[serde(default)]
+    pub tombstones: Vec<Tombstone>,
 }
 
-pub fn get_files_data_recursive(path: &Path, files_data: &mut Vec<FileData>) {
-    if path.is_dir() {
-        for entry in fs::read_dir(path).unwrap() {
-            let entry = entry.unwrap();
-            let path = entry.path();
-            if path.is_file() {
-                let filename = path.to_string_lossy().to_string();
-                let modified = fs::metadata(&path)
-                    .and_then(|meta| meta.modified())
-                    .map(|time| time.duration_since(UNIX_EPOCH))
-                    .map(|dur| dur.unwrap().as_secs())
-                    .unwrap_or(0);
-
-                let md5 = utils::calculate_md5(&filename).unwrap();
-
-                files_data.push(FileData {
-                    filename,
-                    md5,
-                    modified,
-                });
-            } else if path.is_dir() {
-                get_files_data_recursive(&path, files_data);
+fn default_algorithm() -> String {
+    index::MD5.to_owned()
+}
+
+fn get_files_data_recursive(
+    path: &Path,
+    filter: &Filter,
+    index: &mut Index,
+    files_data: &mut Vec<FileData>,
+) {
+    // a folder gone or unreadable since it was listed is skipped, as a file is
+    let entries = match fs::read_dir(path) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+    for entry in entries {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(_) => continue,
+        };
+        let path = entry.path();
+        if path.is_file() {
+            let filename = path.to_string_lossy().to_string();
+            if transfer::is_part(&path) || !filter.syncs(&filename) {
+                continue;
+            }
+            // a symlink out of the share is neither listed nor served
+            if entry.file_type().map(|t| t.is_symlink()).unwrap_or(false)
+                && shared::resolve(&filename).is_err()
+            {
+                continue;
             }
+            // gone or unreadable since read_dir, it is listed next time
+            let meta = match fs::metadata(&path) {
+                Ok(meta) => meta,
+                Err(_) => continue,
+            };
+            // before 1970, no side could tell which copy is newer
+            let modified = match meta.modified().map(|time| time.duration_since(UNIX_EPOCH)) {
+                Ok(Ok(dur)) => dur.as_secs(),
+                _ => continue,
+            };
+            let hash = match index.hash(&filename, &meta) {
+                Ok(hash) => hash,
+                Err(_) => continue,
+            };
+
+            files_data.push(FileData {
+                filename,
+                hash,
+                modified,
+            });
+        } else if path.is_dir() && filter.walks(&path) {
+            get_files_data_recursive(&path, filter, index, files_data);
         }
     }
 }
 
-pub fn get_files_data(path: &Path) -> FilesData {
+fn get_files_data_indexed(
+    path: &Path,
+    filter: &Filter,
+    algorithm: &str,
+    index_file: &str,
+) -> Result<FilesData, String> {
     let mut files_data = vec![];
 
-    get_files_data_recursive(path, &mut files_data);
+    // the rest of the index is kept for another listing
+    let in_scope = |filename: &str| filter.syncs(filename);
+    index::with_index(index_file, algorithm, in_scope, |index| {
+        get_files_data_recursive(path, filter, index, &mut files_data)
+    })?;
+
+    Ok(FilesData {
+        algorithm: algorithm.to_owned(),
+        files_data,
+        tombstones: vec![],
+    })
+}
+
+// the files synced, only those changed since the last listing are hashed
+pub fn get_files_data(path: &Path, filter: &Filter, algorithm: &str) -> Result<FilesData, String> {
+    get_files_data_indexed(path, filter, algorithm, cfg::INDEX_FILE)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::harness::TempDir;
+
+    #[test]
+    fn files_are_hashed_once_until_changed() {
+        let dir = TempDir::new("files_data");
+        let share = dir.join("shared");
+        let index_file = dir.join("index.json").to_string_lossy().to_string();
+        let all = Filter::roots(&[]);
+        let a = dir.write("shared/note/a.md", "hello");
 
-    FilesData { files_data }
+        let listed = get_files_data_indexed(&share, &all, index::MD5, &index_file).unwrap();
+        assert_eq!(listed.files_data.len(), 1);
+        assert_eq!(
+            listed.files_data[0].hash,
+            "5d41402abc4b2a76b9719d911017c592"
+        );
+
+        // an unchanged file is not read again
+        let index = fs::read_to_string(&index_file).unwrap();
+        fs::write(
+            &index_file,
+            index.replace("5d41402abc4b2a76b9719d911017c592", "cached"),
+        )
+        .unwrap();
+        let listed = get_files_data_indexed(&share, &all, index::MD5, &index_file).unwrap();
+        assert_eq!(listed.files_data[0].hash, "cached");
+
+        // another size is
+        fs::write(&a, "hello world").unwrap();
+        let listed = get_files_data_indexed(&share, &all, index::MD5, &index_file).unwrap();
+        assert_eq!(
+            listed.files_data[0].hash,
+            "5eb63bbbe01eeed093cb22bb8f5acdc3"
+        );
+
+        // as is everything for another algorithm
+        let listed = get_files_data_indexed(&share, &all, index::BLAKE3, &index_file).unwrap();
+        assert_eq!(listed.algorithm, index::BLAKE3);
+        assert_eq!(
+            listed.files_data[0].hash,
+            blake3::hash(b"hello world").to_hex().to_string()
+        );
+
+        // a file gone leaves the index
+        fs::remove_file(&a).unwrap();
+        assert!(
+            get_files_data_indexed(&share, &all, index::BLAKE3, &index_file)
+                .unwrap()
+                .files_data
+                .is_empty()
+        );
+        assert!(!fs::read_to_string(&index_file).unwrap().contains("a.md"));
+    }
+
+    #[test]
+    fn files_without_a_time_are_skipped() {
+        let dir = TempDir::new("files_time");
+        let index_file = dir.join("index.json").to_string_lossy().to_string();
+        let share = dir.join("shared");
+        dir.write("shared/a.md", "a");
+        let old = fs::File::create(share.join("old.md")).unwrap();
+        old.set_modified(UNIX_EPOCH - std::time::Duration::from_secs(60))
+            .unwrap();
+
+        let all = Filter::roots(&[]);
+        let listed = get_files_data_indexed(&share, &all, index::MD5, &index_file).unwrap();
+        assert_eq!(listed.files_data.len(), 1);
+        assert!(listed.files_data[0].filename.ends_with("a.md"));
+
+        // nor does a share which is not there fail
+        let missing = dir.join("missing");
+        let listed = get_files_data_indexed(&missing, &all, index::MD5, &index_file).unwrap();
+        assert!(listed.files_data.is_empty());
+    }
 }
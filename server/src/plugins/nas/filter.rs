@@ -0,0 +1,241 @@
+// What of the share is synced: the roots a device subscribes to, all of it
+// on the NAS, less what `.cngignore` names. Its patterns are gitignore's: `*`,
+// `?` and `**`, a `/` inside anchors to the share, a trailing `/` matches
+// directories only, `!` takes a path back and the last match wins.
+
+use std::fs;
+use std::path::Path;
+
+use regex::Regex;
+
+use crate::cfg;
+
+// editor and Finder leftovers, before the patterns of the file
+const DEFAULT_IGNORE: &[&str] = &["*.swp", "*~", ".DS_Store"];
+
+#[derive(Debug)]
+struct Pattern {
+    regex: Regex,
+    negated: bool,
+    dir_only: bool,
+}
+
+impl Pattern {
+    fn new(line: &str) -> Option<Self> {
+        let line = line.trim_end();
+        if line.is_empty() || line.starts_with('#') {
+            return None;
+        }
+
+        let (negated, line) = match line.strip_prefix('!') {
+            Some(line) => (true, line),
+            None => (false, line),
+        };
+        let (dir_only, line) = match line.strip_suffix('/') {
+            Some(line) => (true, line),
+            None => (false, line),
+        };
+        let anchored = line.contains('/');
+        let glob = line.trim_start_matches('/');
+        if glob.is_empty() {
+            return None;
+        }
+
+        let mut regex = String::from(if anchored { "^" } else { "^(?:.*/)?" });
+        let mut chars = glob.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '*' if chars.peek() == Some(&'*') => {
+                    chars.next();
+                    if chars.peek() == Some(&'/') {
+                        chars.next();
+                        regex.push_str("(?:.*/)?");
+                    } else {
+                        regex.push_str(".*");
+                    }
+                }
+                '*' => regex.push_str("[^/]*"),
+                '?' => regex.push_str("[^/]"),
+                c => regex.push_str(&regex::escape(&c.to_string())),
+            }
+        }
+        regex.push('$');
+
+        Some(Self {
+            regex: Regex::new(&regex).ok()?,
+            negated,
+            dir_only,
+        })
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Filter {
+    roots: Vec<String>,
+    patterns: Vec<Pattern>,
+}
+
+impl Filter {
+    // roots are folders of the share, all of it if none
+    pub fn new(roots: &[String], ignore: &str) -> Self {
+        let roots = roots
+            .iter()
+            .map(|root| root.trim_matches('/').to_owned())
+            .filter(|root| !root.is_empty())
+            .collect();
+        let patterns = DEFAULT_IGNORE
+            .iter()
+            .copied()
+            .chain(ignore.lines())
+            .filter_map(Pattern::new)
+            .collect();
+
+        Self { roots, patterns }
+    }
+
+    // the roots only, for the NAS to list what a device asks for
+    pub fn roots(roots: &[String]) -> Self {
+        Self {
+            roots: Self::new(roots, "").roots,
+            patterns: vec![],
+        }
+    }
+
+    // what this device syncs
+    pub fn local() -> Self {
+        let roots = match cfg::name() == cfg::nas() {
+            true => vec![],
+            false => cfg::nas_roots(),
+        };
+        let ignore = fs::read_to_string(cfg::IGNORE_FILE).unwrap_or_default();
+
+        Self::new(&roots, &ignore)
+    }
+
+    fn ignored(&self, relative: &str, is_dir: bool) -> bool {
+        let mut ignored = false;
+        for pattern in &self.patterns {
+            if (is_dir || !pattern.dir_only) && pattern.regex.is_match(relative) {
+                ignored = !pattern.negated;
+            }
+        }
+
+        ignored
+    }
+
+    fn in_roots(&self, relative: &str) -> bool {
+        self.roots.is_empty()
+            || self.roots.iter().any(|root| {
+                relative == root
+                    || relative
+                        .strip_prefix(root.as_str())
+                        .is_some_and(|rest| rest.starts_with('/'))
+            })
+    }
+
+    // a file as listed, "./shared/note/a.md"
+    pub fn syncs(&self, filename: &str) -> bool {
+        let relative = relative(filename);
+        if !self.in_roots(relative) {
+            return false;
+        }
+
+        // nothing comes back from an ignored directory
+        let mut dir = String::new();
+        let mut components = relative.split('/').peekable();
+        while let Some(component) = components.next() {
+            if !dir.is_empty() {
+                dir.push('/');
+            }
+            dir.push_str(component);
+            if self.ignored(&dir, components.peek().is_some()) {
+                return false;
+            }
+        }
+
+        true
+    }
+
+    // a directory which may hold a file synced
+    pub fn walks(&self, dir: &Path) -> bool {
+        let dir = dir.to_string_lossy();
+        let relative = relative(&dir);
+        if relative.is_empty() {
+            return true;
+        }
+
+        let leads_to_root = self.roots.iter().any(|root| {
+            root.strip_prefix(relative)
+                .is_some_and(|rest| rest.starts_with('/'))
+        });
+
+        leads_to_root || (self.in_roots(relative) && !self.ignored(relative, true))
+    }
+}
+
+fn relative(filename: &str) -> &str {
+    let share = cfg::FILE_FOLDER.trim_start_matches("./");
+    let filename = filename.trim_start_matches("./");
+    match filename.strip_prefix(share) {
+        Some(rest) => rest.trim_start_matches('/'),
+        None => filename,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ignore_patterns() {
+        let filter = Filter::new(
+            &[],
+            "# not synced\n\
+             upload/\n\
+             *.log\n\
+             !keep.log\n\
+             /build\n\
+             cache/**/tmp\n",
+        );
+
+        assert!(filter.syncs("./shared/note/a.md"));
+        assert!(!filter.syncs("./shared/note/.a.md.swp"));
+        assert!(!filter.syncs("./shared/note/a.md~"));
+        assert!(!filter.syncs("./shared/photo/.DS_Store"));
+
+        assert!(!filter.syncs("./shared/upload/video.mp4"));
+        assert!(!filter.walks(Path::new("./shared/upload")));
+        // a file named as a directory pattern
+        assert!(filter.syncs("./shared/note/upload"));
+
+        assert!(!filter.syncs("./shared/app/run.log"));
+        assert!(filter.syncs("./shared/app/keep.log"));
+
+        assert!(!filter.syncs("./shared/build/a.o"));
+        assert!(filter.syncs("./shared/src/build/a.o"));
+
+        assert!(!filter.syncs("./shared/cache/tmp"));
+        assert!(!filter.syncs("./shared/cache/a/b/tmp"));
+        assert!(filter.syncs("./shared/cache/a/b/tmp2"));
+    }
+
+    #[test]
+    fn roots() {
+        let filter = Filter::roots(&["note".to_owned(), "photo/2024/".to_owned()]);
+
+        assert!(filter.syncs("./shared/note/a.md"));
+        assert!(filter.syncs("./shared/photo/2024/a.jpg"));
+        assert!(!filter.syncs("./shared/photo/2023/a.jpg"));
+        assert!(!filter.syncs("./shared/notes/a.md"));
+        assert!(!filter.syncs("./shared/a.md"));
+
+        assert!(filter.walks(Path::new("./shared")));
+        assert!(filter.walks(Path::new("./shared/photo")));
+        assert!(filter.walks(Path::new("./shared/photo/2024/jan")));
+        assert!(!filter.walks(Path::new("./shared/photo/2023")));
+        assert!(!filter.walks(Path::new("./shared/upload")));
+
+        // and nothing is ignored
+        assert!(filter.syncs("./shared/note/.DS_Store"));
+    }
+}
@@ -0,0 +1,130 @@
+// The hash of every shared file, kept with what changes whenever its content
+// does, so a listing only hashes the files changed since the last one.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{self, File, Metadata};
+use std::io;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::UNIX_EPOCH;
+
+use serde::{Deserialize, Serialize};
+
+use crate::utils;
+
+pub const MD5: &str = "md5";
+pub const BLAKE3: &str = "blake3";
+
+// a sync and a listing may walk the share at once
+static LOCK: Mutex<()> = Mutex::new(());
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct Entry {
+    size: u64,
+    mtime: u64, // nanoseconds
+    inode: u64,
+    hash: String,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Index {
+    algorithm: String,
+    entries: BTreeMap<String, Entry>,
+    #[serde(skip)]
+    seen: BTreeSet<String>,
+}
+
+impl Index {
+    // another algorithm starts over
+    fn load(path: &str, algorithm: &str) -> Self {
+        let index = fs::read_to_string(path)
+            .ok()
+            .and_then(|content| serde_json::from_str::<Index>(&content).ok())
+            .filter(|index| index.algorithm == algorithm);
+
+        index.unwrap_or_else(|| Self {
+            algorithm: algorithm.to_owned(),
+            ..Self::default()
+        })
+    }
+
+    fn save(&self, path: &str) -> Result<(), String> {
+        let content = serde_json::to_string(self).unwrap();
+        fs::write(path, content).map_err(|e| format!("Failed to write {path}: {e}"))
+    }
+
+    // from the index while the file is unchanged
+    pub fn hash(&mut self, filename: &str, meta: &Metadata) -> io::Result<String> {
+        let (size, mtime, inode) = (meta.len(), mtime(meta), inode(meta));
+        self.seen.insert(filename.to_owned());
+        if let Some(entry) = self.entries.get(filename) {
+            if entry.size == size && entry.mtime == mtime && entry.inode == inode {
+                return Ok(entry.hash.clone());
+            }
+        }
+
+        let hash = hash_file(Path::new(filename), &self.algorithm)?;
+        self.entries.insert(
+            filename.to_owned(),
+            Entry {
+                size,
+                mtime,
+                inode,
+                hash: hash.clone(),
+            },
+        );
+
+        Ok(hash)
+    }
+}
+
+pub fn mtime(meta: &Metadata) -> u64 {
+    meta.modified()
+        .ok()
+        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .map(|dur| dur.as_nanos() as u64)
+        .unwrap_or(0)
+}
+
+#[cfg(unix)]
+fn inode(meta: &Metadata) -> u64 {
+    std::os::unix::fs::MetadataExt::ino(meta)
+}
+
+#[cfg(not(unix))]
+fn inode(_meta: &Metadata) -> u64 {
+    0
+}
+
+// streamed, anything but BLAKE3 is MD5
+pub fn hash_file(path: &Path, algorithm: &str) -> io::Result<String> {
+    match algorithm {
+        BLAKE3 => {
+            let mut hasher = blake3::Hasher::new();
+            hasher.update_reader(File::open(path)?)?;
+            Ok(hasher.finalize().to_hex().to_string())
+        }
+        _ => utils::calculate_md5(&path.to_string_lossy()),
+    }
+}
+
+// the index in `path` as `f` leaves it, without the files in scope it did not
+// hash
+pub fn with_index<T>(
+    path: &str,
+    algorithm: &str,
+    in_scope: impl Fn(&str) -> bool,
+    f: impl FnOnce(&mut Index) -> T,
+) -> Result<T, String> {
+    let _lock = LOCK.lock().unwrap();
+    let mut index = Index::load(path, algorithm);
+    let result = f(&mut index);
+
+    let seen = std::mem::take(&mut index.seen);
+    index
+        .entries
+        .retain(|filename, _| seen.contains(filename) || !in_scope(filename));
+    index.save(path)?;
+
+    Ok(result)
+}
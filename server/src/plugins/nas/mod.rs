@@ -1,5 +1,14 @@
+pub mod address;
 pub mod backup;
 pub mod client;
+pub mod delta;
 pub mod files_data;
+pub mod filter;
+pub mod index;
 pub mod monitor;
+pub mod proto;
+pub mod secure;
 pub mod server;
+pub mod state;
+pub mod tombstones;
+pub mod transfer;
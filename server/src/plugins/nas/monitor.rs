@@ -13,6 +13,8 @@ use log::Level::{Error, Info};
 
 use crate::cfg;
 use crate::msg::{self, log, Msg, Reply};
+use crate::plugins::nas::filter::Filter;
+use crate::plugins::nas::transfer;
 use crate::utils;
 use crate::{error, info, reply_me, unknown};
 
@@ -53,7 +55,14 @@ pub fn monitor(msg_tx: Sender<Msg>) {
         info!(&msg_tx, format!("[{NAME}] Monitoring {path_to_watch:?}"));
 
         while let Some(event) = rx.recv().await {
+            // read again for each event, .cngignore may have changed
+            let filter = Filter::local();
             for path in &event.paths {
+                if transfer::is_part(path)
+                    || !filter.syncs(&monitor_get_file(&path.to_string_lossy()))
+                {
+                    continue;
+                }
                 let path_str = path.display().to_string();
                 let debounce_map = debounce_map.clone();
 
@@ -93,11 +102,19 @@ fn monitor_get_file(file_path: &str) -> String {
 
 async fn handle_event(event: Event, msg_tx_clone: &Sender<Msg>) {
     match event.kind {
-        notify::event::EventKind::Create(_) => (),
+        notify::event::EventKind::Create(_) => {
+            for path in event.paths.iter() {
+                let filename = monitor_get_file(path.to_str().unwrap());
+
+                msg::file_event(msg_tx_clone, "create", &filename).await;
+            }
+        }
         notify::event::EventKind::Modify(_) => {
             for path in event.paths.iter() {
                 let filename = monitor_get_file(path.to_str().unwrap());
 
+                msg::file_event(msg_tx_clone, "modify", &filename).await;
+
                 info!(
                     msg_tx_clone,
                     format!("[{NAME}] [monitor] File is modified: {filename}")
@@ -121,6 +138,8 @@ async fn handle_event(event: Event, msg_tx_clone: &Sender<Msg>) {
             for path in event.paths.iter() {
                 let filename = monitor_get_file(path.to_str().unwrap());
 
+                msg::file_event(msg_tx_clone, "remove", &filename).await;
+
                 info!(
                     msg_tx_clone,
                     format!("[{NAME}] [monitor] File is removed: {filename}")
@@ -0,0 +1,296 @@
+// The NAS wire protocol on both ports: a connection starts with a HELLO, then
+// every request is answered by a response. Requests and responses are JSON in
+// frames of a 4-byte big-endian length, a file or a listing follows its
+// request or response as exactly `size` raw bytes.
+
+use std::fmt;
+use std::future::Future;
+use std::io;
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::time::{self, Duration};
+
+use crate::cfg;
+use crate::plugins::system::upgrade::Release;
+
+pub const VERSION: u32 = 1;
+
+// a request or a response, never a file
+const MAX_FRAME: u32 = 1024 * 1024;
+// how often a busy device pings, well within cfg nas_idle_timeout
+pub const KEEP_ALIVE: u64 = 5;
+
+pub const OK: u16 = 200;
+pub const BAD_REQUEST: u16 = 400;
+pub const FORBIDDEN: u16 = 403;
+pub const NOT_FOUND: u16 = 404;
+pub const FAILED: u16 = 500;
+pub const VERSION_NOT_SUPPORTED: u16 = 505;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "UPPERCASE")]
+pub enum Request {
+    Hello {
+        version: u32,
+        device: String,
+    },
+    // from the NAS: connect back to one of the addresses and sync
+    Sync {
+        addresses: Vec<String>,
+    },
+    // of the roots, all of the share if none
+    List {
+        #[serde(default)]
+        roots: Vec<String>,
+    },
+    Get {
+        path: String,
+    },
+    Put {
+        path: String,
+        size: u64,
+    },
+    // the signature of the NAS copy, answered with its size and its bytes
+    Signature {
+        path: String,
+    },
+    // delta ops against the signed copy, instead of the file
+    PutDelta {
+        path: String,
+        size: u64,
+        block_size: usize,
+    },
+    // the signature of the device copy follows, the ops come back
+    GetDelta {
+        path: String,
+        signature: u64,
+    },
+    Delete {
+        path: String,
+    },
+    Release {
+        release: Release,
+        size: u64,
+    },
+    // the device is still signing or hashing its copy
+    Ping,
+    End,
+}
+
+impl Request {
+    // of a file in the share
+    pub fn path(&self) -> Option<&str> {
+        match self {
+            Request::Get { path }
+            | Request::Put { path, .. }
+            | Request::Signature { path }
+            | Request::PutDelta { path, .. }
+            | Request::GetDelta { path, .. }
+            | Request::Delete { path } => Some(path),
+            _ => None,
+        }
+    }
+}
+
+// as logged
+impl fmt::Display for Request {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Request::Hello { version, device } => write!(f, "HELLO {device} v{version}"),
+            Request::Sync { addresses } => write!(f, "SYNC {}", addresses.join(",")),
+            Request::List { roots } => write!(f, "LIST {}", roots.join(",")),
+            Request::Get { path } => write!(f, "GET {path}"),
+            Request::Put { path, size } => write!(f, "PUT {path} {size}"),
+            Request::Signature { path } => write!(f, "SIGNATURE {path}"),
+            Request::PutDelta { path, size, .. } => write!(f, "PUTDELTA {path} {size}"),
+            Request::GetDelta { path, .. } => write!(f, "GETDELTA {path}"),
+            Request::Delete { path } => write!(f, "DELETE {path}"),
+            Request::Release { release, size } => write!(f, "RELEASE {} {size}", release.version),
+            Request::Ping => write!(f, "PING"),
+            Request::End => write!(f, "END"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Response {
+    pub status: u16,
+    #[serde(default)]
+    pub size: u64,
+    #[serde(default)]
+    pub message: String,
+}
+
+impl Response {
+    pub fn ok(size: u64) -> Self {
+        Self {
+            status: OK,
+            size,
+            message: String::new(),
+        }
+    }
+
+    pub fn error(status: u16, message: String) -> Self {
+        Self {
+            status,
+            size: 0,
+            message,
+        }
+    }
+
+    pub fn is_ok(&self) -> bool {
+        self.status == OK
+    }
+}
+
+pub async fn write_frame<W, T>(writer: &mut W, value: &T) -> io::Result<()>
+where
+    W: AsyncWrite + Unpin,
+    T: Serialize,
+{
+    let json = serde_json::to_vec(value)?;
+    writer.write_u32(json.len() as u32).await?;
+    writer.write_all(&json).await?;
+    writer.flush().await
+}
+
+pub async fn read_frame<R, T>(reader: &mut R) -> io::Result<T>
+where
+    R: AsyncRead + Unpin,
+    T: DeserializeOwned,
+{
+    let len = reader.read_u32().await?;
+    if len > MAX_FRAME {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("frame of {len} bytes"),
+        ));
+    }
+
+    let mut json = vec![0; len as usize];
+    reader.read_exact(&mut json).await?;
+
+    serde_json::from_slice(&json).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+// a request and its response, Err for anything but OK
+pub async fn request<S>(stream: &mut S, request: &Request) -> Result<Response, String>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    write_frame(stream, request)
+        .await
+        .map_err(|e| e.to_string())?;
+    let response: Response = read_frame(stream).await.map_err(|e| e.to_string())?;
+    if !response.is_ok() {
+        return Err(format!("{} {}", response.status, response.message));
+    }
+
+    Ok(response)
+}
+
+// the output of the work, pinging meanwhile so the other end does not time out
+pub async fn keep_alive<S, F>(stream: &mut S, work: F, every: Duration) -> Result<F::Output, String>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+    F: Future,
+{
+    tokio::pin!(work);
+    loop {
+        tokio::select! {
+            output = &mut work => return Ok(output),
+            _ = time::sleep(every) => {
+                request(stream, &Request::Ping).await?;
+            }
+        }
+    }
+}
+
+pub async fn hello<S>(stream: &mut S) -> Result<(), String>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let hello = Request::Hello {
+        version: VERSION,
+        device: cfg::name(),
+    };
+
+    request(stream, &hello).await.map(|_| ())
+}
+
+// the device on the other end
+pub async fn accept<S>(stream: &mut S) -> Result<String, String>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let (status, result) = match read_frame(stream).await {
+        Ok(Request::Hello { version, device }) if version == VERSION => (OK, Ok(device)),
+        Ok(Request::Hello { version, device }) => (
+            VERSION_NOT_SUPPORTED,
+            Err(format!("{device} speaks version {version}, not {VERSION}")),
+        ),
+        Ok(request) => (BAD_REQUEST, Err(format!("no HELLO but {request:?}"))),
+        Err(e) => return Err(e.to_string()),
+    };
+
+    let response = match &result {
+        Ok(_) => Response::ok(0),
+        Err(e) => Response::error(status, e.clone()),
+    };
+    write_frame(stream, &response)
+        .await
+        .map_err(|e| e.to_string())?;
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn frames_and_handshake() {
+        let (mut a, mut b) = tokio::io::duplex(4096);
+
+        write_frame(
+            &mut a,
+            &Request::Get {
+                path: "a.md".to_owned(),
+            },
+        )
+        .await
+        .unwrap();
+        let got: Request = read_frame(&mut b).await.unwrap();
+        assert_eq!(
+            got,
+            Request::Get {
+                path: "a.md".to_owned()
+            }
+        );
+        assert_eq!(got.to_string(), "GET a.md");
+
+        let accepted = tokio::spawn(async move { accept(&mut b).await });
+        assert_eq!(hello(&mut a).await, Ok(()));
+        assert_eq!(accepted.await.unwrap(), Ok(cfg::name()));
+
+        // another version is told so
+        let (mut a, mut b) = tokio::io::duplex(4096);
+        let accepted = tokio::spawn(async move { accept(&mut b).await });
+        let newer = Request::Hello {
+            version: VERSION + 1,
+            device: "pi4".to_owned(),
+        };
+        assert!(request(&mut a, &newer)
+            .await
+            .unwrap_err()
+            .starts_with("505 pi4 speaks"));
+        assert!(accepted.await.unwrap().is_err());
+
+        // a length beyond any request
+        let (mut a, mut b) = tokio::io::duplex(4096);
+        a.write_u32(MAX_FRAME + 1).await.unwrap();
+        assert!(read_frame::<_, Request>(&mut b).await.is_err());
+    }
+}
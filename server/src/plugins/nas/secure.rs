@@ -0,0 +1,372 @@
+// The NAS channel under the protocol: both ends send an ephemeral X25519 key
+// with their device name, derive a key per direction from the shared secret,
+// the key of the pair and both names, then prove it with a first record.
+// Each device has its own key with the NAS, cfg nas_key, which the NAS
+// derives from its cfg nas_secret and the name claimed. A device holds no
+// other key, so it can pass neither for another device nor for the NAS, and
+// is dropped before it can send a request.
+//
+// A record is a 4-byte big-endian length and AES-256-GCM ciphertext, the
+// nonce counts the records so none can be replayed, dropped or reordered.
+
+use std::io;
+
+use aes_gcm::aead::{Aead, KeyInit, OsRng};
+use aes_gcm::{Aes256Gcm, Nonce};
+use hkdf::Hkdf;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream};
+use tokio::time::{timeout, Duration};
+use x25519_dalek::{EphemeralSecret, PublicKey};
+
+use crate::cfg;
+use crate::plugins::nas::proto;
+
+// what the protocol runs on, plain bytes in and out
+pub type Channel = DuplexStream;
+
+const LABEL: &[u8] = b"cng nas 1";
+const PAIR_LABEL: &[u8] = b"cng nas pair";
+
+const RECORD_SIZE: usize = 16 * 1024;
+const TAG_SIZE: usize = 16;
+const CHANNEL_SIZE: usize = 64 * 1024;
+
+const HANDSHAKE_TIMEOUT: u64 = 10;
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Handshake {
+    device: String,
+    key: [u8; 32],
+}
+
+struct Cipher {
+    cipher: Aes256Gcm,
+    count: u64,
+}
+
+impl Cipher {
+    fn new(key: &[u8]) -> Self {
+        Self {
+            cipher: Aes256Gcm::new_from_slice(key).unwrap(),
+            count: 0,
+        }
+    }
+
+    fn nonce(&mut self) -> [u8; 12] {
+        let mut nonce = [0; 12];
+        nonce[4..].copy_from_slice(&self.count.to_be_bytes());
+        self.count += 1;
+
+        nonce
+    }
+
+    fn seal(&mut self, plaintext: &[u8]) -> Vec<u8> {
+        let nonce = self.nonce();
+        self.cipher
+            .encrypt(Nonce::from_slice(&nonce), plaintext)
+            .unwrap()
+    }
+
+    fn open(&mut self, ciphertext: &[u8]) -> io::Result<Vec<u8>> {
+        let nonce = self.nonce();
+        self.cipher
+            .decrypt(Nonce::from_slice(&nonce), ciphertext)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "bad record"))
+    }
+}
+
+// initiator to responder, then responder to initiator
+fn derive(psk: &str, shared: &[u8], initiator: &Handshake, responder: &Handshake) -> [u8; 64] {
+    let salt = Sha256::digest(psk.as_bytes());
+    let hkdf = Hkdf::<Sha256>::new(Some(&salt), shared);
+
+    let mut info = LABEL.to_vec();
+    for handshake in &[initiator, responder] {
+        info.extend_from_slice(&(handshake.device.len() as u32).to_be_bytes());
+        info.extend_from_slice(handshake.device.as_bytes());
+        info.extend_from_slice(&handshake.key);
+    }
+
+    let mut keys = [0; 64];
+    hkdf.expand(&info, &mut keys).unwrap();
+
+    keys
+}
+
+async fn write_record<W>(writer: &mut W, cipher: &mut Cipher, plaintext: &[u8]) -> io::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    let ciphertext = cipher.seal(plaintext);
+    let mut record = (ciphertext.len() as u32).to_be_bytes().to_vec();
+    record.extend_from_slice(&ciphertext);
+    writer.write_all(&record).await?;
+    writer.flush().await
+}
+
+// None once the other end is done
+async fn read_record<R>(reader: &mut R, cipher: &mut Cipher) -> io::Result<Option<Vec<u8>>>
+where
+    R: AsyncRead + Unpin,
+{
+    let len = match reader.read_u32().await {
+        Ok(len) => len as usize,
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    if len > RECORD_SIZE + TAG_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("record of {len} bytes"),
+        ));
+    }
+
+    let mut ciphertext = vec![0; len];
+    reader.read_exact(&mut ciphertext).await?;
+
+    cipher.open(&ciphertext).map(Some)
+}
+
+async fn handshake<S>(
+    stream: &mut S,
+    psk: &str,
+    me: &str,
+    expected: &str,
+    initiator: bool,
+) -> Result<(Cipher, Cipher), String>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let secret = EphemeralSecret::random_from_rng(OsRng);
+    let mine = Handshake {
+        device: me.to_owned(),
+        key: PublicKey::from(&secret).to_bytes(),
+    };
+
+    if initiator {
+        proto::write_frame(stream, &mine)
+            .await
+            .map_err(|e| e.to_string())?;
+    }
+    let theirs: Handshake = proto::read_frame(stream).await.map_err(|e| e.to_string())?;
+    if theirs.device != expected {
+        return Err(format!("{} is not {expected}", theirs.device));
+    }
+    if !initiator {
+        proto::write_frame(stream, &mine)
+            .await
+            .map_err(|e| e.to_string())?;
+    }
+
+    let shared = secret.diffie_hellman(&PublicKey::from(theirs.key));
+    if !shared.was_contributory() {
+        return Err(format!("bad key from {expected}"));
+    }
+
+    let keys = match initiator {
+        true => derive(psk, shared.as_bytes(), &mine, &theirs),
+        false => derive(psk, shared.as_bytes(), &theirs, &mine),
+    };
+    let (mut sealer, mut opener) = match initiator {
+        true => (Cipher::new(&keys[..32]), Cipher::new(&keys[32..])),
+        false => (Cipher::new(&keys[32..]), Cipher::new(&keys[..32])),
+    };
+
+    // only the same cfg key opens the records of the other end
+    write_record(stream, &mut sealer, me.as_bytes())
+        .await
+        .map_err(|e| e.to_string())?;
+    match read_record(stream, &mut opener).await {
+        Ok(Some(device)) if device == expected.as_bytes() => Ok((sealer, opener)),
+        _ => Err(format!("{expected} failed to authenticate")),
+    }
+}
+
+// records to and from the stream, plain bytes to and from the channel
+fn pipe<S>(stream: S, mut sealer: Cipher, mut opener: Cipher) -> Channel
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (channel, inner) = tokio::io::duplex(CHANNEL_SIZE);
+    let (mut inner_rx, mut inner_tx) = tokio::io::split(inner);
+    let (mut stream_rx, mut stream_tx) = tokio::io::split(stream);
+
+    tokio::spawn(async move {
+        let mut buffer = vec![0; RECORD_SIZE];
+        while let Ok(n) = inner_rx.read(&mut buffer).await {
+            if n == 0
+                || write_record(&mut stream_tx, &mut sealer, &buffer[..n])
+                    .await
+                    .is_err()
+            {
+                break;
+            }
+        }
+        let _ = stream_tx.shutdown().await;
+    });
+
+    tokio::spawn(async move {
+        while let Ok(Some(plaintext)) = read_record(&mut stream_rx, &mut opener).await {
+            if inner_tx.write_all(&plaintext).await.is_err() {
+                break;
+            }
+        }
+        // a forged or broken record ends the channel as an EOF
+        let _ = inner_tx.shutdown().await;
+    });
+
+    channel
+}
+
+// of a device with the NAS, from the secret only the NAS has
+pub fn pair_key(secret: &str, device: &str) -> String {
+    let hkdf = Hkdf::<Sha256>::new(None, secret.as_bytes());
+    let mut info = PAIR_LABEL.to_vec();
+    info.extend_from_slice(device.as_bytes());
+    let mut key = [0; 32];
+    hkdf.expand(&info, &mut key).unwrap();
+
+    key.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+// the key with the peer: the NAS derives it, a device has it in cfg
+pub fn psk(expected: &str) -> Result<String, String> {
+    if cfg::name() == cfg::nas() {
+        return Ok(pair_key(&cfg::nas_secret(), expected));
+    }
+
+    match cfg::nas_key() {
+        key if key.is_empty() => Err(format!(
+            "nas_key is not set, `p nas key {}` on {} prints it for cfg.json",
+            cfg::name(),
+            cfg::nas()
+        )),
+        key => Ok(key),
+    }
+}
+
+async fn open<S>(mut stream: S, expected: &str, initiator: bool) -> Result<Channel, String>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (psk, me) = (psk(expected)?, cfg::name());
+    let handshake = handshake(&mut stream, &psk, &me, expected, initiator);
+    let (sealer, opener) = match timeout(Duration::from_secs(HANDSHAKE_TIMEOUT), handshake).await {
+        Ok(result) => result?,
+        Err(_) => return Err(format!("no handshake from {expected}")),
+    };
+
+    Ok(pipe(stream, sealer, opener))
+}
+
+pub async fn connect<S>(stream: S, expected: &str) -> Result<Channel, String>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    open(stream, expected, true).await
+}
+
+pub async fn accept<S>(stream: S, expected: &str) -> Result<Channel, String>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    open(stream, expected, false).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PSK: &str = "an example very very secret key.";
+
+    async fn pair(
+        nas_psk: &'static str,
+        device: &'static str,
+    ) -> (Result<Channel, String>, Result<Channel, String>) {
+        let (mut a, mut b) = tokio::io::duplex(4096);
+        let nas = tokio::spawn(async move {
+            let ciphers = handshake(&mut b, nas_psk, "pi5", device, false).await?;
+            Ok(pipe(b, ciphers.0, ciphers.1))
+        });
+        let ciphers = handshake(&mut a, PSK, "pi4", "pi5", true).await;
+        let device = ciphers.map(|(sealer, opener)| pipe(a, sealer, opener));
+
+        (device, nas.await.unwrap())
+    }
+
+    #[tokio::test]
+    async fn records_between_known_devices() {
+        let (device, nas) = pair(PSK, "pi4").await;
+        let (mut device, mut nas) = (device.unwrap(), nas.unwrap());
+
+        let file = vec![7; RECORD_SIZE * 3 + 5];
+        device.write_all(&file).await.unwrap();
+        let mut received = vec![0; file.len()];
+        nas.read_exact(&mut received).await.unwrap();
+        assert_eq!(received, file);
+
+        nas.write_all(b"ok").await.unwrap();
+        drop(nas);
+        let mut received = vec![];
+        device.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, b"ok");
+    }
+
+    #[tokio::test]
+    async fn unknown_peers_are_rejected() {
+        // another cfg key
+        let (device, nas) = pair("another very very secret key....", "pi4").await;
+        assert!(device.is_err());
+        assert_eq!(nas.unwrap_err(), "pi4 failed to authenticate");
+
+        // the NAS expects another device
+        let (device, nas) = pair(PSK, "pi3").await;
+        assert!(device.is_err());
+        assert_eq!(nas.unwrap_err(), "pi4 is not pi3");
+    }
+
+    #[tokio::test]
+    async fn a_device_key_opens_only_its_own_channel() {
+        let secret = "the secret of the NAS";
+        assert_ne!(pair_key(secret, "pi4"), pair_key(secret, "pi3"));
+        assert_ne!(pair_key(secret, "pi4"), pair_key("another", "pi4"));
+
+        // pi4 with its own key, claiming to be pi3
+        let (mut a, mut b) = tokio::io::duplex(4096);
+        let pi3 = pair_key(secret, "pi3");
+        let nas = tokio::spawn(async move {
+            handshake(&mut b, &pi3, "pi5", "pi3", false)
+                .await
+                .map(|_| ())
+        });
+        let pi4 = pair_key(secret, "pi4");
+        assert!(handshake(&mut a, &pi4, "pi3", "pi5", true).await.is_err());
+        assert_eq!(
+            nas.await.unwrap().unwrap_err(),
+            "pi3 failed to authenticate"
+        );
+
+        // not the NAS here, and no nas_key set
+        if cfg::name() != cfg::nas() && cfg::nas_key().is_empty() {
+            let (a, _b) = tokio::io::duplex(4096);
+            assert!(connect(a, "pi5")
+                .await
+                .unwrap_err()
+                .starts_with("nas_key is not set"));
+        }
+    }
+
+    #[test]
+    fn records_are_bound_to_their_order() {
+        let mut sealer = Cipher::new(&[1; 32]);
+        let mut opener = Cipher::new(&[1; 32]);
+        let first = sealer.seal(b"first");
+        let second = sealer.seal(b"second");
+
+        assert!(opener.open(&second).is_err());
+        assert_eq!(opener.open(&second).unwrap(), b"second");
+        assert!(opener.open(&first).is_err());
+    }
+}
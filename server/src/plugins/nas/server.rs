@@ -1,17 +1,23 @@
-use std::fs::{self, File};
-use std::io::{BufReader, Read, Write};
 use std::path::Path;
 
-use log::Level::{Error, Info};
+use log::Level::{Error, Info, Warn};
+use tokio::fs;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::{TcpListener, TcpStream};
+use tokio::net::TcpListener;
 use tokio::sync::mpsc::Sender;
+use tokio::time::Duration;
 
 use crate::cfg;
 use crate::msg::{self, log, Msg, Reply};
-use crate::plugins::nas::files_data;
-use crate::utils;
+use crate::plugins::nas::filter::Filter;
+use crate::plugins::nas::proto::{self, Request, Response};
+use crate::plugins::nas::secure::{self, Channel};
+use crate::plugins::nas::state::{self, SyncState};
+use crate::plugins::nas::{address, delta, files_data, index, tombstones, transfer};
+use crate::plugins::plugin_system;
+use crate::plugins::system::upgrade;
 use crate::{error, info, reply_me};
+use crate::{shared, utils};
 
 pub const NAME: &str = "nas";
 
@@ -19,45 +25,51 @@ const LISTENING: &str = "0.0.0.0";
 const SERVER_PORT: u16 = 9760;
 const CLIENT_PORT: u16 = 9761;
 
-const BUFFER_SIZE: usize = 4096;
+// a release is read into memory to be verified
+const MAX_RELEASE: u64 = 256 * 1024 * 1024;
 
 struct SyncAction {
-    action: String, // GET or PUT
+    action: String, // GET, PUT, CONFLICT, DELETE on the NAS or REMOVE here
     filename: String,
+    hash: String, // on both sides once done
 }
 
+// what changed since the last sync decides the direction, a file changed on
+// both sides, or never synced and different, is a CONFLICT. A file gone from
+// one side unchanged on the other is deleted there too.
 fn create_sync_actions(
     files_data_nas: &files_data::FilesData,
     files_data_local: &files_data::FilesData,
+    sync_state: &SyncState,
 ) -> Vec<SyncAction> {
     let mut sync_actions: Vec<SyncAction> = vec![];
 
     for file_nas in &files_data_nas.files_data {
-        match files_data_local
+        let (action, hash) = match files_data_local
             .files_data
             .iter()
             .find(|d| d.filename == file_nas.filename)
         {
-            None => sync_actions.push(SyncAction {
-                action: "GET".to_owned(),
-                filename: file_nas.filename.clone(),
-            }),
-            Some(t) => {
-                if t.md5 != file_nas.md5 {
-                    if t.modified < file_nas.modified {
-                        sync_actions.push(SyncAction {
-                            action: "GET".to_owned(),
-                            filename: file_nas.filename.clone(),
-                        })
-                    } else {
-                        sync_actions.push(SyncAction {
-                            action: "PUT".to_owned(),
-                            filename: file_nas.filename.clone(),
-                        })
-                    }
-                }
-            }
-        }
+            None => match sync_state.base(&file_nas.filename) {
+                // deleted here, unchanged on the NAS since
+                Some(base) if base == file_nas.hash => ("DELETE", &file_nas.hash),
+                _ => ("GET", &file_nas.hash),
+            },
+            Some(t) if t.hash == file_nas.hash => continue,
+            Some(t) => match sync_state.base(&t.filename) {
+                // changed on the NAS only
+                Some(base) if base == t.hash => ("GET", &file_nas.hash),
+                // changed here only
+                Some(base) if base == file_nas.hash => ("PUT", &t.hash),
+                _ => ("CONFLICT", &file_nas.hash),
+            },
+        };
+
+        sync_actions.push(SyncAction {
+            action: action.to_owned(),
+            filename: file_nas.filename.clone(),
+            hash: hash.clone(),
+        });
     }
 
     for file_local in &files_data_local.files_data {
@@ -66,9 +78,21 @@ fn create_sync_actions(
             .iter()
             .any(|d| d.filename == file_local.filename)
         {
+            let tombstone = tombstones::covering(&files_data_nas.tombstones, &file_local.filename);
+            let action = match sync_state.base(&file_local.filename) {
+                // deleted on the NAS, unchanged here since
+                Some(base) if base == file_local.hash => "REMOVE",
+                // changed here since, the edit wins
+                Some(_) => "PUT",
+                // never synced, older than the deletion
+                None if tombstone.is_some_and(|t| file_local.modified <= t.deleted) => "REMOVE",
+                None => "PUT",
+            };
+
             sync_actions.push(SyncAction {
-                action: "PUT".to_owned(),
+                action: action.to_owned(),
                 filename: file_local.filename.clone(),
+                hash: file_local.hash.clone(),
             })
         }
     }
@@ -83,212 +107,650 @@ pub fn server(msg_tx_clone: Sender<Msg>) {
         info!(&msg_tx_clone, format!("[{NAME}] Listening on {listening}"));
 
         loop {
-            let (mut socket, _addr) = listener.accept().await.unwrap();
+            let (socket, addr) = listener.accept().await.unwrap();
 
             let msg_tx_clone = msg_tx_clone.clone();
             tokio::spawn(async move {
-                let mut buffer = [0; BUFFER_SIZE];
-                match socket.read(&mut buffer).await {
-                    Ok(size) if size > 0 => {
-                        let mut received_data = Vec::new();
-                        received_data.extend_from_slice(&buffer[..size]);
-
-                        let pos = received_data.iter().position(|&b| b == b'\n').unwrap();
-
-                        let command = &received_data[..=pos];
-                        let command = String::from_utf8_lossy(command).trim().to_string();
-                        received_data.drain(..=pos);
-
-                        info!(&msg_tx_clone, format!("[{NAME}] Recv: {command}"));
-
-                        // PUT files_data
-                        if let Some(nas_ip) = command.strip_prefix("PUT files_data ") {
-                            let nas_ip_clone = nas_ip.to_owned();
-
-                            while let Ok(size) = socket.read(&mut buffer).await {
-                                if size == 0 {
-                                    break;
-                                }
-                                received_data.extend_from_slice(&buffer[..size]);
-                            }
-
-                            info!(
-                                &msg_tx_clone,
-                                format!(
-                                    "[{NAME}] [Ok] Recv: files_data, {}",
-                                    utils::format_number(received_data.len() as u64)
-                                )
-                            );
-
-                            let files_data_nas_str = std::str::from_utf8(&received_data).unwrap();
-                            let files_data_nas: files_data::FilesData =
-                                serde_json::from_str(files_data_nas_str).unwrap();
-
-                            let dir = Path::new(cfg::FILE_FOLDER);
-                            let files_data_local = files_data::get_files_data(dir);
-
-                            let sync_actions: Vec<SyncAction> =
-                                create_sync_actions(&files_data_nas, &files_data_local);
-
-                            info!(&msg_tx_clone, format!("[{NAME}] [Ok] Actions ready"));
-
-                            // connect to NAS
-                            let sync_actions_len = sync_actions.len();
-                            for (idx, item) in sync_actions.iter().enumerate() {
-                                let mut stream = match TcpStream::connect(format!(
-                                    "{nas_ip_clone}:{SERVER_PORT}"
-                                ))
-                                .await
-                                {
-                                    Ok(s) => s,
-                                    Err(e) => {
-                                        error!(
-                                            &msg_tx_clone,
-                                            format!("[{NAME}] Failed to connect to {nas_ip_clone}:{SERVER_PORT}. Err: {e}")
-                                        );
-                                        continue;
-                                    }
-                                };
-
-                                match item.action.as_str() {
-                                    "GET" => {
-                                        info!(
-                                            &msg_tx_clone,
-                                            format!(
-                                                "[{NAME}] [Go] [{}/{sync_actions_len}] {} {}",
-                                                idx + 1,
-                                                item.action,
-                                                item.filename
-                                            )
-                                        );
-                                        let start_ts = utils::ts();
-
-                                        let request = format!("GET {}\n", item.filename);
-                                        stream.write_all(request.as_bytes()).await.unwrap();
-
-                                        let mut buffer = Vec::new();
-                                        stream.read_to_end(&mut buffer).await.unwrap();
-
-                                        if buffer.starts_with(b"ERROR") {
-                                            info!(
-                                                &msg_tx_clone,
-                                                format!(
-                                                    "[{NAME}] Failed to GET. Err: {}",
-                                                    String::from_utf8_lossy(&buffer)
-                                                )
-                                            );
-                                            continue;
-                                        }
-
-                                        let filename = Path::new(&item.filename);
-
-                                        // Ensure the parent directories exist
-                                        if let Some(parent) = filename.parent() {
-                                            fs::create_dir_all(parent).unwrap();
-                                        }
-
-                                        let mut file = File::create(filename).unwrap();
-                                        file.write_all(&buffer).unwrap();
-
-                                        let escaped_time = utils::ts() - start_ts;
-                                        info!(
-                                            &msg_tx_clone,
-                                            format!(
-                                                "[{NAME}] [Ok] [{}/{sync_actions_len}] {} {}, {}.",
-                                                idx + 1,
-                                                item.action,
-                                                item.filename,
-                                                utils::transmit_str(
-                                                    buffer.len() as u64,
-                                                    escaped_time
-                                                )
-                                            )
-                                        );
-                                    }
-                                    "PUT" => {
-                                        info!(
-                                            &msg_tx_clone,
-                                            format!(
-                                                "[{NAME}] [Go] [{}/{sync_actions_len}] {} {}",
-                                                idx + 1,
-                                                item.action,
-                                                item.filename
-                                            )
-                                        );
-                                        let file = match File::open(&item.filename) {
-                                            Ok(s) => s,
-                                            Err(e) => {
-                                                error!(
-                                                    &msg_tx_clone,
-                                                    format!(
-                                                        "[{NAME}] Failed to PUT {}. Err: {e}.",
-                                                        item.filename
-                                                    )
-                                                );
-                                                continue;
-                                            }
-                                        };
-                                        let mut reader = BufReader::new(file);
-
-                                        let request = format!("PUT {}\n", item.filename);
-                                        stream.write_all(request.as_bytes()).await.unwrap();
-
-                                        let mut buffer = [0; BUFFER_SIZE];
-                                        while let Ok(n) = reader.read(&mut buffer) {
-                                            if n == 0 {
-                                                break;
-                                            }
-                                            stream.write_all(&buffer[..n]).await.unwrap();
-                                        }
-
-                                        info!(
-                                            &msg_tx_clone,
-                                            format!(
-                                                "[{NAME}] [Ok] [{}/{sync_actions_len}] {} {}",
-                                                idx + 1,
-                                                item.action,
-                                                item.filename,
-                                            )
-                                        );
-                                    }
-                                    _ => (),
-                                }
-                            }
-
-                            // send END
-                            let mut stream = match TcpStream::connect(format!(
-                                "{nas_ip_clone}:{SERVER_PORT}"
-                            ))
-                            .await
-                            {
-                                Ok(s) => s,
-                                Err(e) => {
-                                    error!(
-                                        &msg_tx_clone,
-                                        format!("[{NAME}] Failed to connect to {nas_ip_clone}:{SERVER_PORT}. Err: {e}")
-                                    );
-                                    return;
-                                }
-                            };
-
-                            info!(&msg_tx_clone, format!("[{NAME}] END"));
-
-                            let request = "END\n".to_owned();
-                            stream.write_all(request.as_bytes()).await.unwrap();
-
-                            msg::cmd(
-                                &msg_tx_clone,
-                                reply_me!(),
-                                NAME.to_owned(),
-                                msg::ACT_NAS.to_owned(),
-                                vec!["sync_local".to_owned(), "true".to_owned()],
-                            )
-                            .await;
-                        }
+                // only the NAS may ask for a sync or a release
+                let socket = match secure::accept(socket, &cfg::nas()).await {
+                    Ok(socket) => socket,
+                    Err(e) => {
+                        error!(&msg_tx_clone, format!("[{NAME}] Rejected {addr}: {e}"));
+                        return;
                     }
-                    _ => (),
+                };
+
+                if let Err(e) = handle(&msg_tx_clone, socket).await {
+                    error!(&msg_tx_clone, format!("[{NAME}] {e}"));
                 }
             });
         }
     });
 }
+
+// a SYNC or a RELEASE from the NAS
+async fn handle(msg_tx: &Sender<Msg>, mut socket: Channel) -> Result<(), String> {
+    let device = proto::accept(&mut socket).await?;
+    let request: Request = proto::read_frame(&mut socket)
+        .await
+        .map_err(|e| e.to_string())?;
+    info!(msg_tx, format!("[{NAME}] Recv: {request} from {device}"));
+
+    match request {
+        Request::Sync { addresses } => {
+            proto::write_frame(&mut socket, &Response::ok(0))
+                .await
+                .map_err(|e| e.to_string())?;
+            drop(socket);
+
+            sync(msg_tx, addresses).await
+        }
+        // asked by system upgrade which then verifies and installs it
+        Request::Release { release, size } => {
+            if size > MAX_RELEASE {
+                let response = Response::error(proto::BAD_REQUEST, format!("{size} bytes"));
+                let _ = proto::write_frame(&mut socket, &response).await;
+                return Err(format!("release {} of {size} bytes", release.version));
+            }
+
+            let mut bytes = vec![0; size as usize];
+            socket
+                .read_exact(&mut bytes)
+                .await
+                .map_err(|e| format!("release {}: {e}", release.version))?;
+
+            info!(
+                msg_tx,
+                format!(
+                    "[{NAME}] [Ok] Recv: release {}, {}",
+                    release.version,
+                    utils::format_number(size)
+                )
+            );
+
+            if let Err(e) = upgrade::save_release(cfg::RELEASES_FOLDER, &release, &bytes) {
+                let response = Response::error(proto::FAILED, e.clone());
+                let _ = proto::write_frame(&mut socket, &response).await;
+                return Err(format!("Failed to save release {}: {e}", release.version));
+            }
+            let _ = proto::write_frame(&mut socket, &Response::ok(size)).await;
+
+            msg::cmd(
+                msg_tx,
+                reply_me!(),
+                plugin_system::NAME.to_owned(),
+                msg::ACT_UPGRADE.to_owned(),
+                vec![release.version],
+            )
+            .await;
+
+            Ok(())
+        }
+        _ => {
+            let response = Response::error(proto::BAD_REQUEST, request.to_string());
+            let _ = proto::write_frame(&mut socket, &response).await;
+
+            Err(format!("unexpected {request}"))
+        }
+    }
+}
+
+// the size received, None if the file failed but the sync can go on
+async fn get(
+    msg_tx: &Sender<Msg>,
+    stream: &mut Channel,
+    filename: &str,
+    path: &Path,
+) -> Result<Option<u64>, String> {
+    // a large copy here is updated by delta
+    match fs::metadata(path).await {
+        Ok(meta) if meta.is_file() && meta.len() >= delta::DELTA_MIN => {
+            return get_delta(msg_tx, stream, filename, path).await;
+        }
+        _ => (),
+    }
+
+    let request = Request::Get {
+        path: filename.to_owned(),
+    };
+    let response = match proto::request(stream, &request).await {
+        Ok(response) => response,
+        Err(e) => {
+            error!(
+                msg_tx,
+                format!("[{NAME}] Failed to GET {filename}. Err: {e}")
+            );
+            return Ok(None);
+        }
+    };
+
+    // the rest of a broken file can not be told from the next response, so
+    // this ends the sync
+    transfer::recv_file(msg_tx, stream, path, response.size)
+        .await
+        .map(Some)
+        .map_err(|e| format!("Failed to GET {filename}. Err: {e}"))
+}
+
+// the size sent, None if the file failed but the sync can go on
+async fn put(
+    msg_tx: &Sender<Msg>,
+    stream: &mut Channel,
+    filename: &str,
+    path: &Path,
+) -> Result<Option<u64>, String> {
+    let size = match fs::metadata(path).await {
+        Ok(meta) if meta.is_file() => meta.len(),
+        _ => {
+            error!(
+                msg_tx,
+                format!("[{NAME}] Failed to PUT {filename}. Err: not a file.")
+            );
+            return Ok(None);
+        }
+    };
+
+    // a large file the NAS has a copy of goes by delta
+    if size >= delta::DELTA_MIN {
+        if let Some(signature) = signature(stream, filename).await? {
+            return put_delta(msg_tx, stream, filename, path, size, &signature).await;
+        }
+    }
+
+    let request = Request::Put {
+        path: filename.to_owned(),
+        size,
+    };
+    proto::write_frame(stream, &request)
+        .await
+        .map_err(|e| e.to_string())?;
+    transfer::send_file(msg_tx, path, size, stream)
+        .await
+        .map_err(|e| format!("Failed to PUT {filename}. Err: {e}"))?;
+
+    let response: Response = proto::read_frame(stream).await.map_err(|e| e.to_string())?;
+    if !response.is_ok() {
+        error!(
+            msg_tx,
+            format!(
+                "[{NAME}] Failed to PUT {filename}. Err: {} {}",
+                response.status, response.message
+            )
+        );
+        return Ok(None);
+    }
+
+    Ok(Some(size))
+}
+
+async fn get_delta(
+    msg_tx: &Sender<Msg>,
+    stream: &mut Channel,
+    filename: &str,
+    path: &Path,
+) -> Result<Option<u64>, String> {
+    // the NAS waits for the request meanwhile
+    let every = Duration::from_secs(proto::KEEP_ALIVE);
+    let signature = match proto::keep_alive(stream, delta::sign(path), every).await? {
+        Ok(signature) => signature,
+        Err(e) => {
+            error!(
+                msg_tx,
+                format!("[{NAME}] Failed to GET {filename}. Err: {e}")
+            );
+            return Ok(None);
+        }
+    };
+    let bytes = signature.to_bytes();
+
+    let request = Request::GetDelta {
+        path: filename.to_owned(),
+        signature: bytes.len() as u64,
+    };
+    proto::write_frame(stream, &request)
+        .await
+        .map_err(|e| e.to_string())?;
+    stream.write_all(&bytes).await.map_err(|e| e.to_string())?;
+    let response: Response = proto::read_frame(stream).await.map_err(|e| e.to_string())?;
+    if !response.is_ok() {
+        error!(
+            msg_tx,
+            format!(
+                "[{NAME}] Failed to GET {filename}. Err: {} {}",
+                response.status, response.message
+            )
+        );
+        return Ok(None);
+    }
+
+    // as a GET, the rest of broken ops ends the sync
+    let literal = delta::recv_delta(stream, path, signature.block_size, path, response.size)
+        .await
+        .map_err(|e| format!("Failed to GET {filename}. Err: {e}"))?;
+    info!(
+        msg_tx,
+        format!(
+            "[{NAME}] [Ok] Delta: {filename}, {} of {}",
+            utils::format_number(literal),
+            utils::format_number(response.size)
+        )
+    );
+
+    Ok(Some(response.size))
+}
+
+// of the NAS copy, None if it has none
+async fn signature(
+    stream: &mut Channel,
+    filename: &str,
+) -> Result<Option<delta::Signature>, String> {
+    let request = Request::Signature {
+        path: filename.to_owned(),
+    };
+    let response = match proto::request(stream, &request).await {
+        Ok(response) => response,
+        Err(_) => return Ok(None),
+    };
+    if response.size > delta::MAX_SIGNATURE {
+        return Err(format!(
+            "signature of {filename} of {} bytes",
+            response.size
+        ));
+    }
+
+    let mut bytes = vec![0; response.size as usize];
+    stream
+        .read_exact(&mut bytes)
+        .await
+        .map_err(|e| e.to_string())?;
+
+    delta::Signature::from_bytes(&bytes)
+        .map(Some)
+        .map_err(|e| format!("signature of {filename}: {e}"))
+}
+
+async fn put_delta(
+    msg_tx: &Sender<Msg>,
+    stream: &mut Channel,
+    filename: &str,
+    path: &Path,
+    size: u64,
+    signature: &delta::Signature,
+) -> Result<Option<u64>, String> {
+    let request = Request::PutDelta {
+        path: filename.to_owned(),
+        size,
+        block_size: signature.block_size,
+    };
+    proto::write_frame(stream, &request)
+        .await
+        .map_err(|e| e.to_string())?;
+    let mut reader = fs::File::open(path)
+        .await
+        .map_err(|e| format!("Failed to PUT {filename}. Err: {e}"))?
+        .take(size);
+    let literal = delta::send_delta(&mut reader, signature, stream)
+        .await
+        .map_err(|e| format!("Failed to PUT {filename}. Err: {e}"))?;
+
+    let response: Response = proto::read_frame(stream).await.map_err(|e| e.to_string())?;
+    if !response.is_ok() {
+        error!(
+            msg_tx,
+            format!(
+                "[{NAME}] Failed to PUT {filename}. Err: {} {}",
+                response.status, response.message
+            )
+        );
+        return Ok(None);
+    }
+    info!(
+        msg_tx,
+        format!(
+            "[{NAME}] [Ok] Delta: {filename}, {} of {}",
+            utils::format_number(literal),
+            utils::format_number(size)
+        )
+    );
+
+    Ok(Some(size))
+}
+
+// ours is moved aside to a conflict copy which goes to the NAS, then theirs
+// takes the name, so both edits end up on both sides
+async fn conflict(
+    msg_tx: &Sender<Msg>,
+    stream: &mut Channel,
+    sync_state: &mut SyncState,
+    algorithm: &str,
+    filename: &str,
+    path: &Path,
+) -> Result<Option<u64>, String> {
+    let copy = state::conflict_name(filename, &cfg::name(), utils::ts(), |name| {
+        shared::resolve(name).map(|p| p.exists()).unwrap_or(true)
+    });
+    let copy_path = shared::resolve(&copy)?;
+    let hash = index::hash_file(path, algorithm).map_err(|e| e.to_string());
+    fs::rename(path, &copy_path)
+        .await
+        .map_err(|e| format!("Failed to keep {filename} as {copy}. Err: {e}"))?;
+    log(
+        msg_tx,
+        reply_me!(),
+        Warn,
+        format!("[{NAME}] Conflict: {filename}, ours kept as {copy}"),
+    )
+    .await;
+
+    let mut size = 0;
+    if let Some(sent) = put(msg_tx, stream, &copy, &copy_path).await? {
+        sync_state.set(&copy, &hash?);
+        size += sent;
+    }
+    match get(msg_tx, stream, filename, path).await? {
+        Some(received) => Ok(Some(size + received)),
+        None => Ok(None),
+    }
+}
+
+// None if the file failed but the sync can go on
+async fn delete(
+    msg_tx: &Sender<Msg>,
+    stream: &mut Channel,
+    filename: &str,
+) -> Result<Option<u64>, String> {
+    let request = Request::Delete {
+        path: filename.to_owned(),
+    };
+    match proto::request(stream, &request).await {
+        Ok(_) => Ok(Some(0)),
+        Err(e) => {
+            error!(
+                msg_tx,
+                format!("[{NAME}] Failed to DELETE {filename}. Err: {e}")
+            );
+            Ok(None)
+        }
+    }
+}
+
+// the directories left empty go as well, up to the share
+async fn remove(msg_tx: &Sender<Msg>, filename: &str, path: &Path) -> Option<u64> {
+    if let Err(e) = fs::remove_file(path).await {
+        error!(
+            msg_tx,
+            format!("[{NAME}] Failed to REMOVE {filename}. Err: {e}")
+        );
+        return None;
+    }
+
+    let share = Path::new(cfg::FILE_FOLDER);
+    let mut dir = path.parent();
+    while let Some(parent) = dir {
+        if parent == share || fs::remove_dir(parent).await.is_err() {
+            break;
+        }
+        dir = parent.parent();
+    }
+
+    Some(0)
+}
+
+async fn sync_files(
+    msg_tx: &Sender<Msg>,
+    stream: &mut Channel,
+    sync_actions: &[SyncAction],
+    sync_state: &mut SyncState,
+    algorithm: &str,
+) -> Result<(), String> {
+    let sync_actions_len = sync_actions.len();
+    for (idx, item) in sync_actions.iter().enumerate() {
+        info!(
+            msg_tx,
+            format!(
+                "[{NAME}] [Go] [{}/{sync_actions_len}] {} {}",
+                idx + 1,
+                item.action,
+                item.filename
+            )
+        );
+        let start_ts = utils::ts();
+        // as listed by the NAS
+        let path = match shared::resolve(&item.filename) {
+            Ok(path) => path,
+            Err(e) => {
+                error!(msg_tx, format!("[{NAME}] Rejected {}: {e}", item.action));
+                continue;
+            }
+        };
+
+        let size = match item.action.as_str() {
+            "GET" => get(msg_tx, stream, &item.filename, &path).await?,
+            "PUT" => put(msg_tx, stream, &item.filename, &path).await?,
+            "CONFLICT" => {
+                conflict(msg_tx, stream, sync_state, algorithm, &item.filename, &path).await?
+            }
+            "DELETE" => delete(msg_tx, stream, &item.filename).await?,
+            "REMOVE" => remove(msg_tx, &item.filename, &path).await,
+            _ => None,
+        };
+        let size = match size {
+            Some(size) => size,
+            None => continue,
+        };
+        match item.action.as_str() {
+            "DELETE" | "REMOVE" => sync_state.forget(&item.filename),
+            _ => sync_state.set(&item.filename, &item.hash),
+        }
+
+        let escaped_time = utils::ts() - start_ts;
+        info!(
+            msg_tx,
+            format!(
+                "[{NAME}] [Ok] [{}/{sync_actions_len}] {} {}, {}.",
+                idx + 1,
+                item.action,
+                item.filename,
+                utils::transmit_str(size, escaped_time)
+            )
+        );
+    }
+
+    Ok(())
+}
+
+// connect to the NAS, compare its files with ours and the state of the last
+// sync, then GET and PUT the differences on the same connection
+async fn sync(msg_tx: &Sender<Msg>, mut nas_addresses: Vec<String>) -> Result<(), String> {
+    let stream = address::connect(&mut nas_addresses, SERVER_PORT)
+        .await
+        .map_err(|e| format!("Failed to connect to NAS. Err: {e}"))?;
+    let mut stream = secure::connect(stream, &cfg::nas()).await?;
+    proto::hello(&mut stream).await?;
+
+    let list = Request::List {
+        roots: cfg::nas_roots(),
+    };
+    let response = proto::request(&mut stream, &list).await?;
+    let mut json = vec![0; response.size as usize];
+    stream
+        .read_exact(&mut json)
+        .await
+        .map_err(|e| e.to_string())?;
+    info!(
+        msg_tx,
+        format!(
+            "[{NAME}] [Ok] Recv: files_data, {}",
+            utils::format_number(response.size)
+        )
+    );
+
+    let mut files_data_nas: files_data::FilesData =
+        serde_json::from_slice(&json).map_err(|e| format!("files_data: {e}"))?;
+    // what is ignored here is neither synced nor deleted on either side
+    let filter = Filter::local();
+    files_data_nas
+        .files_data
+        .retain(|file_data| filter.syncs(&file_data.filename));
+
+    // hashed as the NAS does, off the runtime as it blocks
+    let algorithm = files_data_nas.algorithm.clone();
+    let hashing = tokio::task::spawn_blocking(move || {
+        files_data::get_files_data(Path::new(cfg::FILE_FOLDER), &filter, &algorithm)
+    });
+    let every = Duration::from_secs(proto::KEEP_ALIVE);
+    let files_data_local = proto::keep_alive(&mut stream, hashing, every)
+        .await?
+        .map_err(|e| e.to_string())??;
+
+    let mut sync_state = SyncState::load(cfg::SYNC_STATE_FILE)?;
+    sync_state.in_sync(&files_data_nas, &files_data_local);
+    let sync_actions: Vec<SyncAction> =
+        create_sync_actions(&files_data_nas, &files_data_local, &sync_state);
+
+    info!(msg_tx, format!("[{NAME}] [Ok] Actions ready"));
+
+    // what is done is kept even if the rest fails
+    let synced = sync_files(
+        msg_tx,
+        &mut stream,
+        &sync_actions,
+        &mut sync_state,
+        &files_data_nas.algorithm,
+    )
+    .await;
+    sync_state.save(cfg::SYNC_STATE_FILE)?;
+    synced?;
+
+    info!(msg_tx, format!("[{NAME}] END"));
+    proto::request(&mut stream, &Request::End).await?;
+
+    msg::cmd(
+        msg_tx,
+        reply_me!(),
+        NAME.to_owned(),
+        msg::ACT_NAS.to_owned(),
+        vec!["sync_local".to_owned(), "true".to_owned()],
+    )
+    .await;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::harness::files_data;
+    use crate::plugins::nas::tombstones::Tombstone;
+
+    fn actions(sync_actions: &[SyncAction]) -> Vec<(String, String)> {
+        let mut actions: Vec<(String, String)> = sync_actions
+            .iter()
+            .map(|a| (a.action.clone(), a.filename.clone()))
+            .collect();
+        actions.sort();
+        actions
+    }
+
+    #[test]
+    fn create_sync_actions_by_what_changed_where() {
+        let nas = files_data(&[
+            ("./shared/same", "a", 1),
+            ("./shared/nas_only", "b", 1),
+            ("./shared/nas_changed", "c", 10),
+            ("./shared/local_changed", "d", 20),
+            ("./shared/both_changed", "f", 10),
+            ("./shared/never_synced", "g", 10),
+        ]);
+        let local = files_data(&[
+            ("./shared/same", "a", 5),
+            ("./shared/local_only", "e", 1),
+            // newer here, yet changed on the NAS only
+            ("./shared/nas_changed", "x", 20),
+            ("./shared/local_changed", "y", 10),
+            ("./shared/both_changed", "z", 20),
+            ("./shared/never_synced", "h", 20),
+        ]);
+        let mut sync_state = SyncState::default();
+        sync_state.set("./shared/nas_changed", "x");
+        sync_state.set("./shared/local_changed", "d");
+        sync_state.set("./shared/both_changed", "o");
+
+        let sync_actions = create_sync_actions(&nas, &local, &sync_state);
+        assert_eq!(
+            actions(&sync_actions),
+            vec![
+                ("CONFLICT".to_owned(), "./shared/both_changed".to_owned()),
+                ("CONFLICT".to_owned(), "./shared/never_synced".to_owned()),
+                ("GET".to_owned(), "./shared/nas_changed".to_owned()),
+                ("GET".to_owned(), "./shared/nas_only".to_owned()),
+                ("PUT".to_owned(), "./shared/local_changed".to_owned()),
+                ("PUT".to_owned(), "./shared/local_only".to_owned()),
+            ]
+        );
+
+        let hash = |filename: &str| {
+            sync_actions
+                .iter()
+                .find(|a| a.filename == filename)
+                .map(|a| a.hash.as_str())
+        };
+        assert_eq!(hash("./shared/nas_changed"), Some("c"));
+        assert_eq!(hash("./shared/local_changed"), Some("y"));
+        // the NAS one takes the name
+        assert_eq!(hash("./shared/both_changed"), Some("f"));
+    }
+
+    #[test]
+    fn create_sync_actions_deletions() {
+        let mut nas = files_data(&[
+            ("./shared/deleted_here", "a", 1),
+            ("./shared/deleted_here_edited_there", "b", 1),
+        ]);
+        nas.tombstones = vec![Tombstone {
+            path: "./shared/dir".to_owned(),
+            deleted: 100,
+            device: "pi3".to_owned(),
+        }];
+        let local = files_data(&[
+            ("./shared/deleted_there", "c", 1),
+            ("./shared/deleted_there_edited_here", "x", 1),
+            // never synced here
+            ("./shared/dir/old", "e", 50),
+            ("./shared/dir/new", "f", 150),
+        ]);
+        let mut sync_state = SyncState::default();
+        sync_state.set("./shared/deleted_here", "a");
+        sync_state.set("./shared/deleted_here_edited_there", "o");
+        sync_state.set("./shared/deleted_there", "c");
+        sync_state.set("./shared/deleted_there_edited_here", "d");
+
+        assert_eq!(
+            actions(&create_sync_actions(&nas, &local, &sync_state)),
+            vec![
+                ("DELETE".to_owned(), "./shared/deleted_here".to_owned()),
+                (
+                    "GET".to_owned(),
+                    "./shared/deleted_here_edited_there".to_owned()
+                ),
+                (
+                    "PUT".to_owned(),
+                    "./shared/deleted_there_edited_here".to_owned()
+                ),
+                ("PUT".to_owned(), "./shared/dir/new".to_owned()),
+                ("REMOVE".to_owned(), "./shared/deleted_there".to_owned()),
+                ("REMOVE".to_owned(), "./shared/dir/old".to_owned()),
+            ]
+        );
+    }
+
+    #[test]
+    fn create_sync_actions_nothing_to_do() {
+        let nas = files_data(&[("./shared/a", "a", 1)]);
+        let local = files_data(&[("./shared/a", "a", 2)]);
+        let sync_state = SyncState::default();
+
+        assert!(create_sync_actions(&nas, &local, &sync_state).is_empty());
+        assert!(create_sync_actions(&files_data(&[]), &files_data(&[]), &sync_state).is_empty());
+    }
+}
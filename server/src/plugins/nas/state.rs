@@ -0,0 +1,155 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+
+use crate::plugins::nas::files_data::FilesData;
+
+// the hash of every path as both this device and the NAS held it after the
+// last sync, which tells a change here from a change there
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct SyncState {
+    files: BTreeMap<String, String>,
+}
+
+impl SyncState {
+    pub fn load(path: &str) -> Result<Self, String> {
+        if !Path::new(path).exists() {
+            return Ok(Self::default());
+        }
+
+        let content =
+            fs::read_to_string(path).map_err(|e| format!("Failed to read {path}: {e}"))?;
+        serde_json::from_str(&content).map_err(|e| format!("Failed to parse {path}: {e}"))
+    }
+
+    pub fn save(&self, path: &str) -> Result<(), String> {
+        let content = serde_json::to_string_pretty(self).unwrap();
+        fs::write(path, content).map_err(|e| format!("Failed to write {path}: {e}"))
+    }
+
+    pub fn base(&self, filename: &str) -> Option<&str> {
+        self.files.get(filename).map(|hash| hash.as_str())
+    }
+
+    pub fn set(&mut self, filename: &str, hash: &str) {
+        self.files.insert(filename.to_owned(), hash.to_owned());
+    }
+
+    pub fn forget(&mut self, filename: &str) {
+        self.files.remove(filename);
+    }
+
+    // what is the same on both sides needs no action, what is on neither is
+    // forgotten
+    pub fn in_sync(&mut self, files_data_nas: &FilesData, files_data_local: &FilesData) {
+        let hash = |files_data: &FilesData, filename: &str| {
+            files_data
+                .files_data
+                .iter()
+                .find(|d| d.filename == filename)
+                .map(|d| d.hash.clone())
+        };
+
+        self.files.retain(|filename, _| {
+            hash(files_data_nas, filename).is_some() || hash(files_data_local, filename).is_some()
+        });
+        for file_nas in &files_data_nas.files_data {
+            if hash(files_data_local, &file_nas.filename).as_ref() == Some(&file_nas.hash) {
+                self.set(&file_nas.filename, &file_nas.hash);
+            }
+        }
+    }
+}
+
+// "note/a.md" edited on pi4 as well becomes "note/a (conflict from pi4
+// 2026-10-18).md", numbered if there is one already
+pub fn conflict_name(
+    filename: &str,
+    device: &str,
+    ts: u64,
+    exists: impl Fn(&str) -> bool,
+) -> String {
+    let path = Path::new(filename);
+    let stem = path
+        .file_stem()
+        .map(|s| s.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let ext = path
+        .extension()
+        .map(|e| format!(".{}", e.to_string_lossy()))
+        .unwrap_or_default();
+    let date = DateTime::from_timestamp(ts as i64, 0)
+        .unwrap()
+        .with_timezone(&Local)
+        .format("%Y-%m-%d");
+
+    let mut n = 1;
+    loop {
+        let suffix = match n {
+            1 => String::new(),
+            n => format!(" {n}"),
+        };
+        let name = format!("{stem} (conflict from {device} {date}{suffix}){ext}");
+        let conflict = match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => {
+                parent.join(name).to_string_lossy().to_string()
+            }
+            _ => name,
+        };
+        if !exists(&conflict) {
+            return conflict;
+        }
+        n += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::harness::files_data;
+
+    #[test]
+    fn in_sync_records_what_is_the_same() {
+        let mut state = SyncState::default();
+        state.set("./shared/gone", "g");
+        state.set("./shared/edited", "e");
+
+        state.in_sync(
+            &files_data(&[("./shared/same", "s", 0), ("./shared/edited", "x", 0)]),
+            &files_data(&[("./shared/same", "s", 0), ("./shared/edited", "e", 0)]),
+        );
+
+        assert_eq!(state.base("./shared/same"), Some("s"));
+        // not decided yet
+        assert_eq!(state.base("./shared/edited"), Some("e"));
+        assert_eq!(state.base("./shared/gone"), None);
+    }
+
+    #[test]
+    fn conflict_names() {
+        let ts = 1_792_324_800;
+        let date = DateTime::from_timestamp(ts as i64, 0)
+            .unwrap()
+            .with_timezone(&Local)
+            .format("%Y-%m-%d")
+            .to_string();
+
+        assert_eq!(
+            conflict_name("./shared/note/a.md", "pi4", ts, |_| false),
+            format!("./shared/note/a (conflict from pi4 {date}).md")
+        );
+        assert_eq!(
+            conflict_name("Makefile", "pi4", ts, |_| false),
+            format!("Makefile (conflict from pi4 {date})")
+        );
+
+        let first = format!("a (conflict from pi4 {date}).md");
+        assert_eq!(
+            conflict_name("a.md", "pi4", ts, |name| name == first),
+            format!("a (conflict from pi4 {date} 2).md")
+        );
+    }
+}
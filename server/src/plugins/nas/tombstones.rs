@@ -0,0 +1,201 @@
+// Deletions on the NAS, listed with its files so a device offline at the time
+// removes its copy instead of putting it back. A tombstone of a directory
+// covers everything under it. Paths are kept relative to the share, `note/a`
+// whether it came as `note/a`, `shared/note/a` or `./shared/note/a`.
+
+use std::fs;
+use std::path::Path;
+use std::sync::Mutex;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{cfg, shared, utils};
+
+// the client, the monitor and the plugin all update the file
+static LOCK: Mutex<()> = Mutex::new(());
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Tombstone {
+    pub path: String,
+    pub deleted: u64,
+    pub device: String,
+}
+
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Tombstones {
+    tombstones: Vec<Tombstone>,
+}
+
+impl Tombstones {
+    pub fn load(path: &str) -> Result<Self, String> {
+        if !Path::new(path).exists() {
+            return Ok(Self::default());
+        }
+
+        let content =
+            fs::read_to_string(path).map_err(|e| format!("Failed to read {path}: {e}"))?;
+        serde_json::from_str(&content).map_err(|e| format!("Failed to parse {path}: {e}"))
+    }
+
+    pub fn save(&self, path: &str) -> Result<(), String> {
+        let content = serde_json::to_string_pretty(self).unwrap();
+        fs::write(path, content).map_err(|e| format!("Failed to write {path}: {e}"))
+    }
+
+    // the first deletion is kept, the monitor reports it again
+    pub fn add(&mut self, path: &str, device: &str, ts: u64) {
+        if self.tombstones.iter().any(|t| t.path == path) {
+            return;
+        }
+
+        self.tombstones.push(Tombstone {
+            path: path.to_owned(),
+            deleted: ts,
+            device: device.to_owned(),
+        });
+    }
+
+    pub fn revive(&mut self, path: &str) {
+        self.tombstones.retain(|t| t.path != path);
+    }
+
+    // past the retention, or a file there again
+    pub fn expire(&mut self, now: u64, retention: u64, exists: impl Fn(&str) -> bool) {
+        self.tombstones
+            .retain(|t| t.deleted.saturating_add(retention) > now && !exists(&t.path));
+    }
+}
+
+// without the share in front, `.` and empty parts dropped
+fn lexical(path: &str) -> String {
+    let share = cfg::FILE_FOLDER.trim_start_matches("./");
+    let path = path.trim_start_matches("./");
+    let path = match path.strip_prefix(share) {
+        Some(rest) if rest.is_empty() || rest.starts_with('/') => rest,
+        _ => path,
+    };
+
+    path.split('/')
+        .filter(|part| !part.is_empty() && *part != ".")
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+// as it is recorded, a path out of the share is refused
+fn relative(path: &str) -> Result<String, String> {
+    let relative = lexical(path);
+    shared::resolve(&relative)?;
+
+    Ok(relative)
+}
+
+// the latest deletion of the file or of a directory above it
+pub fn covering<'a>(tombstones: &'a [Tombstone], path: &str) -> Option<&'a Tombstone> {
+    let path = lexical(path);
+    tombstones
+        .iter()
+        .filter(|t| {
+            let deleted = lexical(&t.path);
+            path == deleted
+                || path
+                    .strip_prefix(deleted.as_str())
+                    .is_some_and(|rest| rest.starts_with('/'))
+        })
+        .max_by_key(|t| t.deleted)
+}
+
+fn update(f: impl FnOnce(&mut Tombstones)) -> Result<Tombstones, String> {
+    let _lock = LOCK.lock().unwrap();
+    let mut tombstones = Tombstones::load(cfg::TOMBSTONES_FILE)?;
+    // recorded before they were kept relative
+    for t in tombstones.tombstones.iter_mut() {
+        t.path = lexical(&t.path);
+    }
+    f(&mut tombstones);
+    tombstones.save(cfg::TOMBSTONES_FILE)?;
+
+    Ok(tombstones)
+}
+
+pub fn record(path: &str, device: &str) -> Result<(), String> {
+    let path = relative(path)?;
+    update(|tombstones| tombstones.add(&path, device, utils::ts())).map(|_| ())
+}
+
+pub fn revive(path: &str) -> Result<(), String> {
+    let path = relative(path)?;
+    update(|tombstones| tombstones.revive(&path)).map(|_| ())
+}
+
+// what goes out with the files, the expired ones are dropped first
+pub fn listed() -> Result<Vec<Tombstone>, String> {
+    let retention = cfg::nas_tombstone_days().saturating_mul(24 * 60 * 60);
+    let tombstones = update(|tombstones| {
+        tombstones.expire(utils::ts(), retention, |path| {
+            shared::resolve(path).is_ok_and(|file| file.is_file())
+        })
+    })?;
+
+    Ok(tombstones.tombstones)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tombstones_cover_and_expire() {
+        let mut tombstones = Tombstones::default();
+        tombstones.add("./shared/a.md", "pi4", 100);
+        tombstones.add("./shared/a.md", "pi5", 110);
+        tombstones.add("./shared/dir", "pi3", 200);
+        tombstones.add("./shared/back.md", "pi4", 200);
+
+        let listed = &tombstones.tombstones;
+        assert_eq!(covering(listed, "./shared/a.md").unwrap().device, "pi4");
+        assert_eq!(
+            covering(listed, "./shared/dir/b/c.md").unwrap().deleted,
+            200
+        );
+        assert!(covering(listed, "./shared/dir2/c.md").is_none());
+        assert!(covering(listed, "./shared/b.md").is_none());
+
+        tombstones.revive("./shared/a.md");
+        assert!(covering(&tombstones.tombstones, "./shared/a.md").is_none());
+
+        // a day of retention
+        tombstones.expire(200 + 86400 - 1, 86400, |path| path == "./shared/back.md");
+        assert_eq!(tombstones.tombstones.len(), 1);
+        tombstones.expire(200 + 86400, 86400, |_| false);
+        assert!(tombstones.tombstones.is_empty());
+    }
+
+    #[test]
+    fn paths_have_one_form() {
+        for path in [
+            "note/a",
+            "./note/a",
+            "shared/note/a",
+            "./shared/note/a",
+            "./shared//note/./a",
+        ] {
+            assert_eq!(lexical(path), "note/a");
+        }
+        assert_eq!(lexical("shared2/a"), "shared2/a");
+        assert!(relative("../a").is_err());
+
+        let tombstones = vec![Tombstone {
+            path: "note".to_owned(),
+            deleted: 100,
+            device: "pi4".to_owned(),
+        }];
+        assert!(covering(&tombstones, "./shared/note/a").is_some());
+        assert!(covering(&tombstones, "shared/note").is_some());
+        assert!(covering(&tombstones, "./shared/notes/a").is_none());
+
+        // no overflow with a retention of years
+        let mut tombstones = Tombstones { tombstones };
+        tombstones.expire(200, u64::MAX, |_| false);
+        assert_eq!(tombstones.tombstones.len(), 1);
+    }
+}
@@ -0,0 +1,184 @@
+use std::io;
+use std::path::{Path, PathBuf};
+
+use log::Level::Info;
+use tokio::fs::{self, File};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::mpsc::Sender;
+
+use crate::msg::{log, Msg, Reply};
+use crate::utils;
+use crate::{cfg, info};
+
+pub const NAME: &str = "nas";
+
+// an incoming file until it is complete, never synced nor monitored
+pub const PART_SUFFIX: &str = ".cng-part";
+
+// streamed a chunk at a time with a progress log after each one, so a 4 GB
+// video needs no more than a copy buffer of RAM
+const CHUNK_SIZE: u64 = 64 * 1024 * 1024;
+
+pub fn part_path(path: &Path) -> PathBuf {
+    let mut part = path.as_os_str().to_owned();
+    part.push(PART_SUFFIX);
+
+    PathBuf::from(part)
+}
+
+pub fn is_part(path: &Path) -> bool {
+    path.to_string_lossy().ends_with(PART_SUFFIX)
+}
+
+async fn copy<R, W>(
+    msg_tx: &Sender<Msg>,
+    label: &str,
+    reader: &mut R,
+    writer: &mut W,
+    total: Option<u64>,
+) -> io::Result<u64>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut copied = 0;
+    loop {
+        let n = tokio::io::copy(&mut (&mut *reader).take(CHUNK_SIZE), writer).await?;
+        copied += n;
+        if n < CHUNK_SIZE {
+            break;
+        }
+
+        let total = match total {
+            Some(total) => format!(" / {}", utils::format_number(total)),
+            None => String::new(),
+        };
+        info!(
+            msg_tx,
+            format!(
+                "[{NAME}] [..] {label}: {}{total}",
+                utils::format_number(copied)
+            )
+        );
+    }
+    writer.flush().await?;
+
+    Ok(copied)
+}
+
+fn check_size(copied: u64, size: u64) -> io::Result<()> {
+    if copied != size {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            format!("{copied} of {size} bytes"),
+        ));
+    }
+
+    Ok(())
+}
+
+// exactly size bytes, as announced to the other end
+pub async fn send_file<W>(
+    msg_tx: &Sender<Msg>,
+    path: &Path,
+    size: u64,
+    writer: &mut W,
+) -> io::Result<u64>
+where
+    W: AsyncWrite + Unpin,
+{
+    let file = File::open(path).await?;
+    let label = path.display().to_string();
+    let copied = copy(msg_tx, &label, &mut file.take(size), writer, Some(size)).await?;
+    check_size(copied, size)?;
+
+    Ok(copied)
+}
+
+// written to <path>.cng-part and renamed over path once all the size bytes
+// are in, a dropped connection leaves the previous version untouched
+pub async fn recv_file<R>(
+    msg_tx: &Sender<Msg>,
+    reader: &mut R,
+    path: &Path,
+    size: u64,
+) -> io::Result<u64>
+where
+    R: AsyncRead + Unpin,
+{
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).await?;
+    }
+
+    let part = part_path(path);
+    let label = path.display().to_string();
+    let result = async {
+        let mut file = File::create(&part).await?;
+        let mut reader = reader.take(size);
+        let copied = copy(msg_tx, &label, &mut reader, &mut file, Some(size)).await?;
+        check_size(copied, size)?;
+        file.sync_all().await?;
+        fs::rename(&part, path).await?;
+
+        Ok(copied)
+    }
+    .await;
+
+    if result.is_err() {
+        let _ = fs::remove_file(&part).await;
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tokio::sync::mpsc;
+
+    #[tokio::test]
+    async fn recv_renames_only_complete_files() {
+        let (msg_tx, _msg_rx) = mpsc::channel(16);
+        let dir = std::env::temp_dir().join(format!("cng_transfer_{}", std::process::id()));
+        let path = dir.join("note").join("a.md");
+
+        let mut reader: &[u8] = b"new note";
+        assert_eq!(recv_file(&msg_tx, &mut reader, &path, 8).await.unwrap(), 8);
+        assert_eq!(std::fs::read(&path).unwrap(), b"new note");
+        assert!(!part_path(&path).exists());
+
+        let mut sent = vec![];
+        assert_eq!(send_file(&msg_tx, &path, 8, &mut sent).await.unwrap(), 8);
+        assert_eq!(sent, b"new note");
+
+        // the connection drops halfway, with an error or an early EOF
+        let mut reader = (&b"half"[..]).chain(reset());
+        assert!(recv_file(&msg_tx, &mut reader, &path, 8).await.is_err());
+        let mut reader: &[u8] = b"half";
+        assert!(recv_file(&msg_tx, &mut reader, &path, 8).await.is_err());
+        assert_eq!(std::fs::read(&path).unwrap(), b"new note");
+        assert!(!part_path(&path).exists());
+        assert!(is_part(&part_path(&path)));
+
+        // the file shrank since its size was announced
+        assert!(send_file(&msg_tx, &path, 9, &mut vec![]).await.is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    fn reset() -> impl AsyncRead + Unpin {
+        struct Reset;
+        impl AsyncRead for Reset {
+            fn poll_read(
+                self: std::pin::Pin<&mut Self>,
+                _cx: &mut std::task::Context<'_>,
+                _buf: &mut tokio::io::ReadBuf<'_>,
+            ) -> std::task::Poll<io::Result<()>> {
+                std::task::Poll::Ready(Err(io::ErrorKind::ConnectionReset.into()))
+            }
+        }
+
+        Reset
+    }
+}
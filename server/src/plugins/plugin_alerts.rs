@@ -0,0 +1,591 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::Path;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use log::Level::{Error, Info};
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc::Sender;
+
+use crate::msg::{self, log, Cmd, Data, Msg, Reply};
+use crate::plugins::alerts::channels::{self, Channel, ChannelsCfg, Notification, State};
+use crate::plugins::plugin_rules::{self, Condition};
+use crate::plugins::plugins_main;
+use crate::{cfg, utils};
+use crate::{error, info, init, reply_me, unknown};
+
+pub const NAME: &str = "alerts";
+
+// log and file alerts are events, they never resolve and are notified at most
+// once per repeat, this one if not set
+const EVENT_REPEAT: u64 = 5 * 60;
+
+fn default_severity() -> String {
+    "warning".to_owned()
+}
+
+// as in alerts.json:
+// {
+//     "alerts": [
+//         {
+//             "name": "hot",
+//             "on": "devices",
+//             "when": ["temperature > 75"],
+//             "for": 300,
+//             "severity": "critical",
+//             "message": "{name} is {temperature}°C",
+//             "channels": ["tui", "smtp"]
+//         }
+//     ],
+//     "channels": {
+//         "mqtt": {},
+//         "webhook": { "url": "http://localhost:8080/alerts" },
+//         "smtp": { "server": "localhost:25", "from": "cng@pi5", "to": ["tim@pi5"] }
+//     }
+// }
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct AlertCfg {
+    name: String,
+    on: String, // devices, stocks, log or file
+    #[serde(default)]
+    when: Vec<String>,
+    #[serde(default, rename = "for")]
+    for_secs: u64,
+    // notify again while firing, 0 for never
+    #[serde(default)]
+    repeat: u64,
+    #[serde(default = "default_severity")]
+    severity: String,
+    #[serde(default)]
+    message: String,
+    // all channels if empty
+    #[serde(default)]
+    channels: Vec<String>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct AlertsCfg {
+    #[serde(default)]
+    alerts: Vec<AlertCfg>,
+    #[serde(default)]
+    channels: ChannelsCfg,
+}
+
+#[derive(Debug, Default)]
+struct AlertState {
+    since: Option<u64>, // when the conditions started to hold
+    firing: bool,
+    notified: Option<u64>,
+}
+
+#[derive(Debug)]
+struct Alert {
+    cfg: AlertCfg,
+    conditions: Vec<Condition>,
+    // per device or stock code, a single "" for log and file
+    states: HashMap<String, AlertState>,
+}
+
+impl Alert {
+    fn new(cfg: AlertCfg) -> Result<Self, String> {
+        let topics = [
+            msg::TOPIC_DEVICES,
+            msg::TOPIC_STOCKS,
+            msg::TOPIC_LOG,
+            msg::TOPIC_FILE,
+        ];
+        if !topics.contains(&cfg.on.as_str()) {
+            return Err(format!("unknown event: {:?}", cfg.on));
+        }
+
+        let conditions = cfg
+            .when
+            .iter()
+            .map(|c| Condition::parse(c))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(Self {
+            cfg,
+            conditions,
+            states: HashMap::new(),
+        })
+    }
+
+    fn is_event(&self) -> bool {
+        self.cfg.on == msg::TOPIC_LOG || self.cfg.on == msg::TOPIC_FILE
+    }
+
+    // the state to notify, if any
+    fn check(&mut self, key: &str, event: &serde_json::Value, now: u64) -> Option<State> {
+        let matched = self.conditions.iter().all(|c| c.eval(event));
+        let is_event = self.is_event();
+        let repeat = self.cfg.repeat;
+        let state = self.states.entry(key.to_owned()).or_default();
+
+        if is_event {
+            let repeat = if repeat == 0 { EVENT_REPEAT } else { repeat };
+            if !matched || matches!(state.notified, Some(n) if now.saturating_sub(n) < repeat) {
+                return None;
+            }
+            state.notified = Some(now);
+            return Some(State::Firing);
+        }
+
+        if !matched {
+            state.since = None;
+            if state.firing {
+                state.firing = false;
+                state.notified = Some(now);
+                return Some(State::Resolved);
+            }
+            return None;
+        }
+
+        let since = *state.since.get_or_insert(now);
+        if now.saturating_sub(since) < self.cfg.for_secs {
+            return None;
+        }
+
+        let again =
+            matches!(state.notified, Some(n) if repeat > 0 && now.saturating_sub(n) >= repeat);
+        if state.firing && !again {
+            return None;
+        }
+
+        state.firing = true;
+        state.notified = Some(now);
+
+        Some(State::Firing)
+    }
+
+    fn firing(&self) -> Vec<String> {
+        let mut keys: Vec<String> = self
+            .states
+            .iter()
+            .filter(|(_, s)| s.firing)
+            .map(|(k, _)| k.clone())
+            .collect();
+        keys.sort();
+
+        keys
+    }
+
+    fn message(&self, key: &str, event: &serde_json::Value) -> String {
+        if !self.cfg.message.is_empty() {
+            return plugin_rules::fill(&self.cfg.message, &self.cfg.name, event);
+        }
+
+        let when = self.cfg.when.join(", ");
+        match key {
+            "" => when,
+            key => format!("{key}: {when}"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Plugin {
+    name: String,
+    msg_tx: Sender<Msg>,
+    path: String,
+    alerts: Vec<Alert>,
+    channels: Vec<Arc<dyn Channel>>,
+}
+
+impl Plugin {
+    pub fn new(msg_tx: Sender<Msg>) -> Self {
+        let channels = channels::channels(&msg_tx, &ChannelsCfg::default())
+            .into_iter()
+            .map(Arc::from)
+            .collect();
+
+        Self {
+            name: NAME.to_owned(),
+            msg_tx,
+            path: cfg::ALERTS_FILE.to_owned(),
+            alerts: vec![],
+            channels,
+        }
+    }
+
+    async fn init(&mut self) {
+        self.load(reply_me!()).await;
+
+        init!(&self.msg_tx, NAME);
+    }
+
+    async fn load(&mut self, reply: Reply) {
+        // an empty file to start with
+        if !Path::new(&self.path).exists() {
+            let empty = serde_json::to_string_pretty(&AlertsCfg::default()).unwrap();
+            if let Err(e) = File::create(&self.path).and_then(|mut f| writeln!(f, "{empty}")) {
+                error!(
+                    &self.msg_tx,
+                    format!("[{NAME}] Failed to create {}: {e}", self.path)
+                );
+            }
+        }
+
+        let alerts_cfg = match fs::read_to_string(&self.path)
+            .map_err(|e| e.to_string())
+            .and_then(|s| serde_json::from_str::<AlertsCfg>(&s).map_err(|e| e.to_string()))
+        {
+            Ok(alerts_cfg) => alerts_cfg,
+            Err(e) => {
+                log(
+                    &self.msg_tx,
+                    reply,
+                    Error,
+                    format!("[{NAME}] Failed to load {}: {e}", self.path),
+                )
+                .await;
+                return;
+            }
+        };
+
+        self.channels = channels::channels(&self.msg_tx, &alerts_cfg.channels)
+            .into_iter()
+            .map(Arc::from)
+            .collect();
+
+        self.alerts.clear();
+        for alert_cfg in alerts_cfg.alerts {
+            let name = alert_cfg.name.clone();
+            let unknown_channel = alert_cfg
+                .channels
+                .iter()
+                .find(|c| !self.channels.iter().any(|ch| ch.name() == c.as_str()));
+            if let Some(channel) = unknown_channel {
+                log(
+                    &self.msg_tx,
+                    reply.clone(),
+                    Error,
+                    format!("[{NAME}] alert {name:?}: channel {channel:?} is not configured"),
+                )
+                .await;
+                continue;
+            }
+
+            match Alert::new(alert_cfg) {
+                Ok(alert) => self.alerts.push(alert),
+                Err(e) => {
+                    log(
+                        &self.msg_tx,
+                        reply.clone(),
+                        Error,
+                        format!("[{NAME}] alert {name:?}: {e}"),
+                    )
+                    .await;
+                }
+            }
+        }
+
+        let channel_names: Vec<&str> = self.channels.iter().map(|c| c.name()).collect();
+        log(
+            &self.msg_tx,
+            reply,
+            Info,
+            format!(
+                "[{NAME}] {} alerts loaded, channels: {}.",
+                self.alerts.len(),
+                channel_names.join(", ")
+            ),
+        )
+        .await;
+    }
+
+    // every channel sends on its own, a slow webhook doesn't hold the others
+    fn notify(&self, notification: Notification, names: &[String]) {
+        for channel in &self.channels {
+            if !names.is_empty() && !names.iter().any(|n| n == channel.name()) {
+                continue;
+            }
+
+            let channel = Arc::clone(channel);
+            let notification = notification.clone();
+            let msg_tx = self.msg_tx.clone();
+            tokio::spawn(async move {
+                if let Err(e) = channel.send(&notification).await {
+                    error!(&msg_tx, format!("[{NAME}] {}: {e}", channel.name()));
+                }
+            });
+        }
+    }
+
+    async fn event(&mut self, topic: &str, key: &str, event: serde_json::Value) {
+        let now = utils::ts();
+
+        for idx in 0..self.alerts.len() {
+            let alert = &mut self.alerts[idx];
+            if alert.cfg.on != topic {
+                continue;
+            }
+
+            if let Some(state) = alert.check(key, &event, now) {
+                let notification = Notification {
+                    ts: now,
+                    device: cfg::name(),
+                    alert: alert.cfg.name.clone(),
+                    key: key.to_owned(),
+                    state,
+                    severity: alert.cfg.severity.clone(),
+                    message: alert.message(key, &event),
+                };
+                let names = alert.cfg.channels.clone();
+                self.notify(notification, &names);
+            }
+        }
+    }
+
+    async fn list(&mut self, cmd: &Cmd) {
+        match &cmd.reply {
+            Reply::Device(_) => {
+                for alert in &self.alerts {
+                    let firing = alert.firing();
+                    let state = if firing.is_empty() {
+                        "ok".to_owned()
+                    } else {
+                        format!("firing: {}", firing.join(", "))
+                    };
+                    log(
+                        &self.msg_tx,
+                        cmd.reply.clone(),
+                        Info,
+                        format!(
+                            "[{NAME}] {} ({}) on {} when {}: {state}",
+                            alert.cfg.name,
+                            alert.cfg.severity,
+                            alert.cfg.on,
+                            alert.cfg.when.join(", ")
+                        ),
+                    )
+                    .await;
+                }
+            }
+            Reply::Web(sender) => {
+                let alerts: Vec<serde_json::Value> = self
+                    .alerts
+                    .iter()
+                    .map(|a| serde_json::json!({ "alert": a.cfg, "firing": a.firing() }))
+                    .collect();
+                sender
+                    .send(serde_json::to_value(alerts).unwrap())
+                    .await
+                    .unwrap();
+            }
+        }
+    }
+
+    // a test notification to check the channels
+    async fn test(&mut self, cmd: &Cmd) {
+        let names: Vec<String> = cmd.data.clone();
+        if let Some(name) = names
+            .iter()
+            .find(|n| !self.channels.iter().any(|c| c.name() == n.as_str()))
+        {
+            log(
+                &self.msg_tx,
+                cmd.reply.clone(),
+                Error,
+                format!("[{NAME}] test: channel {name:?} is not configured"),
+            )
+            .await;
+            return;
+        }
+
+        let notification = Notification {
+            ts: utils::ts(),
+            device: cfg::name(),
+            alert: msg::ACT_TEST.to_owned(),
+            key: String::new(),
+            state: State::Firing,
+            severity: "info".to_owned(),
+            message: format!("a test from {}", cfg::name()),
+        };
+        self.notify(notification, &names);
+    }
+
+    async fn help(&self) {
+        info!(
+            &self.msg_tx,
+            format!(
+                "[{NAME}] {ACT_HELP}, {ACT_INIT}, {ACT_LIST}, {ACT_RELOAD}, {ACT_TEST} [channel ...]",
+                ACT_HELP = msg::ACT_HELP,
+                ACT_INIT = msg::ACT_INIT,
+                ACT_LIST = msg::ACT_LIST,
+                ACT_RELOAD = msg::ACT_RELOAD,
+                ACT_TEST = msg::ACT_TEST,
+            )
+        );
+    }
+}
+
+#[async_trait]
+impl plugins_main::Plugin for Plugin {
+    fn name(&self) -> &str {
+        self.name.as_str()
+    }
+
+    fn topics(&self) -> &[&'static str] {
+        &[
+            msg::TOPIC_DEVICES,
+            msg::TOPIC_STOCKS,
+            msg::TOPIC_LOG,
+            msg::TOPIC_FILE,
+        ]
+    }
+
+    async fn msg(&mut self, msg: &Msg) -> bool {
+        match &msg.data {
+            Data::Cmd(cmd) => match cmd.action.as_str() {
+                msg::ACT_HELP => self.help().await,
+                msg::ACT_INIT => self.init().await,
+                msg::ACT_LIST | msg::ACT_SHOW => self.list(cmd).await,
+                msg::ACT_RELOAD => self.load(cmd.reply.clone()).await,
+                msg::ACT_TEST => self.test(cmd).await,
+                _ => {
+                    unknown!(&self.msg_tx, NAME, cmd.action);
+                }
+            },
+            Data::Devices(devices) => {
+                for device in devices {
+                    let event = plugin_rules::device_event(device);
+                    self.event(msg::TOPIC_DEVICES, &device.name, event).await;
+                }
+            }
+            Data::Stocks(stocks) => {
+                for stock in stocks {
+                    let event = serde_json::to_value(stock).unwrap();
+                    self.event(msg::TOPIC_STOCKS, &stock.code, event).await;
+                }
+            }
+            Data::Log(log) => {
+                // don't react to our own logs, the tui channel is a log
+                if !log.msg.starts_with(&format!("[{NAME}]")) {
+                    let event = serde_json::to_value(log).unwrap();
+                    self.event(msg::TOPIC_LOG, "", event).await;
+                }
+            }
+            Data::FileEvent(file_event) => {
+                let event = serde_json::to_value(file_event).unwrap();
+                self.event(msg::TOPIC_FILE, "", event).await;
+            }
+            _ => {
+                unknown!(&self.msg_tx, NAME, msg);
+            }
+        }
+
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::harness::Harness;
+    use crate::msg::{DevInfo, Metric};
+    use crate::plugins::plugins_main::Plugin as _;
+    use serde_json::json;
+
+    fn alert(json: serde_json::Value) -> Alert {
+        Alert::new(serde_json::from_value(json).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn firing_for_and_resolved() {
+        let mut a = alert(
+            json!({"name": "hot", "on": "devices", "when": ["temperature > 75"], "for": 300}),
+        );
+        let hot = json!({"name": "pi5", "temperature": 80.0});
+        let cool = json!({"name": "pi5", "temperature": 60.0});
+
+        assert_eq!(a.check("pi5", &hot, 1000), None);
+        assert_eq!(a.check("pi5", &hot, 1299), None);
+        assert_eq!(a.check("pi5", &hot, 1300), Some(State::Firing));
+        // de-duplicated while firing
+        assert_eq!(a.check("pi5", &hot, 1400), None);
+        assert_eq!(a.firing(), vec!["pi5".to_owned()]);
+        // per device
+        assert_eq!(a.check("nas", &cool, 1400), None);
+
+        assert_eq!(a.check("pi5", &cool, 1500), Some(State::Resolved));
+        assert_eq!(a.check("pi5", &cool, 1600), None);
+        assert!(a.firing().is_empty());
+
+        // the period starts again
+        assert_eq!(a.check("pi5", &hot, 1700), None);
+    }
+
+    #[test]
+    fn repeat_and_events() {
+        let mut a = alert(
+            json!({"name": "full", "on": "devices", "when": ["disk_usage > 90"], "repeat": 3600}),
+        );
+        let full = json!({"disk_usage": 91});
+        assert_eq!(a.check("nas", &full, 0), Some(State::Firing));
+        assert_eq!(a.check("nas", &full, 3599), None);
+        assert_eq!(a.check("nas", &full, 3600), Some(State::Firing));
+
+        let mut a =
+            alert(json!({"name": "sync", "on": "log", "when": ["level == ERROR", "msg ~ [nas"]}));
+        let failed = json!({"level": "ERROR", "msg": "[nas::server] Failed to PUT a.txt."});
+        assert_eq!(a.check("", &failed, 0), Some(State::Firing));
+        assert_eq!(a.check("", &failed, EVENT_REPEAT - 1), None);
+        assert_eq!(
+            a.check(
+                "",
+                &json!({"level": "INFO", "msg": "[nas] ok"}),
+                EVENT_REPEAT
+            ),
+            None
+        );
+        assert_eq!(a.check("", &failed, EVENT_REPEAT), Some(State::Firing));
+        assert!(a.firing().is_empty());
+    }
+
+    #[test]
+    fn messages() {
+        let a = alert(
+            json!({"name": "tsmc", "on": "stocks", "when": ["last_price > 1000"], "message": "{rule} {name} at {last_price}"}),
+        );
+        let event = json!({"code": "2330", "name": "台積電", "last_price": "1005.0"});
+        assert_eq!(a.message("2330", &event), "tsmc 台積電 at 1005.0");
+
+        let a = alert(json!({"name": "offline", "on": "devices", "when": ["onboard == false"]}));
+        assert_eq!(a.message("linds", &event), "linds: onboard == false");
+
+        assert!(
+            Alert::new(serde_json::from_value(json!({"name": "x", "on": "weather"})).unwrap())
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn devices_fire_on_the_tui() {
+        let mut h = Harness::new();
+        let mut plugin = Plugin::new(h.msg_tx.clone());
+        plugin.alerts.push(alert(json!({"name": "hot", "on": "devices", "when": ["temperature > 75"], "severity": "critical"})));
+
+        let mut device = DevInfo::new("pi5");
+        device.telemetry.insert(
+            msg::TM_TEMPERATURE.to_owned(),
+            Metric::parse(msg::TM_TEMPERATURE, "80"),
+        );
+        plugin
+            .msg(&Msg {
+                ts: utils::ts(),
+                plugin: msg::BUS.to_owned(),
+                data: Data::Devices(vec![device]),
+            })
+            .await;
+
+        tokio::task::yield_now().await;
+        h.run().await;
+
+        let log = h.logs.iter().find(|l| l.msg.contains("[FIRING]")).unwrap();
+        assert_eq!(log.level, log::Level::Error);
+        assert_eq!(log.msg, "[alerts] [FIRING] hot: pi5: temperature > 75");
+    }
+}
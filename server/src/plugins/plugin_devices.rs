@@ -1,20 +1,181 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+
 use async_trait::async_trait;
-use log::Level::{Error, Info};
+use log::Level::{Error, Info, Warn};
+use serde::{Deserialize, Serialize};
 use tokio::sync::mpsc::Sender;
 
 use crate::cfg;
-use crate::msg::{self, devices, log, Cmd, Data, DevInfo, Msg, Reply};
-use crate::plugins::{plugin_mqtt, plugin_nas, plugin_system, plugins_main};
+use crate::msg::{self, devices, log, Cmd, Data, DevInfo, Msg, Reply, Value};
+use crate::plugins::devices::metrics::{Metrics, Sample};
+use crate::plugins::{mqtt, plugin_mqtt, plugin_system, plugins_main};
 use crate::utils;
 use crate::{error, info, init, reply_me, unknown};
 
 pub const NAME: &str = "devices";
 
+// last_seen changes with every publish, so the registry is saved at most this often
+const SAVE_INTERVAL: u64 = 60;
+const HISTORY_COUNT: usize = 20;
+// a history file past it is trimmed to what availability reads
+const HISTORY_MAX_SIZE: u64 = 64 * 1024;
+const METRICS_RANGE: &str = "1d";
+// how often silent devices are looked for
+const CHECK_INTERVAL: u64 = 30;
+// a silent device is stale if it doesn't answer a system update in time
+const ASK_TIMEOUT: u64 = 60;
+// replies to a broadcast ask after this are not counted
+const BROADCAST_TIMEOUT: u64 = 10;
+
+const DAY: u64 = 24 * 60 * 60;
+const WEEK: u64 = 7 * DAY;
+const MONTH: u64 = 30 * DAY;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct Transition {
+    ts: u64,
+    onboard: bool,
+}
+
+// percentage of the known time in [from, to) the device was onboard, the
+// state before the first transition is unknown
+fn availability(history: &[Transition], from: u64, to: u64) -> Option<f32> {
+    let mut state = history
+        .iter()
+        .take_while(|t| t.ts <= from)
+        .last()
+        .map(|t| t.onboard);
+    let mut cursor = from;
+    let (mut on, mut known) = (0, 0);
+
+    for t in history.iter().filter(|t| t.ts > from && t.ts < to) {
+        if let Some(onboard) = state {
+            known += t.ts - cursor;
+            if onboard {
+                on += t.ts - cursor;
+            }
+        }
+        state = Some(t.onboard);
+        cursor = t.ts;
+    }
+
+    if let Some(onboard) = state {
+        known += to - cursor;
+        if onboard {
+            on += to - cursor;
+        }
+    }
+
+    if known == 0 {
+        None
+    } else {
+        Some(on as f32 * 100.0 / known as f32)
+    }
+}
+
+fn availability_str(availability: Option<f32>) -> String {
+    match availability {
+        Some(availability) => format!("{availability:.1}%"),
+        None => "n/a".to_owned(),
+    }
+}
+
+// the last month and the state it started with
+fn trim_history(history: &[Transition], now: u64) -> &[Transition] {
+    let from = now.saturating_sub(MONTH);
+    let first = history.iter().take_while(|t| t.ts <= from).count();
+
+    &history[first.saturating_sub(1)..]
+}
+
+fn load_history(path: &str) -> Result<Vec<Transition>, String> {
+    if !Path::new(path).exists() {
+        return Ok(vec![]);
+    }
+
+    let file = File::open(path).map_err(|e| format!("Failed to open {path}: {e}"))?;
+    let mut history = vec![];
+    for line in BufReader::new(file).lines() {
+        let line = line.map_err(|e| format!("Failed to read {path}: {e}"))?;
+        if let Ok(transition) = serde_json::from_str(&line) {
+            history.push(transition);
+        }
+    }
+
+    Ok(history)
+}
+
+fn rewrite_history(path: &str) -> Result<(), String> {
+    let history = load_history(path)?;
+    let mut content = String::new();
+    for t in trim_history(&history, utils::ts()) {
+        content.push_str(&serde_json::to_string(t).unwrap());
+        content.push('\n');
+    }
+
+    let part = format!("{path}.part");
+    fs::write(&part, content)
+        .and_then(|_| fs::rename(&part, path))
+        .map_err(|e| format!("Failed to trim {path}: {e}"))
+}
+
+// only what is still true after a restart, the descriptions (text) but not
+// the readings
+fn registry_entry(device: &DevInfo) -> DevInfo {
+    DevInfo {
+        ts: device.ts,
+        name: device.name.clone(),
+        onboard: None,
+        last_seen: device.last_seen,
+        stale: false,
+        telemetry: device
+            .telemetry
+            .iter()
+            .filter(|(_, m)| matches!(m.value, Value::Text(_)))
+            .map(|(k, m)| (k.clone(), m.clone()))
+            .collect(),
+    }
+}
+
+// an ask to @tag or *, replies are aggregated until the deadline
+#[derive(Debug)]
+struct Broadcast {
+    target: String,
+    reply: Reply,
+    deadline: u64,
+    // None for no response yet, then ok or not
+    results: BTreeMap<String, Option<bool>>,
+}
+
+impl Broadcast {
+    fn names(&self, result: Option<bool>) -> Vec<String> {
+        self.results
+            .iter()
+            .filter(|(_, r)| **r == result)
+            .map(|(name, _)| name.clone())
+            .collect()
+    }
+}
+
 #[derive(Debug)]
 pub struct Plugin {
     name: String,
     msg_tx: Sender<Msg>,
     devices: Vec<DevInfo>,
+    registry: String,
+    history_folder: String,
+    // nothing is written before init loads the registry
+    persist: bool,
+    saved: u64,
+    last_transitions: HashMap<String, bool>,
+    metrics: Metrics,
+    stale_after: u64,
+    // when silent devices were asked for a system update
+    asked: HashMap<String, u64>,
+    broadcasts: Vec<Broadcast>,
 }
 
 impl Plugin {
@@ -23,30 +184,178 @@ impl Plugin {
             name: NAME.to_owned(),
             msg_tx,
             devices: vec![],
+            registry: cfg::DEVICES_FILE.to_owned(),
+            history_folder: cfg::HISTORY_FOLDER.to_owned(),
+            persist: false,
+            saved: 0,
+            last_transitions: HashMap::new(),
+            metrics: Metrics::new(),
+            stale_after: cfg::stale_after(),
+            asked: HashMap::new(),
+            broadcasts: vec![],
         }
     }
 
-    async fn device_update(&mut self, device: &DevInfo) {
-        async fn ask_device_update(msg_tx: &Sender<Msg>, device_name: &str) {
-            msg::cmd(
-                msg_tx,
-                reply_me!(),
-                plugin_mqtt::NAME.to_owned(),
-                msg::ACT_ASK.to_owned(),
-                vec![
-                    device_name.to_owned(),
-                    "p".to_owned(),
-                    plugin_system::NAME.to_owned(),
-                    msg::ACT_UPDATE.to_owned(),
-                ],
-            )
-            .await;
+    fn history_path(&self, name: &str) -> String {
+        format!(
+            "{}/{}.jsonl",
+            self.history_folder,
+            sanitize_filename::sanitize(name)
+        )
+    }
+
+    fn load_registry(&mut self) -> Result<(), String> {
+        if !Path::new(&self.registry).exists() {
+            return Ok(());
         }
 
+        let content = fs::read_to_string(&self.registry)
+            .map_err(|e| format!("Failed to read {}: {e}", self.registry))?;
+        self.devices = serde_json::from_str(&content)
+            .map_err(|e| format!("Failed to parse {}: {e}", self.registry))?;
+
+        for device in &self.devices {
+            let history = load_history(&self.history_path(&device.name))?;
+            if let Some(t) = history.last() {
+                self.last_transitions.insert(device.name.clone(), t.onboard);
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn save_registry(&mut self, force: bool) {
+        let now = utils::ts();
+        if !self.persist || (!force && now.saturating_sub(self.saved) < SAVE_INTERVAL) {
+            return;
+        }
+        self.saved = now;
+
+        let registry: Vec<DevInfo> = self.devices.iter().map(registry_entry).collect();
+        let result = serde_json::to_string_pretty(&registry)
+            .map_err(|e| e.to_string())
+            .and_then(|s| {
+                File::create(&self.registry)
+                    .and_then(|mut file| file.write_all(s.as_bytes()))
+                    .map_err(|e| e.to_string())
+            });
+
+        if let Err(e) = result {
+            error!(
+                &self.msg_tx,
+                format!("[{NAME}] Failed to save {}: {e}", self.registry)
+            );
+        }
+    }
+
+    async fn record_metrics(&mut self, device: &DevInfo) {
+        for (metric, m) in &device.telemetry {
+            if let Some(value) = m.number() {
+                let sample = Sample {
+                    ts: device.ts,
+                    value: value as f32,
+                };
+                if let Err(e) = self.metrics.record(&device.name, metric, sample) {
+                    error!(&self.msg_tx, format!("[{NAME}] {e}"));
+                }
+            }
+        }
+    }
+
+    // append to the device's history if it is a change
+    async fn transition(&mut self, name: &str, onboard: bool) {
+        if !self.persist || self.last_transitions.get(name) == Some(&onboard) {
+            return;
+        }
+        self.last_transitions.insert(name.to_owned(), onboard);
+
+        let path = self.history_path(name);
+        let line = serde_json::to_string(&Transition {
+            ts: utils::ts(),
+            onboard,
+        })
+        .unwrap();
+        let result = fs::create_dir_all(&self.history_folder).and_then(|_| {
+            OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&path)
+                .and_then(|mut file| writeln!(file, "{line}"))
+        });
+
+        if let Err(e) = result {
+            error!(
+                &self.msg_tx,
+                format!("[{NAME}] Failed to write {path}: {e}")
+            );
+            return;
+        }
+
+        let size = fs::metadata(&path).map(|m| m.len()).unwrap_or(0);
+        if size > HISTORY_MAX_SIZE {
+            if let Err(e) = rewrite_history(&path) {
+                error!(&self.msg_tx, format!("[{NAME}] {e}"));
+            }
+        }
+    }
+
+    // ask the silent onboard devices for an update, and mark them stale if
+    // they don't answer
+    async fn tick(&mut self) {
+        let now = utils::ts();
+        self.finish_broadcasts(now).await;
+
+        let mut changed = vec![];
+
+        for d in self.devices.iter_mut() {
+            if d.onboard != Some(true) || d.stale {
+                continue;
+            }
+            let silent = now.saturating_sub(d.last_seen.unwrap_or(d.ts));
+            if silent <= self.stale_after {
+                continue;
+            }
+
+            match self.asked.get(&d.name) {
+                None => {
+                    self.asked.insert(d.name.clone(), now);
+                    ask_device_update(&self.msg_tx, &d.name).await;
+                }
+                Some(asked) if now.saturating_sub(*asked) >= ASK_TIMEOUT => {
+                    self.asked.remove(&d.name);
+                    d.stale = true;
+                    info!(
+                        &self.msg_tx,
+                        format!(
+                            "[{NAME}] device '{}' stale, last seen {} ago",
+                            d.name,
+                            utils::uptime_str(silent)
+                        )
+                    );
+                    changed.push(d.name.clone());
+                }
+                Some(_) => (),
+            }
+        }
+
+        if changed.is_empty() {
+            return;
+        }
+        for name in changed {
+            self.transition(&name, false).await;
+        }
+        self.save_registry(true).await;
+        devices(&self.msg_tx, self.devices.clone()).await;
+    }
+
+    async fn device_update(&mut self, device: &DevInfo) {
+        self.record_metrics(device).await;
+
         if let Some(d) = self.devices.iter_mut().find(|d| d.name == device.name) {
             d.ts = device.ts;
             // log if onboard is changed
-            if device.onboard.is_some() && (device.onboard != d.onboard) {
+            let changed = device.onboard.is_some() && (device.onboard != d.onboard);
+            if changed {
                 info!(
                     &self.msg_tx,
                     format!(
@@ -57,65 +366,41 @@ impl Plugin {
                     )
                 );
             }
-            if device.onboard.is_some() {
+            if let Some(onboard) = device.onboard {
                 // ask system update if onboard from false to true
-                if device.onboard.unwrap() && (d.onboard.is_none() || !d.onboard.unwrap()) {
+                if onboard && !d.onboard.unwrap_or(false) {
                     ask_device_update(&self.msg_tx, &device.name).await;
                 }
                 d.onboard = device.onboard;
             }
-            if device.app_uptime.is_some() {
-                d.app_uptime = device.app_uptime;
-            }
-            if device.host_uptime.is_some() {
-                d.host_uptime = device.host_uptime;
-            }
-            if device.tailscale_ip.is_some() {
-                d.tailscale_ip = device.tailscale_ip.clone();
-            }
-            if device.version.is_some() {
-                d.version = device.version.clone();
-            }
-            if device.temperature.is_some() {
-                d.temperature = device.temperature;
-            }
-            if device.os.is_some() {
-                d.os = device.os.clone();
-            }
-            if device.cpu_arch.is_some() {
-                d.cpu_arch = device.cpu_arch.clone();
-            }
-            if device.cpu_usage.is_some() {
-                d.cpu_usage = device.cpu_usage;
-            }
-            if device.memory_usage.is_some() {
-                d.memory_usage = device.memory_usage;
-            }
-            if device.disk_usage.is_some() {
-                d.disk_usage = device.disk_usage;
-            }
-            if device.weather.is_some() {
-                d.weather = device.weather.clone();
-            }
+            d.telemetry.extend(device.telemetry.clone());
             if device.last_seen.is_some() {
                 d.last_seen = device.last_seen;
+                self.asked.remove(&device.name);
+            }
+
+            // seen again, or offboard which is not stale
+            let back = d.stale && (device.last_seen.is_some() || device.onboard.is_some());
+            if back {
+                d.stale = false;
+                if d.onboard == Some(true) {
+                    info!(
+                        &self.msg_tx,
+                        format!("[{NAME}] device '{}' back", device.name)
+                    );
+                }
+            }
+
+            // clear all but last_seen if not onboard
+            if device.onboard == Some(false) {
+                d.telemetry.clear();
             }
 
-            // clear all if not onboard
-            if device.onboard.is_some() && !device.onboard.unwrap() {
-                d.app_uptime = None;
-                d.host_uptime = None;
-                d.tailscale_ip = None;
-                d.version = None;
-                d.temperature = None;
-                d.os = None;
-                d.cpu_arch = None;
-                d.cpu_usage = None;
-                d.memory_usage = None;
-                d.disk_usage = None;
-                d.weather = None;
-                // d.last_seen = None;  // keep last_seen
+            let onboard = d.onboard == Some(true);
+            if changed || back {
+                self.transition(&device.name, onboard).await;
             }
+            self.save_registry(changed || back).await;
         } else {
             self.devices.push(device.clone());
             if device.onboard.is_some() {
@@ -132,178 +417,486 @@ impl Plugin {
             if device.onboard.is_some() && device.onboard.unwrap() {
                 ask_device_update(&self.msg_tx, &device.name).await;
             }
+
+            if let Some(onboard) = device.onboard {
+                self.transition(&device.name, onboard).await;
+            }
+            self.save_registry(true).await;
         }
 
         devices(&self.msg_tx, self.devices.clone()).await;
+    }
 
-        // send to plugin_nas
-        self.msg_tx
-            .send(Msg {
-                ts: utils::ts(),
-                plugin: plugin_nas::NAME.to_owned(),
-                data: Data::Devices(self.devices.clone()),
+    // the onboard devices of the tag, or all of them for *, but me
+    fn targets(&self, target: &str) -> Vec<String> {
+        let tag = target.strip_prefix(mqtt::utils::TAG_PREFIX);
+        self.devices
+            .iter()
+            .filter(|d| d.onboard == Some(true) && d.name != cfg::name())
+            .filter(|d| match tag {
+                Some(tag) => d.tags().iter().any(|t| t == tag),
+                None => target == mqtt::utils::ALL_DEVICES,
             })
-            .await
-            .unwrap();
+            .map(|d| d.name.clone())
+            .collect()
+    }
+
+    async fn ask(&mut self, cmd: &Cmd) {
+        let target = match cmd.data.first() {
+            Some(target) if mqtt::utils::is_broadcast(target) => target,
+            _ => {
+                log(
+                    &self.msg_tx,
+                    cmd.reply.clone(),
+                    Error,
+                    format!("[{NAME}] ask: @tag or * is missing."),
+                )
+                .await;
+                return;
+            }
+        };
+
+        let names = self.targets(target);
+        if names.is_empty() {
+            log(
+                &self.msg_tx,
+                cmd.reply.clone(),
+                Error,
+                format!("[{NAME}] ask {target}: no onboard device."),
+            )
+            .await;
+            return;
+        }
+
+        for name in &names {
+            let mut data = vec![name.clone()];
+            data.extend_from_slice(&cmd.data[1..]);
+            msg::cmd(
+                &self.msg_tx,
+                reply_me!(),
+                plugin_mqtt::NAME.to_owned(),
+                msg::ACT_ASK.to_owned(),
+                data,
+            )
+            .await;
+        }
+
+        // the web api takes the first response, so only the summary
+        if let Reply::Device(_) = &cmd.reply {
+            log(
+                &self.msg_tx,
+                cmd.reply.clone(),
+                Info,
+                format!("[{NAME}] ask {target}: {}", names.join(", ")),
+            )
+            .await;
+        }
+
+        self.broadcasts.push(Broadcast {
+            target: target.to_owned(),
+            reply: cmd.reply.clone(),
+            deadline: utils::ts() + BROADCAST_TIMEOUT,
+            results: names.into_iter().map(|name| (name, None)).collect(),
+        });
+
+        let msg_tx_clone = self.msg_tx.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(tokio::time::Duration::from_secs(BROADCAST_TIMEOUT)).await;
+            msg::cmd(
+                &msg_tx_clone,
+                reply_me!(),
+                NAME.to_owned(),
+                msg::ACT_TICK.to_owned(),
+                vec![],
+            )
+            .await;
+        });
+    }
+
+    // a reply from another device: level msg
+    async fn reply(&mut self, cmd: &Cmd) {
+        let from = match cmd.data.first() {
+            Some(from) => from,
+            None => {
+                unknown!(&self.msg_tx, NAME, cmd.data);
+                return;
+            }
+        };
+        let payload = cmd.data[1..].join(" ");
+        let failed = payload.split_whitespace().next() == Some(Error.as_str());
+
+        let mut reply = reply_me!();
+        let now = utils::ts();
+        if let Some(broadcast) = self
+            .broadcasts
+            .iter_mut()
+            .rev()
+            .find(|b| now < b.deadline && b.results.contains_key(from))
+        {
+            let result = broadcast.results.get_mut(from).unwrap();
+            *result = Some(result.unwrap_or(true) && !failed);
+            if let Reply::Device(_) = &broadcast.reply {
+                reply = broadcast.reply.clone();
+            }
+        }
+
+        log(&self.msg_tx, reply, Info, format!("R: {from}: {payload}")).await;
+    }
+
+    async fn finish_broadcasts(&mut self, now: u64) {
+        let (done, pending) = self
+            .broadcasts
+            .drain(..)
+            .partition(|b: &Broadcast| now >= b.deadline);
+        self.broadcasts = pending;
+
+        for broadcast in done {
+            let ok = broadcast.names(Some(true));
+            let failed = broadcast.names(Some(false));
+            let no_response = broadcast.names(None);
+
+            match &broadcast.reply {
+                Reply::Device(_) => {
+                    let mut summary = format!(
+                        "[{NAME}] ask {}: {} ok, {} failed, {} no response",
+                        broadcast.target,
+                        ok.len(),
+                        failed.len(),
+                        no_response.len()
+                    );
+                    if !failed.is_empty() {
+                        summary += &format!(", failed: {}", failed.join(", "));
+                    }
+                    if !no_response.is_empty() {
+                        summary += &format!(", no response: {}", no_response.join(", "));
+                    }
+                    let level = if ok.len() == broadcast.results.len() {
+                        Info
+                    } else {
+                        Warn
+                    };
+                    log(&self.msg_tx, broadcast.reply.clone(), level, summary).await;
+                }
+                Reply::Web(sender) => {
+                    let summary = serde_json::json!({
+                        "target": broadcast.target,
+                        "ok": ok,
+                        "failed": failed,
+                        "no_response": no_response,
+                    });
+                    if let Err(e) = sender.send(summary).await {
+                        error!(&self.msg_tx, format!("[{NAME}] {e}"));
+                    }
+                }
+            }
+        }
     }
 
     async fn init(&mut self) {
+        let msg_tx_clone = self.msg_tx.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(tokio::time::Duration::from_secs(CHECK_INTERVAL)).await;
+                msg::cmd(
+                    &msg_tx_clone,
+                    reply_me!(),
+                    NAME.to_owned(),
+                    msg::ACT_TICK.to_owned(),
+                    vec![],
+                )
+                .await;
+            }
+        });
+
+        match self.load_registry() {
+            Ok(()) => {
+                info!(
+                    &self.msg_tx,
+                    format!("[{NAME}] {} devices restored.", self.devices.len())
+                );
+                if !self.devices.is_empty() {
+                    devices(&self.msg_tx, self.devices.clone()).await;
+                }
+            }
+            Err(e) => {
+                error!(&self.msg_tx, format!("[{NAME}] {e}"));
+            }
+        }
+        self.persist = true;
+        self.metrics.open(cfg::METRICS_FOLDER);
+
         init!(&self.msg_tx, NAME);
     }
 
-    async fn show_device(&self, cmd: &Cmd, device: &DevInfo) {
-        // name
-        log(
-            &self.msg_tx,
-            cmd.reply.clone(),
-            Info,
-            device.name.to_string(),
-        )
-        .await;
+    async fn metrics(&mut self, cmd: &Cmd) {
+        let (name, metric) = match (cmd.data.first(), cmd.data.get(1)) {
+            (Some(name), Some(metric)) => (name, metric),
+            (Some(name), None) => {
+                log(
+                    &self.msg_tx,
+                    cmd.reply.clone(),
+                    Info,
+                    format!(
+                        "[{NAME}] {name} metrics: {}",
+                        self.metrics.metrics(name).join(", ")
+                    ),
+                )
+                .await;
+                return;
+            }
+            _ => {
+                log(
+                    &self.msg_tx,
+                    cmd.reply.clone(),
+                    Error,
+                    format!("[{NAME}] metrics: device is missing."),
+                )
+                .await;
+                return;
+            }
+        };
+        let range = cmd.data.get(2).map(|r| r.as_str()).unwrap_or(METRICS_RANGE);
 
-        // onboard
-        log(
-            &self.msg_tx,
-            cmd.reply.clone(),
-            Info,
-            format!("    Onboard: {}", onboard_str(&device.onboard),),
-        )
-        .await;
+        let now = utils::ts();
+        let samples = match utils::parse_duration(range).and_then(|secs| {
+            self.metrics
+                .query(name, metric, now.saturating_sub(secs), now + 1)
+        }) {
+            Ok(samples) => samples,
+            Err(e) => {
+                log(
+                    &self.msg_tx,
+                    cmd.reply.clone(),
+                    Error,
+                    format!("[{NAME}] metrics: {e}"),
+                )
+                .await;
+                return;
+            }
+        };
 
-        // app uptime
-        let app_uptime = if let Some(t) = device.app_uptime {
-            utils::uptime_str(t)
-        } else {
-            "n/a".to_owned()
+        match &cmd.reply {
+            Reply::Device(_) => {
+                if samples.is_empty() {
+                    log(
+                        &self.msg_tx,
+                        cmd.reply.clone(),
+                        Info,
+                        format!("[{NAME}] {name} {metric} {range}: no samples"),
+                    )
+                    .await;
+                    return;
+                }
+
+                let values: Vec<f32> = samples.iter().map(|s| s.value).collect();
+                let min = values.iter().cloned().fold(f32::INFINITY, f32::min);
+                let max = values.iter().cloned().fold(f32::NEG_INFINITY, f32::max);
+                let avg = values.iter().sum::<f32>() / values.len() as f32;
+                log(
+                    &self.msg_tx,
+                    cmd.reply.clone(),
+                    Info,
+                    format!(
+                        "[{NAME}] {name} {metric} {range}: min {min:.1}, avg {avg:.1}, max {max:.1}, last {:.1} ({} samples)",
+                        values[values.len() - 1],
+                        values.len()
+                    ),
+                )
+                .await;
+                log(
+                    &self.msg_tx,
+                    cmd.reply.clone(),
+                    Info,
+                    format!("    {}", utils::sparkline(&values)),
+                )
+                .await;
+            }
+            Reply::Web(sender) => {
+                sender
+                    .send(serde_json::to_value(samples).unwrap())
+                    .await
+                    .unwrap();
+            }
+        }
+    }
+
+    async fn history(&mut self, cmd: &Cmd) {
+        let name = match cmd.data.first() {
+            Some(name) => name,
+            None => {
+                log(
+                    &self.msg_tx,
+                    cmd.reply.clone(),
+                    Error,
+                    format!("[{NAME}] history: device is missing."),
+                )
+                .await;
+                return;
+            }
         };
-        log(
-            &self.msg_tx,
-            cmd.reply.clone(),
-            Info,
-            format!("    App uptime: {app_uptime}"),
-        )
-        .await;
+        let count = cmd
+            .data
+            .get(1)
+            .and_then(|c| c.parse::<usize>().ok())
+            .unwrap_or(HISTORY_COUNT);
 
-        // host uptime
-        let host_uptime = if let Some(t) = device.host_uptime {
-            utils::uptime_str(t)
-        } else {
-            "n/a".to_owned()
+        let history = match load_history(&self.history_path(name)) {
+            Ok(history) => history,
+            Err(e) => {
+                log(
+                    &self.msg_tx,
+                    cmd.reply.clone(),
+                    Error,
+                    format!("[{NAME}] {e}"),
+                )
+                .await;
+                return;
+            }
         };
-        log(
-            &self.msg_tx,
-            cmd.reply.clone(),
-            Info,
-            format!("    Host uptime: {host_uptime}"),
-        )
-        .await;
 
-        // tailscale ip
-        let tailscale_ip = device.tailscale_ip.clone().unwrap_or("n/a".to_owned());
-        log(
-            &self.msg_tx,
-            cmd.reply.clone(),
-            Info,
-            format!("    Tailscale IP: {tailscale_ip}"),
-        )
-        .await;
+        let now = utils::ts();
+        let day = availability(&history, now.saturating_sub(DAY), now);
+        let week = availability(&history, now.saturating_sub(WEEK), now);
+        let month = availability(&history, now.saturating_sub(MONTH), now);
 
-        // version
-        let version = device.version.clone().unwrap_or("n/a".to_owned());
-        log(
-            &self.msg_tx,
-            cmd.reply.clone(),
-            Info,
-            format!("    Version: {version}"),
-        )
-        .await;
+        match &cmd.reply {
+            Reply::Device(_) => {
+                log(
+                    &self.msg_tx,
+                    cmd.reply.clone(),
+                    Info,
+                    format!("[{NAME}] {name} history:"),
+                )
+                .await;
 
-        // temperature
-        let temperature = if let Some(t) = device.temperature {
-            format!("{:.1}°C", t)
-        } else {
-            "n/a".to_owned()
-        };
-        log(
-            &self.msg_tx,
-            cmd.reply.clone(),
-            Info,
-            format!("    Temperature: {temperature}"),
-        )
-        .await;
+                let start = history.len().saturating_sub(count);
+                for (idx, t) in history.iter().enumerate().skip(start) {
+                    let until = history.get(idx + 1).map(|next| next.ts).unwrap_or(now);
+                    log(
+                        &self.msg_tx,
+                        cmd.reply.clone(),
+                        Info,
+                        format!(
+                            "    {} {:3} for {}",
+                            utils::ts_str_full(t.ts),
+                            onboard_str(&Some(t.onboard)),
+                            utils::uptime_str(until - t.ts)
+                        ),
+                    )
+                    .await;
+                }
 
-        // os
-        let os = device.os.clone().unwrap_or("n/a".to_owned());
-        log(
-            &self.msg_tx,
-            cmd.reply.clone(),
-            Info,
-            format!("    OS: {os}"),
-        )
-        .await;
+                log(
+                    &self.msg_tx,
+                    cmd.reply.clone(),
+                    Info,
+                    format!(
+                        "    Availability: day {}, week {}, month {}",
+                        availability_str(day),
+                        availability_str(week),
+                        availability_str(month)
+                    ),
+                )
+                .await;
+            }
+            Reply::Web(sender) => {
+                let start = history.len().saturating_sub(count);
+                sender
+                    .send(serde_json::json!({
+                        "name": name,
+                        "history": history[start..],
+                        "availability": { "day": day, "week": week, "month": month },
+                    }))
+                    .await
+                    .unwrap();
+            }
+        }
+    }
 
-        // cpu arch
-        let cpu_arch = device.cpu_arch.clone().unwrap_or("n/a".to_owned());
-        log(
-            &self.msg_tx,
-            cmd.reply.clone(),
-            Info,
-            format!("    CPU Arch: {cpu_arch}"),
-        )
-        .await;
+    // the fleet's version skew
+    async fn versions(&self, cmd: &Cmd) {
+        let versions = versions(&self.devices);
+        let latest = versions.first().and_then(|(v, _)| v.clone());
 
-        // cpu usage
-        let cpu_usage = if let Some(t) = device.cpu_usage {
-            format!("{:.1}%", t)
-        } else {
-            "n/a".to_owned()
-        };
-        log(
-            &self.msg_tx,
-            cmd.reply.clone(),
-            Info,
-            format!("    CPU Usage: {cpu_usage}"),
-        )
-        .await;
+        match &cmd.reply {
+            Reply::Device(_) => {
+                for (version, names) in &versions {
+                    let version = match version {
+                        Some(v) if Some(v) == latest.as_ref() => format!("{v} (latest)"),
+                        Some(v) => v.clone(),
+                        None => "n/a".to_owned(),
+                    };
+                    log(
+                        &self.msg_tx,
+                        cmd.reply.clone(),
+                        Info,
+                        format!("[{NAME}] {version}: {}", names.join(", ")),
+                    )
+                    .await;
+                }
 
-        // memory usage
-        let memory_usage = if let Some(t) = device.memory_usage {
-            format!("{:.1}%", t)
-        } else {
-            "n/a".to_owned()
-        };
-        log(
-            &self.msg_tx,
-            cmd.reply.clone(),
-            Info,
-            format!("    Memory Usage: {memory_usage}"),
-        )
-        .await;
+                let behind: usize = versions
+                    .iter()
+                    .filter(|(v, _)| v.is_some() && *v != latest)
+                    .map(|(_, names)| names.len())
+                    .sum();
+                if behind > 0 {
+                    log(
+                        &self.msg_tx,
+                        cmd.reply.clone(),
+                        Warn,
+                        format!("[{NAME}] {behind} devices behind {}", latest.unwrap()),
+                    )
+                    .await;
+                }
+            }
+            Reply::Web(sender) => {
+                let versions: Vec<serde_json::Value> = versions
+                    .into_iter()
+                    .map(|(version, names)| serde_json::json!({ "version": version, "devices": names }))
+                    .collect();
+                sender
+                    .send(serde_json::json!({ "latest": latest, "versions": versions }))
+                    .await
+                    .unwrap();
+            }
+        }
+    }
 
-        // disk usage
-        let disk_usage = if let Some(t) = device.disk_usage {
-            format!("{:.1}%", t)
-        } else {
-            "n/a".to_owned()
-        };
+    async fn show_device(&self, cmd: &Cmd, device: &DevInfo) {
+        // name
         log(
             &self.msg_tx,
             cmd.reply.clone(),
             Info,
-            format!("    Disk Usage: {disk_usage}"),
+            device.name.to_string(),
         )
         .await;
 
-        // weather
-        let weather = device.weather.clone().unwrap_or("n/a".to_owned());
+        // onboard
         log(
             &self.msg_tx,
             cmd.reply.clone(),
             Info,
-            format!("    Weather: {weather}"),
+            format!(
+                "    Onboard: {}{}",
+                onboard_str(&device.onboard),
+                if device.stale { " (stale)" } else { "" }
+            ),
         )
         .await;
 
+        // telemetry, built-in and custom
+        for (key, metric) in &device.telemetry {
+            log(
+                &self.msg_tx,
+                cmd.reply.clone(),
+                Info,
+                format!("    {key}: {metric}"),
+            )
+            .await;
+        }
+
         // last update
         log(
             &self.msg_tx,
@@ -342,11 +935,15 @@ impl Plugin {
             cmd.reply.clone(),
             Info,
             format!(
-                "[{NAME}] {ACT_INIT}, {ACT_HELP}, {ACT_SHOW} [device]",
+                "[{NAME}] {ACT_INIT}, {ACT_HELP}, {ACT_SHOW} [device], {ACT_HISTORY} <device> [count], {ACT_METRICS} <device> [metric] [range], {ACT_ASK} <@tag|*> p <plugin> <action> [data ...], {ACT_VERSIONS}",
                 NAME = NAME,
                 ACT_INIT = msg::ACT_INIT,
                 ACT_HELP = msg::ACT_HELP,
                 ACT_SHOW = msg::ACT_SHOW,
+                ACT_HISTORY = msg::ACT_HISTORY,
+                ACT_METRICS = msg::ACT_METRICS,
+                ACT_ASK = msg::ACT_ASK,
+                ACT_VERSIONS = msg::ACT_VERSIONS,
             ),
         )
         .await;
@@ -365,6 +962,12 @@ impl plugins_main::Plugin for Plugin {
                 msg::ACT_INIT => self.init().await,
                 msg::ACT_HELP => self.help(cmd).await,
                 msg::ACT_SHOW => self.show(cmd).await,
+                msg::ACT_HISTORY => self.history(cmd).await,
+                msg::ACT_METRICS => self.metrics(cmd).await,
+                msg::ACT_TICK => self.tick().await,
+                msg::ACT_ASK => self.ask(cmd).await,
+                msg::ACT_VERSIONS => self.versions(cmd).await,
+                msg::ACT_REPLY => self.reply(cmd).await,
                 _ => {
                     log(
                         &self.msg_tx,
@@ -387,6 +990,46 @@ impl plugins_main::Plugin for Plugin {
     }
 }
 
+async fn ask_device_update(msg_tx: &Sender<Msg>, device_name: &str) {
+    msg::cmd(
+        msg_tx,
+        reply_me!(),
+        plugin_mqtt::NAME.to_owned(),
+        msg::ACT_ASK.to_owned(),
+        vec![
+            device_name.to_owned(),
+            "p".to_owned(),
+            plugin_system::NAME.to_owned(),
+            msg::ACT_UPDATE.to_owned(),
+        ],
+    )
+    .await;
+}
+
+// 0.3.10 after 0.3.9, anything not a number first
+fn version_key(version: &str) -> Vec<u64> {
+    version.split('.').map(|n| n.parse().unwrap_or(0)).collect()
+}
+
+// newest first, devices without a version last
+fn versions(devices: &[DevInfo]) -> Vec<(Option<String>, Vec<String>)> {
+    let mut versions: BTreeMap<Option<String>, Vec<String>> = BTreeMap::new();
+    for device in devices {
+        versions
+            .entry(device.text(msg::TM_VERSION))
+            .or_default()
+            .push(device.name.clone());
+    }
+
+    let mut versions: Vec<_> = versions.into_iter().collect();
+    versions.sort_by(|(a, _), (b, _)| match (a, b) {
+        (Some(a), Some(b)) => version_key(b).cmp(&version_key(a)),
+        _ => b.cmp(a),
+    });
+
+    versions
+}
+
 fn onboard_str(onboard: &Option<bool>) -> &str {
     if let Some(onboard) = onboard {
         if *onboard {
@@ -398,3 +1041,359 @@ fn onboard_str(onboard: &Option<bool>) -> &str {
         "n/a"
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::harness::{Harness, TempDir};
+    use crate::msg::Metric;
+
+    fn dev_info(name: &str) -> DevInfo {
+        DevInfo::new(name)
+    }
+
+    fn with(mut device: DevInfo, key: &str, value: Value) -> DevInfo {
+        device
+            .telemetry
+            .insert(key.to_owned(), Metric::new(value, None));
+        device
+    }
+
+    fn last_devices(h: &Harness) -> Vec<DevInfo> {
+        h.published
+            .iter()
+            .rev()
+            .find_map(|d| match d {
+                Data::Devices(devices) => Some(devices.clone()),
+                _ => None,
+            })
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn device_update_merges_and_clears() {
+        let mut h = Harness::new();
+        utils::set_ts(Some(1000));
+
+        // onboard asks the device for a system update
+        let mut device = dev_info("pi5");
+        device.onboard = Some(true);
+        device.last_seen = Some(1000);
+        h.send(NAME, Data::DeviceUpdate(device)).await;
+
+        assert_eq!(h.asks.len(), 1);
+        assert_eq!(h.asks[0].0, "pi5");
+        assert!(h.asks[0].1.ends_with("p system update"));
+
+        // partial updates are merged
+        utils::set_ts(Some(1010));
+        let device = with(dev_info("pi5"), msg::TM_CPU_USAGE, Value::Number(12.5));
+        let device = with(device, msg::TM_VERSION, Value::Text("0.3.3".to_owned()));
+        h.send(NAME, Data::DeviceUpdate(device)).await;
+
+        let devices = last_devices(&h);
+        assert_eq!(devices.len(), 1);
+        assert_eq!(devices[0].onboard, Some(true));
+        assert_eq!(devices[0].number(msg::TM_CPU_USAGE), Some(12.5));
+        assert_eq!(devices[0].text(msg::TM_VERSION).as_deref(), Some("0.3.3"));
+        assert_eq!(devices[0].ts, 1010);
+
+        // still onboard, no more ask
+        let mut device = dev_info("pi5");
+        device.onboard = Some(true);
+        h.send(NAME, Data::DeviceUpdate(device)).await;
+        assert_eq!(h.asks.len(), 1);
+
+        // offboard clears everything but last_seen
+        let mut device = dev_info("pi5");
+        device.onboard = Some(false);
+        h.send(NAME, Data::DeviceUpdate(device)).await;
+
+        let devices = last_devices(&h);
+        assert_eq!(devices[0].onboard, Some(false));
+        assert!(devices[0].telemetry.is_empty());
+        assert_eq!(devices[0].last_seen, Some(1000));
+
+        assert!(h
+            .log_msgs()
+            .iter()
+            .any(|m| m.starts_with("[devices] device 'pi5' off at")));
+
+        utils::set_ts(None);
+    }
+
+    #[tokio::test]
+    async fn silent_device_is_asked_then_stale() {
+        let mut h = Harness::new();
+        utils::set_ts(Some(1000));
+
+        let mut device = dev_info("pi5");
+        device.onboard = Some(true);
+        device.last_seen = Some(1000);
+        h.send(NAME, Data::DeviceUpdate(device)).await;
+        assert_eq!(h.asks.len(), 1);
+
+        let stale_after = cfg::stale_after();
+
+        // still in time
+        utils::set_ts(Some(1000 + stale_after));
+        h.cmd(NAME, msg::ACT_TICK, &[]).await;
+        assert_eq!(h.asks.len(), 1);
+
+        // silent, asked once for an update before it is stale
+        utils::set_ts(Some(1001 + stale_after));
+        h.cmd(NAME, msg::ACT_TICK, &[]).await;
+        h.cmd(NAME, msg::ACT_TICK, &[]).await;
+        assert_eq!(h.asks.len(), 2);
+        assert!(h.asks[1].1.ends_with("p system update"));
+        assert!(!last_devices(&h)[0].stale);
+
+        utils::set_ts(Some(1001 + stale_after + ASK_TIMEOUT));
+        h.cmd(NAME, msg::ACT_TICK, &[]).await;
+        let devices = last_devices(&h);
+        assert!(devices[0].stale);
+        assert_eq!(devices[0].onboard, Some(true));
+        assert!(h
+            .log_msgs()
+            .iter()
+            .any(|m| m.starts_with("[devices] device 'pi5' stale")));
+
+        // a publish brings it back
+        let mut device = dev_info("pi5");
+        device.last_seen = Some(utils::ts());
+        h.send(NAME, Data::DeviceUpdate(device)).await;
+        assert!(!last_devices(&h)[0].stale);
+        assert!(h
+            .log_msgs()
+            .contains(&"[devices] device 'pi5' back".to_owned()));
+
+        utils::set_ts(None);
+    }
+
+    #[tokio::test]
+    async fn broadcast_ask_aggregates_replies() {
+        let mut h = Harness::new();
+        utils::set_ts(Some(1000));
+
+        for (name, tags, onboard) in [
+            ("pi5", "pi,office", true),
+            ("pi4", "pi", true),
+            ("pi3", "pi", true),
+            ("pi2", "pi", false),
+            ("nas", "nas", true),
+        ] {
+            let mut device = with(dev_info(name), msg::TM_TAGS, Value::Text(tags.to_owned()));
+            device.onboard = Some(onboard);
+            h.send(NAME, Data::DeviceUpdate(device)).await;
+        }
+        h.asks.clear();
+
+        h.cmd(
+            plugin_mqtt::NAME,
+            msg::ACT_ASK,
+            &["@pi", "p", "system", "update"],
+        )
+        .await;
+        let mut asked: Vec<&str> = h.asks.iter().map(|(d, _)| d.as_str()).collect();
+        asked.sort();
+        assert_eq!(asked, vec!["pi3", "pi4", "pi5"]);
+        assert!(h.asks[0].1.ends_with("p system update"));
+
+        // as published by pi5 and pi4 on tln/<me>/reply/<from>
+        for (from, payload) in [("pi5", "INFO updated"), ("pi4", "ERROR Device is missing.")] {
+            let enc_payload = utils::encrypt(&cfg::key(), payload).unwrap();
+            let publish = rumqttc::Publish::new(
+                format!("tln/{}/reply/{from}", cfg::name()),
+                rumqttc::QoS::AtMostOnce,
+                enc_payload,
+            );
+            mqtt::utils::process_event(
+                &h.msg_tx,
+                rumqttc::Event::Incoming(rumqttc::Packet::Publish(publish)),
+            )
+            .await;
+        }
+        h.run().await;
+        assert!(h.log_msgs().contains(&"R: pi5: INFO updated".to_owned()));
+
+        // not yet
+        h.cmd(NAME, msg::ACT_TICK, &[]).await;
+        assert!(!h.log_msgs().iter().any(|m| m.contains("no response")));
+
+        utils::set_ts(Some(1000 + BROADCAST_TIMEOUT));
+        h.cmd(NAME, msg::ACT_TICK, &[]).await;
+        let summary = h.logs.last().unwrap();
+        assert_eq!(summary.level, Warn);
+        assert_eq!(
+            summary.msg,
+            "[devices] ask @pi: 1 ok, 1 failed, 1 no response, failed: pi4, no response: pi3"
+        );
+
+        h.cmd(
+            plugin_mqtt::NAME,
+            msg::ACT_ASK,
+            &["@tv", "p", "system", "update"],
+        )
+        .await;
+        assert!(h
+            .log_msgs()
+            .contains(&"[devices] ask @tv: no onboard device.".to_owned()));
+
+        utils::set_ts(None);
+    }
+
+    #[tokio::test]
+    async fn versions_newest_first() {
+        let mut h = Harness::new();
+
+        for (name, version) in [
+            ("pi5", Some("0.3.10")),
+            ("pi4", Some("0.3.9")),
+            ("nas", Some("0.3.10")),
+            ("linds", None),
+        ] {
+            let device = match version {
+                Some(v) => with(dev_info(name), msg::TM_VERSION, Value::Text(v.to_owned())),
+                None => dev_info(name),
+            };
+            h.send(NAME, Data::DeviceUpdate(device)).await;
+        }
+
+        h.cmd(NAME, msg::ACT_VERSIONS, &[]).await;
+        // the nas plugin, subscribed to devices, logs the new ones too
+        let logs: Vec<String> = h
+            .log_msgs()
+            .into_iter()
+            .filter(|l| l.starts_with("[devices]"))
+            .collect();
+        assert_eq!(
+            logs,
+            vec![
+                "[devices] 0.3.10 (latest): pi5, nas",
+                "[devices] 0.3.9: pi4",
+                "[devices] n/a: linds",
+                "[devices] 1 devices behind 0.3.10",
+            ]
+        );
+
+        let values = h.web_cmd(NAME, msg::ACT_VERSIONS, &[]).await;
+        assert_eq!(values[0]["latest"], "0.3.10");
+        assert_eq!(values[0]["versions"][1]["devices"][0], "pi4");
+    }
+
+    #[test]
+    fn availability_over_known_time() {
+        let history = vec![
+            Transition {
+                ts: 100,
+                onboard: true,
+            },
+            Transition {
+                ts: 200,
+                onboard: false,
+            },
+            Transition {
+                ts: 250,
+                onboard: true,
+            },
+        ];
+
+        assert_eq!(availability(&history, 0, 100), None);
+        // unknown before 100, on 100-200, off 200-250, on 250-300
+        assert_eq!(availability(&history, 0, 300), Some(75.0));
+        assert_eq!(availability(&history, 150, 300), Some(200.0 / 3.0));
+        assert_eq!(availability(&history, 260, 300), Some(100.0));
+        assert_eq!(availability(&[], 0, 300), None);
+    }
+
+    #[test]
+    fn history_is_trimmed_to_the_month() {
+        let history: Vec<Transition> = [10, 20, 30]
+            .iter()
+            .map(|days| Transition {
+                ts: days * DAY,
+                onboard: days % 20 != 0,
+            })
+            .collect();
+
+        // the transition before the month tells how it started
+        assert_eq!(trim_history(&history, 45 * DAY)[0].ts, 10 * DAY);
+        assert_eq!(trim_history(&history, 55 * DAY)[0].ts, 20 * DAY);
+        assert_eq!(trim_history(&history, 100 * DAY).len(), 1);
+        assert_eq!(trim_history(&history, 0).len(), 3);
+    }
+
+    #[tokio::test]
+    async fn registry_and_history_are_persisted() {
+        let dir = TempDir::new("devices");
+        let (msg_tx, mut msg_rx) = tokio::sync::mpsc::channel(1024);
+
+        let new_plugin = || {
+            let mut plugin = Plugin::new(msg_tx.clone());
+            plugin.registry = dir.join("devices.json").to_string_lossy().to_string();
+            plugin.history_folder = dir.join("history").to_string_lossy().to_string();
+            plugin
+        };
+
+        let mut plugin = new_plugin();
+        plugin.init().await;
+        for (ts, onboard) in [(1000, true), (1010, true), (2000, false), (3000, true)] {
+            utils::set_ts(Some(ts));
+            let device = with(dev_info("pi5"), msg::TM_CPU_USAGE, Value::Number(10.0));
+            let mut device = with(device, msg::TM_VERSION, Value::Text("0.3.3".to_owned()));
+            device.onboard = Some(onboard);
+            device.last_seen = Some(ts);
+            plugin.device_update(&device).await;
+        }
+
+        // restored without the live fields, and no duplicate transition
+        let mut plugin = new_plugin();
+        plugin.init().await;
+        utils::set_ts(Some(3100));
+        let mut device = dev_info("pi5");
+        device.onboard = Some(true);
+        plugin.device_update(&device).await;
+
+        let history = load_history(&plugin.history_path("pi5")).unwrap();
+        utils::set_ts(None);
+        while msg_rx.try_recv().is_ok() {}
+
+        assert_eq!(plugin.devices.len(), 1);
+        assert_eq!(
+            plugin.devices[0].text(msg::TM_VERSION).as_deref(),
+            Some("0.3.3")
+        );
+        assert_eq!(plugin.devices[0].last_seen, Some(3000));
+        assert_eq!(plugin.devices[0].number(msg::TM_CPU_USAGE), None);
+        assert_eq!(
+            history,
+            vec![
+                Transition {
+                    ts: 1000,
+                    onboard: true
+                },
+                Transition {
+                    ts: 2000,
+                    onboard: false
+                },
+                Transition {
+                    ts: 3000,
+                    onboard: true
+                },
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn show_for_web() {
+        let mut h = Harness::new();
+
+        h.send(NAME, Data::DeviceUpdate(dev_info("pi5"))).await;
+        h.send(NAME, Data::DeviceUpdate(dev_info("linds"))).await;
+
+        let values = h.web_cmd(NAME, msg::ACT_SHOW, &[]).await;
+        assert_eq!(values.len(), 1);
+        assert_eq!(values[0][0]["name"], "pi5");
+        assert_eq!(values[0][1]["name"], "linds");
+    }
+}
@@ -6,9 +6,9 @@ use async_trait::async_trait;
 use log::Level::{Error, Info};
 use tokio::sync::mpsc::Sender;
 
-use crate::cfg;
 use crate::msg::{self, log, Cmd, Data, Msg, Reply};
 use crate::plugins::plugins_main;
+use crate::{cfg, shared};
 use crate::{error, info, init, unknown};
 
 pub const NAME: &str = "file";
@@ -20,6 +20,7 @@ pub struct Plugin {
     msg_tx: Sender<Msg>,
     filename: Option<String>,
     sequence: usize,
+    folder: String,
 }
 
 impl Plugin {
@@ -29,20 +30,33 @@ impl Plugin {
             msg_tx,
             filename: None,
             sequence: 0,
+            folder: cfg::FILE_FOLDER.to_owned(),
         }
     }
 
+    #[cfg(test)]
+    pub fn with_folder(msg_tx: Sender<Msg>, folder: &str) -> Self {
+        Self {
+            folder: folder.to_owned(),
+            ..Self::new(msg_tx)
+        }
+    }
+
+    fn resolve(&self, path: &str) -> Result<std::path::PathBuf, String> {
+        shared::resolve_in(Path::new(&self.folder), path)
+    }
+
     async fn init(&mut self) {
-        if !Path::new(cfg::FILE_FOLDER).exists() {
-            fs::create_dir(cfg::FILE_FOLDER).unwrap();
+        if !Path::new(&self.folder).exists() {
+            fs::create_dir(&self.folder).unwrap();
             info!(
                 &self.msg_tx,
-                format!("[{NAME}] Folder '{}' is created.", cfg::FILE_FOLDER)
+                format!("[{NAME}] Folder '{}' is created.", self.folder)
             );
         } else {
             info!(
                 &self.msg_tx,
-                format!("[{NAME}] Folder '{}' is existed.", cfg::FILE_FOLDER)
+                format!("[{NAME}] Folder '{}' is existed.", self.folder)
             );
         }
 
@@ -67,7 +81,7 @@ impl Plugin {
         .await;
 
         // list files in shared file
-        let paths = fs::read_dir(cfg::FILE_FOLDER).unwrap();
+        let paths = fs::read_dir(&self.folder).unwrap();
         for path in paths {
             let path = path.unwrap().path();
             log(
@@ -101,10 +115,22 @@ impl Plugin {
             }
         }
 
-        let path = format!("{}/{}", cfg::FILE_FOLDER, cmd.data[0]);
+        let path = match self.resolve(&cmd.data[0]) {
+            Ok(path) => path,
+            Err(e) => {
+                log(
+                    &self.msg_tx,
+                    cmd.reply.clone(),
+                    Error,
+                    format!("[{NAME}] put rejected: {e}"),
+                )
+                .await;
+                return;
+            }
+        };
 
         // check if file exist or not
-        if !Path::new(&path).exists() {
+        if !path.exists() {
             log(
                 &self.msg_tx,
                 cmd.reply.clone(),
@@ -119,11 +145,7 @@ impl Plugin {
         let file = File::open(path).unwrap();
         let metadata = file.metadata().unwrap();
         let size = metadata.len();
-        let sequence = if size % BUFFER_SIZE as u64 == 0 {
-            (size / BUFFER_SIZE as u64) as usize
-        } else {
-            (size / BUFFER_SIZE as u64) as usize + 1
-        };
+        let sequence = size.div_ceil(BUFFER_SIZE as u64) as usize;
 
         msg::file_filename(
             &self.msg_tx,
@@ -174,10 +196,23 @@ impl Plugin {
                     )
                     .await;
 
+                    let path = match self.resolve(&cmd.data[1]) {
+                        Ok(path) => path,
+                        Err(e) => {
+                            log(
+                                &self.msg_tx,
+                                cmd.reply.clone(),
+                                Error,
+                                format!("[{NAME}] file rejected: {e}"),
+                            )
+                            .await;
+                            return;
+                        }
+                    };
+
                     self.filename = Some(cmd.data[1].clone());
                     self.sequence = 0;
 
-                    let path = format!("{}/{}", cfg::FILE_FOLDER, self.filename.as_ref().unwrap());
                     let _ = File::create(path).unwrap();
                 }
                 "content" => {
@@ -204,7 +239,13 @@ impl Plugin {
                         return;
                     }
 
-                    let path = format!("{}/{}", cfg::FILE_FOLDER, self.filename.as_ref().unwrap());
+                    let path = match self.resolve(self.filename.as_ref().unwrap()) {
+                        Ok(path) => path,
+                        Err(e) => {
+                            error!(&self.msg_tx, format!("[{NAME}] file rejected: {e}"));
+                            return;
+                        }
+                    };
                     let mut file = OpenOptions::new().append(true).open(path).unwrap();
 
                     let content = ascii85::decode(&cmd.data[2]).unwrap();
@@ -308,3 +349,66 @@ impl plugins_main::Plugin for Plugin {
         false
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::harness::{Harness, TempDir};
+
+    #[tokio::test]
+    async fn file_reassembles_chunks_in_sequence() {
+        let mut h = Harness::new();
+        let dir = TempDir::new("file");
+        let folder = dir.join("shared");
+        fs::create_dir_all(&folder).unwrap();
+        let folder = folder.to_string_lossy().to_string();
+        h.plugins
+            .replace(Box::new(Plugin::with_folder(h.msg_tx.clone(), &folder)));
+        let filename = "harness_file_test.txt";
+        let path = format!("{folder}/{filename}");
+
+        h.cmd(NAME, msg::ACT_FILE, &["filename", filename, "2"])
+            .await;
+        h.cmd(
+            NAME,
+            msg::ACT_FILE,
+            &["content", "0", &ascii85::encode(b"hello ")],
+        )
+        .await;
+        // out of order is rejected
+        h.cmd(
+            NAME,
+            msg::ACT_FILE,
+            &["content", "2", &ascii85::encode(b"oops")],
+        )
+        .await;
+        h.cmd(
+            NAME,
+            msg::ACT_FILE,
+            &["content", "1", &ascii85::encode(b"world")],
+        )
+        .await;
+        h.cmd(NAME, msg::ACT_FILE, &["end", "2"]).await;
+
+        let content = fs::read_to_string(&path).unwrap();
+
+        assert_eq!(content, "hello world");
+        let logs = h.log_msgs();
+        assert!(logs.contains(&"[file] file: invalid sequence: 2".to_owned()));
+        assert!(logs.contains(&"[file] file: end: 2".to_owned()));
+
+        // no filename after end
+        h.cmd(NAME, msg::ACT_FILE, &["end", "0"]).await;
+        assert_eq!(h.log_msgs().last().unwrap(), "[file] file: no filename");
+
+        // nothing out of the share
+        h.cmd(NAME, msg::ACT_FILE, &["filename", "../escape.txt", "1"])
+            .await;
+        assert!(h
+            .log_msgs()
+            .last()
+            .unwrap()
+            .starts_with("[file] file rejected: \"../escape.txt\" goes up"));
+        assert!(!dir.join("escape.txt").exists());
+    }
+}
@@ -4,7 +4,6 @@ use tokio::sync::mpsc::Sender;
 
 use crate::cfg;
 use crate::msg::{self, log, Cmd, Data, Msg, Reply};
-use crate::panels::panels_main;
 use crate::plugins::plugins_main;
 use crate::{error, info, init, unknown};
 
@@ -99,29 +98,25 @@ impl plugins_main::Plugin for Plugin {
                     unknown!(&self.msg_tx, NAME, cmd.action);
                 }
             },
-            // redirect log to panels
-            Data::Log(log) => match cfg::mode().as_str() {
-                cfg::MODE_CLI => {
-                    if self.trace == 0 && log.level == Trace {
-                        return false;
-                    }
-                    println!("[{}] {}", log.level, log.msg);
+            // publish log to subscribers (e.g. panels)
+            Data::Log(log) => {
+                if self.trace == 0 && log.level == Trace {
+                    return false;
                 }
-                cfg::MODE_GUI => {
-                    if self.trace == 0 && log.level == Trace {
-                        return false;
-                    }
-                    self.msg_tx
-                        .send(Msg {
-                            ts: msg.ts,
-                            plugin: panels_main::NAME.to_owned(),
-                            data: Data::Log(log.clone()),
-                        })
-                        .await
-                        .unwrap();
+
+                if cfg::mode() == cfg::MODE_CLI {
+                    println!("[{}] {}", log.level, log.msg);
                 }
-                _ => (),
-            },
+
+                self.msg_tx
+                    .send(Msg {
+                        ts: msg.ts,
+                        plugin: msg::BUS.to_owned(),
+                        data: Data::Log(log.clone()),
+                    })
+                    .await
+                    .unwrap();
+            }
             _ => {
                 unknown!(&self.msg_tx, NAME, msg);
             }
@@ -1,11 +1,12 @@
 use async_trait::async_trait;
 use log::Level::{Error, Info};
 use rumqttc::{AsyncClient, LastWill, MqttOptions, QoS};
+use std::collections::HashSet;
 use tokio::sync::mpsc::Sender;
 
 use crate::cfg;
 use crate::msg::{self, log, Cmd, Data, Msg, Reply};
-use crate::plugins::{mqtt, plugins_main};
+use crate::plugins::{mqtt, plugin_devices, plugins_main};
 use crate::utils;
 use crate::{error, info, init, reply_me, unknown};
 
@@ -18,6 +19,8 @@ pub struct Plugin {
     name: String,
     msg_tx: Sender<Msg>,
     client: Option<AsyncClient>,
+    // devices publishing tags also read tln/<name>/reply/<from>
+    tagged: HashSet<String>,
 }
 
 impl Plugin {
@@ -26,6 +29,7 @@ impl Plugin {
             name: NAME.to_owned(),
             msg_tx,
             client: None,
+            tagged: HashSet::new(),
         }
     }
 
@@ -136,7 +140,7 @@ impl Plugin {
             mqtt::utils::publish(
                 &self.msg_tx,
                 self.client.as_ref(),
-                &format!("tln/{}/{}", device, msg::ACT_REPLY),
+                &self.reply_topic(device),
                 false,
                 &enc_msg,
             )
@@ -144,6 +148,15 @@ impl Plugin {
         }
     }
 
+    // older devices only match tln/<name>/reply
+    fn reply_topic(&self, device: &str) -> String {
+        if self.tagged.contains(device) {
+            format!("tln/{}/{}/{}", device, msg::ACT_REPLY, cfg::name())
+        } else {
+            format!("tln/{}/{}", device, msg::ACT_REPLY)
+        }
+    }
+
     async fn ask(&mut self, cmd: &Cmd) {
         let target_device = match &cmd.data.first() {
             Some(t) => t.to_owned(),
@@ -159,21 +172,21 @@ impl Plugin {
             }
         };
 
-        let mut msg = String::new();
-        msg += &format!("r {} ", cfg::name());
-        for t in &cmd.data[1..] {
-            // if t content space
-            if t.contains(" ") {
-                msg += &format!("\"{t}\"");
-            } else {
-                msg += t;
-            }
-            msg += " ";
+        if mqtt::utils::is_broadcast(target_device) {
+            msg::cmd(
+                &self.msg_tx,
+                cmd.reply.clone(),
+                plugin_devices::NAME.to_owned(),
+                msg::ACT_ASK.to_owned(),
+                cmd.data.clone(),
+            )
+            .await;
+            return;
         }
 
-        let msg = msg.trim();
+        let msg = mqtt::utils::ask_payload(&cfg::name(), &cmd.data[1..]);
 
-        let enc_msg = utils::encrypt(&cfg::key(), msg).unwrap();
+        let enc_msg = utils::encrypt(&cfg::key(), &msg).unwrap();
 
         mqtt::utils::publish(
             &self.msg_tx,
@@ -258,6 +271,14 @@ impl Plugin {
             "p mqtt ask pi5 p system quit".to_owned(),
         )
         .await;
+
+        log(
+            &self.msg_tx,
+            cmd.reply.clone(),
+            Info,
+            "p mqtt ask @pi p system update, or * for all devices".to_owned(),
+        )
+        .await;
     }
 }
 
@@ -267,6 +288,10 @@ impl plugins_main::Plugin for Plugin {
         self.name.as_str()
     }
 
+    fn topics(&self) -> &[&'static str] {
+        &[msg::TOPIC_DEVICES]
+    }
+
     async fn msg(&mut self, msg: &Msg) -> bool {
         match &msg.data {
             Data::Cmd(cmd) => match cmd.action.as_str() {
@@ -295,6 +320,13 @@ impl plugins_main::Plugin for Plugin {
                     unknown!(&self.msg_tx, NAME, cmd.action);
                 }
             },
+            Data::Devices(devices) => {
+                self.tagged = devices
+                    .iter()
+                    .filter(|device| device.telemetry.contains_key(msg::TM_TAGS))
+                    .map(|device| device.name.clone())
+                    .collect();
+            }
             _ => {
                 unknown!(&self.msg_tx, NAME, msg);
             }
@@ -303,3 +335,88 @@ impl plugins_main::Plugin for Plugin {
         false
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::harness::Harness;
+    use crate::plugins::plugin_devices;
+    use crate::plugins::plugins_main::Plugin as _;
+
+    #[tokio::test]
+    async fn replies_to_older_devices_on_the_legacy_topic() {
+        let (msg_tx, _msg_rx) = tokio::sync::mpsc::channel(16);
+        let mut plugin = Plugin::new(msg_tx);
+        assert_eq!(plugin.reply_topic("pi5"), "tln/pi5/reply");
+
+        let mut device = msg::DevInfo::new("pi5");
+        device.telemetry.insert(
+            msg::TM_TAGS.to_owned(),
+            msg::Metric::parse(msg::TM_TAGS, "home"),
+        );
+        let devices = vec![device, msg::DevInfo::new("pi4")];
+        plugin
+            .msg(&Msg {
+                ts: 0,
+                plugin: msg::BUS.to_owned(),
+                data: Data::Devices(devices),
+            })
+            .await;
+
+        assert_eq!(
+            plugin.reply_topic("pi5"),
+            format!("tln/pi5/reply/{}", cfg::name())
+        );
+        assert_eq!(plugin.reply_topic("pi4"), "tln/pi4/reply");
+    }
+
+    #[tokio::test]
+    async fn ask_myself_loops_back() {
+        let mut h = Harness::new();
+
+        h.cmd(
+            super::NAME,
+            "ask",
+            &[&crate::cfg::name(), "p", "plugins", "show"],
+        )
+        .await;
+
+        assert!(h.log_msgs().contains(&plugin_devices::NAME.to_owned()));
+        assert!(h.asks.is_empty());
+    }
+
+    #[tokio::test]
+    async fn ask_other_device_is_published() {
+        let mut h = Harness::new();
+
+        h.cmd(super::NAME, "ask", &["other", "p", "wol", "wake", "linds"])
+            .await;
+
+        assert_eq!(h.asks.len(), 1);
+        assert_eq!(h.asks[0].0, "other");
+        assert!(h.asks[0].1.ends_with("p wol wake linds"));
+    }
+
+    #[tokio::test]
+    async fn ask_from_other_device_replies_to_it() {
+        let mut h = Harness::new();
+
+        h.cmd("wol", "wake", &[]).await;
+        assert!(h.replies.is_empty());
+
+        // as if pi5 asked us, errors are replied to pi5
+        crate::msg::cmd(
+            &h.msg_tx,
+            crate::msg::Reply::Device("pi5".to_owned()),
+            "wol".to_owned(),
+            "wake".to_owned(),
+            vec![],
+        )
+        .await;
+        h.run().await;
+
+        assert_eq!(h.replies.len(), 1);
+        assert_eq!(h.replies[0].0, "pi5");
+        assert!(h.replies[0].1.contains("Device is missing."));
+    }
+}
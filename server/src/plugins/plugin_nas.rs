@@ -3,14 +3,13 @@ use std::path::Path;
 use std::str;
 
 use async_trait::async_trait;
-use log::Level::{Error, Info};
+use log::Level::{self, Error, Info};
 use tokio::sync::mpsc::{self, Sender};
 
-use crate::cfg;
 use crate::msg::{self, log, Cmd, Data, DevInfo, Msg, Reply};
-use crate::plugins::nas::{backup, client, monitor, server};
+use crate::plugins::nas::{address, backup, client, monitor, secure, server, tombstones};
 use crate::plugins::{plugin_mqtt, plugins_main};
-use crate::utils;
+use crate::{cfg, shared};
 use crate::{error, info, init, reply_me, unknown};
 
 pub const NAME: &str = "nas";
@@ -19,7 +18,7 @@ pub const NAME: &str = "nas";
 struct DevInfoNas {
     name: String,
     onboard: Option<bool>,
-    tailscale_ip: Option<String>,
+    addresses: Vec<String>,
     sync: bool,
 }
 
@@ -27,7 +26,6 @@ struct DevInfoNas {
 pub struct Plugin {
     name: String,
     msg_tx: Sender<Msg>,
-    tailscale_ip: String,
     devices: Vec<DevInfoNas>,
     client_tx: Option<Sender<client::ClientMsg>>,
     sync: bool,
@@ -39,7 +37,6 @@ impl Plugin {
         Self {
             name: NAME.to_owned(),
             msg_tx,
-            tailscale_ip: utils::get_tailscale_ip(),
             devices: vec![],
             client_tx: None,
             sync: false,
@@ -48,11 +45,15 @@ impl Plugin {
     }
 
     async fn init(&mut self) {
+        if let Err(e) = secure::psk(&cfg::nas()) {
+            error!(&self.msg_tx, format!("[{NAME}] No sync: {e}"));
+        }
+
         // NAS: start the client
         if cfg::name() == cfg::nas() {
             let (client_tx, client_rx) = mpsc::channel(1024);
             self.client_tx = Some(client_tx);
-            client::client(self.msg_tx.clone(), client_rx, self.tailscale_ip.clone());
+            client::client(self.msg_tx.clone(), client_rx);
         }
 
         // Not NAS: start the server
@@ -89,10 +90,237 @@ impl Plugin {
     async fn help(&self) {
         info!(
             &self.msg_tx,
-            format!("[{NAME}] {ACT_SHOW}", ACT_SHOW = msg::ACT_SHOW)
+            format!(
+                "[{NAME}] {ACT_SHOW}, {ACT_BACKUPS} list|diff <date> [<date>]|verify <date>, {ACT_RESTORE} <date> <path>, {ACT_KEY} <device>",
+                ACT_SHOW = msg::ACT_SHOW,
+                ACT_BACKUPS = msg::ACT_BACKUPS,
+                ACT_RESTORE = msg::ACT_RESTORE,
+                ACT_KEY = msg::ACT_KEY
+            )
         );
     }
 
+    async fn reply(&self, cmd: &Cmd, level: Level, msg: String) {
+        log(
+            &self.msg_tx,
+            cmd.reply.clone(),
+            level,
+            format!("[{NAME}] {msg}"),
+        )
+        .await;
+    }
+
+    // the snapshots are only on the NAS
+    async fn is_nas(&self, cmd: &Cmd) -> bool {
+        if cfg::name() != cfg::nas() {
+            self.reply(
+                cmd,
+                Error,
+                format!("{}: {} is not the NAS.", cmd.action, cfg::name()),
+            )
+            .await;
+            return false;
+        }
+
+        true
+    }
+
+    async fn backups(&self, cmd: &Cmd) {
+        if !self.is_nas(cmd).await {
+            return;
+        }
+
+        let backup_dir = Path::new(backup::BACKUP_DIR);
+        match cmd.data.first().map(String::as_str) {
+            None | Some("list") => {
+                let lines = backup::list(backup_dir);
+                self.reply(cmd, Info, format!("Backups: {}", lines.len()))
+                    .await;
+                for line in lines {
+                    self.reply(cmd, Info, line).await;
+                }
+            }
+            Some("diff") => {
+                let from = match cmd.data.get(1).map(|date| backup::parse_date(date)) {
+                    Some(Ok(from)) => from,
+                    Some(Err(e)) => {
+                        return self.reply(cmd, Error, format!("backups diff: {e}")).await
+                    }
+                    None => {
+                        return self
+                            .reply(cmd, Error, "backups diff: date is missing.".to_owned())
+                            .await
+                    }
+                };
+                // from the snapshot before it when only one is given
+                let (from, to) = match cmd.data.get(2).map(|date| backup::parse_date(date)) {
+                    Some(Ok(to)) => (from, to),
+                    Some(Err(e)) => {
+                        return self.reply(cmd, Error, format!("backups diff: {e}")).await
+                    }
+                    None => match backup::snapshots(backup_dir)
+                        .into_iter()
+                        .rfind(|d| *d < from)
+                    {
+                        Some(previous) => (previous, from),
+                        None => {
+                            return self
+                                .reply(cmd, Error, format!("backups diff: nothing before {from}."))
+                                .await
+                        }
+                    },
+                };
+
+                match backup::diff(backup_dir, from, to) {
+                    Ok(lines) => {
+                        self.reply(
+                            cmd,
+                            Info,
+                            format!("Backups {from} -> {to}: {} changes", lines.len()),
+                        )
+                        .await;
+                        for line in lines {
+                            self.reply(cmd, Info, line).await;
+                        }
+                    }
+                    Err(e) => self.reply(cmd, Error, format!("backups diff: {e}")).await,
+                }
+            }
+            Some("verify") => {
+                let date = match cmd.data.get(1).map(|date| backup::parse_date(date)) {
+                    Some(Ok(date)) => date,
+                    Some(Err(e)) => {
+                        return self.reply(cmd, Error, format!("backups verify: {e}")).await
+                    }
+                    None => {
+                        return self
+                            .reply(cmd, Error, "backups verify: date is missing.".to_owned())
+                            .await
+                    }
+                };
+
+                let verified = tokio::task::spawn_blocking(move || {
+                    backup::verify(Path::new(backup::BACKUP_DIR), date)
+                })
+                .await
+                .map_err(|e| e.to_string())
+                .and_then(|result| result);
+                match verified {
+                    Ok((bad, checked)) if bad.is_empty() => {
+                        self.reply(
+                            cmd,
+                            Info,
+                            format!("Backup {date} verified: {checked} files"),
+                        )
+                        .await;
+                    }
+                    Ok((bad, checked)) => {
+                        self.reply(
+                            cmd,
+                            Error,
+                            format!("Backup {date} damaged: {} of {checked} files", bad.len()),
+                        )
+                        .await;
+                        for line in bad {
+                            self.reply(cmd, Error, line).await;
+                        }
+                    }
+                    Err(e) => self.reply(cmd, Error, format!("backups verify: {e}")).await,
+                }
+            }
+            Some(sub) => {
+                self.reply(
+                    cmd,
+                    Error,
+                    format!("backups: unknown {sub:?}, list, diff or verify."),
+                )
+                .await;
+            }
+        }
+    }
+
+    // the nas_key of a device, printed here only as it would travel over MQTT
+    async fn key(&self, cmd: &Cmd) {
+        if !self.is_nas(cmd).await {
+            return;
+        }
+        if !matches!(&cmd.reply, Reply::Device(device) if *device == cfg::name()) {
+            self.reply(
+                cmd,
+                Error,
+                format!("{}: only on the NAS itself.", cmd.action),
+            )
+            .await;
+            return;
+        }
+
+        match cmd.data.first() {
+            Some(device) => {
+                let key = secure::pair_key(&cfg::nas_secret(), device);
+                self.reply(cmd, Info, format!("{device}: \"nas_key\": \"{key}\""))
+                    .await;
+            }
+            None => {
+                self.reply(cmd, Error, format!("{}: device is missing.", cmd.action))
+                    .await;
+            }
+        }
+    }
+
+    async fn restore(&self, cmd: &Cmd) {
+        if !self.is_nas(cmd).await {
+            return;
+        }
+
+        let (date, path) = match (cmd.data.first(), cmd.data.get(1)) {
+            (Some(date), Some(path)) => (date, path),
+            _ => {
+                return self
+                    .reply(cmd, Error, "restore: date or path is missing.".to_owned())
+                    .await
+            }
+        };
+        let date = match backup::parse_date(date) {
+            Ok(date) => date,
+            Err(e) => return self.reply(cmd, Error, format!("restore: {e}")).await,
+        };
+        let relative = match shared::resolve(path) {
+            Ok(resolved) => resolved
+                .strip_prefix(cfg::FILE_FOLDER)
+                .map(|relative| relative.to_string_lossy().to_string())
+                .unwrap_or_default(),
+            Err(e) => {
+                return self
+                    .reply(cmd, Error, format!("restore rejected: {e}"))
+                    .await
+            }
+        };
+
+        // the monitor then syncs what comes back to the devices
+        let restored = tokio::task::spawn_blocking(move || {
+            backup::restore(
+                Path::new(backup::BACKUP_DIR),
+                date,
+                Path::new(cfg::FILE_FOLDER),
+                &relative,
+            )
+        })
+        .await
+        .map_err(|e| e.to_string())
+        .and_then(|result| result);
+        match restored {
+            Ok(count) => {
+                self.reply(
+                    cmd,
+                    Info,
+                    format!("Restored from {date}: {path}, {count} files"),
+                )
+                .await;
+            }
+            Err(e) => self.reply(cmd, Error, format!("restore: {e}")).await,
+        }
+    }
+
     async fn show_devices(&self, cmd: &Cmd) {
         log(
             &self.msg_tx,
@@ -107,20 +335,20 @@ impl Plugin {
             cmd.reply.clone(),
             Info,
             format!(
-                "[{NAME}] {:10} {:7} {:15} {:4}",
-                "Name", "Onboard", "Tailscale IP", "Sync"
+                "[{NAME}] {:10} {:7} {:4} {}",
+                "Name", "Onboard", "Sync", "Addresses"
             ),
         )
         .await;
 
         for device in &self.devices {
-            let (onboard, tailscale_ip, sync) = get_device_display(device);
+            let (onboard, addresses, sync) = get_device_display(device);
             log(
                 &self.msg_tx,
                 cmd.reply.clone(),
                 Info,
                 format!(
-                    "[{NAME}] {:10} {onboard:7} {tailscale_ip:15} {sync:4}",
+                    "[{NAME}] {:10} {onboard:7} {sync:4} {addresses}",
                     device.name
                 ),
             )
@@ -178,7 +406,7 @@ impl Plugin {
                         action: "SYNC_DEVICE".to_owned(),
                         data: vec![
                             device_nas.name.clone(),
-                            device_nas.tailscale_ip.clone().unwrap(),
+                            device_nas.addresses.join(","),
                             u64::MAX.to_string(),
                         ],
                     })
@@ -194,7 +422,7 @@ impl Plugin {
                     let device_nas = DevInfoNas {
                         name: device.name.clone(),
                         onboard: device.onboard,
-                        tailscale_ip: device.tailscale_ip.clone(),
+                        addresses: address::device_addresses(device),
                         sync: false,
                     };
                     info!(
@@ -212,8 +440,9 @@ impl Plugin {
                         send_sync_device(&self.msg_tx, self.client_tx.as_ref(), device_nas).await;
                     }
 
-                    if device_nas.tailscale_ip != device.tailscale_ip {
-                        device_nas.tailscale_ip = device.tailscale_ip.clone();
+                    let addresses = address::device_addresses(device);
+                    if device_nas.addresses != addresses {
+                        device_nas.addresses = addresses;
                         device_nas.sync = false;
                         send_sync_device(&self.msg_tx, self.client_tx.as_ref(), device_nas).await;
                     }
@@ -260,9 +489,16 @@ impl Plugin {
                 .await;
             }
             "remote_modify" => {
-                // let filename = cmd.data.get(1).unwrap();
+                let filename = cmd.data.get(1).unwrap();
                 let remote_modify_time = cmd.data.get(2).unwrap();
 
+                // NAS: a file there again is no longer deleted
+                if cfg::name() == cfg::nas() {
+                    if let Err(e) = tombstones::revive(filename) {
+                        error!(&self.msg_tx, format!("[{NAME}] {e}"));
+                    }
+                }
+
                 // if I am NAS_CLIENT, send to NAS_SERVER
                 // and if I am synced
                 if cfg::name() != cfg::nas() && self.sync {
@@ -279,7 +515,8 @@ impl Plugin {
                             "nas".to_owned(),
                             "ask_sync".to_owned(),
                             cfg::name().to_owned(),
-                            self.tailscale_ip.to_owned(),
+                            // a new lease or tailscale ip is picked up by the next sync
+                            address::addresses(&cfg::nas_addresses()).join(","),
                             remote_modify_time.to_owned(),
                         ],
                     )
@@ -294,8 +531,7 @@ impl Plugin {
 
                         if device.onboard.is_some()
                             && device.onboard.unwrap()
-                            && device.tailscale_ip.is_some()
-                            && device.tailscale_ip.as_ref().unwrap() != "n/a"
+                            && !device.addresses.is_empty()
                         {
                             self.client_tx
                                 .as_ref()
@@ -304,7 +540,7 @@ impl Plugin {
                                     action: "SYNC_DEVICE".to_owned(),
                                     data: vec![
                                         device.name.clone(),
-                                        device.tailscale_ip.clone().unwrap(),
+                                        device.addresses.join(","),
                                         remote_modify_time.to_owned(),
                                     ],
                                 })
@@ -316,7 +552,7 @@ impl Plugin {
             }
             "ask_sync" => {
                 let device_name = cmd.data.get(1).unwrap();
-                let device_tailscale_ip = cmd.data.get(2).unwrap();
+                let device_addresses = cmd.data.get(2).unwrap();
                 let remote_modify_time = cmd.data.get(3).unwrap();
                 log(
                     &self.msg_tx,
@@ -333,13 +569,57 @@ impl Plugin {
                         action: "SYNC_DEVICE".to_owned(),
                         data: vec![
                             device_name.to_owned(),
-                            device_tailscale_ip.to_owned(),
+                            device_addresses.to_owned(),
                             remote_modify_time.to_owned(),
                         ],
                     })
                     .await
                     .unwrap();
             }
+            // from system upgrade, sent back over the NAS channel
+            msg::ACT_RELEASE => {
+                let (device_name, device_addresses, version) =
+                    match (cmd.data.get(1), cmd.data.get(2), cmd.data.get(3)) {
+                        (Some(name), Some(ip), Some(version)) => (name, ip, version),
+                        _ => {
+                            log(
+                                &self.msg_tx,
+                                cmd.reply.clone(),
+                                Error,
+                                format!(
+                                    "[{NAME}] release: device, addresses or version is missing."
+                                ),
+                            )
+                            .await;
+                            return;
+                        }
+                    };
+
+                match self.client_tx.as_ref() {
+                    Some(client_tx) => {
+                        client_tx
+                            .send(client::ClientMsg {
+                                action: "SEND_RELEASE".to_owned(),
+                                data: vec![
+                                    device_name.to_owned(),
+                                    device_addresses.to_owned(),
+                                    version.to_owned(),
+                                ],
+                            })
+                            .await
+                            .unwrap();
+                    }
+                    None => {
+                        log(
+                            &self.msg_tx,
+                            cmd.reply.clone(),
+                            Error,
+                            format!("[{NAME}] release: {} is not the NAS.", cfg::name()),
+                        )
+                        .await;
+                    }
+                }
+            }
             "remote_remove" => {
                 let filename = cmd.data.get(1).unwrap();
                 log(
@@ -350,6 +630,13 @@ impl Plugin {
                 )
                 .await;
 
+                // NAS: kept for the devices not onboard now, they sync later
+                if cfg::name() == cfg::nas() {
+                    if let Err(e) = tombstones::record(filename, &cfg::name()) {
+                        error!(&self.msg_tx, format!("[{NAME}] {e}"));
+                    }
+                }
+
                 // send to all devices except myself
                 for device in &self.devices {
                     if device.name == cfg::name() {
@@ -357,8 +644,7 @@ impl Plugin {
                     }
                     if device.onboard.is_some()
                         && device.onboard.unwrap()
-                        && device.tailscale_ip.is_some()
-                        && device.tailscale_ip.as_ref().unwrap() != "n/a"
+                        && !device.addresses.is_empty()
                     {
                         msg::cmd(
                             &self.msg_tx,
@@ -380,8 +666,39 @@ impl Plugin {
             }
             "remove" => {
                 let filename = cmd.data.get(1).unwrap();
-                if Path::new(filename).exists() {
-                    let _ = fs::remove_file(filename);
+                let path = match shared::resolve(filename) {
+                    Ok(path) => path,
+                    Err(e) => {
+                        log(
+                            &self.msg_tx,
+                            cmd.reply.clone(),
+                            Error,
+                            format!("[{NAME}] remove rejected: {e}"),
+                        )
+                        .await;
+                        return;
+                    }
+                };
+                if path.exists() {
+                    // a directory with files left is emptied by the sync
+                    if let Err(e) = shared::remove(&path) {
+                        log(
+                            &self.msg_tx,
+                            cmd.reply.clone(),
+                            Error,
+                            format!("[{NAME}] remove {filename}: {e}"),
+                        )
+                        .await;
+                        return;
+                    }
+                    // NAS: the device which removed it first
+                    if cfg::name() == cfg::nas() {
+                        if let Reply::Device(device) = &cmd.reply {
+                            if let Err(e) = tombstones::record(filename, device) {
+                                error!(&self.msg_tx, format!("[{NAME}] {e}"));
+                            }
+                        }
+                    }
                     log(
                         &self.msg_tx,
                         cmd.reply.clone(),
@@ -418,6 +735,10 @@ impl plugins_main::Plugin for Plugin {
         self.name.as_str()
     }
 
+    fn topics(&self) -> &[&'static str] {
+        &[msg::TOPIC_DEVICES]
+    }
+
     async fn msg(&mut self, msg: &Msg) -> bool {
         match &msg.data {
             Data::Cmd(cmd) => match cmd.action.as_str() {
@@ -425,6 +746,9 @@ impl plugins_main::Plugin for Plugin {
                 msg::ACT_INIT => self.init().await,
                 msg::ACT_SHOW => self.show(cmd).await,
                 msg::ACT_NAS => self.nas(cmd).await,
+                msg::ACT_BACKUPS => self.backups(cmd).await,
+                msg::ACT_RESTORE => self.restore(cmd).await,
+                msg::ACT_KEY => self.key(cmd).await,
                 _ => {
                     log(
                         &self.msg_tx,
@@ -448,10 +772,7 @@ impl plugins_main::Plugin for Plugin {
 }
 
 fn is_ready_to_sync(device_nas: &DevInfoNas) -> bool {
-    device_nas.onboard.unwrap_or(false)
-        && device_nas.tailscale_ip.is_some()
-        && device_nas.tailscale_ip.as_ref().unwrap() != "n/a"
-        && !device_nas.sync
+    device_nas.onboard.unwrap_or(false) && !device_nas.addresses.is_empty() && !device_nas.sync
 }
 
 fn get_device_display(device: &DevInfoNas) -> (String, String, String) {
@@ -460,17 +781,14 @@ fn get_device_display(device: &DevInfoNas) -> (String, String, String) {
         Some(false) => "N",
         None => "n/a",
     };
-    let tailscale_ip = match &device.tailscale_ip {
-        Some(ip) => ip,
-        None => "n/a",
+    let addresses = if device.addresses.is_empty() {
+        "n/a".to_owned()
+    } else {
+        device.addresses.join(", ")
     };
     let sync = if device.sync { "Y" } else { "N" };
 
-    (
-        onboard.to_string(),
-        tailscale_ip.to_string(),
-        sync.to_string(),
-    )
+    (onboard.to_string(), addresses, sync.to_string())
 }
 
 fn list_files_recursively(path: &Path) -> Vec<String> {
@@ -0,0 +1,763 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::Path;
+
+use async_trait::async_trait;
+use log::Level::{Error, Info};
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc::Sender;
+
+use crate::msg::{self, log, Cmd, Data, DevInfo, Msg, Reply};
+use crate::plugins::plugins_main;
+use crate::{cfg, command, utils};
+use crate::{error, info, init, reply_me, unknown};
+
+pub const NAME: &str = "rules";
+
+fn default_level() -> String {
+    "info".to_owned()
+}
+
+// as in rules.json:
+// {
+//     "name": "wake-linds",
+//     "on": "devices",
+//     "when": ["name == linds", "onboard == false"],
+//     "for": 600,
+//     "cooldown": 3600,
+//     "log": "info",
+//     "do": ["p wol wake {name}"]
+// }
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct RuleCfg {
+    name: String,
+    on: String, // devices, log or file
+    #[serde(default)]
+    when: Vec<String>,
+    #[serde(default, rename = "for")]
+    for_secs: u64,
+    #[serde(default)]
+    cooldown: u64,
+    #[serde(default = "default_level")]
+    log: String,
+    #[serde(rename = "do")]
+    actions: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Op {
+    Eq,
+    Ne,
+    Gt,
+    Ge,
+    Lt,
+    Le,
+    Contains,
+}
+
+// "<field> <op> <value>", the value may contain spaces
+#[derive(Debug, Clone, PartialEq)]
+pub struct Condition {
+    field: String,
+    op: Op,
+    value: String,
+}
+
+impl Condition {
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let mut parts = s.trim().splitn(3, ' ');
+        let (field, op, value) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(field), Some(op), Some(value)) => (field, op, value.trim()),
+            _ => return Err(format!("condition should be '<field> <op> <value>': {s:?}")),
+        };
+
+        let op = match op {
+            "==" => Op::Eq,
+            "!=" => Op::Ne,
+            ">" => Op::Gt,
+            ">=" => Op::Ge,
+            "<" => Op::Lt,
+            "<=" => Op::Le,
+            "~" => Op::Contains,
+            _ => return Err(format!("unknown op {op:?} in {s:?}")),
+        };
+
+        Ok(Self {
+            field: field.to_owned(),
+            op,
+            value: value.trim_matches('"').to_owned(),
+        })
+    }
+
+    // numbers are compared as numbers, missing fields never match
+    pub fn eval(&self, event: &serde_json::Value) -> bool {
+        let actual = match event.get(&self.field).and_then(value_str) {
+            Some(actual) => actual,
+            None => return false,
+        };
+
+        if self.op == Op::Contains {
+            return actual.contains(&self.value);
+        }
+
+        match (actual.parse::<f64>(), self.value.parse::<f64>()) {
+            (Ok(a), Ok(b)) => match self.op {
+                Op::Eq => a == b,
+                Op::Ne => a != b,
+                Op::Gt => a > b,
+                Op::Ge => a >= b,
+                Op::Lt => a < b,
+                Op::Le => a <= b,
+                Op::Contains => unreachable!(),
+            },
+            _ => match self.op {
+                Op::Eq => actual == self.value,
+                Op::Ne => actual != self.value,
+                _ => false,
+            },
+        }
+    }
+}
+
+fn value_str(value: &serde_json::Value) -> Option<String> {
+    match value {
+        serde_json::Value::Null => None,
+        serde_json::Value::String(s) => Some(s.clone()),
+        value => Some(value.to_string()),
+    }
+}
+
+// replace {field} in an action with the value from the event. The action is
+// split into words first: a value making a whole word is that word, a value
+// within a word (a shell command) is quoted, so no value adds a word nor runs
+// anything.
+fn render(action: &str, rule: &str, event: &serde_json::Value) -> Result<String, String> {
+    let value = |field: &str| field_value(field, rule, event);
+
+    let mut words = vec![];
+    for word in shlex::split(action).ok_or(format!("invalid quoting: {action:?}"))? {
+        let whole = word
+            .strip_prefix('{')
+            .and_then(|w| w.strip_suffix('}'))
+            .and_then(value);
+        words.push(match whole {
+            Some(value) => value,
+            None => substitute(&word, &value, true)?,
+        });
+    }
+
+    shlex::try_join(words.iter().map(String::as_str)).map_err(|e| e.to_string())
+}
+
+// {field} replaced in a text, e.g. a message
+pub fn fill(text: &str, rule: &str, event: &serde_json::Value) -> String {
+    let value = |field: &str| field_value(field, rule, event);
+    substitute(text, &value, false).unwrap_or_else(|_| text.to_owned())
+}
+
+fn field_value(field: &str, rule: &str, event: &serde_json::Value) -> Option<String> {
+    match field {
+        "rule" => Some(rule.to_owned()),
+        _ => event
+            .get(field)
+            .map(|value| value_str(value).unwrap_or("n/a".to_owned())),
+    }
+}
+
+// in one pass, so a value is never substituted into
+fn substitute(
+    word: &str,
+    value: &dyn Fn(&str) -> Option<String>,
+    quote: bool,
+) -> Result<String, String> {
+    let mut out = String::new();
+    let mut rest = word;
+    while let Some(start) = rest.find('{') {
+        out.push_str(&rest[..start]);
+        let field = &rest[start + 1..];
+        match field
+            .find('}')
+            .and_then(|end| Some((end, value(&field[..end])?)))
+        {
+            Some((end, value)) => {
+                match quote {
+                    true => out.push_str(&shlex::try_quote(&value).map_err(|e| e.to_string())?),
+                    false => out.push_str(&value),
+                }
+                rest = &field[end + 1..];
+            }
+            None => {
+                out.push('{');
+                rest = field;
+            }
+        }
+    }
+    out.push_str(rest);
+
+    Ok(out)
+}
+
+#[derive(Debug, Default)]
+struct RuleState {
+    since: Option<u64>, // when the conditions started to hold
+    fired: Option<u64>,
+    active: bool,                     // fired since the conditions started to hold
+    event: Option<serde_json::Value>, // the last one, checked again on a tick
+}
+
+#[derive(Debug)]
+struct Rule {
+    cfg: RuleCfg,
+    conditions: Vec<Condition>,
+    level: log::Level,
+    // per device for devices rules, a single "" for the others
+    states: HashMap<String, RuleState>,
+}
+
+impl Rule {
+    fn new(cfg: RuleCfg) -> Result<Self, String> {
+        if ![msg::TOPIC_DEVICES, msg::TOPIC_LOG, msg::TOPIC_FILE].contains(&cfg.on.as_str()) {
+            return Err(format!("unknown event: {:?}", cfg.on));
+        }
+
+        let conditions = cfg
+            .when
+            .iter()
+            .map(|c| Condition::parse(c))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        for action in &cfg.actions {
+            command::parse_p(action)?;
+        }
+
+        let level = cfg
+            .log
+            .parse::<log::Level>()
+            .map_err(|_| format!("unknown log level: {:?}", cfg.log))?;
+
+        Ok(Self {
+            cfg,
+            conditions,
+            level,
+            states: HashMap::new(),
+        })
+    }
+
+    // true if the rule should fire now
+    fn check(&mut self, key: &str, event: &serde_json::Value, now: u64) -> bool {
+        let matched = self.conditions.iter().all(|c| c.eval(event));
+        let state = self.states.entry(key.to_owned()).or_default();
+
+        if !matched {
+            state.since = None;
+            state.active = false;
+            state.event = None;
+            return false;
+        }
+        state.event = Some(event.clone());
+
+        let since = *state.since.get_or_insert(now);
+        if now.saturating_sub(since) < self.cfg.for_secs {
+            return false;
+        }
+
+        // once per period without cooldown, otherwise at most once per cooldown
+        let cooling =
+            matches!(state.fired, Some(fired) if now.saturating_sub(fired) < self.cfg.cooldown);
+        if cooling || (state.active && self.cfg.cooldown == 0) {
+            return false;
+        }
+
+        state.fired = Some(now);
+        state.active = true;
+
+        true
+    }
+}
+
+#[derive(Debug)]
+pub struct Plugin {
+    name: String,
+    msg_tx: Sender<Msg>,
+    path: String,
+    rules: Vec<Rule>,
+}
+
+impl Plugin {
+    pub fn new(msg_tx: Sender<Msg>) -> Self {
+        Self {
+            name: NAME.to_owned(),
+            msg_tx,
+            path: cfg::RULES_FILE.to_owned(),
+            rules: vec![],
+        }
+    }
+
+    async fn init(&mut self) {
+        self.load(reply_me!()).await;
+
+        init!(&self.msg_tx, NAME);
+    }
+
+    async fn load(&mut self, reply: Reply) {
+        // an empty file to start with
+        if !Path::new(&self.path).exists() {
+            if let Err(e) = File::create(&self.path).and_then(|mut f| f.write_all(b"[]\n")) {
+                error!(
+                    &self.msg_tx,
+                    format!("[{NAME}] Failed to create {}: {e}", self.path)
+                );
+            }
+        }
+
+        let rule_cfgs = match fs::read_to_string(&self.path)
+            .map_err(|e| e.to_string())
+            .and_then(|s| serde_json::from_str::<Vec<RuleCfg>>(&s).map_err(|e| e.to_string()))
+        {
+            Ok(rule_cfgs) => rule_cfgs,
+            Err(e) => {
+                log(
+                    &self.msg_tx,
+                    reply,
+                    Error,
+                    format!("[{NAME}] Failed to load {}: {e}", self.path),
+                )
+                .await;
+                return;
+            }
+        };
+
+        self.rules.clear();
+        for rule_cfg in rule_cfgs {
+            let name = rule_cfg.name.clone();
+            match Rule::new(rule_cfg) {
+                Ok(rule) => self.rules.push(rule),
+                Err(e) => {
+                    log(
+                        &self.msg_tx,
+                        reply.clone(),
+                        Error,
+                        format!("[{NAME}] rule {name:?}: {e}"),
+                    )
+                    .await;
+                }
+            }
+        }
+
+        log(
+            &self.msg_tx,
+            reply,
+            Info,
+            format!("[{NAME}] {} rules loaded.", self.rules.len()),
+        )
+        .await;
+    }
+
+    async fn event(&mut self, topic: &str, key: &str, event: serde_json::Value) {
+        let now = utils::ts();
+
+        let mut fired = vec![];
+        let mut timers = vec![];
+        for (idx, rule) in self.rules.iter_mut().enumerate() {
+            if rule.cfg.on != topic {
+                continue;
+            }
+            if rule.check(key, &event, now) {
+                fired.push(idx);
+            } else if rule.cfg.for_secs > 0
+                && rule.states.get(key).and_then(|s| s.since) == Some(now)
+            {
+                timers.push(rule.cfg.for_secs);
+            }
+        }
+
+        for idx in fired {
+            self.fire(idx, key, &event).await;
+        }
+        // a device gone quiet sends no other event
+        for secs in timers {
+            self.arm(secs);
+        }
+    }
+
+    fn arm(&self, secs: u64) {
+        let msg_tx_clone = self.msg_tx.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(tokio::time::Duration::from_secs(secs + 1)).await;
+            msg::cmd(
+                &msg_tx_clone,
+                reply_me!(),
+                NAME.to_owned(),
+                msg::ACT_TICK.to_owned(),
+                vec![],
+            )
+            .await;
+        });
+    }
+
+    // the conditions of the last events may have held long enough by now
+    async fn tick(&mut self) {
+        let now = utils::ts();
+
+        let mut fired = vec![];
+        for (idx, rule) in self.rules.iter_mut().enumerate() {
+            let pending: Vec<(String, serde_json::Value)> = rule
+                .states
+                .iter()
+                .filter(|(_, state)| state.since.is_some() && !state.active)
+                .filter_map(|(key, state)| Some((key.clone(), state.event.clone()?)))
+                .collect();
+            for (key, event) in pending {
+                if rule.check(&key, &event, now) {
+                    fired.push((idx, key, event));
+                }
+            }
+        }
+
+        for (idx, key, event) in fired {
+            self.fire(idx, &key, &event).await;
+        }
+    }
+
+    async fn fire(&self, idx: usize, key: &str, event: &serde_json::Value) {
+        let rule = &self.rules[idx];
+        let fired = if key.is_empty() {
+            format!("[{NAME}] {} fired.", rule.cfg.name)
+        } else {
+            format!("[{NAME}] {} fired: {key}", rule.cfg.name)
+        };
+        log(&self.msg_tx, reply_me!(), rule.level, fired).await;
+
+        for action in &rule.cfg.actions {
+            match render(action, &rule.cfg.name, event).and_then(|a| command::parse_p(&a)) {
+                Ok((plugin, action, data)) => {
+                    msg::cmd(&self.msg_tx, reply_me!(), plugin, action, data).await;
+                }
+                Err(e) => {
+                    error!(&self.msg_tx, format!("[{NAME}] {}: {e}", rule.cfg.name));
+                }
+            }
+        }
+    }
+
+    async fn list(&mut self, cmd: &Cmd) {
+        match &cmd.reply {
+            Reply::Device(_) => {
+                for rule in &self.rules {
+                    log(
+                        &self.msg_tx,
+                        cmd.reply.clone(),
+                        Info,
+                        format!(
+                            "[{NAME}] {}: on {} when {} for {}s, cooldown {}s, do {}",
+                            rule.cfg.name,
+                            rule.cfg.on,
+                            rule.cfg.when.join(" && "),
+                            rule.cfg.for_secs,
+                            rule.cfg.cooldown,
+                            rule.cfg.actions.join("; "),
+                        ),
+                    )
+                    .await;
+                }
+            }
+            Reply::Web(sender) => {
+                let rule_cfgs: Vec<&RuleCfg> = self.rules.iter().map(|r| &r.cfg).collect();
+                sender
+                    .send(serde_json::to_value(rule_cfgs).unwrap())
+                    .await
+                    .unwrap();
+            }
+        }
+    }
+
+    async fn help(&self) {
+        info!(
+            &self.msg_tx,
+            format!(
+                "[{NAME}] {ACT_HELP}, {ACT_INIT}, {ACT_LIST}, {ACT_RELOAD}",
+                ACT_HELP = msg::ACT_HELP,
+                ACT_INIT = msg::ACT_INIT,
+                ACT_LIST = msg::ACT_LIST,
+                ACT_RELOAD = msg::ACT_RELOAD,
+            )
+        );
+    }
+}
+
+// the telemetry values are fields of the event, like name and onboard
+pub fn device_event(device: &DevInfo) -> serde_json::Value {
+    let mut event = serde_json::json!({
+        "name": device.name,
+        "onboard": device.onboard,
+        "last_seen": device.last_seen,
+        "stale": device.stale,
+    });
+    for (key, metric) in &device.telemetry {
+        event[key] = serde_json::to_value(&metric.value).unwrap();
+    }
+
+    event
+}
+
+#[async_trait]
+impl plugins_main::Plugin for Plugin {
+    fn name(&self) -> &str {
+        self.name.as_str()
+    }
+
+    fn topics(&self) -> &[&'static str] {
+        &[msg::TOPIC_DEVICES, msg::TOPIC_LOG, msg::TOPIC_FILE]
+    }
+
+    async fn msg(&mut self, msg: &Msg) -> bool {
+        match &msg.data {
+            Data::Cmd(cmd) => match cmd.action.as_str() {
+                msg::ACT_HELP => self.help().await,
+                msg::ACT_INIT => self.init().await,
+                msg::ACT_LIST | msg::ACT_SHOW => self.list(cmd).await,
+                msg::ACT_RELOAD => self.load(cmd.reply.clone()).await,
+                msg::ACT_TICK => self.tick().await,
+                _ => {
+                    unknown!(&self.msg_tx, NAME, cmd.action);
+                }
+            },
+            Data::Devices(devices) => {
+                for device in devices {
+                    self.event(msg::TOPIC_DEVICES, &device.name, device_event(device))
+                        .await;
+                }
+            }
+            Data::Log(log) => {
+                // don't react to our own logs
+                if !log.msg.starts_with(&format!("[{NAME}]")) {
+                    let event = serde_json::to_value(log).unwrap();
+                    self.event(msg::TOPIC_LOG, "", event).await;
+                }
+            }
+            Data::FileEvent(file_event) => {
+                let event = serde_json::to_value(file_event).unwrap();
+                self.event(msg::TOPIC_FILE, "", event).await;
+            }
+            _ => {
+                unknown!(&self.msg_tx, NAME, msg);
+            }
+        }
+
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn rule(json: serde_json::Value) -> Rule {
+        Rule::new(serde_json::from_value(json).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn conditions() {
+        let event = json!({"name": "pi5", "onboard": false, "disk_usage": 91.5, "os": null});
+
+        let eval = |s: &str| Condition::parse(s).unwrap().eval(&event);
+        assert!(eval("name == pi5"));
+        assert!(eval("name != linds"));
+        assert!(eval("onboard == false"));
+        assert!(eval("disk_usage > 90"));
+        assert!(!eval("disk_usage <= 90"));
+        assert!(eval("name ~ pi"));
+        assert!(!eval("name > pi"));
+        assert!(!eval("os == n/a"));
+        assert!(!eval("missing != 1"));
+
+        let event = json!({"level": "ERROR", "msg": "[nas] Failed to connect"});
+        assert!(Condition::parse("msg ~ \"Failed to\"")
+            .unwrap()
+            .eval(&event));
+
+        assert!(Condition::parse("name pi5").is_err());
+        assert!(Condition::parse("name =~ pi5").is_err());
+    }
+
+    #[test]
+    fn device_event_flattens_telemetry() {
+        let mut device = DevInfo::new("nas");
+        device.onboard = Some(true);
+        for (key, payload) in [
+            ("disk_usage", "91.5"),
+            ("ups_battery", r#"{"value": 40, "unit": "%"}"#),
+        ] {
+            device
+                .telemetry
+                .insert(key.to_owned(), msg::Metric::parse(key, payload));
+        }
+
+        let event = device_event(&device);
+        let eval = |s: &str| Condition::parse(s).unwrap().eval(&event);
+        assert!(eval("disk_usage > 90"));
+        assert!(eval("ups_battery < 50"));
+        assert!(eval("onboard == true"));
+    }
+
+    #[test]
+    fn rule_validation() {
+        let bad = |json: serde_json::Value| Rule::new(serde_json::from_value(json).unwrap());
+        assert!(bad(json!({"name": "a", "on": "weather", "do": []})).is_err());
+        assert!(bad(json!({"name": "a", "on": "log", "do": ["wol wake linds"]})).is_err());
+        assert!(bad(json!({"name": "a", "on": "log", "log": "loud", "do": []})).is_err());
+    }
+
+    #[test]
+    fn check_for_and_cooldown() {
+        let mut r = rule(json!({
+            "name": "wake", "on": "devices", "when": ["onboard == false"],
+            "for": 600, "cooldown": 3600, "do": ["p wol wake {name}"]
+        }));
+        let off = json!({"name": "linds", "onboard": false});
+        let on = json!({"name": "linds", "onboard": true});
+
+        assert!(!r.check("linds", &off, 1000));
+        assert!(!r.check("linds", &off, 1599));
+        assert!(r.check("linds", &off, 1600));
+        // cooldown
+        assert!(!r.check("linds", &off, 2000));
+        assert!(!r.check("linds", &on, 2100));
+        assert!(!r.check("linds", &off, 2200));
+        assert!(!r.check("linds", &off, 4999));
+        assert!(r.check("linds", &off, 5200));
+        // other devices have their own state
+        assert!(!r.check("pi5", &off, 5200));
+
+        assert_eq!(
+            render("p wol wake {name}", "wake", &off).unwrap(),
+            "p wol wake linds"
+        );
+    }
+
+    #[test]
+    fn check_once_per_period_without_cooldown() {
+        let mut r = rule(json!({
+            "name": "upload", "on": "file", "when": ["filename ~ /upload/"],
+            "do": ["p mqtt publish upload false {filename}"]
+        }));
+        let upload = json!({"action": "create", "filename": "./shared/upload/a.txt"});
+        let other = json!({"action": "create", "filename": "./shared/b.txt"});
+
+        assert!(r.check("", &upload, 1));
+        assert!(!r.check("", &upload, 2));
+        assert!(!r.check("", &other, 3));
+        assert!(r.check("", &upload, 4));
+    }
+
+    #[tokio::test]
+    async fn event_runs_actions() {
+        let (msg_tx, mut msg_rx) = tokio::sync::mpsc::channel(16);
+        let mut plugin = Plugin::new(msg_tx);
+        plugin.rules.push(rule(json!({
+            "name": "disk", "on": "devices", "when": ["disk_usage > 90"],
+            "log": "error", "do": ["p mqtt publish alert false \"disk {name} {disk_usage}%\""]
+        })));
+
+        plugin
+            .event(
+                msg::TOPIC_DEVICES,
+                "pi5",
+                json!({"name": "pi5", "disk_usage": 95.0}),
+            )
+            .await;
+
+        match msg_rx.try_recv().unwrap().data {
+            Data::Log(log) => {
+                assert_eq!(log.level, log::Level::Error);
+                assert_eq!(log.msg, "[rules] disk fired: pi5");
+            }
+            data => panic!("unexpected {:?}", data),
+        }
+        match msg_rx.try_recv().unwrap().data {
+            Data::Cmd(cmd) => {
+                assert_eq!(cmd.action, msg::ACT_PUBLISH);
+                assert_eq!(cmd.data, vec!["alert", "false", "disk pi5 95.0%"]);
+            }
+            data => panic!("unexpected {:?}", data),
+        }
+        assert!(msg_rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn render_keeps_values_to_their_word() {
+        let upload =
+            json!({"filename": "./shared/a b.txt", "name": "x\"; rm -rf ~; \"", "device": "my pc"});
+
+        // a value as a whole word stays one word
+        let action = render("p wol wake {device}", "r", &upload).unwrap();
+        assert_eq!(
+            command::parse_p(&action).unwrap(),
+            (
+                "wol".to_owned(),
+                "wake".to_owned(),
+                vec!["my pc".to_owned()]
+            )
+        );
+
+        // within a shell command, quoted for the shell
+        let action = render("p shell cmd \"ls -l {filename} {name}\"", "r", &upload).unwrap();
+        let (_, _, data) = command::parse_p(&action).unwrap();
+        assert_eq!(data.len(), 1);
+        assert_eq!(
+            shlex::split(&data[0]).unwrap(),
+            vec!["ls", "-l", "./shared/a b.txt", "x\"; rm -rf ~; \""]
+        );
+
+        // unknown fields and the rule name
+        let action = render("p mqtt publish {rule} false {missing}", "alert", &upload).unwrap();
+        assert_eq!(
+            command::parse_p(&action).unwrap().2,
+            vec!["alert", "false", "{missing}"]
+        );
+        assert_eq!(
+            fill("{rule}: {device} up", "alert", &upload),
+            "alert: my pc up"
+        );
+    }
+
+    #[tokio::test]
+    async fn for_fires_on_a_tick_without_another_event() {
+        let (msg_tx, mut msg_rx) = tokio::sync::mpsc::channel(16);
+        let mut plugin = Plugin::new(msg_tx);
+        plugin.rules.push(rule(json!({
+            "name": "off", "on": "devices", "when": ["onboard == false"], "for": 300,
+            "do": ["p wol wake {name}"]
+        })));
+
+        let now = utils::ts();
+        plugin
+            .event(
+                msg::TOPIC_DEVICES,
+                "linds",
+                json!({"name": "linds", "onboard": false}),
+            )
+            .await;
+        assert!(msg_rx.try_recv().is_err());
+
+        // as if the device stayed quiet for the whole period
+        plugin.rules[0].states.get_mut("linds").unwrap().since = Some(now - 300);
+        plugin.tick().await;
+        match msg_rx.try_recv().unwrap().data {
+            Data::Log(log) => assert_eq!(log.msg, "[rules] off fired: linds"),
+            data => panic!("unexpected {:?}", data),
+        }
+        match msg_rx.try_recv().unwrap().data {
+            Data::Cmd(cmd) => assert_eq!(cmd.data, vec!["linds"]),
+            data => panic!("unexpected {:?}", data),
+        }
+
+        // once
+        plugin.tick().await;
+        assert!(msg_rx.try_recv().is_err());
+        // and a clock gone back does not panic
+        assert!(!plugin.rules[0].check("linds", &json!({"onboard": false}), 0));
+    }
+}
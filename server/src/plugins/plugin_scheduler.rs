@@ -0,0 +1,677 @@
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::Path;
+
+use async_trait::async_trait;
+use chrono::{Datelike, Duration, Local, NaiveDate, NaiveDateTime, TimeZone, Timelike};
+use log::Level::{Error, Info};
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc::Sender;
+use tokio::task::JoinHandle;
+
+use crate::msg::{self, log, Cmd, Data, Msg, Reply};
+use crate::plugins::{
+    plugin_stocks, plugin_system, plugin_weather, plugin_worldtime, plugins_main,
+};
+use crate::{cfg, command, utils};
+use crate::{error, info, init, reply_me, unknown};
+
+pub const NAME: &str = "scheduler";
+
+// give up looking for the next run of a cron that never matches, e.g. Feb 30
+const CRON_MAX_STEPS: usize = 100_000;
+
+// one cron field as a bitmask, supports *, a, a-b, */n, a-b/n and lists
+fn parse_field(s: &str, min: u32, max: u32) -> Result<u64, String> {
+    let mut mask = 0u64;
+
+    for part in s.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (
+                range,
+                step.parse::<u32>()
+                    .map_err(|_| format!("invalid step: {part:?}"))?,
+            ),
+            None => (part, 1),
+        };
+        if step == 0 {
+            return Err(format!("invalid step: {part:?}"));
+        }
+
+        let (from, to) = if range == "*" {
+            (min, max)
+        } else if let Some((from, to)) = range.split_once('-') {
+            (
+                from.parse::<u32>()
+                    .map_err(|_| format!("invalid range: {part:?}"))?,
+                to.parse::<u32>()
+                    .map_err(|_| format!("invalid range: {part:?}"))?,
+            )
+        } else {
+            let value = range
+                .parse::<u32>()
+                .map_err(|_| format!("invalid value: {part:?}"))?;
+            // "5/15" means from 5 to the end every 15
+            (value, if part.contains('/') { max } else { value })
+        };
+
+        if from < min || to > max || from > to {
+            return Err(format!("out of range {min}-{max}: {part:?}"));
+        }
+
+        for value in (from..=to).step_by(step as usize) {
+            mask |= 1 << value;
+        }
+    }
+
+    Ok(mask)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cron {
+    minute: u64,
+    hour: u64,
+    dom: u64,
+    month: u64,
+    dow: u64,
+    dom_any: bool,
+    dow_any: bool,
+}
+
+impl Cron {
+    // minute hour day-of-month month day-of-week, in local time
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let fields: Vec<&str> = s.split_whitespace().collect();
+        if fields.len() != 5 {
+            return Err(format!("cron needs 5 fields: {s:?}"));
+        }
+
+        let mut dow = parse_field(fields[4], 0, 7)?;
+        // both 0 and 7 are Sunday
+        if dow & (1 << 7) != 0 {
+            dow |= 1;
+        }
+
+        Ok(Self {
+            minute: parse_field(fields[0], 0, 59)?,
+            hour: parse_field(fields[1], 0, 23)?,
+            dom: parse_field(fields[2], 1, 31)?,
+            month: parse_field(fields[3], 1, 12)?,
+            dow,
+            dom_any: fields[2] == "*",
+            dow_any: fields[4] == "*",
+        })
+    }
+
+    fn day_matches(&self, date: NaiveDate) -> bool {
+        let dom = self.dom & (1 << date.day()) != 0;
+        let dow = self.dow & (1 << date.weekday().num_days_from_sunday()) != 0;
+
+        // as in cron, when both are restricted either one is enough
+        match (self.dom_any, self.dow_any) {
+            (true, true) => true,
+            (true, false) => dow,
+            (false, true) => dom,
+            (false, false) => dom || dow,
+        }
+    }
+
+    // the first matching minute after ts
+    pub fn next(&self, ts: u64) -> Option<u64> {
+        let start = Local.timestamp_opt((ts / 60 + 1) as i64 * 60, 0).single()?;
+        let mut t: NaiveDateTime = start.naive_local();
+
+        for _ in 0..CRON_MAX_STEPS {
+            if self.month & (1 << t.month()) == 0 {
+                let (year, month) = if t.month() == 12 {
+                    (t.year() + 1, 1)
+                } else {
+                    (t.year(), t.month() + 1)
+                };
+                t = NaiveDate::from_ymd_opt(year, month, 1)?.and_hms_opt(0, 0, 0)?;
+                continue;
+            }
+
+            if !self.day_matches(t.date()) {
+                t = t.date().succ_opt()?.and_hms_opt(0, 0, 0)?;
+                continue;
+            }
+
+            if self.hour & (1 << t.hour()) == 0 {
+                t = t.date().and_hms_opt(t.hour(), 0, 0)? + Duration::hours(1);
+                continue;
+            }
+
+            if self.minute & (1 << t.minute()) == 0 {
+                t += Duration::minutes(1);
+                continue;
+            }
+
+            // skipped by a DST gap, keep looking
+            match Local.from_local_datetime(&t).earliest() {
+                Some(datetime) => return Some(datetime.timestamp() as u64),
+                None => t += Duration::minutes(1),
+            }
+        }
+
+        None
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Schedule {
+    Every(u64),
+    Cron(Cron),
+}
+
+impl Schedule {
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let s = s.trim();
+        match s {
+            "@hourly" => Cron::parse("0 * * * *").map(Schedule::Cron),
+            "@daily" | "@midnight" => Cron::parse("0 0 * * *").map(Schedule::Cron),
+            "@weekly" => Cron::parse("0 0 * * 0").map(Schedule::Cron),
+            "@monthly" => Cron::parse("0 0 1 * *").map(Schedule::Cron),
+            _ => match s.strip_prefix("@every ") {
+                Some(every) => utils::parse_duration(every.trim()).map(Schedule::Every),
+                None => Cron::parse(s).map(Schedule::Cron),
+            },
+        }
+    }
+
+    pub fn next(&self, ts: u64) -> Option<u64> {
+        match self {
+            Schedule::Every(secs) => ts.checked_add(*secs),
+            Schedule::Cron(cron) => cron.next(ts),
+        }
+    }
+}
+
+// what is persisted, the schedule is kept as typed
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct JobCfg {
+    name: String,
+    schedule: String,
+    cmd: String,
+}
+
+#[derive(Debug, Serialize)]
+struct Job {
+    name: String,
+    schedule: String,
+    cmd: String,
+    next_run: Option<u64>,
+    #[serde(skip)]
+    parsed: Schedule,
+}
+
+impl Job {
+    fn new(job_cfg: JobCfg, now: u64) -> Result<Self, String> {
+        let parsed = Schedule::parse(&job_cfg.schedule)?;
+        command::parse_p(&job_cfg.cmd)?;
+
+        // intervals start with a run, as the polling loops did
+        let next_run = match parsed {
+            Schedule::Every(_) => Some(now),
+            Schedule::Cron(ref cron) => match cron.next(now) {
+                Some(next_run) => Some(next_run),
+                None => return Err(format!("{:?} never runs", job_cfg.schedule)),
+            },
+        };
+
+        Ok(Self {
+            name: job_cfg.name,
+            schedule: job_cfg.schedule,
+            cmd: job_cfg.cmd,
+            next_run,
+            parsed,
+        })
+    }
+
+    fn job_cfg(&self) -> JobCfg {
+        JobCfg {
+            name: self.name.clone(),
+            schedule: self.schedule.clone(),
+            cmd: self.cmd.clone(),
+        }
+    }
+}
+
+fn default_jobs() -> Vec<JobCfg> {
+    [
+        (plugin_weather::NAME, "@every 1h"),
+        (plugin_worldtime::NAME, "@every 5m"),
+        (plugin_stocks::NAME, "@every 1m"),
+        (plugin_system::NAME, "@every 5m"),
+    ]
+    .iter()
+    .map(|(plugin, schedule)| JobCfg {
+        name: plugin.to_string(),
+        schedule: schedule.to_string(),
+        cmd: format!("p {plugin} {}", msg::ACT_POLL),
+    })
+    .collect()
+}
+
+#[derive(Debug)]
+pub struct Plugin {
+    name: String,
+    msg_tx: Sender<Msg>,
+    path: String,
+    jobs: Vec<Job>,
+    timer: Option<JoinHandle<()>>,
+}
+
+impl Plugin {
+    pub fn new(msg_tx: Sender<Msg>) -> Self {
+        Self {
+            name: NAME.to_owned(),
+            msg_tx,
+            path: cfg::SCHEDULER_FILE.to_owned(),
+            jobs: vec![],
+            timer: None,
+        }
+    }
+
+    async fn init(&mut self) {
+        let job_cfgs = if Path::new(&self.path).exists() {
+            match fs::read_to_string(&self.path)
+                .map_err(|e| e.to_string())
+                .and_then(|s| serde_json::from_str::<Vec<JobCfg>>(&s).map_err(|e| e.to_string()))
+            {
+                Ok(job_cfgs) => job_cfgs,
+                // polling stops without jobs, the system heartbeat with it:
+                // the jobs running are kept, the default ones to start with
+                Err(e) if !self.jobs.is_empty() => {
+                    error!(
+                        &self.msg_tx,
+                        format!("[{NAME}] Failed to load {}, jobs kept: {e}", self.path)
+                    );
+                    self.arm();
+                    init!(&self.msg_tx, NAME);
+                    return;
+                }
+                Err(e) => {
+                    error!(
+                        &self.msg_tx,
+                        format!("[{NAME}] Failed to load {}, default jobs: {e}", self.path)
+                    );
+                    default_jobs()
+                }
+            }
+        } else {
+            default_jobs()
+        };
+
+        let now = utils::ts();
+        self.jobs.clear();
+        for job_cfg in job_cfgs {
+            let name = job_cfg.name.clone();
+            match Job::new(job_cfg, now) {
+                Ok(job) => self.jobs.push(job),
+                Err(e) => {
+                    error!(&self.msg_tx, format!("[{NAME}] job {name:?}: {e}"));
+                }
+            }
+        }
+
+        if !Path::new(&self.path).exists() {
+            self.save().await;
+        }
+        self.arm();
+
+        init!(&self.msg_tx, NAME);
+    }
+
+    async fn save(&self) {
+        let job_cfgs: Vec<JobCfg> = self.jobs.iter().map(|j| j.job_cfg()).collect();
+        let result = serde_json::to_string_pretty(&job_cfgs)
+            .map_err(|e| e.to_string())
+            .and_then(|s| {
+                File::create(&self.path)
+                    .and_then(|mut file| file.write_all(s.as_bytes()))
+                    .map_err(|e| e.to_string())
+            });
+
+        if let Err(e) = result {
+            error!(
+                &self.msg_tx,
+                format!("[{NAME}] Failed to save {}: {e}", self.path)
+            );
+        }
+    }
+
+    // a single timer for the earliest job, it is re-armed on every change
+    fn arm(&mut self) {
+        if let Some(timer) = self.timer.take() {
+            timer.abort();
+        }
+
+        let next_run = match self.jobs.iter().filter_map(|j| j.next_run).min() {
+            Some(next_run) => next_run,
+            None => return,
+        };
+
+        let msg_tx_clone = self.msg_tx.clone();
+        let delay = next_run.saturating_sub(utils::ts());
+        self.timer = Some(tokio::spawn(async move {
+            tokio::time::sleep(tokio::time::Duration::from_secs(delay)).await;
+            msg::cmd(
+                &msg_tx_clone,
+                reply_me!(),
+                NAME.to_owned(),
+                msg::ACT_TICK.to_owned(),
+                vec![],
+            )
+            .await;
+        }));
+    }
+
+    async fn run(&self, job: &Job, reply: Reply) {
+        match command::parse_p(&job.cmd) {
+            Ok((plugin, action, data)) => {
+                msg::cmd(&self.msg_tx, reply, plugin, action, data).await;
+            }
+            Err(e) => {
+                log(
+                    &self.msg_tx,
+                    reply,
+                    Error,
+                    format!("[{NAME}] job {:?}: {e}", job.name),
+                )
+                .await;
+            }
+        }
+    }
+
+    async fn tick(&mut self) {
+        let now = utils::ts();
+
+        for idx in 0..self.jobs.len() {
+            match self.jobs[idx].next_run {
+                Some(next_run) if next_run <= now => (),
+                _ => continue,
+            }
+
+            self.run(&self.jobs[idx], reply_me!()).await;
+
+            let job = &mut self.jobs[idx];
+            job.next_run = job.parsed.next(now);
+        }
+
+        self.arm();
+    }
+
+    async fn add(&mut self, cmd: &Cmd) {
+        if cmd.data.len() < 5 {
+            log(
+                &self.msg_tx,
+                cmd.reply.clone(),
+                Error,
+                format!("[{NAME}] add <name> <schedule> p <plugin> <action> ..."),
+            )
+            .await;
+            return;
+        }
+
+        let job_cfg = JobCfg {
+            name: cmd.data[0].clone(),
+            schedule: cmd.data[1].clone(),
+            cmd: shlex::try_join(cmd.data[2..].iter().map(|s| s.as_str()))
+                .unwrap_or_else(|_| cmd.data[2..].join(" ")),
+        };
+
+        let job = match Job::new(job_cfg, utils::ts()) {
+            Ok(job) => job,
+            Err(e) => {
+                log(
+                    &self.msg_tx,
+                    cmd.reply.clone(),
+                    Error,
+                    format!("[{NAME}] add: {e}"),
+                )
+                .await;
+                return;
+            }
+        };
+
+        let next_run = job.next_run.map(utils::ts_str).unwrap_or_default();
+        log(
+            &self.msg_tx,
+            cmd.reply.clone(),
+            Info,
+            format!("[{NAME}] {:?} added, next run: {next_run}", job.name),
+        )
+        .await;
+
+        // same name replaces
+        self.jobs.retain(|j| j.name != job.name);
+        self.jobs.push(job);
+        self.save().await;
+        self.arm();
+    }
+
+    async fn remove(&mut self, cmd: &Cmd) {
+        let name = match cmd.data.first() {
+            Some(name) => name,
+            None => {
+                log(
+                    &self.msg_tx,
+                    cmd.reply.clone(),
+                    Error,
+                    format!("[{NAME}] remove: name is missing."),
+                )
+                .await;
+                return;
+            }
+        };
+
+        let len = self.jobs.len();
+        self.jobs.retain(|j| &j.name != name);
+        if self.jobs.len() == len {
+            log(
+                &self.msg_tx,
+                cmd.reply.clone(),
+                Error,
+                format!("[{NAME}] job not found: {name:?}"),
+            )
+            .await;
+            return;
+        }
+
+        log(
+            &self.msg_tx,
+            cmd.reply.clone(),
+            Info,
+            format!("[{NAME}] {name:?} removed"),
+        )
+        .await;
+
+        self.save().await;
+        self.arm();
+    }
+
+    async fn run_now(&mut self, cmd: &Cmd) {
+        let job = match cmd
+            .data
+            .first()
+            .and_then(|name| self.jobs.iter().find(|j| &j.name == name))
+        {
+            Some(job) => job,
+            None => {
+                log(
+                    &self.msg_tx,
+                    cmd.reply.clone(),
+                    Error,
+                    format!("[{NAME}] job not found: {:?}", cmd.data.first()),
+                )
+                .await;
+                return;
+            }
+        };
+
+        self.run(job, cmd.reply.clone()).await;
+    }
+
+    async fn list(&mut self, cmd: &Cmd) {
+        match &cmd.reply {
+            Reply::Device(_) => {
+                for job in &self.jobs {
+                    let next_run = job.next_run.map(utils::ts_str).unwrap_or("n/a".to_owned());
+                    log(
+                        &self.msg_tx,
+                        cmd.reply.clone(),
+                        Info,
+                        format!(
+                            "[{NAME}] {:12} {:16} next: {next_run}  {}",
+                            job.name, job.schedule, job.cmd
+                        ),
+                    )
+                    .await;
+                }
+            }
+            Reply::Web(sender) => {
+                sender
+                    .send(serde_json::to_value(&self.jobs).unwrap())
+                    .await
+                    .unwrap();
+            }
+        }
+    }
+
+    async fn help(&self) {
+        info!(
+            &self.msg_tx,
+            format!(
+                "[{NAME}] {ACT_HELP}, {ACT_INIT}, {ACT_LIST}, {ACT_ADD} <name> <\"cron\"|\"@every 5m\"> p <plugin> <action> ..., {ACT_REMOVE} <name>, {ACT_RUN_NOW} <name>",
+                ACT_HELP = msg::ACT_HELP,
+                ACT_INIT = msg::ACT_INIT,
+                ACT_LIST = msg::ACT_LIST,
+                ACT_ADD = msg::ACT_ADD,
+                ACT_REMOVE = msg::ACT_REMOVE,
+                ACT_RUN_NOW = msg::ACT_RUN_NOW,
+            )
+        );
+    }
+}
+
+#[async_trait]
+impl plugins_main::Plugin for Plugin {
+    fn name(&self) -> &str {
+        self.name.as_str()
+    }
+
+    async fn msg(&mut self, msg: &Msg) -> bool {
+        match &msg.data {
+            Data::Cmd(cmd) => match cmd.action.as_str() {
+                msg::ACT_HELP => self.help().await,
+                msg::ACT_INIT => self.init().await,
+                msg::ACT_LIST | msg::ACT_SHOW => self.list(cmd).await,
+                msg::ACT_ADD => self.add(cmd).await,
+                msg::ACT_REMOVE => self.remove(cmd).await,
+                msg::ACT_RUN_NOW => self.run_now(cmd).await,
+                msg::ACT_TICK => self.tick().await,
+                _ => {
+                    unknown!(&self.msg_tx, NAME, cmd.action);
+                }
+            },
+            _ => {
+                unknown!(&self.msg_tx, NAME, msg);
+            }
+        }
+
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ts(y: i32, mo: u32, d: u32, h: u32, mi: u32) -> u64 {
+        Local
+            .with_ymd_and_hms(y, mo, d, h, mi, 0)
+            .earliest()
+            .unwrap()
+            .timestamp() as u64
+    }
+
+    #[test]
+    fn parse_schedules() {
+        assert_eq!(Schedule::parse("@every 90"), Ok(Schedule::Every(90)));
+        assert_eq!(Schedule::parse("@every 5m"), Ok(Schedule::Every(300)));
+        assert_eq!(Schedule::parse("@every 1h"), Ok(Schedule::Every(3600)));
+        assert!(Schedule::parse("@every 0").is_err());
+        assert!(Schedule::parse("@every 5x").is_err());
+        assert!(Schedule::parse("@every 999999999999999w").is_err());
+        assert_eq!(Schedule::Every(u64::MAX).next(1), None);
+        assert!(Schedule::parse("0 7 * *").is_err());
+        assert!(Schedule::parse("60 7 * * *").is_err());
+        assert!(Schedule::parse("0 7 * * 1-").is_err());
+        assert!(Schedule::parse("*/0 * * * *").is_err());
+    }
+
+    #[test]
+    fn cron_weekdays_at_seven() {
+        let cron = Cron::parse("0 7 * * 1-5").unwrap();
+
+        // Friday 2025-05-02 08:00 -> Monday 2025-05-05 07:00
+        assert_eq!(cron.next(ts(2025, 5, 2, 8, 0)), Some(ts(2025, 5, 5, 7, 0)));
+        // Monday 06:59 -> same day
+        assert_eq!(cron.next(ts(2025, 5, 5, 6, 59)), Some(ts(2025, 5, 5, 7, 0)));
+        // strictly after
+        assert_eq!(cron.next(ts(2025, 5, 5, 7, 0)), Some(ts(2025, 5, 6, 7, 0)));
+    }
+
+    #[test]
+    fn cron_steps_lists_and_months() {
+        let cron = Cron::parse("*/15 9,18 * * *").unwrap();
+        assert_eq!(
+            cron.next(ts(2025, 5, 1, 9, 20)),
+            Some(ts(2025, 5, 1, 9, 30))
+        );
+        assert_eq!(
+            cron.next(ts(2025, 5, 1, 9, 45)),
+            Some(ts(2025, 5, 1, 18, 0))
+        );
+
+        let cron = Cron::parse("0 0 1 1 *").unwrap();
+        assert_eq!(cron.next(ts(2025, 5, 1, 0, 0)), Some(ts(2026, 1, 1, 0, 0)));
+
+        // Sunday as 7, or the 13th
+        let cron = Cron::parse("30 12 13 * 7").unwrap();
+        assert_eq!(
+            cron.next(ts(2025, 5, 5, 0, 0)),
+            Some(ts(2025, 5, 11, 12, 30))
+        );
+        assert_eq!(
+            cron.next(ts(2025, 5, 11, 13, 0)),
+            Some(ts(2025, 5, 13, 12, 30))
+        );
+
+        assert_eq!(Cron::parse("0 0 30 2 *").unwrap().next(0), None);
+    }
+
+    #[tokio::test]
+    async fn a_broken_file_keeps_the_jobs() {
+        let path = std::env::temp_dir().join(format!("cng_scheduler_{}.json", std::process::id()));
+        fs::write(&path, "[{\"name\": ").unwrap();
+        let (msg_tx, _msg_rx) = tokio::sync::mpsc::channel(64);
+        let mut plugin = Plugin::new(msg_tx);
+        plugin.path = path.to_string_lossy().to_string();
+
+        plugin.init().await;
+        let names: Vec<String> = plugin.jobs.iter().map(|j| j.job_cfg().name).collect();
+        let defaults: Vec<String> = default_jobs().into_iter().map(|j| j.name).collect();
+        assert!(!names.is_empty());
+        assert_eq!(names, defaults);
+
+        plugin.jobs.truncate(1);
+        plugin.init().await;
+        assert_eq!(plugin.jobs.len(), 1);
+        // the file is left for the user to fix
+        assert_eq!(fs::read_to_string(&path).unwrap(), "[{\"name\": ");
+
+        fs::remove_file(&path).unwrap();
+    }
+}
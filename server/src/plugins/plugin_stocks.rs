@@ -1,3 +1,5 @@
+use std::sync::Arc;
+
 use async_trait::async_trait;
 use log::Level::{Error, Info};
 use tokio::sync::mpsc::Sender;
@@ -7,18 +9,18 @@ use crate::msg::{self, log, Cmd, Data, Msg, Reply};
 use crate::plugins::plugins_main;
 use crate::{
     cfg,
-    utils::{self, Stock},
+    utils::{self, Fetch, Stock},
 };
 use crate::{error, info, init, reply_me, unknown};
 
 pub const NAME: &str = "stocks";
-const STOCK_POLLING: u64 = 60;
 
 #[derive(Debug)]
 pub struct Plugin {
     name: String,
     msg_tx: Sender<Msg>,
     stocks: Vec<Stock>,
+    fetch: Arc<dyn Fetch>,
 }
 
 impl Plugin {
@@ -32,50 +34,60 @@ impl Plugin {
                 Stock::new("2412".to_owned()), // 中華電
                 Stock::new("2330".to_owned()), // 台積電
             ],
+            fetch: Arc::new(utils::Http),
+        }
+    }
+
+    #[cfg(test)]
+    pub fn with_fetcher(msg_tx: Sender<Msg>, fetch: Arc<dyn Fetch>) -> Self {
+        Self {
+            fetch,
+            ..Self::new(msg_tx)
         }
     }
 
     async fn init(&mut self) {
+        init!(&self.msg_tx, NAME);
+    }
+
+    // run by the scheduler
+    async fn poll(&mut self) {
         let msg_tx_clone = self.msg_tx.clone();
         let stocks = self.stocks.clone();
+        let fetch = self.fetch.clone();
         tokio::spawn(async move {
-            loop {
-                for stock in &stocks {
-                    let stock_info = utils::get_stock_info(&stock.code).await;
-                    if let Ok(stock_info) = stock_info {
-                        msg::cmd(
-                            &msg_tx_clone,
-                            reply_me!(),
-                            NAME.to_owned(),
-                            msg::ACT_STOCK.to_owned(),
-                            vec![
-                                stock_info.code.clone(),
-                                stock_info.name.clone(),
-                                stock_info.last_price.clone(),
-                                stock_info.high_price.clone(),
-                                stock_info.low_price.clone(),
-                                stock_info.prev_close.clone(),
-                                stock_info.datetime.clone(),
-                            ],
-                        )
-                        .await;
-                    }
+            for stock in &stocks {
+                let stock_info = utils::get_stock_info(&*fetch, &stock.code).await;
+                if let Ok(stock_info) = stock_info {
+                    msg::cmd(
+                        &msg_tx_clone,
+                        reply_me!(),
+                        NAME.to_owned(),
+                        msg::ACT_STOCK.to_owned(),
+                        vec![
+                            stock_info.code.clone(),
+                            stock_info.name.clone(),
+                            stock_info.last_price.clone(),
+                            stock_info.high_price.clone(),
+                            stock_info.low_price.clone(),
+                            stock_info.prev_close.clone(),
+                            stock_info.datetime.clone(),
+                        ],
+                    )
+                    .await;
                 }
-
-                tokio::time::sleep(tokio::time::Duration::from_secs(STOCK_POLLING)).await;
             }
         });
-
-        init!(&self.msg_tx, NAME);
     }
 
     async fn update(&mut self, cmd: &Cmd) {
         let msg_tx_clone = self.msg_tx.clone();
         let stocks = self.stocks.clone();
+        let fetch = self.fetch.clone();
         let reply_clone = cmd.reply.clone();
         tokio::spawn(async move {
             for stock in &stocks {
-                let stock_info = utils::get_stock_info(&stock.code).await;
+                let stock_info = utils::get_stock_info(&*fetch, &stock.code).await;
 
                 info!(&msg_tx_clone, format!("[{NAME}] {} updated.", stock.code));
 
@@ -159,11 +171,12 @@ impl Plugin {
         info!(
             &self.msg_tx,
             format!(
-                "[{NAME}] help: {}, {}, {}, {}",
+                "[{NAME}] help: {}, {}, {}, {}, {}",
                 msg::ACT_INIT,
                 msg::ACT_SHOW,
                 msg::ACT_UPDATE,
-                msg::ACT_STOCK
+                msg::ACT_STOCK,
+                msg::ACT_POLL
             )
         );
     }
@@ -182,6 +195,7 @@ impl plugins_main::Plugin for Plugin {
                 msg::ACT_INIT => self.init().await,
                 msg::ACT_SHOW => self.show(cmd).await,
                 msg::ACT_UPDATE => self.update(cmd).await,
+                msg::ACT_POLL => self.poll().await,
                 msg::ACT_STOCK => self.stock(cmd).await,
                 _ => {
                     unknown!(&self.msg_tx, NAME, cmd.action);
@@ -195,3 +209,26 @@ impl plugins_main::Plugin for Plugin {
         false
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::harness::Harness;
+
+    #[tokio::test]
+    async fn stocks_are_updated_and_shown() {
+        let mut h = Harness::new();
+        h.cmd(NAME, msg::ACT_UPDATE, &[]).await;
+        h.settle().await;
+        h.cmd(NAME, msg::ACT_SHOW, &[]).await;
+
+        let logs = h.log_msgs();
+        assert!(logs.contains(&"[stocks] update".to_owned()));
+        let tsmc = logs.iter().find(|l| l.starts_with("2330")).unwrap();
+        assert!(tsmc.contains("台積電"), "{}", tsmc);
+        assert!(tsmc.contains("950.00"), "{}", tsmc);
+        // not known to the fake site
+        let other = logs.iter().find(|l| l.starts_with("2317")).unwrap();
+        assert!(other.contains("n/a"), "{}", other);
+    }
+}
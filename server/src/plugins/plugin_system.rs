@@ -1,16 +1,23 @@
+use std::collections::BTreeMap;
+
 use async_trait::async_trait;
 use log::Level::{Error, Info};
 use serde::Serialize;
 use tokio::sync::mpsc::Sender;
 
-use crate::msg::{self, log, Cmd, Data, Msg, Reply};
-use crate::plugins::{plugin_mqtt, plugins_main};
+use crate::msg::{self, log, Cmd, Data, Metric, Msg, Reply, Value};
+use crate::plugins::nas::address;
+use crate::plugins::system::upgrade::{self, Pending};
+use crate::plugins::{mqtt, plugin_mqtt, plugin_nas, plugins_main};
 use crate::{cfg, utils};
 use crate::{error, info, init, reply_me, unknown};
 
 pub const NAME: &str = "system";
-const VERSION: &str = "0.3.3";
-const ONBOARD_POLLING: u64 = 300;
+pub const VERSION: &str = "0.3.3";
+// an upgraded binary not onboard by then is rolled back
+const UPGRADE_TIMEOUT: u64 = 5 * 60;
+// for the replies to go out before quitting
+const RESTART_DELAY: u64 = 3;
 
 fn get_temperature() -> f32 {
     let components = sysinfo::Components::new_with_refreshed_list();
@@ -31,12 +38,12 @@ fn get_cpu_arch() -> String {
     sysinfo::System::cpu_arch()
 }
 
-fn get_cpu_usage() -> f32 {
+async fn get_cpu_usage() -> f32 {
     let mut s = sysinfo::System::new_with_specifics(
         sysinfo::RefreshKind::nothing().with_cpu(sysinfo::CpuRefreshKind::everything()),
     );
     // Wait a bit because CPU usage is based on diff.
-    std::thread::sleep(sysinfo::MINIMUM_CPU_UPDATE_INTERVAL);
+    tokio::time::sleep(sysinfo::MINIMUM_CPU_UPDATE_INTERVAL).await;
     // Refresh CPUs again to get actual value.
     s.refresh_cpu_usage();
     s.global_cpu_usage()
@@ -61,89 +68,161 @@ fn get_disk_usage() -> f32 {
     ((total_space - available_space) * 100 / total_space) as f32
 }
 
-async fn update_system(msg_tx: &Sender<Msg>, reply: Reply) {
-    // tailscale ip
-    let tailscale_ip = utils::get_tailscale_ip();
-    msg::cmd(
-        msg_tx,
-        reply.clone(),
-        NAME.to_owned(),
-        msg::ACT_UPDATE_ITEM.to_owned(),
-        vec!["tailscale_ip".to_owned(), tailscale_ip.clone()],
-    )
-    .await;
+// a metric key with the characters allowed in a topic, "/" is root
+fn metric_key(prefix: &str, name: &str, metric: Option<&str>) -> String {
+    let name: String = name
+        .trim_matches('/')
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    let name = if name.is_empty() { "root" } else { &name };
 
-    // weather
-    let weather = utils::device_weather().await;
-    msg::cmd(
-        msg_tx,
-        reply.clone(),
-        NAME.to_owned(),
-        msg::ACT_UPDATE_ITEM.to_owned(),
-        vec!["weather".to_owned(), weather],
-    )
-    .await;
+    match metric {
+        Some(metric) => format!("{prefix}.{name}.{metric}"),
+        None => format!("{prefix}.{name}"),
+    }
+}
 
-    // temperature
-    let temperature = get_temperature();
-    msg::cmd(
-        msg_tx,
-        reply.clone(),
-        NAME.to_owned(),
-        msg::ACT_UPDATE_ITEM.to_owned(),
-        vec!["temperature".to_owned(), temperature.to_string()],
-    )
-    .await;
+// a payload with its unit, see msg::Metric::parse
+fn with_unit(value: f64, unit: &str) -> String {
+    serde_json::to_string(&Metric::new(Value::Number(value), Some(unit))).unwrap()
+}
 
-    let os = get_os();
-    msg::cmd(
-        msg_tx,
-        reply.clone(),
-        NAME.to_owned(),
-        msg::ACT_UPDATE_ITEM.to_owned(),
-        vec!["os".to_owned(), os],
-    )
-    .await;
+async fn get_cpu_cores() -> Vec<(String, String)> {
+    let mut s = sysinfo::System::new_with_specifics(
+        sysinfo::RefreshKind::nothing().with_cpu(sysinfo::CpuRefreshKind::everything()),
+    );
+    tokio::time::sleep(sysinfo::MINIMUM_CPU_UPDATE_INTERVAL).await;
+    s.refresh_cpu_usage();
 
-    let cpu_arch = get_cpu_arch();
-    msg::cmd(
-        msg_tx,
-        reply.clone(),
-        NAME.to_owned(),
-        msg::ACT_UPDATE_ITEM.to_owned(),
-        vec!["cpu_arch".to_owned(), cpu_arch],
-    )
-    .await;
+    s.cpus()
+        .iter()
+        .enumerate()
+        .map(|(idx, cpu)| {
+            (
+                metric_key(msg::TM_CPU, &idx.to_string(), None),
+                with_unit(cpu.cpu_usage() as f64, "%"),
+            )
+        })
+        .collect()
+}
 
-    let cpu_usage = get_cpu_usage();
-    msg::cmd(
-        msg_tx,
-        reply.clone(),
-        NAME.to_owned(),
-        msg::ACT_UPDATE_ITEM.to_owned(),
-        vec!["cpu_usage".to_owned(), cpu_usage.to_string()],
-    )
-    .await;
+fn get_host_load() -> Vec<(String, String)> {
+    let load = sysinfo::System::load_average();
+    let s = sysinfo::System::new_with_specifics(
+        sysinfo::RefreshKind::nothing()
+            .with_memory(sysinfo::MemoryRefreshKind::nothing().with_swap())
+            .with_processes(sysinfo::ProcessRefreshKind::nothing()),
+    );
+    let swap_usage = if s.total_swap() == 0 {
+        0.0
+    } else {
+        s.used_swap() as f64 * 100.0 / s.total_swap() as f64
+    };
+
+    vec![
+        (msg::TM_LOAD_1.to_owned(), load.one.to_string()),
+        (msg::TM_LOAD_5.to_owned(), load.five.to_string()),
+        (msg::TM_LOAD_15.to_owned(), load.fifteen.to_string()),
+        (msg::TM_SWAP_USAGE.to_owned(), format!("{swap_usage:.1}")),
+        (
+            msg::TM_PROCESSES.to_owned(),
+            s.processes().len().to_string(),
+        ),
+    ]
+}
 
-    let memory_usage = get_memory_usage();
-    msg::cmd(
-        msg_tx,
-        reply.clone(),
-        NAME.to_owned(),
-        msg::ACT_UPDATE_ITEM.to_owned(),
-        vec!["memory_usage".to_owned(), memory_usage.to_string()],
-    )
-    .await;
+fn get_disks() -> Vec<(String, String)> {
+    let disks = sysinfo::Disks::new_with_refreshed_list();
+    let mut metrics = vec![];
+    for disk in disks.list() {
+        if disk.total_space() == 0 {
+            continue;
+        }
+        let mount = disk.mount_point().to_string_lossy();
+        let used = disk.total_space() - disk.available_space();
+        metrics.push((
+            metric_key(msg::TM_DISK, &mount, Some("usage")),
+            with_unit(used as f64 * 100.0 / disk.total_space() as f64, "%"),
+        ));
+        metrics.push((
+            metric_key(msg::TM_DISK, &mount, Some("free")),
+            with_unit(disk.available_space() as f64, "B"),
+        ));
+    }
 
-    let disk_usage = get_disk_usage();
-    msg::cmd(
-        msg_tx,
-        reply.clone(),
-        NAME.to_owned(),
-        msg::ACT_UPDATE_ITEM.to_owned(),
-        vec!["disk_usage".to_owned(), disk_usage.to_string()],
-    )
-    .await;
+    metrics
+}
+
+// rates over a second
+async fn get_networks() -> Vec<(String, String)> {
+    let mut networks = sysinfo::Networks::new_with_refreshed_list();
+    tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
+    networks.refresh(true);
+
+    let mut metrics = vec![];
+    for (interface, data) in networks.list() {
+        metrics.push((
+            metric_key(msg::TM_NET, interface, Some("rx")),
+            with_unit(data.received() as f64, "B/s"),
+        ));
+        metrics.push((
+            metric_key(msg::TM_NET, interface, Some("tx")),
+            with_unit(data.transmitted() as f64, "B/s"),
+        ));
+    }
+    metrics.sort();
+
+    metrics
+}
+
+async fn get_host_metrics() -> Vec<(String, String)> {
+    let mut metrics = get_cpu_cores().await;
+    metrics.extend(get_host_load());
+    metrics.extend(get_disks());
+    metrics.extend(get_networks().await);
+
+    metrics
+}
+
+async fn update_system(msg_tx: &Sender<Msg>, reply: Reply) {
+    let telemetry = vec![
+        (msg::TM_TAILSCALE_IP, utils::get_tailscale_ip()),
+        (
+            msg::TM_ADDRESSES,
+            address::addresses(&cfg::nas_addresses()).join(","),
+        ),
+        (msg::TM_WEATHER, utils::device_weather().await),
+        (msg::TM_TEMPERATURE, get_temperature().to_string()),
+        (msg::TM_OS, get_os()),
+        (msg::TM_CPU_ARCH, get_cpu_arch()),
+        (msg::TM_CPU_USAGE, get_cpu_usage().await.to_string()),
+        (msg::TM_MEMORY_USAGE, get_memory_usage().to_string()),
+        (msg::TM_DISK_USAGE, get_disk_usage().to_string()),
+    ];
+
+    let host_metrics = get_host_metrics().await;
+    let telemetry = telemetry
+        .into_iter()
+        .map(|(key, value)| (key.to_owned(), value))
+        .chain(host_metrics);
+
+    for (key, value) in telemetry {
+        msg::cmd(
+            msg_tx,
+            reply.clone(),
+            NAME.to_owned(),
+            msg::ACT_UPDATE_ITEM.to_owned(),
+            vec![key, value],
+        )
+        .await;
+    }
 
     msg::cmd(
         msg_tx,
@@ -158,15 +237,15 @@ async fn update_system(msg_tx: &Sender<Msg>, reply: Reply) {
 #[derive(Debug)]
 struct Device {
     name: String,
-    temperature: f32,
-    weather: String,
-    os: String,
-    cpu_arch: String,
-    cpu_usage: f32,
-    memory_usage: f32,
-    disk_usage: f32,
     ts_start: u64,
-    tailscale_ip: String,
+    // the last values of update_system, published as tln/<name>/<key>
+    telemetry: BTreeMap<String, String>,
+}
+
+impl Device {
+    fn get(&self, key: &str) -> String {
+        self.telemetry.get(key).cloned().unwrap_or("n/a".to_owned())
+    }
 }
 
 #[derive(Debug, Serialize)]
@@ -184,167 +263,321 @@ pub struct Plugin {
     msg_tx: Sender<Msg>,
     name: String,
     device: Device,
+    releases: String,
+    binary: String,
+    pending: String,
+    // the version being fetched from the NAS, and who asked for it
+    upgrading: Option<(String, Reply)>,
 }
 
 impl Plugin {
     pub fn new(msg_tx: Sender<Msg>) -> Self {
         let device = Device {
             name: cfg::name().to_owned(),
-            temperature: 0.0,
-            weather: "n/a".to_owned(),
-            os: "n/a".to_owned(),
-            cpu_arch: "n/a".to_owned(),
-            cpu_usage: 0.0,
-            memory_usage: 0.0,
-            disk_usage: 0.0,
             ts_start: utils::uptime(),
-            tailscale_ip: "n/a".to_owned(),
+            telemetry: BTreeMap::new(),
         };
 
         Self {
             msg_tx,
             device,
             name: NAME.to_owned(),
+            releases: cfg::RELEASES_FOLDER.to_owned(),
+            binary: cfg::BINARY_FILE.to_owned(),
+            pending: cfg::UPGRADE_FILE.to_owned(),
+            upgrading: None,
         }
     }
 
     async fn init(&mut self) {
-        let msg_tx_clone = self.msg_tx.clone();
-        tokio::spawn(async move {
-            loop {
-                update_system(&msg_tx_clone, reply_me!()).await;
-                tokio::time::sleep(tokio::time::Duration::from_secs(ONBOARD_POLLING)).await;
+        if let Some(pending) = upgrade::pending(&self.pending) {
+            if pending.to == VERSION {
+                // confirmed by the update after a connack, see tick otherwise
+                let msg_tx_clone = self.msg_tx.clone();
+                tokio::spawn(async move {
+                    tokio::time::sleep(tokio::time::Duration::from_secs(UPGRADE_TIMEOUT)).await;
+                    msg::cmd(
+                        &msg_tx_clone,
+                        reply_me!(),
+                        NAME.to_owned(),
+                        msg::ACT_TICK.to_owned(),
+                        vec![],
+                    )
+                    .await;
+                });
+            } else {
+                // the daemon rolled back, or didn't run the new binary
+                error!(
+                    &self.msg_tx,
+                    format!(
+                        "[{NAME}] upgrade from {} to {} failed, running {VERSION}",
+                        pending.from, pending.to
+                    )
+                );
+                let _ = std::fs::remove_file(&self.pending);
             }
-        });
+        }
 
         init!(&self.msg_tx, NAME);
     }
 
-    async fn show(&mut self, cmd: &Cmd) {
-        match &cmd.reply {
-            Reply::Device(_) => {
-                // device name
-                log(
-                    &self.msg_tx,
-                    cmd.reply.clone(),
-                    Info,
-                    format!("[{NAME}] Device name: {}", self.device.name),
-                )
-                .await;
+    // roll back an upgrade which never came onboard
+    async fn tick(&mut self) -> bool {
+        let pending = match upgrade::pending(&self.pending) {
+            Some(pending) if pending.to == VERSION => pending,
+            _ => return false,
+        };
 
-                // app uptime
-                log(
+        match upgrade::rollback(&self.binary, &self.pending) {
+            Ok(()) => {
+                error!(
                     &self.msg_tx,
-                    cmd.reply.clone(),
-                    Info,
                     format!(
-                        "[{NAME}] App uptime: {}",
-                        utils::uptime_str(utils::uptime() - self.device.ts_start)
-                    ),
-                )
-                .await;
+                        "[{NAME}] upgrade to {VERSION} not onboard in {}, back to {}",
+                        utils::uptime_str(UPGRADE_TIMEOUT),
+                        pending.from
+                    )
+                );
+                true
+            }
+            Err(e) => {
+                error!(&self.msg_tx, format!("[{NAME}] rollback failed: {e}"));
+                false
+            }
+        }
+    }
 
-                // host uptime
+    // from the local releases, else from the NAS which sends it back over
+    // the NAS channel and asks again
+    async fn upgrade(&mut self, cmd: &Cmd) {
+        let version = match cmd.data.first() {
+            Some(version) => version.clone(),
+            None => {
                 log(
                     &self.msg_tx,
                     cmd.reply.clone(),
-                    Info,
-                    format!(
-                        "[{NAME}] Host uptime: {}",
-                        utils::uptime_str(utils::uptime())
-                    ),
+                    Error,
+                    format!("[{NAME}] upgrade: version is missing."),
                 )
                 .await;
+                return;
+            }
+        };
 
-                // tailscale ip
-                log(
-                    &self.msg_tx,
-                    cmd.reply.clone(),
-                    Info,
-                    format!("[{NAME}] Tailscale IP: {}", self.device.tailscale_ip),
-                )
-                .await;
+        let reply = match self.upgrading.take() {
+            Some((v, reply)) if v == version => reply,
+            _ => cmd.reply.clone(),
+        };
 
-                // version
-                log(
-                    &self.msg_tx,
-                    cmd.reply.clone(),
-                    Info,
-                    format!("[{NAME}] Version: {VERSION}"),
-                )
-                .await;
+        if version == VERSION {
+            log(
+                &self.msg_tx,
+                reply,
+                Info,
+                format!("[{NAME}] upgrade: already {VERSION}"),
+            )
+            .await;
+            return;
+        }
 
-                // temperature
-                log(
-                    &self.msg_tx,
-                    cmd.reply.clone(),
-                    Info,
-                    format!("[{NAME}] Temperature: {:.1}°C", get_temperature()),
-                )
-                .await;
+        let key = cfg::release_key();
+        if key.is_empty() {
+            log(
+                &self.msg_tx,
+                reply,
+                Error,
+                format!("[{NAME}] upgrade: release_key is not set in cfg.json."),
+            )
+            .await;
+            return;
+        }
 
-                // os
-                log(
+        let result = match upgrade::load_release(&self.releases, &version) {
+            Ok(Some(release)) => Ok(release),
+            Ok(None) if cfg::name() != cfg::nas() => {
+                let addresses = address::addresses(&cfg::nas_addresses()).join(",");
+                msg::cmd(
                     &self.msg_tx,
-                    cmd.reply.clone(),
-                    Info,
-                    format!("[{NAME}] OS: {}", get_os()),
+                    reply_me!(),
+                    plugin_mqtt::NAME.to_owned(),
+                    msg::ACT_ASK.to_owned(),
+                    vec![
+                        cfg::nas(),
+                        "p".to_owned(),
+                        plugin_nas::NAME.to_owned(),
+                        msg::ACT_NAS.to_owned(),
+                        msg::ACT_RELEASE.to_owned(),
+                        cfg::name(),
+                        addresses,
+                        version.clone(),
+                    ],
                 )
                 .await;
-
-                // cpu arch
                 log(
                     &self.msg_tx,
-                    cmd.reply.clone(),
+                    reply.clone(),
                     Info,
-                    format!("[{NAME}] CPU Arch: {}", get_cpu_arch()),
+                    format!("[{NAME}] upgrade: fetching {version} from {}", cfg::nas()),
                 )
                 .await;
+                self.upgrading = Some((version, reply));
+                return;
+            }
+            Ok(None) => Err(format!("{version} not found in {}", self.releases)),
+            Err(e) => Err(e),
+        }
+        .and_then(|(release, bytes)| {
+            upgrade::verify(&release, &bytes, &key).map(|_| (release, bytes))
+        });
 
-                // cpu usage
-                log(
-                    &self.msg_tx,
-                    cmd.reply.clone(),
-                    Info,
-                    format!("[{NAME}] CPU Usage: {:.1}%", get_cpu_usage()),
-                )
-                .await;
+        let result = result.and_then(|(release, bytes)| {
+            let pending = Pending {
+                from: VERSION.to_owned(),
+                to: release.version.clone(),
+                ts: utils::ts(),
+            };
+            upgrade::install(&self.binary, &bytes, &self.pending, &pending)
+        });
+
+        if let Err(e) = result {
+            log(
+                &self.msg_tx,
+                reply,
+                Error,
+                format!("[{NAME}] upgrade to {version} failed: {e}"),
+            )
+            .await;
+            return;
+        }
+
+        log(
+            &self.msg_tx,
+            reply,
+            Info,
+            format!("[{NAME}] upgrade: {version} installed, restarting"),
+        )
+        .await;
+
+        let msg_tx_clone = self.msg_tx.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(tokio::time::Duration::from_secs(RESTART_DELAY)).await;
+            msg::cmd(
+                &msg_tx_clone,
+                reply_me!(),
+                NAME.to_owned(),
+                msg::ACT_QUIT.to_owned(),
+                vec![],
+            )
+            .await;
+        });
+    }
 
-                // memory usage
+    // sign a binary as a release, where release_secret is set
+    async fn release(&mut self, cmd: &Cmd) {
+        let (version, path) = match (cmd.data.first(), cmd.data.get(1)) {
+            (Some(version), Some(path)) => (version, path),
+            _ => {
                 log(
                     &self.msg_tx,
                     cmd.reply.clone(),
-                    Info,
-                    format!("[{NAME}] Memory Usage: {:.1}%", get_memory_usage()),
+                    Error,
+                    format!("[{NAME}] release: version or binary is missing."),
                 )
                 .await;
+                return;
+            }
+        };
 
-                // disk usage
+        let secret = cfg::release_secret();
+        let result = std::fs::read(path)
+            .map_err(|e| format!("{path}: {e}"))
+            .and_then(|bytes| {
+                let release = upgrade::sign(version, &bytes, &secret)?;
+                upgrade::save_release(&self.releases, &release, &bytes)?;
+                Ok((release, upgrade::public_key(&secret)?))
+            });
+
+        match result {
+            Ok((release, key)) => {
                 log(
                     &self.msg_tx,
                     cmd.reply.clone(),
                     Info,
-                    format!("[{NAME}] Disk Usage: {:.1}%", get_disk_usage()),
+                    format!(
+                        "[{NAME}] release {version}: sha256 {}, key {key}",
+                        release.sha256
+                    ),
                 )
                 .await;
-
-                // weather
+            }
+            Err(e) => {
                 log(
                     &self.msg_tx,
                     cmd.reply.clone(),
-                    Info,
-                    format!("[{NAME}] Weather: {}", self.device.weather),
+                    Error,
+                    format!("[{NAME}] release {version} failed: {e}"),
                 )
                 .await;
             }
+        }
+    }
+
+    // run by the scheduler
+    async fn poll(&mut self) {
+        let msg_tx_clone = self.msg_tx.clone();
+        tokio::spawn(async move {
+            update_system(&msg_tx_clone, reply_me!()).await;
+        });
+    }
+
+    async fn show(&mut self, cmd: &Cmd) {
+        match &cmd.reply {
+            Reply::Device(_) => {
+                let msg_tx = self.msg_tx.clone();
+                let reply = cmd.reply.clone();
+                let name = self.device.name.clone();
+                let app_uptime = utils::uptime() - self.device.ts_start;
+                let tailscale_ip = self.device.get(msg::TM_TAILSCALE_IP);
+                let weather = self.device.get(msg::TM_WEATHER);
+
+                // the usages are sampled over a while, replied from a task as poll does
+                tokio::spawn(async move {
+                    let mut lines = vec![
+                        format!("[{NAME}] Device name: {name}"),
+                        format!("[{NAME}] App uptime: {}", utils::uptime_str(app_uptime)),
+                        format!(
+                            "[{NAME}] Host uptime: {}",
+                            utils::uptime_str(utils::uptime())
+                        ),
+                        format!("[{NAME}] Tailscale IP: {tailscale_ip}"),
+                        format!("[{NAME}] Version: {VERSION}"),
+                        format!("[{NAME}] Temperature: {:.1}°C", get_temperature()),
+                        format!("[{NAME}] OS: {}", get_os()),
+                        format!("[{NAME}] CPU Arch: {}", get_cpu_arch()),
+                        format!("[{NAME}] CPU Usage: {:.1}%", get_cpu_usage().await),
+                        format!("[{NAME}] Memory Usage: {:.1}%", get_memory_usage()),
+                        format!("[{NAME}] Disk Usage: {:.1}%", get_disk_usage()),
+                        format!("[{NAME}] Weather: {weather}"),
+                    ];
+
+                    // per core, disk and interface
+                    for (key, value) in get_host_metrics().await {
+                        lines.push(format!("[{NAME}] {key}: {}", Metric::parse(&key, &value)));
+                    }
+
+                    for line in lines {
+                        log(&msg_tx, reply.clone(), Info, line).await;
+                    }
+                });
+            }
             Reply::Web(sender) => {
                 let device_for_web = DeviceForWeb {
                     name: self.device.name.clone(),
                     app_uptime: utils::uptime() - self.device.ts_start,
                     host_uptime: utils::uptime(),
                     temperature: get_temperature(),
-                    weather: self.device.weather.clone(),
-                    tailscale_ip: self.device.tailscale_ip.clone(),
+                    weather: self.device.get(msg::TM_WEATHER),
+                    tailscale_ip: self.device.get(msg::TM_TAILSCALE_IP),
                 };
                 sender
                     .send(serde_json::to_value(device_for_web).unwrap())
@@ -356,205 +589,57 @@ impl Plugin {
 
     // self update
     async fn update_item(&mut self, cmd: &Cmd) {
-        match cmd.data.first().unwrap().as_str() {
-            "weather" => {
-                self.device.weather = cmd.data.get(1).unwrap().to_owned();
-            }
-            "temperature" => {
-                let temperature = cmd.data.get(1).unwrap().parse::<f32>().unwrap_or(0.0);
-                self.device.temperature = temperature;
-            }
-            "tailscale_ip" => {
-                self.device.tailscale_ip = cmd.data.get(1).unwrap().to_owned();
-            }
-            "os" => {
-                self.device.os = cmd.data.get(1).unwrap().to_owned();
-            }
-            "cpu_arch" => {
-                self.device.cpu_arch = cmd.data.get(1).unwrap().to_owned();
-            }
-            "cpu_usage" => {
-                let cpu_usage = cmd.data.get(1).unwrap().parse::<f32>().unwrap_or(0.0);
-                self.device.cpu_usage = cpu_usage;
-            }
-            "memory_usage" => {
-                let memory_usage = cmd.data.get(1).unwrap().parse::<f32>().unwrap_or(0.0);
-                self.device.memory_usage = memory_usage;
-            }
-            "disk_usage" => {
-                let disk_usage = cmd.data.get(1).unwrap().parse::<f32>().unwrap_or(0.0);
-                self.device.disk_usage = disk_usage;
+        match (cmd.data.first(), cmd.data.get(1)) {
+            (Some(key), Some(value)) => {
+                self.device.telemetry.insert(key.clone(), value.clone());
             }
             _ => {
-                unknown!(&self.msg_tx, NAME, cmd.data.first().unwrap());
+                unknown!(&self.msg_tx, NAME, cmd.data);
             }
         }
     }
 
     async fn update(&mut self, cmd: &Cmd) {
-        // onboard
-        msg::cmd(
-            &self.msg_tx,
-            cmd.reply.clone(),
-            plugin_mqtt::NAME.to_owned(),
-            msg::ACT_PUBLISH.to_owned(),
-            vec!["onboard".to_owned(), "true".to_owned(), "1".to_owned()],
-        )
-        .await;
-
-        // app uptime
-        let uptime = utils::uptime() - self.device.ts_start;
-        msg::cmd(
-            &self.msg_tx,
-            cmd.reply.clone(),
-            plugin_mqtt::NAME.to_owned(),
-            msg::ACT_PUBLISH.to_owned(),
-            vec![
-                "app_uptime".to_owned(),
-                "false".to_owned(),
-                uptime.to_string(),
-            ],
-        )
-        .await;
-
-        // host uptime
-        let uptime = utils::uptime();
-        msg::cmd(
-            &self.msg_tx,
-            cmd.reply.clone(),
-            plugin_mqtt::NAME.to_owned(),
-            msg::ACT_PUBLISH.to_owned(),
-            vec![
-                "host_uptime".to_owned(),
-                "false".to_owned(),
-                uptime.to_string(),
-            ],
-        )
-        .await;
-
-        // tailscale ip
-        msg::cmd(
-            &self.msg_tx,
-            cmd.reply.clone(),
-            plugin_mqtt::NAME.to_owned(),
-            msg::ACT_PUBLISH.to_owned(),
-            vec![
-                "tailscale_ip".to_owned(),
-                "false".to_owned(),
-                self.device.tailscale_ip.clone(),
-            ],
-        )
-        .await;
-
-        // version
-        msg::cmd(
-            &self.msg_tx,
-            cmd.reply.clone(),
-            plugin_mqtt::NAME.to_owned(),
-            msg::ACT_PUBLISH.to_owned(),
-            vec!["version".to_owned(), "false".to_owned(), VERSION.to_owned()],
-        )
-        .await;
-
-        // temperature
-        msg::cmd(
-            &self.msg_tx,
-            cmd.reply.clone(),
-            plugin_mqtt::NAME.to_owned(),
-            msg::ACT_PUBLISH.to_owned(),
-            vec![
-                "temperature".to_owned(),
-                "false".to_owned(),
-                self.device.temperature.to_string(),
-            ],
-        )
-        .await;
-
-        // os
-        msg::cmd(
-            &self.msg_tx,
-            cmd.reply.clone(),
-            plugin_mqtt::NAME.to_owned(),
-            msg::ACT_PUBLISH.to_owned(),
-            vec!["os".to_owned(), "false".to_owned(), self.device.os.clone()],
-        )
-        .await;
-
-        // cpu arch
-        msg::cmd(
-            &self.msg_tx,
-            cmd.reply.clone(),
-            plugin_mqtt::NAME.to_owned(),
-            msg::ACT_PUBLISH.to_owned(),
-            vec![
-                "cpu_arch".to_owned(),
-                "false".to_owned(),
-                self.device.cpu_arch.clone(),
-            ],
-        )
-        .await;
-
-        // cpu usage
-        msg::cmd(
-            &self.msg_tx,
-            cmd.reply.clone(),
-            plugin_mqtt::NAME.to_owned(),
-            msg::ACT_PUBLISH.to_owned(),
-            vec![
-                "cpu_usage".to_owned(),
-                "false".to_owned(),
-                self.device.cpu_usage.to_string(),
-            ],
-        )
-        .await;
-
-        // memory usage
-        msg::cmd(
-            &self.msg_tx,
-            cmd.reply.clone(),
-            plugin_mqtt::NAME.to_owned(),
-            msg::ACT_PUBLISH.to_owned(),
-            vec![
-                "memory_usage".to_owned(),
-                "false".to_owned(),
-                self.device.memory_usage.to_string(),
-            ],
-        )
-        .await;
+        let publish = |key: &str, retain: bool, value: String| {
+            msg::cmd(
+                &self.msg_tx,
+                cmd.reply.clone(),
+                plugin_mqtt::NAME.to_owned(),
+                msg::ACT_PUBLISH.to_owned(),
+                vec![key.to_owned(), retain.to_string(), value],
+            )
+        };
 
-        // disk usage
-        msg::cmd(
-            &self.msg_tx,
-            cmd.reply.clone(),
-            plugin_mqtt::NAME.to_owned(),
-            msg::ACT_PUBLISH.to_owned(),
-            vec![
-                "disk_usage".to_owned(),
-                "false".to_owned(),
-                self.device.disk_usage.to_string(),
-            ],
-        )
-        .await;
+        // the scheduler updates too, only a connack proves the broker is reached
+        let onboard = cmd.data.first().map(String::as_str) == Some(mqtt::utils::ONBOARD);
+        if onboard {
+            if let Some(pending) = upgrade::confirm(&self.pending, VERSION) {
+                info!(
+                    &self.msg_tx,
+                    format!("[{NAME}] upgraded from {} to {VERSION}", pending.from)
+                );
+            }
+        }
 
-        // weather
-        msg::cmd(
-            &self.msg_tx,
-            cmd.reply.clone(),
-            plugin_mqtt::NAME.to_owned(),
-            msg::ACT_PUBLISH.to_owned(),
-            vec![
-                "weather".to_owned(),
-                "false".to_owned(),
-                self.device.weather.clone(),
-            ],
+        publish(mqtt::utils::ONBOARD, true, "1".to_owned()).await;
+        publish(msg::TM_TAGS, true, cfg::tags().join(",")).await;
+        publish(
+            msg::TM_APP_UPTIME,
+            false,
+            (utils::uptime() - self.device.ts_start).to_string(),
         )
         .await;
+        publish(msg::TM_HOST_UPTIME, false, utils::uptime().to_string()).await;
+        publish(msg::TM_VERSION, false, VERSION.to_owned()).await;
+        for (key, value) in &self.device.telemetry {
+            publish(key, false, value.clone()).await;
+        }
     }
 
     async fn help(&mut self) {
         info!(
             &self.msg_tx,
-            format!("[{NAME}] help: init, show, update, update_item, quit")
+            format!("[{NAME}] help: init, show, update, update_item, poll, quit, upgrade <version>, release <version> <binary>")
         );
     }
 }
@@ -574,6 +659,10 @@ impl plugins_main::Plugin for Plugin {
                 msg::ACT_SHOW => self.show(cmd).await,
                 msg::ACT_UPDATE => self.update(cmd).await,
                 msg::ACT_UPDATE_ITEM => self.update_item(cmd).await,
+                msg::ACT_POLL => self.poll().await,
+                msg::ACT_UPGRADE => self.upgrade(cmd).await,
+                msg::ACT_RELEASE => self.release(cmd).await,
+                msg::ACT_TICK => ret = self.tick().await,
                 msg::ACT_QUIT => {
                     ret = true;
                 }
@@ -589,3 +678,109 @@ impl plugins_main::Plugin for Plugin {
         ret
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::harness::Harness;
+
+    #[tokio::test]
+    async fn upgrade_to_the_running_version() {
+        let mut h = Harness::new();
+
+        h.cmd(NAME, msg::ACT_UPGRADE, &[]).await;
+        h.cmd(NAME, msg::ACT_UPGRADE, &[VERSION]).await;
+        assert_eq!(
+            h.log_msgs(),
+            vec![
+                "[system] upgrade: version is missing.".to_owned(),
+                format!("[system] upgrade: already {VERSION}"),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn show_replies_from_a_task() {
+        let mut h = Harness::new();
+
+        let show = h.cmd(NAME, msg::ACT_SHOW, &[]);
+        tokio::time::timeout(tokio::time::Duration::from_millis(500), show)
+            .await
+            .expect("show waited for the samples");
+        assert!(h.log_msgs().is_empty());
+
+        for _ in 0..50 {
+            tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+            h.run().await;
+            if h.log_msgs()
+                .iter()
+                .any(|l| l.starts_with("[system] Weather"))
+            {
+                break;
+            }
+        }
+        let logs = h.log_msgs();
+        assert_eq!(logs[0], format!("[system] Device name: {}", cfg::name()));
+        assert!(logs.iter().any(|l| l.starts_with("[system] CPU Usage: ")));
+    }
+
+    #[tokio::test]
+    async fn upgrade_is_confirmed_on_connack() {
+        let dir = std::env::temp_dir().join(format!("cng_system_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (msg_tx, _msg_rx) = tokio::sync::mpsc::channel(1024);
+        let mut plugin = Plugin::new(msg_tx);
+        plugin.pending = dir.join("upgrade.json").to_string_lossy().to_string();
+        let pending = Pending {
+            from: "0.3.2".to_owned(),
+            to: VERSION.to_owned(),
+            ts: 1000,
+        };
+        std::fs::write(&plugin.pending, serde_json::to_string(&pending).unwrap()).unwrap();
+
+        let update = |data: &[&str]| Cmd {
+            reply: reply_me!(),
+            action: msg::ACT_UPDATE.to_owned(),
+            data: data.iter().map(|d| d.to_string()).collect(),
+        };
+        plugin.update(&update(&[])).await;
+        assert_eq!(upgrade::pending(&plugin.pending), Some(pending));
+        plugin.update(&update(&[mqtt::utils::ONBOARD])).await;
+        assert_eq!(upgrade::pending(&plugin.pending), None);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn metric_keys_and_units() {
+        assert_eq!(
+            metric_key(msg::TM_DISK, "/", Some("free")),
+            "disk.root.free"
+        );
+        assert_eq!(
+            metric_key(msg::TM_DISK, "/mnt/nas data", Some("usage")),
+            "disk.mnt_nas_data.usage"
+        );
+        assert_eq!(metric_key(msg::TM_CPU, "3", None), "cpu.3");
+
+        let key = metric_key(msg::TM_NET, "eth0", Some("rx"));
+        let metric = Metric::parse(&key, &with_unit(2_500_000.0, "B/s"));
+        assert_eq!(metric.to_string(), "2.5MB/s");
+        assert_eq!(
+            Metric::parse(msg::TM_SWAP_USAGE, "12.5").to_string(),
+            "12.5%"
+        );
+    }
+
+    #[test]
+    fn descriptions_stay_text() {
+        let version = Metric::parse(msg::TM_VERSION, "1.0");
+        assert_eq!(version.value, Value::Text("1.0".to_owned()));
+        assert_eq!(version.to_string(), "1.0");
+        let tags = Metric::parse(msg::TM_TAGS, "10");
+        assert_eq!(tags.value, Value::Text("10".to_owned()));
+
+        let custom = Metric::parse("queue", "10");
+        assert_eq!(custom.value, Value::Number(10.0));
+    }
+}
@@ -16,22 +16,66 @@ use crate::{error, info, init, unknown};
 
 pub const NAME: &str = "todos";
 
-#[derive(Serialize, Deserialize, Debug)]
-struct Todo {
-    title: String,
-    desc: String,
-    priority: i32,
-    due: DateTime,
-    completed: bool,
-    created: DateTime,
-    updated: DateTime,
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Todo {
+    pub title: String,
+    pub desc: String,
+    pub priority: i32,
+    pub due: DateTime,
+    pub completed: bool,
+    pub created: DateTime,
+    pub updated: DateTime,
+}
+
+// where the todos are kept, MongoDB but for the tests
+#[async_trait]
+pub trait Store: Send + Sync + std::fmt::Debug {
+    async fn find(&self) -> Result<Vec<Todo>, String>;
+    async fn insert(&self, todo: Todo) -> Result<(), String>;
+}
+
+#[derive(Debug)]
+struct MongoStore {
+    client: Client,
+}
+
+impl MongoStore {
+    fn collection(&self) -> mongodb::Collection<Todo> {
+        self.client.database("cng").collection("todos")
+    }
+}
+
+#[async_trait]
+impl Store for MongoStore {
+    async fn find(&self) -> Result<Vec<Todo>, String> {
+        let mut cursor = self
+            .collection()
+            .find(doc! {})
+            .await
+            .map_err(|e| format!("{:?}", e))?;
+
+        let mut todos = vec![];
+        while let Some(result) = cursor.next().await {
+            todos.push(result.map_err(|e| format!("{:?}", e))?);
+        }
+
+        Ok(todos)
+    }
+
+    async fn insert(&self, todo: Todo) -> Result<(), String> {
+        self.collection()
+            .insert_one(todo)
+            .await
+            .map(|_| ())
+            .map_err(|e| format!("{:?}", e))
+    }
 }
 
 #[derive(Debug)]
 pub struct Plugin {
     name: String,
     msg_tx: Sender<Msg>,
-    client: Option<Client>,
+    store: Option<Box<dyn Store>>,
 }
 
 impl Plugin {
@@ -39,18 +83,26 @@ impl Plugin {
         Self {
             name: NAME.to_owned(),
             msg_tx,
-            client: None,
+            store: None,
+        }
+    }
+
+    #[cfg(test)]
+    pub fn with_store(msg_tx: Sender<Msg>, store: Box<dyn Store>) -> Self {
+        Self {
+            store: Some(store),
+            ..Self::new(msg_tx)
         }
     }
 
     async fn init(&mut self) {
         match utils::connect(&cfg::db()).await {
-            Ok(t) => {
-                self.client = Some(t);
+            Ok(client) => {
+                self.store = Some(Box::new(MongoStore { client }));
                 info!(&self.msg_tx, format!("[{NAME}] DB connected"));
             }
             Err(e) => {
-                self.client = None;
+                self.store = None;
                 error!(
                     &self.msg_tx,
                     format!("[{NAME}] Failed to connect to DB: {:?}", e)
@@ -62,114 +114,115 @@ impl Plugin {
     }
 
     async fn show(&mut self, cmd: &Cmd) {
-        if self.client.is_none() {
-            log(
-                &self.msg_tx,
-                cmd.reply.clone(),
-                Error,
-                format!("[{NAME}] DB not connected"),
-            )
-            .await;
-            return;
-        }
+        let store = match self.store.as_ref() {
+            Some(store) => store,
+            None => {
+                log(
+                    &self.msg_tx,
+                    cmd.reply.clone(),
+                    Error,
+                    format!("[{NAME}] DB not connected"),
+                )
+                .await;
+                return;
+            }
+        };
 
-        let client = self.client.as_ref().unwrap();
-        let collection: mongodb::Collection<Todo> = client.database("cng").collection("todos");
-        // let filter = doc! { "title": "title test" };
-        let filter = doc! {};
-        let mut cursor = collection.find(filter).await.unwrap();
-        while let Some(result) = cursor.next().await {
-            match result {
-                Ok(document) => match &cmd.reply {
-                    Reply::Device(_) => {
-                        log(
-                            &self.msg_tx,
-                            cmd.reply.clone(),
-                            Info,
-                            format!("[{}]", document.title),
-                        )
-                        .await;
-                        log(
-                            &self.msg_tx,
-                            cmd.reply.clone(),
-                            Info,
-                            format!("    desc: {}", document.desc),
-                        )
-                        .await;
-                        log(
-                            &self.msg_tx,
-                            cmd.reply.clone(),
-                            Info,
-                            format!("    completed: {}", document.completed),
-                        )
-                        .await;
-                        log(
-                            &self.msg_tx,
-                            cmd.reply.clone(),
-                            Info,
-                            format!("    priority: {}", document.priority),
-                        )
-                        .await;
-                        log(
-                            &self.msg_tx,
-                            cmd.reply.clone(),
-                            Info,
-                            format!("    due: {}", document.due),
-                        )
-                        .await;
-                        log(
-                            &self.msg_tx,
-                            cmd.reply.clone(),
-                            Info,
-                            format!("    created: {}", document.created),
-                        )
-                        .await;
-                        log(
-                            &self.msg_tx,
-                            cmd.reply.clone(),
-                            Info,
-                            format!("    updated: {}", document.updated),
-                        )
-                        .await;
-                    }
-                    Reply::Web(sender) => {
-                        sender
-                            .send(serde_json::to_value(document).unwrap())
-                            .await
-                            .unwrap();
+        match store.find().await {
+            Ok(documents) => {
+                for document in documents {
+                    match &cmd.reply {
+                        Reply::Device(_) => {
+                            log(
+                                &self.msg_tx,
+                                cmd.reply.clone(),
+                                Info,
+                                format!("[{}]", document.title),
+                            )
+                            .await;
+                            log(
+                                &self.msg_tx,
+                                cmd.reply.clone(),
+                                Info,
+                                format!("    desc: {}", document.desc),
+                            )
+                            .await;
+                            log(
+                                &self.msg_tx,
+                                cmd.reply.clone(),
+                                Info,
+                                format!("    completed: {}", document.completed),
+                            )
+                            .await;
+                            log(
+                                &self.msg_tx,
+                                cmd.reply.clone(),
+                                Info,
+                                format!("    priority: {}", document.priority),
+                            )
+                            .await;
+                            log(
+                                &self.msg_tx,
+                                cmd.reply.clone(),
+                                Info,
+                                format!("    due: {}", document.due),
+                            )
+                            .await;
+                            log(
+                                &self.msg_tx,
+                                cmd.reply.clone(),
+                                Info,
+                                format!("    created: {}", document.created),
+                            )
+                            .await;
+                            log(
+                                &self.msg_tx,
+                                cmd.reply.clone(),
+                                Info,
+                                format!("    updated: {}", document.updated),
+                            )
+                            .await;
+                        }
+                        Reply::Web(sender) => {
+                            sender
+                                .send(serde_json::to_value(document).unwrap())
+                                .await
+                                .unwrap();
+                        }
                     }
-                },
-                Err(e) => {
-                    log(
-                        &self.msg_tx,
-                        cmd.reply.clone(),
-                        Error,
-                        format!("[{NAME}] Failed to find document: {:?}", e),
-                    )
-                    .await;
                 }
             }
+            Err(e) => {
+                log(
+                    &self.msg_tx,
+                    cmd.reply.clone(),
+                    Error,
+                    format!("[{NAME}] Failed to find document: {e}"),
+                )
+                .await;
+            }
         }
     }
 
     async fn add(&mut self, cmd: &Cmd) {
-        if self.client.is_none() {
-            log(
-                &self.msg_tx,
-                cmd.reply.clone(),
-                Error,
-                format!("[{NAME}] DB not connected"),
-            )
-            .await;
-            return;
-        }
+        let store = match self.store.as_ref() {
+            Some(store) => store,
+            None => {
+                log(
+                    &self.msg_tx,
+                    cmd.reply.clone(),
+                    Error,
+                    format!("[{NAME}] DB not connected"),
+                )
+                .await;
+                return;
+            }
+        };
 
         let title = cmd.data.first().unwrap();
         let desc = cmd.data.get(1).unwrap();
         let priority = cmd.data.get(2).unwrap().parse::<i32>().unwrap();
 
-        let client = self.client.as_ref().unwrap();
-        let collection: mongodb::Collection<Todo> = client.database("cng").collection("todos");
         let todo = Todo {
             title: title.to_owned(),
             desc: desc.to_owned(),
@@ -179,7 +232,15 @@ impl Plugin {
             created: DateTime::now(),
             updated: DateTime::now(),
         };
-        collection.insert_one(todo).await.unwrap();
+        if let Err(e) = store.insert(todo).await {
+            log(
+                &self.msg_tx,
+                cmd.reply.clone(),
+                Error,
+                format!("[{NAME}] Failed to insert document: {e}"),
+            )
+            .await;
+        }
     }
 
     async fn help(&self) {}
@@ -210,3 +271,25 @@ impl plugins_main::Plugin for Plugin {
         false
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::harness::Harness;
+
+    #[tokio::test]
+    async fn todos_are_added_and_shown() {
+        let mut h = Harness::new();
+        h.cmd(NAME, msg::ACT_ADD, &["backup", "the nas", "2"]).await;
+        h.cmd(NAME, msg::ACT_SHOW, &[]).await;
+
+        let logs = h.log_msgs();
+        assert_eq!(logs[0], "[backup]");
+        assert_eq!(logs[1], "    desc: the nas");
+        assert!(logs.contains(&"    priority: 2".to_owned()));
+
+        let values = h.web_cmd(NAME, msg::ACT_SHOW, &[]).await;
+        assert_eq!(values.len(), 1);
+        assert_eq!(values[0]["title"], "backup");
+    }
+}
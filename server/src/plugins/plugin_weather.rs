@@ -1,3 +1,5 @@
+use std::sync::Arc;
+
 use async_trait::async_trait;
 use log::Level::{Error, Info, Trace};
 use tokio::sync::mpsc::Sender;
@@ -5,11 +7,10 @@ use tokio::sync::mpsc::Sender;
 use crate::cfg;
 use crate::msg::{self, log, City, Cmd, Data, Msg, Reply};
 use crate::plugins::plugins_main;
-use crate::utils::{self, Weather, WeatherDaily};
+use crate::utils::{self, Fetch, Weather, WeatherDaily};
 use crate::{error, info, init, reply_me, trace, unknown};
 
 pub const NAME: &str = "weather";
-const WEATHER_POLLING: u64 = 60 * 60;
 
 async fn update_weather(msg_tx: &Sender<Msg>, city_name: &str, weather: Weather) {
     msg::cmd(
@@ -47,6 +48,7 @@ async fn update_weather(msg_tx: &Sender<Msg>, city_name: &str, weather: Weather)
 }
 
 async fn update_weather_all(
+    fetch: &dyn Fetch,
     weather: &[City],
     msg_tx: &Sender<Msg>,
     reply: Reply,
@@ -61,7 +63,7 @@ async fn update_weather_all(
         )
         .await;
 
-        let weather = utils::weather(city.latitude, city.longitude).await;
+        let weather = utils::weather(fetch, city.latitude, city.longitude).await;
         if let Ok(weather) = weather {
             update_weather(msg_tx, &city.name, weather).await;
         }
@@ -73,6 +75,7 @@ pub struct Plugin {
     name: String,
     msg_tx: Sender<Msg>,
     weather: Vec<City>,
+    fetch: Arc<dyn Fetch>,
 }
 
 impl Plugin {
@@ -138,22 +141,32 @@ impl Plugin {
             name: NAME.to_owned(),
             msg_tx,
             weather,
+            fetch: Arc::new(utils::Http),
+        }
+    }
+
+    #[cfg(test)]
+    pub fn with_fetcher(msg_tx: Sender<Msg>, fetch: Arc<dyn Fetch>) -> Self {
+        Self {
+            fetch,
+            ..Self::new(msg_tx)
         }
     }
 
     async fn init(&mut self) {
+        init!(&self.msg_tx, NAME);
+    }
+
+    // run by the scheduler
+    async fn poll(&mut self) {
         let msg_tx_clone = self.msg_tx.clone();
         let weather = self.weather.clone();
+        let fetch = self.fetch.clone();
         tokio::spawn(async move {
-            loop {
-                trace!(&msg_tx_clone, format!("[{NAME}] polling."));
+            trace!(&msg_tx_clone, format!("[{NAME}] polling."));
 
-                update_weather_all(&weather, &msg_tx_clone, reply_me!(), Trace).await;
-                tokio::time::sleep(tokio::time::Duration::from_secs(WEATHER_POLLING)).await;
-            }
+            update_weather_all(&*fetch, &weather, &msg_tx_clone, reply_me!(), Trace).await;
         });
-
-        init!(&self.msg_tx, NAME);
     }
 
     async fn show(&mut self, cmd: &Cmd) {
@@ -236,8 +249,9 @@ impl Plugin {
         let msg_tx_clone = self.msg_tx.clone();
         let weather = self.weather.clone();
         let reply_clone = cmd.reply.clone();
+        let fetch = self.fetch.clone();
         tokio::spawn(async move {
-            update_weather_all(&weather, &msg_tx_clone, reply_clone.clone(), Info).await;
+            update_weather_all(&*fetch, &weather, &msg_tx_clone, reply_clone.clone(), Info).await;
             log(
                 &msg_tx_clone,
                 reply_clone,
@@ -304,12 +318,13 @@ impl Plugin {
         info!(
             &self.msg_tx,
             format!(
-                "[{NAME}] {ACT_HELP}, {ACT_INIT}, {ACT_SHOW}, {ACT_WEATHER}, {ACT_UPDATE}",
+                "[{NAME}] {ACT_HELP}, {ACT_INIT}, {ACT_SHOW}, {ACT_WEATHER}, {ACT_UPDATE}, {ACT_POLL}",
                 ACT_HELP = msg::ACT_HELP,
                 ACT_INIT = msg::ACT_INIT,
                 ACT_SHOW = msg::ACT_SHOW,
                 ACT_WEATHER = msg::ACT_WEATHER,
                 ACT_UPDATE = msg::ACT_UPDATE,
+                ACT_POLL = msg::ACT_POLL,
             )
         );
     }
@@ -330,6 +345,7 @@ impl plugins_main::Plugin for Plugin {
                 msg::ACT_WEATHER => self.weather(cmd).await,
                 msg::ACT_WEATHER_DAILY => self.weather_daily(cmd).await,
                 msg::ACT_UPDATE => self.update(cmd).await,
+                msg::ACT_POLL => self.poll().await,
                 _ => {
                     unknown!(&self.msg_tx, NAME, cmd.action);
                 }
@@ -342,3 +358,26 @@ impl plugins_main::Plugin for Plugin {
         false
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::harness::Harness;
+
+    #[tokio::test]
+    async fn weather_is_updated_and_shown() {
+        let mut h = Harness::new();
+        h.cmd(NAME, msg::ACT_UPDATE, &[]).await;
+        h.settle().await;
+        h.cmd(NAME, msg::ACT_SHOW, &[]).await;
+
+        let logs = h.log_msgs();
+        assert!(logs.contains(&"[weather] updated.".to_owned()));
+        let tokyo = logs.iter().position(|l| l == "[weather] Tokyo:").unwrap();
+        assert_eq!(logs[tokyo + 2], "[weather]     Temperature: 27.5°C");
+        assert_eq!(
+            logs[tokyo + 5],
+            "[weather]     2025-05-02: 29°C/21.8°C, 80%, 小雨"
+        );
+    }
+}
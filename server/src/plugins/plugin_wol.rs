@@ -7,7 +7,7 @@ use crate::msg::{self, log, Cmd, Data, Msg, Reply};
 use crate::plugins::plugins_main;
 use crate::{error, info, init, unknown};
 
-const NAME: &str = "wol";
+pub const NAME: &str = "wol";
 const LIN_DS_MAC: [u8; 6] = [0x90, 0x09, 0xd0, 0x64, 0x4e, 0xa4];
 
 #[derive(Debug)]
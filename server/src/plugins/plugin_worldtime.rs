@@ -8,7 +8,6 @@ use crate::{cfg, utils};
 use crate::{error, info, init, reply_me, trace, unknown};
 
 pub const NAME: &str = "worldtime";
-const WORLDTIME_POLLING: u64 = 5 * 60;
 
 async fn update_worldtime(
     cities: &[Worldtime],
@@ -74,18 +73,18 @@ impl Plugin {
     }
 
     async fn init(&mut self) {
+        init!(&self.msg_tx, NAME);
+    }
+
+    // run by the scheduler
+    async fn poll(&mut self) {
         let msg_tx_clone = self.msg_tx.clone();
         let cities = self.cities.clone();
         tokio::spawn(async move {
-            loop {
-                trace!(&msg_tx_clone, format!("[{NAME}] polling."));
+            trace!(&msg_tx_clone, format!("[{NAME}] polling."));
 
-                update_worldtime(&cities, &msg_tx_clone, reply_me!(), Trace).await;
-                tokio::time::sleep(tokio::time::Duration::from_secs(WORLDTIME_POLLING)).await;
-            }
+            update_worldtime(&cities, &msg_tx_clone, reply_me!(), Trace).await;
         });
-
-        init!(&self.msg_tx, NAME);
     }
 
     async fn update(&mut self, cmd: &Cmd) {
@@ -132,12 +131,13 @@ impl Plugin {
         info!(
             &self.msg_tx,
             format!(
-                "[{NAME}] {ACT_INIT}, {ACT_HELP}, {ACT_SHOW}, {ACT_UPDATE}, {ACT_WORLDTIME}",
+                "[{NAME}] {ACT_INIT}, {ACT_HELP}, {ACT_SHOW}, {ACT_UPDATE}, {ACT_WORLDTIME}, {ACT_POLL}",
                 ACT_INIT = msg::ACT_INIT,
                 ACT_HELP = msg::ACT_HELP,
                 ACT_SHOW = msg::ACT_SHOW,
                 ACT_UPDATE = msg::ACT_UPDATE,
                 ACT_WORLDTIME = msg::ACT_WORLDTIME,
+                ACT_POLL = msg::ACT_POLL,
             )
         );
     }
@@ -156,6 +156,7 @@ impl plugins_main::Plugin for Plugin {
                 msg::ACT_INIT => self.init().await,
                 msg::ACT_SHOW => self.show(cmd).await,
                 msg::ACT_UPDATE => self.update(cmd).await,
+                msg::ACT_POLL => self.poll().await,
                 msg::ACT_WORLDTIME => self.worldtime(cmd).await,
                 _ => {
                     unknown!(&self.msg_tx, NAME, cmd.action);
@@ -5,9 +5,11 @@ use tokio::sync::mpsc::Sender;
 use crate::cfg;
 use crate::msg::{self, cmd, log, Cmd, Data, Msg, Reply};
 use crate::plugins::{
-    plugin_devices, plugin_file, plugin_log, plugin_mqtt, plugin_nas, plugin_ping, plugin_shell,
-    plugin_stocks, plugin_system, plugin_todos, plugin_weather, plugin_wol, plugin_worldtime,
+    plugin_alerts, plugin_devices, plugin_file, plugin_log, plugin_mqtt, plugin_nas, plugin_ping,
+    plugin_rules, plugin_scheduler, plugin_shell, plugin_stocks, plugin_system, plugin_todos,
+    plugin_weather, plugin_wol, plugin_worldtime,
 };
+use crate::recorder::Recorder;
 use crate::{error, info, init, reply_me, unknown};
 
 pub const NAME: &str = "plugins";
@@ -15,12 +17,23 @@ pub const NAME: &str = "plugins";
 #[async_trait]
 pub trait Plugin {
     fn name(&self) -> &str;
+    // subscribed when the plugin is created, before any msg is published
+    fn topics(&self) -> &[&'static str] {
+        &[]
+    }
     async fn msg(&mut self, msg: &Msg) -> bool;
 }
 
+struct Subscription {
+    topic: String,
+    subscriber: String,
+}
+
 pub struct Plugins {
     plugins: Vec<Box<dyn Plugin>>,
     msg_tx: Sender<Msg>,
+    subscriptions: Vec<Subscription>,
+    recorder: Recorder,
 }
 
 impl Plugins {
@@ -39,14 +52,53 @@ impl Plugins {
             Box::new(plugin_todos::Plugin::new(msg_tx.clone())) as Box<dyn Plugin>,
             Box::new(plugin_nas::Plugin::new(msg_tx.clone())) as Box<dyn Plugin>,
             Box::new(plugin_stocks::Plugin::new(msg_tx.clone())) as Box<dyn Plugin>,
+            Box::new(plugin_scheduler::Plugin::new(msg_tx.clone())) as Box<dyn Plugin>,
+            Box::new(plugin_rules::Plugin::new(msg_tx.clone())) as Box<dyn Plugin>,
+            Box::new(plugin_alerts::Plugin::new(msg_tx.clone())) as Box<dyn Plugin>,
         ];
 
-        Self { plugins, msg_tx }
+        let mut plugins = Self {
+            plugins,
+            msg_tx,
+            subscriptions: vec![],
+            recorder: Recorder::new(cfg::RECORD_FILE),
+        };
+        let topics: Vec<(&str, String)> = plugins
+            .plugins
+            .iter()
+            .flat_map(|p| {
+                p.topics()
+                    .iter()
+                    .map(move |topic| (*topic, p.name().to_owned()))
+            })
+            .collect();
+        for (topic, subscriber) in topics {
+            plugins.add_subscription(topic, &subscriber);
+        }
+
+        plugins
+    }
+
+    pub fn add_subscription(&mut self, topic: &str, subscriber: &str) {
+        if !self
+            .subscriptions
+            .iter()
+            .any(|s| s.topic == topic && s.subscriber == subscriber)
+        {
+            self.subscriptions.push(Subscription {
+                topic: topic.to_owned(),
+                subscriber: subscriber.to_owned(),
+            });
+        }
     }
 
     pub async fn init(&mut self) {
         init!(&self.msg_tx, NAME);
 
+        if cfg::record() == 1 {
+            self.record_start(reply_me!()).await;
+        }
+
         for plugin in &mut self.plugins {
             cmd(
                 &self.msg_tx,
@@ -59,6 +111,68 @@ impl Plugins {
         }
     }
 
+    // record every msg on the bus if the recorder is started
+    pub async fn record(&mut self, msg: &Msg) {
+        if let Err(e) = self.recorder.record(msg) {
+            error!(&self.msg_tx, format!("[{NAME}] Record stopped. Err: {e}"));
+        }
+    }
+
+    async fn record_start(&mut self, reply: Reply) {
+        match self.recorder.start() {
+            Ok(_) => {
+                log(
+                    &self.msg_tx,
+                    reply,
+                    Info,
+                    format!("[{NAME}] Recording to {}", self.recorder.path()),
+                )
+                .await;
+            }
+            Err(e) => {
+                log(
+                    &self.msg_tx,
+                    reply,
+                    Error,
+                    format!("[{NAME}] Failed to record. Err: {e}"),
+                )
+                .await;
+            }
+        }
+    }
+
+    async fn record_cmd(&mut self, cmd: &Cmd) {
+        match cmd.data.first().map(|s| s.as_str()) {
+            Some("start") => self.record_start(cmd.reply.clone()).await,
+            Some("stop") => {
+                self.recorder.stop().await;
+                log(
+                    &self.msg_tx,
+                    cmd.reply.clone(),
+                    Info,
+                    format!("[{NAME}] Record stopped"),
+                )
+                .await;
+            }
+            _ => {
+                log(
+                    &self.msg_tx,
+                    cmd.reply.clone(),
+                    Error,
+                    format!("[{NAME}] record: start or stop is missing."),
+                )
+                .await;
+            }
+        }
+    }
+
+    // a fake or a stand-in in place of the plugin of the same name
+    pub fn replace(&mut self, plugin: Box<dyn Plugin>) {
+        if let Some(p) = self.get_plugin_mut(plugin.name()) {
+            *p = plugin;
+        }
+    }
+
     fn get_plugin_mut(&mut self, name: &str) -> Option<&mut Box<dyn Plugin>> {
         self.plugins.iter_mut().find(|p| p.name() == name)
     }
@@ -73,10 +187,101 @@ impl Plugins {
             )
             .await;
         }
+
+        log(
+            &self.msg_tx,
+            cmd.reply.clone(),
+            Info,
+            format!(
+                "[{NAME}] record: {} ({})",
+                if self.recorder.is_recording() {
+                    "on"
+                } else {
+                    "off"
+                },
+                self.recorder.path()
+            ),
+        )
+        .await;
+
+        for subscription in &self.subscriptions {
+            log(
+                &self.msg_tx,
+                cmd.reply.clone(),
+                Info,
+                format!(
+                    "[{NAME}] topic '{}' -> {}",
+                    subscription.topic, subscription.subscriber
+                ),
+            )
+            .await;
+        }
+    }
+
+    async fn subscribe(&mut self, cmd: &Cmd) {
+        let (topic, subscriber) = match (cmd.data.first(), cmd.data.get(1)) {
+            (Some(topic), Some(subscriber)) => (topic, subscriber),
+            _ => {
+                log(
+                    &self.msg_tx,
+                    cmd.reply.clone(),
+                    Error,
+                    format!("[{NAME}] subscribe: topic or subscriber is missing."),
+                )
+                .await;
+                return;
+            }
+        };
+
+        self.add_subscription(topic, subscriber);
+    }
+
+    async fn unsubscribe(&mut self, cmd: &Cmd) {
+        let (topic, subscriber) = match (cmd.data.first(), cmd.data.get(1)) {
+            (Some(topic), Some(subscriber)) => (topic, subscriber),
+            _ => {
+                log(
+                    &self.msg_tx,
+                    cmd.reply.clone(),
+                    Error,
+                    format!("[{NAME}] unsubscribe: topic or subscriber is missing."),
+                )
+                .await;
+                return;
+            }
+        };
+
+        self.subscriptions
+            .retain(|s| &s.topic != topic || &s.subscriber != subscriber);
+    }
+
+    // deliver the msg to every subscriber of its topic
+    async fn publish(&mut self, msg: &Msg) {
+        let topic = match msg.data.topic() {
+            Some(t) => t,
+            None => {
+                unknown!(&self.msg_tx, NAME, msg);
+                return;
+            }
+        };
+
+        for subscription in self.subscriptions.iter().filter(|s| s.topic == topic) {
+            self.msg_tx
+                .send(Msg {
+                    ts: msg.ts,
+                    plugin: subscription.subscriber.clone(),
+                    data: msg.data.clone(),
+                })
+                .await
+                .unwrap();
+        }
     }
 
     async fn help(&self) {
-        info!(&self.msg_tx, format!("[{NAME}] help: init, show"));
+        info!(
+            &self.msg_tx,
+            format!("[{NAME}] help: init, show, subscribe <topic> <subscriber>, unsubscribe <topic> <subscriber>, record [start|stop]")
+        );
     }
 
     pub async fn msg(&mut self, msg: &Msg) -> bool {
@@ -86,6 +291,9 @@ impl Plugins {
                 Data::Cmd(cmd) => match cmd.action.as_str() {
                     msg::ACT_HELP => self.help().await,
                     msg::ACT_SHOW => self.show(cmd).await,
+                    msg::ACT_SUBSCRIBE => self.subscribe(cmd).await,
+                    msg::ACT_UNSUBSCRIBE => self.unsubscribe(cmd).await,
+                    msg::ACT_RECORD => self.record_cmd(cmd).await,
                     _ => {
                         log(
                             &self.msg_tx,
@@ -100,6 +308,8 @@ impl Plugins {
                     unknown!(&self.msg_tx, NAME, msg);
                 }
             }
+        } else if msg.plugin == msg::BUS {
+            self.publish(msg).await;
         } else {
             match self.get_plugin_mut(&msg.plugin) {
                 Some(t) => ret = t.msg(msg).await,
@@ -124,3 +334,24 @@ impl Plugins {
         ret
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::harness::Harness;
+
+    #[tokio::test]
+    async fn plugins_are_subscribed_before_init() {
+        let mut h = Harness::new();
+        h.cmd(NAME, msg::ACT_SHOW, &[]).await;
+
+        let logs = h.log_msgs();
+        for line in [
+            "[plugins] topic 'devices' -> nas",
+            "[plugins] topic 'log' -> rules",
+            "[plugins] topic 'stocks' -> alerts",
+        ] {
+            assert!(logs.iter().any(|l| l == line), "{} in {:?}", line, logs);
+        }
+    }
+}
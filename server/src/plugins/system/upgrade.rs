@@ -0,0 +1,262 @@
+use std::convert::TryInto;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::Path;
+
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+// <folder>/<version>/cng and <folder>/<version>/release.json
+const RELEASE_BINARY: &str = "cng";
+const RELEASE_FILE: &str = "release.json";
+
+// as in release.json, also the header of a release sent over the NAS channel
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Release {
+    pub version: String,
+    pub sha256: String,
+    pub signature: String, // of "cng <version> <sha256>"
+}
+
+// an installed binary waiting for its first onboard
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Pending {
+    pub from: String,
+    pub to: String,
+    pub ts: u64,
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn from_hex(s: &str) -> Result<Vec<u8>, String> {
+    if !s.is_ascii() {
+        return Err(format!("not hex: {s:?}"));
+    }
+    if !s.len().is_multiple_of(2) {
+        return Err(format!("odd hex length: {}", s.len()));
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).map_err(|e| format!("{e}: {s:?}")))
+        .collect()
+}
+
+fn key_bytes(s: &str) -> Result<[u8; 32], String> {
+    from_hex(s)?
+        .try_into()
+        .map_err(|_| "a key is 32 bytes".to_owned())
+}
+
+pub fn sha256_hex(bytes: &[u8]) -> String {
+    to_hex(&Sha256::digest(bytes))
+}
+
+fn signed_message(version: &str, sha256: &str) -> String {
+    format!("cng {version} {sha256}")
+}
+
+pub fn public_key(secret: &str) -> Result<String, String> {
+    let signing_key = SigningKey::from_bytes(&key_bytes(secret)?);
+    Ok(to_hex(signing_key.verifying_key().as_bytes()))
+}
+
+pub fn sign(version: &str, bytes: &[u8], secret: &str) -> Result<Release, String> {
+    let signing_key = SigningKey::from_bytes(&key_bytes(secret)?);
+    let sha256 = sha256_hex(bytes);
+    let signature = signing_key.sign(signed_message(version, &sha256).as_bytes());
+
+    Ok(Release {
+        version: version.to_owned(),
+        sha256,
+        signature: to_hex(&signature.to_bytes()),
+    })
+}
+
+pub fn verify(release: &Release, bytes: &[u8], key: &str) -> Result<(), String> {
+    let sha256 = sha256_hex(bytes);
+    if sha256 != release.sha256 {
+        return Err(format!("sha256 mismatch: {sha256}"));
+    }
+
+    let verifying_key =
+        VerifyingKey::from_bytes(&key_bytes(key)?).map_err(|e| format!("bad key: {e}"))?;
+    let signature: [u8; 64] = from_hex(&release.signature)?
+        .try_into()
+        .map_err(|_| "a signature is 64 bytes".to_owned())?;
+    verifying_key
+        .verify(
+            signed_message(&release.version, &release.sha256).as_bytes(),
+            &Signature::from_bytes(&signature),
+        )
+        .map_err(|_| "bad signature".to_owned())
+}
+
+pub fn save_release(folder: &str, release: &Release, bytes: &[u8]) -> Result<(), String> {
+    let dir = Path::new(folder).join(sanitize_filename::sanitize(&release.version));
+    fs::create_dir_all(&dir).map_err(|e| format!("{}: {e}", dir.display()))?;
+
+    fs::write(dir.join(RELEASE_BINARY), bytes).map_err(|e| e.to_string())?;
+    let json = serde_json::to_string_pretty(release).unwrap();
+    fs::write(dir.join(RELEASE_FILE), json).map_err(|e| e.to_string())
+}
+
+// None if there is no such release
+pub fn load_release(folder: &str, version: &str) -> Result<Option<(Release, Vec<u8>)>, String> {
+    let dir = Path::new(folder).join(sanitize_filename::sanitize(version));
+    if !dir.join(RELEASE_FILE).exists() {
+        return Ok(None);
+    }
+
+    let json = fs::read_to_string(dir.join(RELEASE_FILE)).map_err(|e| e.to_string())?;
+    let release: Release = serde_json::from_str(&json).map_err(|e| e.to_string())?;
+    if release.version != version {
+        return Err(format!("{RELEASE_FILE} is for {}", release.version));
+    }
+    let bytes = fs::read(dir.join(RELEASE_BINARY)).map_err(|e| e.to_string())?;
+
+    Ok(Some((release, bytes)))
+}
+
+fn prev_path(binary: &str) -> String {
+    format!("{binary}.prev")
+}
+
+// keep the running binary as .prev and rename the new one over it, so the
+// daemon never starts a half written binary
+pub fn install(
+    binary: &str,
+    bytes: &[u8],
+    pending_path: &str,
+    pending: &Pending,
+) -> Result<(), String> {
+    let new_path = format!("{binary}.new");
+    let mut file = File::create(&new_path).map_err(|e| format!("{new_path}: {e}"))?;
+    file.write_all(bytes)
+        .and_then(|_| file.sync_all())
+        .map_err(|e| format!("{new_path}: {e}"))?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(&new_path, fs::Permissions::from_mode(0o755))
+            .map_err(|e| format!("{new_path}: {e}"))?;
+    }
+
+    // the first upgrade of a `cargo run` keeps what cargo built
+    let current = match Path::new(binary).exists() {
+        true => Some(Path::new(binary).to_path_buf()),
+        false => std::env::current_exe().ok(),
+    };
+    if let Some(current) = current {
+        fs::copy(&current, prev_path(binary)).map_err(|e| format!("{}: {e}", current.display()))?;
+    }
+    fs::write(pending_path, serde_json::to_string(pending).unwrap())
+        .map_err(|e| format!("{pending_path}: {e}"))?;
+    fs::rename(&new_path, binary).map_err(|e| format!("{binary}: {e}"))
+}
+
+pub fn pending(pending_path: &str) -> Option<Pending> {
+    let json = fs::read_to_string(pending_path).ok()?;
+    serde_json::from_str(&json).ok()
+}
+
+// the new binary is onboard
+pub fn confirm(pending_path: &str, version: &str) -> Option<Pending> {
+    let pending = pending(pending_path).filter(|p| p.to == version)?;
+    let _ = fs::remove_file(pending_path);
+
+    Some(pending)
+}
+
+pub fn rollback(binary: &str, pending_path: &str) -> Result<(), String> {
+    let prev = prev_path(binary);
+    if !Path::new(&prev).exists() {
+        return Err(format!("{prev} not found"));
+    }
+    fs::rename(&prev, binary).map_err(|e| format!("{binary}: {e}"))?;
+    let _ = fs::remove_file(pending_path);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &str = "0707070707070707070707070707070707070707070707070707070707070707";
+
+    #[test]
+    fn signed_releases_verify() {
+        let key = public_key(SECRET).unwrap();
+        let release = sign("0.3.4", b"new cng", SECRET).unwrap();
+        assert_eq!(release.sha256, sha256_hex(b"new cng"));
+        assert_eq!(verify(&release, b"new cng", &key), Ok(()));
+
+        assert!(verify(&release, b"evil cng", &key)
+            .unwrap_err()
+            .starts_with("sha256 mismatch"));
+
+        // the version is signed too
+        let mut renamed = release.clone();
+        renamed.version = "0.3.5".to_owned();
+        assert_eq!(
+            verify(&renamed, b"new cng", &key),
+            Err("bad signature".to_owned())
+        );
+
+        let other_key = public_key(&"01".repeat(32)).unwrap();
+        assert_eq!(
+            verify(&release, b"new cng", &other_key),
+            Err("bad signature".to_owned())
+        );
+        assert!(verify(&release, b"new cng", "").is_err());
+
+        // a multi-byte char is not split in two
+        let mut garbled = release.clone();
+        garbled.signature = format!("é{}", &release.signature[2..]);
+        assert!(verify(&garbled, b"new cng", &key).is_err());
+        assert!(public_key(&"é".repeat(32)).is_err());
+    }
+
+    #[test]
+    fn install_confirm_and_rollback() {
+        let dir = std::env::temp_dir().join(format!("cng_upgrade_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let releases = dir.join("releases").to_string_lossy().to_string();
+        let binary = dir.join("cng").to_string_lossy().to_string();
+        let pending_path = dir.join("upgrade.json").to_string_lossy().to_string();
+
+        let release = sign("0.3.4", b"new cng", SECRET).unwrap();
+        save_release(&releases, &release, b"new cng").unwrap();
+        assert_eq!(
+            load_release(&releases, "0.3.4").unwrap(),
+            Some((release, b"new cng".to_vec()))
+        );
+        assert_eq!(load_release(&releases, "0.3.5").unwrap(), None);
+
+        let p = Pending {
+            from: "0.3.3".to_owned(),
+            to: "0.3.4".to_owned(),
+            ts: 1000,
+        };
+        fs::write(&binary, b"old cng").unwrap();
+        install(&binary, b"new cng", &pending_path, &p).unwrap();
+        assert_eq!(fs::read(&binary).unwrap(), b"new cng");
+        assert_eq!(pending(&pending_path), Some(p.clone()));
+
+        // not the version which was installed
+        assert_eq!(confirm(&pending_path, "0.3.3"), None);
+
+        rollback(&binary, &pending_path).unwrap();
+        assert_eq!(fs::read(&binary).unwrap(), b"old cng");
+        assert_eq!(pending(&pending_path), None);
+
+        install(&binary, b"new cng", &pending_path, &p).unwrap();
+        assert_eq!(confirm(&pending_path, "0.3.4"), Some(p));
+        assert_eq!(pending(&pending_path), None);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,323 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc::{self, UnboundedSender};
+use tokio::task::JoinHandle;
+
+use crate::cfg;
+use crate::msg::{Data, Msg, Reply};
+
+const MAX_RECORD_SIZE: u64 = 16 * 1024 * 1024;
+const MAX_RECORD_FILES: usize = 5;
+
+pub const SOURCE_LOCAL: &str = "local";
+pub const SOURCE_MQTT: &str = "mqtt";
+pub const SOURCE_WEB: &str = "web";
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Record {
+    pub ts: u64,
+    pub plugin: String,
+    pub source: String,
+    pub data: Data,
+}
+
+impl Record {
+    pub fn into_msg(self) -> Msg {
+        Msg {
+            ts: self.ts,
+            plugin: self.plugin,
+            data: self.data,
+        }
+    }
+}
+
+// the bus only queues a record, the file is written and rotated by a blocking
+// task of its own
+#[derive(Debug)]
+pub struct Recorder {
+    path: String,
+    max_size: u64,
+    writer: Option<(UnboundedSender<Record>, JoinHandle<()>)>,
+    // why the writer stopped, told on the next record
+    failed: Arc<Mutex<Option<String>>>,
+}
+
+impl Recorder {
+    pub fn new(path: &str) -> Self {
+        Self {
+            path: path.to_owned(),
+            max_size: MAX_RECORD_SIZE,
+            writer: None,
+            failed: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.writer.is_some()
+    }
+
+    pub fn start(&mut self) -> Result<(), String> {
+        if self.writer.is_some() {
+            return Ok(());
+        }
+
+        let mut writer = Writer::open(&self.path, self.max_size)?;
+        let (record_tx, mut record_rx) = mpsc::unbounded_channel::<Record>();
+        let failed = self.failed.clone();
+        let handle = tokio::task::spawn_blocking(move || {
+            while let Some(record) = record_rx.blocking_recv() {
+                if let Err(e) = writer.write(&record) {
+                    *failed.lock().unwrap() = Some(e);
+                    return;
+                }
+            }
+        });
+        self.writer = Some((record_tx, handle));
+
+        Ok(())
+    }
+
+    // once what is queued is written
+    pub async fn stop(&mut self) {
+        if let Some((record_tx, handle)) = self.writer.take() {
+            drop(record_tx);
+            let _ = handle.await;
+        }
+    }
+
+    // queue the msg as one line of JSON, recording stops on any error
+    pub fn record(&mut self, msg: &Msg) -> Result<(), String> {
+        let record_tx = match &self.writer {
+            Some((record_tx, _)) => record_tx,
+            None => return Ok(()),
+        };
+
+        let record = Record {
+            ts: msg.ts,
+            plugin: msg.plugin.clone(),
+            source: source(msg).to_owned(),
+            data: msg.data.clone(),
+        };
+
+        if record_tx.send(record).is_err() {
+            self.writer = None;
+            let failed = self.failed.lock().unwrap().take();
+            return Err(failed.unwrap_or_else(|| "Writer stopped".to_owned()));
+        }
+
+        Ok(())
+    }
+}
+
+struct Writer {
+    path: String,
+    file: File,
+    size: u64,
+    max_size: u64,
+}
+
+impl Writer {
+    fn open(path: &str, max_size: u64) -> Result<Self, String> {
+        if let Some(parent) = Path::new(path).parent() {
+            fs::create_dir_all(parent).map_err(|e| format!("Failed to create {parent:?}: {e}"))?;
+        }
+
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| format!("Failed to open {path}: {e}"))?;
+        let size = file.metadata().map(|m| m.len()).unwrap_or(0);
+
+        Ok(Self {
+            path: path.to_owned(),
+            file,
+            size,
+            max_size,
+        })
+    }
+
+    fn write(&mut self, record: &Record) -> Result<(), String> {
+        let mut line =
+            serde_json::to_string(record).map_err(|e| format!("Failed to serialize: {e}"))?;
+        line.push('\n');
+
+        if self.size + line.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+
+        self.file
+            .write_all(line.as_bytes())
+            .map_err(|e| format!("Failed to write {}: {e}", self.path))?;
+        self.size += line.len() as u64;
+
+        Ok(())
+    }
+
+    // bus.jsonl -> bus.jsonl.1 -> ... -> bus.jsonl.<MAX_RECORD_FILES - 1>
+    fn rotate(&mut self) -> Result<(), String> {
+        for idx in (1..MAX_RECORD_FILES - 1).rev() {
+            let from = format!("{}.{idx}", self.path);
+            if Path::new(&from).exists() {
+                fs::rename(&from, format!("{}.{}", self.path, idx + 1))
+                    .map_err(|e| format!("Failed to rotate {from}: {e}"))?;
+            }
+        }
+        fs::rename(&self.path, format!("{}.1", self.path))
+            .map_err(|e| format!("Failed to rotate {}: {e}", self.path))?;
+
+        *self = Self::open(&self.path, self.max_size)?;
+
+        Ok(())
+    }
+}
+
+fn source(msg: &Msg) -> &'static str {
+    match &msg.data {
+        Data::Cmd(cmd) => match &cmd.reply {
+            Reply::Web(_) => SOURCE_WEB,
+            Reply::Device(device) if *device != cfg::name() => SOURCE_MQTT,
+            Reply::Device(_) => SOURCE_LOCAL,
+        },
+        Data::DeviceUpdate(_) => SOURCE_MQTT,
+        _ => SOURCE_LOCAL,
+    }
+}
+
+pub fn load(path: &str) -> Result<Vec<Record>, String> {
+    let file = File::open(path).map_err(|e| format!("Failed to open {path}: {e}"))?;
+
+    let mut records = vec![];
+    for (idx, line) in BufReader::new(file).lines().enumerate() {
+        let line = line.map_err(|e| format!("Failed to read {path}: {e}"))?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let record: Record = serde_json::from_str(&line)
+            .map_err(|e| format!("Failed to parse {path}:{}: {e}", idx + 1))?;
+        records.push(record);
+    }
+
+    Ok(records)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::msg::{Cmd, DevInfo};
+    use crate::utils;
+
+    fn cmd(action: &str, reply: Reply) -> Msg {
+        Msg {
+            ts: utils::ts(),
+            plugin: "devices".to_owned(),
+            data: Data::Cmd(Cmd {
+                reply,
+                action: action.to_owned(),
+                data: vec!["pi5".to_owned()],
+            }),
+        }
+    }
+
+    #[tokio::test]
+    async fn records_load_back_with_their_source() {
+        let dir = std::env::temp_dir().join(format!("cng_record_{}", std::process::id()));
+        let path = dir.join("bus.jsonl").to_string_lossy().to_string();
+        let mut recorder = Recorder::new(&path);
+        recorder.start().unwrap();
+
+        recorder
+            .record(&cmd("show", Reply::Device(cfg::name())))
+            .unwrap();
+        recorder
+            .record(&cmd("history", Reply::Device("remote".to_owned())))
+            .unwrap();
+        recorder
+            .record(&Msg {
+                ts: 1,
+                plugin: "devices".to_owned(),
+                data: Data::DeviceUpdate(DevInfo::new("pi4")),
+            })
+            .unwrap();
+        recorder.stop().await;
+        // not recording, nothing written
+        recorder
+            .record(&cmd("show", Reply::Device(cfg::name())))
+            .unwrap();
+
+        let records = load(&path).unwrap();
+        let sources: Vec<&str> = records.iter().map(|r| r.source.as_str()).collect();
+        assert_eq!(sources, vec![SOURCE_LOCAL, SOURCE_MQTT, SOURCE_MQTT]);
+        match &records[1].data {
+            Data::Cmd(cmd) => assert_eq!((cmd.action.as_str(), cmd.data.len()), ("history", 1)),
+            data => panic!("{:?}", data),
+        }
+
+        fs::write(&path, "{\"ts\": 1}\n").unwrap();
+        assert!(load(&path).unwrap_err().contains(":1:"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn rotates_past_the_size() {
+        let dir = std::env::temp_dir().join(format!("cng_rotate_{}", std::process::id()));
+        let path = dir.join("bus.jsonl").to_string_lossy().to_string();
+        let mut recorder = Recorder::new(&path);
+        recorder.max_size = 200;
+        recorder.start().unwrap();
+
+        // over a line each, more than there are files
+        for _ in 0..MAX_RECORD_FILES + 2 {
+            recorder
+                .record(&cmd("show", Reply::Device(cfg::name())))
+                .unwrap();
+            recorder
+                .record(&cmd("show", Reply::Device(cfg::name())))
+                .unwrap();
+        }
+        recorder.stop().await;
+
+        for idx in 1..MAX_RECORD_FILES {
+            let rotated = format!("{path}.{idx}");
+            assert!(fs::metadata(&rotated).unwrap().len() <= 200);
+            assert!(!load(&rotated).unwrap().is_empty());
+        }
+        assert!(!Path::new(&format!("{path}.{MAX_RECORD_FILES}")).exists());
+        assert!(!load(&path).unwrap().is_empty());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn a_failed_write_stops_recording() {
+        let dir = std::env::temp_dir().join(format!("cng_record_failed_{}", std::process::id()));
+        let path = dir.join("bus.jsonl").to_string_lossy().to_string();
+        // nothing can be rotated onto a folder
+        fs::create_dir_all(format!("{path}.1/x")).unwrap();
+        let mut recorder = Recorder::new(&path);
+        recorder.max_size = 1;
+        recorder.start().unwrap();
+
+        let mut failed = None;
+        for _ in 0..100 {
+            if let Err(e) = recorder.record(&cmd("show", Reply::Device(cfg::name()))) {
+                failed = Some(e);
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        assert!(failed.unwrap().starts_with("Failed to rotate"));
+        assert!(!recorder.is_recording());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,123 @@
+// Every path from the wire, the web or a command is resolved here, so nothing
+// outside cfg::FILE_FOLDER is ever read, written or removed.
+
+use std::fs;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+
+use crate::cfg;
+
+// relative to the share, or prefixed by it as the NAS lists them; the path
+// to use, still prefixed by the share
+pub fn resolve(path: &str) -> Result<PathBuf, String> {
+    resolve_in(Path::new(cfg::FILE_FOLDER), path)
+}
+
+// as resolve, under another root
+pub fn resolve_in(root: &Path, path: &str) -> Result<PathBuf, String> {
+    let prefix = format!("{}/", root.display());
+    let relative = Path::new(path.strip_prefix(&prefix).unwrap_or(path));
+
+    let mut named = false;
+    for component in relative.components() {
+        match component {
+            Component::Normal(_) => named = true,
+            Component::CurDir => (),
+            Component::ParentDir => return Err(format!("{path:?} goes up")),
+            Component::RootDir | Component::Prefix(_) => {
+                return Err(format!("{path:?} is absolute"))
+            }
+        }
+    }
+    if !named {
+        return Err(format!("{path:?} names no file"));
+    }
+
+    // a symlink in the share must not lead out of it, the file itself may not
+    // exist yet so the closest existing ancestor is checked
+    let resolved = root.join(relative);
+    let real_root = root
+        .canonicalize()
+        .map_err(|e| format!("{}: {e}", root.display()))?;
+    let mut existing = resolved.as_path();
+    while fs::symlink_metadata(existing).is_err() {
+        existing = match existing.parent() {
+            Some(parent) => parent,
+            None => break,
+        };
+    }
+    let real = existing
+        .canonicalize()
+        .map_err(|e| format!("{path:?}: {e}"))?;
+    if !real.starts_with(&real_root) {
+        return Err(format!("{path:?} leads out of {}", root.display()));
+    }
+
+    Ok(resolved)
+}
+
+// a file, or a directory only once it is empty: what was in it goes file by
+// file, each with its own tombstone
+pub fn remove(path: &Path) -> io::Result<()> {
+    match fs::symlink_metadata(path)?.is_dir() {
+        true => fs::remove_dir(path),
+        false => fs::remove_file(path),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::harness::TempDir;
+
+    #[test]
+    fn paths_stay_in_the_share() {
+        let dir = TempDir::new("shared");
+        let root = dir.join("shared");
+        fs::create_dir_all(root.join("note")).unwrap();
+        let prefixed = format!("{}/note/a.md", root.display());
+
+        assert_eq!(resolve_in(&root, "note/a.md"), Ok(root.join("note/a.md")));
+        assert_eq!(resolve_in(&root, &prefixed), Ok(root.join("note/a.md")));
+        // not there yet
+        assert_eq!(
+            resolve_in(&root, "new/dir/b.md"),
+            Ok(root.join("new/dir/b.md"))
+        );
+
+        assert!(resolve_in(&root, "../../etc/passwd")
+            .unwrap_err()
+            .ends_with("goes up"));
+        assert!(resolve_in(&root, "note/../../x").is_err());
+        assert!(resolve_in(&root, "/etc/passwd")
+            .unwrap_err()
+            .ends_with("is absolute"));
+        assert!(resolve_in(&root, "").is_err());
+        assert!(resolve_in(&root, "./.").is_err());
+
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(&*dir, root.join("out")).unwrap();
+            std::os::unix::fs::symlink(root.join("note"), root.join("in")).unwrap();
+
+            assert!(resolve_in(&root, "out/secret")
+                .unwrap_err()
+                .contains("leads out of"));
+            assert!(resolve_in(&root, "out").is_err());
+            assert_eq!(resolve_in(&root, "in/a.md"), Ok(root.join("in/a.md")));
+        }
+    }
+
+    #[test]
+    fn only_empty_directories_are_removed() {
+        let dir = TempDir::new("remove");
+        dir.write("note/a.md", "a");
+
+        assert!(remove(&dir.join("note")).is_err());
+        assert!(dir.join("note/a.md").exists());
+        remove(&dir.join("note/a.md")).unwrap();
+        remove(&dir.join("note")).unwrap();
+        assert!(!dir.join("note").exists());
+        assert!(remove(&dir.join("note")).is_err());
+    }
+}
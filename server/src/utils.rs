@@ -3,6 +3,7 @@ use std::time::{SystemTime, UNIX_EPOCH};
 use aes_gcm::aead::rand_core::RngCore;
 use aes_gcm::aead::{generic_array::GenericArray, Aead, KeyInit, OsRng};
 use aes_gcm::{Aes256Gcm, Nonce}; // Or `Aes128Gcm`
+use async_trait::async_trait;
 use base64::{engine::general_purpose, Engine as _};
 use chrono::{DateTime, Local};
 use serde::{Deserialize, Serialize};
@@ -18,18 +19,24 @@ pub fn format_number(num: u64) -> String {
     }
 }
 
-fn format_speed(num: f64) -> String {
-    if num >= 1_000_000_000.0 {
-        format!("{:.1}GB/s", num / 1_000_000_000.0)
+pub fn size_str(num: f64) -> String {
+    if num >= 1_000_000_000_000.0 {
+        format!("{:.1}TB", num / 1_000_000_000_000.0)
+    } else if num >= 1_000_000_000.0 {
+        format!("{:.1}GB", num / 1_000_000_000.0)
     } else if num >= 1_000_000.0 {
-        format!("{:.1}MB/s", num / 1_000_000.0)
+        format!("{:.1}MB", num / 1_000_000.0)
     } else if num >= 1_000.0 {
-        format!("{:.1}KB/s", num / 1_000.0)
+        format!("{:.1}KB", num / 1_000.0)
     } else {
-        format!("{:.1}B/s", num)
+        format!("{:.1}B", num)
     }
 }
 
+fn format_speed(num: f64) -> String {
+    format!("{}/s", size_str(num))
+}
+
 pub fn transmit_str(transmit_size: u64, escaped_time: u64) -> String {
     let escaped_time = if escaped_time == 0 { 1 } else { escaped_time };
     let speed = transmit_size as f64 / escaped_time as f64;
@@ -41,7 +48,23 @@ pub fn transmit_str(transmit_size: u64, escaped_time: u64) -> String {
     )
 }
 
+#[cfg(test)]
+thread_local! {
+    static FAKE_TS: std::cell::Cell<Option<u64>> = const { std::cell::Cell::new(None) };
+}
+
+// fake clock for tests, None to go back to the system clock
+#[cfg(test)]
+pub fn set_ts(ts: Option<u64>) {
+    FAKE_TS.with(|t| t.set(ts));
+}
+
 pub fn ts() -> u64 {
+    #[cfg(test)]
+    if let Some(ts) = FAKE_TS.with(|t| t.get()) {
+        return ts;
+    }
+
     SystemTime::now()
         .duration_since(UNIX_EPOCH)
         .unwrap()
@@ -76,6 +99,54 @@ pub fn uptime() -> u64 {
     System::uptime()
 }
 
+// "90", "5m", "1h", "1d" or "1w" in seconds
+pub fn parse_duration(s: &str) -> Result<u64, String> {
+    let (num, unit) = match s.char_indices().last() {
+        Some((idx, c)) if c.is_ascii_alphabetic() => (&s[..idx], c),
+        _ => (s, 's'),
+    };
+    let num = num
+        .parse::<u64>()
+        .map_err(|_| format!("invalid duration: {s:?}"))?;
+    let unit = match unit {
+        's' => 1,
+        'm' => 60,
+        'h' => 60 * 60,
+        'd' => 24 * 60 * 60,
+        'w' => 7 * 24 * 60 * 60,
+        _ => return Err(format!("invalid duration unit: {s:?}")),
+    };
+    let secs = num
+        .checked_mul(unit)
+        .ok_or_else(|| format!("duration too long: {s:?}"))?;
+
+    if secs == 0 {
+        return Err(format!("duration must be positive: {s:?}"));
+    }
+
+    Ok(secs)
+}
+
+const SPARKS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+
+// values as a line of block characters, scaled between their min and max
+pub fn sparkline(values: &[f32]) -> String {
+    let min = values.iter().cloned().fold(f32::INFINITY, f32::min);
+    let max = values.iter().cloned().fold(f32::NEG_INFINITY, f32::max);
+    let range = max - min;
+
+    values
+        .iter()
+        .map(|v| {
+            if range > 0.0 {
+                SPARKS[(((v - min) / range) * 7.0).round() as usize]
+            } else {
+                SPARKS[0]
+            }
+        })
+        .collect()
+}
+
 pub fn uptime_str(uptime: u64) -> String {
     let mut uptime = uptime;
     let days = uptime / 86400;
@@ -171,26 +242,45 @@ pub struct Weather {
     pub daily: Vec<WeatherDaily>,
 }
 
-pub async fn weather(latitude: f32, longitude: f32) -> Result<Weather, String> {
-    let client = reqwest::Client::new();
+// the body of a GET, answered without the network in the tests
+#[async_trait]
+pub trait Fetch: Send + Sync + std::fmt::Debug {
+    async fn get(&self, url: &str, timeout: u64) -> Result<String, String>;
+}
+
+#[derive(Debug)]
+pub struct Http;
+
+#[async_trait]
+impl Fetch for Http {
+    async fn get(&self, url: &str, timeout: u64) -> Result<String, String> {
+        let response = reqwest::Client::new()
+            .get(url)
+            .timeout(tokio::time::Duration::from_secs(timeout))
+            .send()
+            .await
+            .map_err(|e| e.to_string())?;
+
+        response.text().await.map_err(|e| e.to_string())
+    }
+}
+
+pub async fn weather(fetch: &dyn Fetch, latitude: f32, longitude: f32) -> Result<Weather, String> {
     let url = format!(
         "https://api.open-meteo.com/v1/forecast?latitude={latitude}&longitude={longitude}&daily=temperature_2m_max,temperature_2m_min,precipitation_probability_max,weather_code&current_weather=true"
     );
 
-    let response = client
-        .get(url)
-        .timeout(tokio::time::Duration::from_secs(5))
-        .send()
+    let body = fetch
+        .get(&url, 5)
         .await
         .map_err(|e| format!("Failed to get weather: {e}"))?;
 
-    let body = response
-        .text()
-        .await
-        .map_err(|e| format!("Failed to get weather: {e}"))?;
+    parse_weather(&body)
+}
 
+pub fn parse_weather(body: &str) -> Result<Weather, String> {
     let weather_data: serde_json::Value =
-        serde_json::from_str(&body).map_err(|e| format!("Failed to parse weather data: {e}"))?;
+        serde_json::from_str(body).map_err(|e| format!("Failed to parse weather data: {e}"))?;
 
     let current = &weather_data["current_weather"];
     let time = current["time"]
@@ -414,17 +504,24 @@ pub fn get_tailscale_ip() -> String {
 use std::fs::File;
 use std::io::{BufReader, Read};
 
+// a buffer at a time, so a large file is never read into memory
 pub fn calculate_md5(path: &str) -> std::io::Result<String> {
     let file = File::open(path)?;
     let mut reader = BufReader::new(file);
-    let mut buffer = Vec::new();
-    reader.read_to_end(&mut buffer)?;
+    let mut buffer = vec![0; 64 * 1024];
+    let mut context = md5::Context::new();
+    loop {
+        let n = reader.read(&mut buffer)?;
+        if n == 0 {
+            break;
+        }
+        context.consume(&buffer[..n]);
+    }
 
-    let digest = md5::compute(buffer);
-    Ok(format!("{:x}", digest))
+    Ok(format!("{:x}", context.compute()))
 }
 
-#[derive(Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Stock {
     pub code: String,
     pub name: String,
@@ -449,29 +546,20 @@ impl Stock {
     }
 }
 
-pub async fn get_stock_info(code: &str) -> Result<Stock, String> {
-    let response = match reqwest::Client::new()
-        .get(format!(
-            "https://mis.twse.com.tw/stock/api/getStockInfo.jsp?ex_ch=tse_{code}.tw"
-        ))
-        .timeout(tokio::time::Duration::from_secs(10))
-        .send()
-        .await
-    {
+pub async fn get_stock_info(fetch: &dyn Fetch, code: &str) -> Result<Stock, String> {
+    let url = format!("https://mis.twse.com.tw/stock/api/getStockInfo.jsp?ex_ch=tse_{code}.tw");
+    let response = match fetch.get(&url, 10).await {
         Ok(response) => response,
         Err(e) => {
             return Err(format!("Failed to get stock_price: {e}"));
         }
     };
 
-    let response = match response.text().await {
-        Ok(response) => response,
-        Err(e) => {
-            return Err(format!("Failed to get stock_price: {e}"));
-        }
-    };
+    parse_stock_info(code, &response)
+}
 
-    let response: serde_json::Value = match serde_json::from_str(&response) {
+pub fn parse_stock_info(code: &str, response: &str) -> Result<Stock, String> {
+    let response: serde_json::Value = match serde_json::from_str(response) {
         Ok(response) => response,
         Err(e) => {
             return Err(format!("Failed to get stock_price: {e}"));
@@ -509,3 +597,66 @@ pub async fn get_stock_info(code: &str) -> Result<Stock, String> {
         datetime: datetime.to_owned(),
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::harness::{FAKE_STOCK, FAKE_WEATHER};
+
+    #[test]
+    fn parse_weather_ok() {
+        let weather = parse_weather(FAKE_WEATHER).unwrap();
+        assert_eq!(weather.time, "2025-05-01T10:00");
+        assert_eq!(weather.temperature, 27.5);
+        assert_eq!(weather.weathercode, 3);
+        assert_eq!(weather.daily.len(), 2);
+        assert_eq!(weather.daily[1].time, "2025-05-02");
+        assert_eq!(weather.daily[1].precipitation_probability_max, 80);
+        assert_eq!(weather.daily[1].weather_code, 61);
+    }
+
+    #[test]
+    fn parse_weather_err() {
+        assert!(parse_weather("not json").is_err());
+        assert_eq!(
+            parse_weather(r#"{"current_weather": {}}"#).unwrap_err(),
+            "Missing current_weather.time"
+        );
+
+        let body = r#"{
+            "current_weather": {"time": "t", "temperature": 1.0, "weathercode": 0},
+            "daily": {
+                "time": ["2025-05-01"],
+                "temperature_2m_max": [30.1, 29.0],
+                "temperature_2m_min": [22.3],
+                "precipitation_probability_max": [10],
+                "weather_code": [3]
+            }
+        }"#;
+        assert_eq!(
+            parse_weather(body).unwrap_err(),
+            "Mismatch in forecast array lengths"
+        );
+    }
+
+    #[test]
+    fn parse_stock_info_ok() {
+        let stock = parse_stock_info("2330", FAKE_STOCK).unwrap();
+        assert_eq!(stock.code, "2330");
+        assert_eq!(stock.name, "台積電");
+        assert_eq!(stock.last_price, "950.00");
+        assert_eq!(stock.high_price, "955.00");
+        assert_eq!(stock.low_price, "940.00");
+        assert_eq!(stock.prev_close, "945.00");
+        assert_eq!(stock.datetime, "20250501 13:30:00");
+    }
+
+    #[test]
+    fn parse_stock_info_err() {
+        assert!(parse_stock_info("2330", "not json").is_err());
+        assert_eq!(
+            parse_stock_info("9999", r#"{"msgArray": []}"#).unwrap_err(),
+            "無此股票"
+        );
+    }
+}
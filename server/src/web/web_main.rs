@@ -24,7 +24,7 @@ const API_V1_UPLOAD: &str = "/api/v1/upload";
 use crate::{
     cfg,
     msg::{self, log, Msg, Reply},
-    utils,
+    shared, utils,
 };
 
 const NAME: &str = "web";
@@ -128,7 +128,19 @@ async fn upload_file(mut payload: Multipart, sender: web::Data<Sender<Msg>>) ->
             .map(sanitize_filename::sanitize)
             .unwrap_or_else(|| format!("upload-{}.bin", uuid::Uuid::new_v4()));
 
-        let filepath = format!("{}/{filename}", cfg::UPLOAD_FOLDER);
+        let filepath = match shared::resolve(&format!("{}/{filename}", cfg::UPLOAD_FOLDER)) {
+            Ok(filepath) => filepath.display().to_string(),
+            Err(e) => {
+                log(
+                    &sender,
+                    Reply::Device(cfg::name()),
+                    log::Level::Error,
+                    format!("[{NAME}] Rejected upload: {e}"),
+                )
+                .await;
+                return HttpResponse::Forbidden().body(e);
+            }
+        };
         info!(&sender, format!("[{NAME}] [Go] {filepath}"));
 
         let start_ts = utils::ts();
@@ -16,6 +16,21 @@ do
 	cd ..
 
 	cd server
-	$HOME/.cargo/bin/cargo run
+	# installed by `system upgrade`
+	if [ -x ./cng ]; then
+		# an upgrade pending at start is for this run to confirm
+		pending=0
+		[ -f ./upgrade.json ] && pending=1
+
+		./cng
+
+		# exited before coming onboard, upgrade.json is left for the
+		# previous binary to report
+		if [ $pending = 1 ] && [ -f ./upgrade.json ] && [ -f ./cng.prev ]; then
+			mv -f ./cng.prev ./cng
+		fi
+	else
+		$HOME/.cargo/bin/cargo run
+	fi
 	cd ..
 done